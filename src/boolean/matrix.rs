@@ -41,8 +41,9 @@ pub fn transitive_closure(m: &Matrix) -> Matrix {
     for row in 0..length {
         for col in 0..length {
             if r_plus[col][row] {
-                for k in 0..length {
-                    r_plus[col][k] = r_plus[col][k] || r_plus[row][k];
+                let reached = r_plus[row].clone();
+                for (cell, reached) in r_plus[col].iter_mut().zip(reached) {
+                    *cell = *cell || reached;
                 }
             }
         }
@@ -79,31 +80,11 @@ pub fn combine_matrix(m1: &Matrix, m2: &Matrix) -> Matrix {
     final_matrix
 }
 
-pub fn print_matrix(m: &Matrix) {
-    let mut iter = m.iter();
-
-    while let Some(row) = iter.next() {
-        let parsed = row
-            .iter()
-            .map(|x| match x {
-                true => 1,
-                false => 0,
-            })
-            .collect::<Vec<i32>>();
-
-        println!("{:?}", parsed);
-    }
-}
-
 pub fn create_identity(size: usize) -> Matrix {
     let mut identity: Matrix = vec![vec![false; size]; size];
 
-    for row in 0..size {
-        for col in 0..size {
-            if row == col {
-                identity[row][col] = true;
-            }
-        }
+    for (row, line) in identity.iter_mut().enumerate() {
+        line[row] = true;
     }
 
     identity
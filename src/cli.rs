@@ -0,0 +1,509 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use lang_translator::compiler::backend::Target;
+use lang_translator::compiler::bytecode::Program;
+use lang_translator::compiler::diagnostics::{Color, Emitter};
+use lang_translator::compiler::opt::manager::PassManager;
+use lang_translator::compiler::vm::Vm;
+use lang_translator::{Emit, Options};
+
+pub const USAGE: &str = "\
+Usage: lang-translator compile <input> [options]
+       lang-translator run <input> [--trace] [--step-limit <n>] [--color <when>]
+
+run executes a .s17b bytecode file, or compiles a source file to bytecode first, on the VM. GET
+reads stdin, PUT writes stdout and --trace writes every instruction to stderr.
+
+Options:
+    -o <file>            Write the output to file, - for stdout
+    --out-dir <dir>      Directory the output goes in, created if missing
+    --emit <stage>       tokens, symbols, polish, bytecode, quads or asm (default)
+    --target <target>    i386 (default), x86-64, gas, c, wasm, llvm, elf, jvm or riscv
+    --passes=<list>      Optimisation passes to run in order: fold, cse, licm, strength, dce, ssa
+    --dump-after=<list>  Write the IR to stderr after each of these passes
+    --registers          Keep values in registers, x86-64, gas and elf only
+    --peephole           Clean up the instructions, x86-64, gas and elf only
+    --color <when>       Colour errors and warnings: auto (default), always or never
+    -h, --help           Print this message
+";
+
+// Exit codes, a usage error is the caller's fault and a compile error the program's
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub input: String,
+    pub output: Option<String>,
+    pub out_dir: Option<String>,
+    pub color: Color,
+    pub options: Options,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunArgs {
+    pub input: String,
+    pub trace: bool,
+    pub step_limit: Option<u64>,
+    pub color: Color,
+}
+
+// What the arguments asked for, help is not an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Compile(Args),
+    Run(RunArgs),
+    Help,
+}
+
+// Extension of the file a stage is written to when no -o is given
+fn extension(options: &Options) -> String {
+    match options.emit {
+        Emit::Tokens => String::from("tokens"),
+        Emit::Symbols => String::from("symbols"),
+        Emit::Polish => String::from("polish"),
+        Emit::Bytecode => String::from("s17b"),
+        Emit::Quads => String::from("ir"),
+        Emit::Asm => options.backend().extension().to_string(),
+    }
+}
+
+impl Command {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut args = args.iter();
+        match args.next().map(String::as_str) {
+            Some("compile") => (),
+            Some("run") => return Command::parse_run(args),
+            Some("-h" | "--help") => return Ok(Command::Help),
+            Some(e) => return Err(format!("[ Error ] Unknown command: {}", e)),
+            None => return Err(String::from("[ Error ] Missing command")),
+        }
+
+        let mut input = None;
+        let mut output = None;
+        let mut out_dir = None;
+        let mut emit = Emit::Asm;
+        let mut target = Target::I386;
+        let mut passes = PassManager::default();
+        let mut color = Color::Auto;
+        let mut registers = false;
+        let mut peephole = false;
+        while let Some(arg) = args.next() {
+            // Options with a value take it as the next argument or after an `=`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next().cloned())
+                    .ok_or(format!("[ Error ] Missing value for {}", flag))
+            };
+            match flag {
+                "-h" | "--help" => return Ok(Command::Help),
+                "-o" => output = Some(value()?),
+                "--out-dir" => out_dir = Some(value()?),
+                "--emit" => emit = value()?.parse()?,
+                "--target" => target = value()?.parse()?,
+                "--color" => color = value()?.parse()?,
+                "--registers" => registers = true,
+                "--peephole" => peephole = true,
+                _ if passes.option(arg)? => (),
+                _ if arg.starts_with('-') && arg != "-" => {
+                    return Err(format!("[ Error ] Unknown option: {}", arg))
+                }
+                _ if input.is_some() => {
+                    return Err(format!("[ Error ] More than one input: {}", arg))
+                }
+                _ => input = Some(arg.clone()),
+            }
+        }
+        for (flag, set) in [("--registers", registers), ("--peephole", peephole)] {
+            if set && target.x86_64().is_none() {
+                return Err(format!(
+                    "[ Error ] {} needs the x86-64, gas or elf target",
+                    flag
+                ));
+            }
+        }
+
+        let mut args = Args {
+            input: input.ok_or("[ Error ] Missing input file")?,
+            output,
+            out_dir,
+            color,
+            options: Options {
+                emit,
+                target,
+                passes,
+                registers,
+                peephole,
+                ..Options::default()
+            },
+        };
+        // The class has to be named after the file it is in
+        let path = args.output_path("class");
+        if let Some(stem) = path
+            .as_deref()
+            .unwrap_or(Path::new(&args.input))
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+        {
+            args.options.name = stem.to_string();
+        }
+        Ok(Command::Compile(args))
+    }
+}
+
+impl Command {
+    fn parse_run(mut args: std::slice::Iter<String>) -> Result<Self, String> {
+        let mut input = None;
+        let mut trace = false;
+        let mut step_limit = None;
+        let mut color = Color::Auto;
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next().cloned())
+                    .ok_or(format!("[ Error ] Missing value for {}", flag))
+            };
+            match flag {
+                "-h" | "--help" => return Ok(Command::Help),
+                "--trace" => trace = true,
+                "--step-limit" => {
+                    let limit = value()?;
+                    step_limit = Some(
+                        limit
+                            .parse()
+                            .map_err(|_| format!("[ Error ] Bad step limit: {}", limit))?,
+                    );
+                }
+                "--color" => color = value()?.parse()?,
+                _ if arg.starts_with('-') => {
+                    return Err(format!("[ Error ] Unknown option: {}", arg))
+                }
+                _ if input.is_some() => {
+                    return Err(format!("[ Error ] More than one input: {}", arg))
+                }
+                _ => input = Some(arg.clone()),
+            }
+        }
+        Ok(Command::Run(RunArgs {
+            input: input.ok_or("[ Error ] Missing input file")?,
+            trace,
+            step_limit,
+            color,
+        }))
+    }
+}
+
+impl Args {
+    // File the output goes to, None for stdout. Without -o it is named after the input.
+    pub fn output_path(&self, extension: &str) -> Option<PathBuf> {
+        let dir = Path::new(self.out_dir.as_deref().unwrap_or("."));
+        match self.output.as_deref() {
+            Some("-") => None,
+            Some(output) => Some(dir.join(output)),
+            None => {
+                let stem = Path::new(&self.input).file_stem().unwrap_or_default();
+                Some(dir.join(stem).with_extension(extension))
+            }
+        }
+    }
+}
+
+// Run the command line, returning the exit code
+pub fn run(args: &[String]) -> i32 {
+    match Command::parse(args) {
+        Ok(Command::Help) => {
+            print!("{}", USAGE);
+            EXIT_OK
+        }
+        Ok(Command::Compile(args)) => match compile(&args) {
+            Ok(()) => EXIT_OK,
+            Err(e) => {
+                eprintln!("{}", e);
+                EXIT_ERROR
+            }
+        },
+        Ok(Command::Run(args)) => match execute(&args) {
+            Ok(()) => EXIT_OK,
+            Err(e) => {
+                eprintln!("{}", e);
+                EXIT_ERROR
+            }
+        },
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            EXIT_USAGE
+        }
+    }
+}
+
+pub fn compile(args: &Args) -> Result<(), String> {
+    let input = args.input.as_str();
+    let source = fs::read_to_string(input)
+        .map_err(|e| format!("[ Error ] Could not read {}: {}", input, e))?;
+    if let Some(dir) = &args.out_dir {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("[ Error ] Could not create {}: {}", dir, e))?;
+    }
+
+    let options = &args.options;
+    let emitter = Emitter::new(input, &source, args.color);
+    let output = lang_translator::compile(&source, options)
+        .map_err(|diagnostics| diagnostics.render(&emitter).trim_end().to_string())?;
+    eprint!("{}", emitter.render_all(&output.warnings));
+    eprint!("{}", output.dumps);
+    let bytes = output.emitted(options.emit);
+    match args.output_path(&extension(options)) {
+        Some(path) => write(&path, &bytes, output.executable)
+            .map_err(|e| format!("[ Error ] Could not write {}: {}", path.display(), e)),
+        None => io::stdout()
+            .write_all(&bytes)
+            .map_err(|e| format!("[ Error ] Could not write the output: {}", e)),
+    }
+}
+
+// Run a bytecode file, or a source file compiled to bytecode, with stdin and stdout
+pub fn execute(args: &RunArgs) -> Result<(), String> {
+    let input = args.input.as_str();
+    let read = |e: io::Error| {
+        let e = e.to_string();
+        format!(
+            "[ Error ] Could not read {}: {}",
+            input,
+            e.trim_start_matches("[ Error ] ")
+        )
+    };
+    let program = match Path::new(input).extension().and_then(|ext| ext.to_str()) {
+        Some("s17b") => Program::read(&mut fs::File::open(input).map_err(read)?).map_err(read)?,
+        _ => {
+            let source = fs::read_to_string(input).map_err(read)?;
+            let options = Options {
+                emit: Emit::Bytecode,
+                ..Options::default()
+            };
+            let emitter = Emitter::new(input, &source, args.color);
+            let output = lang_translator::compile(&source, &options)
+                .map_err(|diagnostics| diagnostics.render(&emitter).trim_end().to_string())?;
+            eprint!("{}", emitter.render_all(&output.warnings));
+            Program::read(&mut output.code.as_slice()).map_err(read)?
+        }
+    };
+
+    let mut trace = io::stderr();
+    let mut vm = Vm::new(&program);
+    if let Some(limit) = args.step_limit {
+        vm = vm.step_limit(limit);
+    }
+    if args.trace {
+        vm = vm.trace(&mut trace);
+    }
+    vm.run(&mut io::stdin().lock(), &mut io::stdout())
+        .map_err(|e| e.to_string())
+}
+
+// Replace whatever is at path, an executable is created with the mode to run it
+fn write(path: &Path, bytes: &[u8], executable: bool) -> io::Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    if executable {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o755);
+    }
+    options.open(path)?.write_all(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_cli_parse() {
+        let command = Command::parse(&args(
+            "compile input.java -o out.asm --emit=quads --target x86-64 --passes=fold,cse,dce \
+             --dump-after=cse --out-dir build --color=never --registers --peephole",
+        ))
+        .unwrap();
+
+        let Command::Compile(compile) = command else {
+            panic!("[ Error ] Not a compile: {:?}", command);
+        };
+        assert_eq!(compile.input, "input.java");
+        assert_eq!(compile.output.as_deref(), Some("out.asm"));
+        assert_eq!(compile.out_dir.as_deref(), Some("build"));
+        assert_eq!(compile.color, Color::Never);
+        assert_eq!(compile.options.emit, Emit::Quads);
+        assert_eq!(compile.options.target, Target::X86_64);
+        assert_eq!(compile.options.passes.pipeline, ["fold", "cse", "dce"]);
+        assert!(compile.options.registers);
+        assert!(compile.options.peephole);
+        assert_eq!(compile.options.name, "out");
+        assert_eq!(Command::parse(&args("--help")), Ok(Command::Help));
+        assert_eq!(
+            Command::parse(&args("compile input.java --help")),
+            Ok(Command::Help)
+        );
+    }
+
+    #[test]
+    fn test_cli_parse_run() {
+        assert_eq!(
+            Command::parse(&args(
+                "run pgm1.s17b --trace --step-limit=100 --color never"
+            )),
+            Ok(Command::Run(RunArgs {
+                input: String::from("pgm1.s17b"),
+                trace: true,
+                step_limit: Some(100),
+                color: Color::Never,
+            }))
+        );
+        let error = |line: &str| Command::parse(&args(line)).unwrap_err();
+        assert_eq!(error("run"), "[ Error ] Missing input file");
+        assert_eq!(
+            error("run a.s17b --step-limit many"),
+            "[ Error ] Bad step limit: many"
+        );
+        assert_eq!(error("run a.s17b -O2"), "[ Error ] Unknown option: -O2");
+        assert_eq!(run(&args("run does/not/exist.s17b")), EXIT_ERROR);
+    }
+
+    #[test]
+    fn test_cli_parse_errors() {
+        let error = |line: &str| Command::parse(&args(line)).unwrap_err();
+
+        assert_eq!(error(""), "[ Error ] Missing command");
+        assert_eq!(error("exec a.java"), "[ Error ] Unknown command: exec");
+        assert_eq!(error("compile"), "[ Error ] Missing input file");
+        assert_eq!(error("compile a.java -o"), "[ Error ] Missing value for -o");
+        assert_eq!(
+            error("compile a.java --emit ast"),
+            "[ Error ] Unknown stage to emit: ast"
+        );
+        assert_eq!(
+            error("compile a.java --target arm"),
+            "[ Error ] Unknown target: arm"
+        );
+        assert_eq!(error("compile a.java -O2"), "[ Error ] Unknown option: -O2");
+        assert_eq!(
+            error("compile a.java b.java"),
+            "[ Error ] More than one input: b.java"
+        );
+        assert_eq!(
+            error("compile a.java --color sometimes"),
+            "[ Error ] Unknown colour setting: sometimes"
+        );
+        assert_eq!(
+            error("compile a.java --registers"),
+            "[ Error ] --registers needs the x86-64, gas or elf target"
+        );
+        assert_eq!(
+            error("compile a.java --target c --peephole"),
+            "[ Error ] --peephole needs the x86-64, gas or elf target"
+        );
+        assert_eq!(
+            error("compile a.java --passes=inline"),
+            "[ Error ] Unknown pass: inline"
+        );
+        assert_eq!(run(&args("compile")), EXIT_USAGE);
+        assert_eq!(run(&args("compile does/not/exist.java")), EXIT_ERROR);
+    }
+
+    #[test]
+    fn test_cli_output_path() {
+        let compile = |line: &str| match Command::parse(&args(line)).unwrap() {
+            Command::Compile(args) => args,
+            command => unreachable!("{:?}", command),
+        };
+
+        assert_eq!(
+            compile("compile src/input.java").output_path("asm"),
+            Some(PathBuf::from("./input.asm"))
+        );
+        assert_eq!(
+            compile("compile src/input.java --out-dir build --emit quads").output_path("ir"),
+            Some(PathBuf::from("build/input.ir"))
+        );
+        assert_eq!(
+            compile("compile input.java --out-dir build -o out.asm").output_path("asm"),
+            Some(PathBuf::from("build/out.asm"))
+        );
+        assert_eq!(compile("compile input.java -o -").output_path("asm"), None);
+    }
+
+    #[test]
+    fn test_cli_compile() {
+        // Each run gets its own directory so tests running at once do not share files
+        let dir = std::env::temp_dir().join(format!("lang-translator-cli-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("pgm1.java");
+        fs::write(
+            &input,
+            "CLASS Pgm1 {\nVAR a, b;\nGET(a);\nb = a + 5 * 2;\nPUT(b);\n}\n",
+        )
+        .unwrap();
+        let compile = |options: &str| {
+            let line = format!(
+                "compile {} --out-dir {} {}",
+                input.display(),
+                dir.display(),
+                options
+            );
+            run(&args(&line))
+        };
+
+        assert_eq!(compile("--emit quads --passes=fold"), EXIT_OK);
+        assert_eq!(
+            fs::read_to_string(dir.join("pgm1.ir")).unwrap(),
+            "    get $a\n\
+             \x20   add %temp_2, $a, 10\n\
+             \x20   copy $b, %temp_2\n\
+             \x20   put $b\n"
+        );
+
+        assert_eq!(compile("--target c -o out.c"), EXIT_OK);
+        assert_eq!(
+            fs::read_to_string(dir.join("out.c")).unwrap(),
+            "#include <inttypes.h>\n#include <stdint.h>\n#include <stdio.h>\n\n\
+             static int16_t v_a = 0;\n\
+             static int16_t v_b = 0;\n\n\
+             int main(void)\n{\n\
+             \x20   if (scanf(\"%\" SCNd16, &v_a) != 1) v_a = 0;\n\
+             \x20   v_b = v_a + 5 * 2;\n\
+             \x20   printf(\"%\" PRId16 \"\\n\", v_b);\n\
+             \x20   return 0;\n}\n"
+        );
+
+        // The bytecode file runs on the VM the way run would run it
+        assert_eq!(compile("--emit bytecode"), EXIT_OK);
+        let mut file = fs::File::open(dir.join("pgm1.s17b")).unwrap();
+        let program = Program::read(&mut file).unwrap();
+        let mut output = Vec::new();
+        Vm::new(&program)
+            .run(&mut "3\n".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, b"13\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::compiler::backend::structured::{self, Expr, Node, Program, Render, Stmt};
+use crate::compiler::backend::Relation;
+
+// Readable C99 output. Single use temps are folded back into expressions and the label/branch
+// shapes IF and WHILE lower to are turned back into if and while blocks. Anything that does not
+// match those shapes is kept as goto. Syntax does not parse IF or WHILE yet, so those shapes only
+// come from IR read with ir::parse.
+pub struct C;
+
+// Prints an expression with C operator precedence
+struct Infix<'a>(&'a Expr);
+
+// Every name the program chose is written with a prefix, so a variable called `int`, `main` or
+// `printf` cannot clash with C or the C library
+fn var(name: &str) -> String {
+    format!("v_{}", name)
+}
+
+fn procedure(name: &str) -> String {
+    format!("p_{}", name)
+}
+
+fn label(name: &str) -> String {
+    format!("l_{}", name)
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Bin('+', _, _) | Expr::Bin('-', _, _) => 1,
+        Expr::Bin(_, _, _) => 2,
+        _ => 3,
+    }
+}
+
+// Operators are left associative, so the right hand side needs brackets at equal precedence
+impl fmt::Display for Infix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Expr::Var(name) => write!(f, "{}", var(name)),
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Bin(op, left, right) => {
+                if precedence(left) < precedence(self.0) {
+                    write!(f, "({})", Infix(left))?;
+                } else {
+                    write!(f, "{}", Infix(left))?;
+                }
+                write!(f, " {} ", op)?;
+                if precedence(right) <= precedence(self.0) {
+                    write!(f, "({})", Infix(right))
+                } else {
+                    write!(f, "{}", Infix(right))
+                }
+            }
+        }
+    }
+}
+
+impl C {
+    fn operator(rel: Relation) -> &'static str {
+        match rel {
+            Relation::Equal => "==",
+            Relation::NEqual => "!=",
+            Relation::GreaterThan => ">",
+            Relation::LessThan => "<",
+            Relation::GEqual => ">=",
+            Relation::LEqual => "<=",
+        }
+    }
+
+    fn write_condition(
+        out: &mut dyn Write,
+        rel: Relation,
+        left: &Expr,
+        right: &Expr,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "{} {} {}",
+            Infix(left),
+            C::operator(rel),
+            Infix(right)
+        ))
+    }
+
+    fn write_nodes(out: &mut dyn Write, nodes: &[Node], depth: usize) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        for node in nodes {
+            match node {
+                Node::Stmt(Stmt::Assign(dest, expr)) => {
+                    out.write_fmt(format_args!("{}{} = {};\n", indent, var(dest), Infix(expr)))?
+                }
+                // EOF or anything that is not a number reads as 0
+                Node::Stmt(Stmt::Get(dest)) => out.write_fmt(format_args!(
+                    "{}if (scanf(\"%\" SCNd16, &{}) != 1) {} = 0;\n",
+                    indent,
+                    var(dest),
+                    var(dest)
+                ))?,
+                Node::Stmt(Stmt::Put(expr)) => out.write_fmt(format_args!(
+                    "{}printf(\"%\" PRId16 \"\\n\", {});\n",
+                    indent,
+                    Infix(expr)
+                ))?,
+                Node::Stmt(Stmt::Call(name)) => {
+                    out.write_fmt(format_args!("{}{}();\n", indent, procedure(name)))?
+                }
+                Node::Stmt(Stmt::Label(name)) => {
+                    out.write_fmt(format_args!("{}:;\n", label(name)))?
+                }
+                Node::Stmt(Stmt::Goto(name)) => {
+                    out.write_fmt(format_args!("{}goto {};\n", indent, label(name)))?
+                }
+                Node::Stmt(Stmt::Branch(rel, left, right, name)) => {
+                    out.write_fmt(format_args!("{}if (", indent))?;
+                    C::write_condition(out, rel.negate(), left, right)?;
+                    out.write_fmt(format_args!(") goto {};\n", label(name)))?;
+                }
+                Node::If(rel, left, right, body) => {
+                    out.write_fmt(format_args!("{}if (", indent))?;
+                    C::write_condition(out, *rel, left, right)?;
+                    out.write_fmt(format_args!(") {{\n"))?;
+                    C::write_nodes(out, body, depth + 1)?;
+                    out.write_fmt(format_args!("{}}}\n", indent))?;
+                }
+                Node::While(_, _, rel, left, right, body) => {
+                    out.write_fmt(format_args!("{}while (", indent))?;
+                    C::write_condition(out, *rel, left, right)?;
+                    out.write_fmt(format_args!(") {{\n"))?;
+                    C::write_nodes(out, body, depth + 1)?;
+                    out.write_fmt(format_args!("{}}}\n", indent))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_function(out: &mut dyn Write, program: &Program, body: &[Stmt]) -> io::Result<()> {
+        let body = program.fold_temps(body);
+        for local in program.locals(&body) {
+            out.write_fmt(format_args!("    int16_t {};\n", var(&local)))?;
+        }
+        let refs = structured::label_refs(&body);
+        C::write_nodes(out, &structured::structure(&body, &refs), 1)
+    }
+}
+
+impl Render for C {
+    fn extension(&self) -> &str {
+        "c"
+    }
+
+    fn render(&self, out: &mut dyn Write, program: &Program) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "#include <inttypes.h>\n#include <stdint.h>\n#include <stdio.h>\n\n"
+        ))?;
+
+        let globals = program.globals();
+        for global in &globals {
+            out.write_fmt(format_args!(
+                "static int16_t {} = {};\n",
+                var(global),
+                program.value(global)
+            ))?;
+        }
+        if !globals.is_empty() {
+            out.write_fmt(format_args!("\n"))?;
+        }
+
+        for (name, _) in &program.procedures {
+            out.write_fmt(format_args!("static void {}(void);\n", procedure(name)))?;
+        }
+        for (name, body) in &program.procedures {
+            out.write_fmt(format_args!(
+                "\nstatic void {}(void)\n{{\n",
+                procedure(name)
+            ))?;
+            C::write_function(out, program, body)?;
+            out.write_fmt(format_args!("}}\n"))?;
+        }
+        if !program.procedures.is_empty() {
+            out.write_fmt(format_args!("\n"))?;
+        }
+
+        out.write_fmt(format_args!("int main(void)\n{{\n"))?;
+        C::write_function(out, program, &program.main)?;
+        out.write_fmt(format_args!("    return 0;\n}}\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::backend::sample::var;
+    use crate::compiler::backend::structured::Structured;
+    use crate::compiler::backend::{Symbol, SymbolClass};
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::ir;
+    use crate::compiler::syntax::{Opcode, Operand, Quad};
+
+    fn generate(quads: crate::compiler::syntax::QuadList, symbols: Vec<Symbol>) -> String {
+        let mut gen =
+            Generator::with_writer(quads, symbols, Box::new(Structured::new(C)), Vec::new());
+        gen.consume_quads().unwrap();
+        String::from_utf8(gen.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_c_output() {
+        let source = generate(sample::quads(), sample::symbols());
+
+        assert_eq!(
+            source,
+            "#include <inttypes.h>\n\
+             #include <stdint.h>\n\
+             #include <stdio.h>\n\
+             \n\
+             static int16_t v_a = 0;\n\
+             static int16_t v_b = 0;\n\
+             static int16_t v_c = 0;\n\
+             \n\
+             static void p_show(void);\n\
+             \n\
+             static void p_show(void)\n\
+             {\n\
+             \x20   v_c = (v_a + v_b) * 3;\n\
+             \x20   printf(\"%\" PRId16 \"\\n\", v_c);\n\
+             }\n\
+             \n\
+             int main(void)\n\
+             {\n\
+             \x20   if (scanf(\"%\" SCNd16, &v_a) != 1) v_a = 0;\n\
+             \x20   if (scanf(\"%\" SCNd16, &v_b) != 1) v_b = 0;\n\
+             \x20   while (v_a > 0) {\n\
+             \x20       v_a = v_a - 1;\n\
+             \x20       v_b = v_b * 2;\n\
+             \x20   }\n\
+             \x20   if (v_a < v_b) {\n\
+             \x20       p_show();\n\
+             \x20   }\n\
+             \x20   printf(\"%\" PRId16 \"\\n\", v_b / 2);\n\
+             \x20   return 0;\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_c_unstructured_jumps() {
+        // A jump back to a label that is also branched to cannot become a while loop
+        let quads = ir::parse(
+            "L1:\n\
+             \x20   br.eq $a, $b, @L1\n\
+             \x20   jmp @L1\n",
+        )
+        .unwrap();
+
+        let source = generate(quads, Vec::new());
+
+        assert!(source.contains("l_L1:;\n    if (v_a != v_b) goto l_L1;\n    goto l_L1;\n"));
+        assert!(source.contains("static int16_t v_a = 0;\nstatic int16_t v_b = 0;\n"));
+    }
+
+    #[test]
+    fn test_c_keyword_names() {
+        // Variables named after C keywords and the library functions the output calls
+        let quads = vec![
+            Quad::get(var("int")),
+            Quad::binary(Opcode::Add, var("int"), var("main"), Operand::temp(1)),
+            Quad::assign(Operand::temp(1), var("printf")),
+            Quad::assign(var("scanf"), var("return")),
+            Quad::put(var("printf")),
+            Quad::put(var("return")),
+        ];
+        let symbol = |name: &str, class| Symbol {
+            name: name.to_string(),
+            class,
+            value: 0,
+        };
+        let mut symbols: Vec<_> = ["int", "main", "printf", "scanf", "return"]
+            .iter()
+            .map(|name| symbol(name, SymbolClass::Identifier))
+            .collect();
+        symbols.push(symbol("temp_1", SymbolClass::Temp));
+
+        let source = generate(quads, symbols);
+
+        assert!(source.contains(
+            "static int16_t v_int = 0;\n\
+             static int16_t v_main = 0;\n\
+             static int16_t v_printf = 0;\n\
+             static int16_t v_scanf = 0;\n\
+             static int16_t v_return = 0;\n\
+             \n\
+             int main(void)\n\
+             {\n\
+             \x20   if (scanf(\"%\" SCNd16, &v_int) != 1) v_int = 0;\n\
+             \x20   v_printf = v_int + v_main;\n\
+             \x20   v_return = v_scanf;\n\
+             \x20   printf(\"%\" PRId16 \"\\n\", v_printf);\n\
+             \x20   printf(\"%\" PRId16 \"\\n\", v_return);\n\
+             \x20   return 0;\n\
+             }\n"
+        ));
+    }
+}
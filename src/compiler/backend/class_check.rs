@@ -0,0 +1,627 @@
+// Reads back the class files the JVM backend writes and checks what the JVM would: the constant
+// pool references have the right kinds, every instruction decodes, branches land on instruction
+// boundaries, the operand stack never underflows or passes max_stack, locals stay under
+// max_locals, and there is a StackMapTable frame at every branch target and after every goto or
+// return. Only the instructions and constants the backend uses are understood.
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Constant {
+    Utf8(String),
+    Integer(i32),
+    Class(u16),
+    NameAndType(u16, u16),
+    Fieldref(u16, u16),
+    Methodref(u16, u16),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub super_name: String,
+    pub fields: Vec<(String, String)>,
+    pub methods: Vec<Method>,
+}
+
+#[derive(Debug)]
+pub struct Method {
+    pub name: String,
+    pub descriptor: String,
+    pub max_stack: u16,
+    pub max_locals: u16,
+    pub code: Vec<u8>,
+    // Bytecode offsets that have a stack map frame
+    pub frames: Vec<usize>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or(format!("[ Error ] Class file ends at {}", self.bytes.len()))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+struct Pool(Vec<Constant>);
+
+impl Pool {
+    fn get(&self, index: u16) -> Result<&Constant, String> {
+        match index {
+            0 => Err("[ Error ] Constant pool index 0".to_string()),
+            _ => self.0.get(index as usize - 1).ok_or(format!(
+                "[ Error ] Constant pool index {} out of range",
+                index
+            )),
+        }
+    }
+
+    fn utf8(&self, index: u16) -> Result<&str, String> {
+        match self.get(index)? {
+            Constant::Utf8(value) => Ok(value),
+            c => Err(format!(
+                "[ Error ] Expected Utf8 at {}, found {:?}",
+                index, c
+            )),
+        }
+    }
+
+    fn class(&self, index: u16) -> Result<&str, String> {
+        match self.get(index)? {
+            Constant::Class(name) => self.utf8(*name),
+            c => Err(format!(
+                "[ Error ] Expected Class at {}, found {:?}",
+                index, c
+            )),
+        }
+    }
+
+    fn name_and_type(&self, index: u16) -> Result<(&str, &str), String> {
+        match self.get(index)? {
+            Constant::NameAndType(name, descriptor) => {
+                Ok((self.utf8(*name)?, self.utf8(*descriptor)?))
+            }
+            c => Err(format!(
+                "[ Error ] Expected NameAndType at {}, found {:?}",
+                index, c
+            )),
+        }
+    }
+
+    // Descriptor of a Fieldref or Methodref, checking which of the two it is
+    fn member(&self, index: u16, field: bool) -> Result<&str, String> {
+        let (class, name_and_type) = match (self.get(index)?, field) {
+            (Constant::Fieldref(class, nat), true) | (Constant::Methodref(class, nat), false) => {
+                (*class, *nat)
+            }
+            (c, _) => return Err(format!("[ Error ] Unexpected member at {}: {:?}", index, c)),
+        };
+        self.class(class)?;
+        Ok(self.name_and_type(name_and_type)?.1)
+    }
+}
+
+fn read_pool(reader: &mut Reader) -> Result<Pool, String> {
+    let count = reader.u16()?;
+    let mut constants = Vec::new();
+    for _ in 1..count {
+        let constant = match reader.u8()? {
+            1 => {
+                let len = reader.u16()? as usize;
+                let bytes = reader.take(len)?.to_vec();
+                Constant::Utf8(String::from_utf8(bytes).map_err(|e| e.to_string())?)
+            }
+            3 => Constant::Integer(reader.u32()? as i32),
+            7 => Constant::Class(reader.u16()?),
+            9 => Constant::Fieldref(reader.u16()?, reader.u16()?),
+            10 => Constant::Methodref(reader.u16()?, reader.u16()?),
+            12 => Constant::NameAndType(reader.u16()?, reader.u16()?),
+            tag => return Err(format!("[ Error ] Unknown constant tag: {}", tag)),
+        };
+        constants.push(constant);
+    }
+
+    // Every reference inside the pool has to point at the right kind of constant
+    let pool = Pool(constants);
+    for (index, constant) in pool.0.iter().enumerate() {
+        match constant {
+            Constant::Class(name) => {
+                pool.utf8(*name)?;
+            }
+            Constant::NameAndType(..) => {
+                pool.name_and_type(index as u16 + 1)?;
+            }
+            Constant::Fieldref(..) => {
+                pool.member(index as u16 + 1, true)?;
+            }
+            Constant::Methodref(..) => {
+                pool.member(index as u16 + 1, false)?;
+            }
+            Constant::Utf8(_) | Constant::Integer(_) => (),
+        }
+    }
+    Ok(pool)
+}
+
+// Stack slots taken by the values of a descriptor, only int, boolean, references and void occur
+fn slots(descriptor: &str) -> Result<i32, String> {
+    match descriptor {
+        "V" => Ok(0),
+        "I" | "Z" => Ok(1),
+        d if d.starts_with('L') && d.ends_with(';') => Ok(1),
+        d if d.starts_with('[') => Ok(1),
+        d => Err(format!("[ Error ] Unsupported descriptor: {}", d)),
+    }
+}
+
+// Slots popped for the arguments and pushed for the result
+fn method_slots(descriptor: &str) -> Result<(i32, i32), String> {
+    let close = descriptor
+        .find(')')
+        .filter(|_| descriptor.starts_with('('))
+        .ok_or(format!("[ Error ] Bad method descriptor: {}", descriptor))?;
+    let mut args = 0;
+    let mut params = &descriptor[1..close];
+    while !params.is_empty() {
+        let len = match params.as_bytes()[0] {
+            b'L' => params.find(';').map(|end| end + 1),
+            b'[' => {
+                let element = params.trim_start_matches('[');
+                let dims = params.len() - element.len();
+                match element.as_bytes().first() {
+                    Some(b'L') => element.find(';').map(|end| dims + end + 1),
+                    Some(_) => Some(dims + 1),
+                    None => None,
+                }
+            }
+            _ => Some(1),
+        }
+        .ok_or(format!("[ Error ] Bad method descriptor: {}", descriptor))?;
+        args += slots(&params[..len])?;
+        params = &params[len..];
+    }
+    Ok((args, slots(&descriptor[close + 1..])?))
+}
+
+fn read_frames(reader: &mut Reader) -> Result<Vec<usize>, String> {
+    let skip_types = |reader: &mut Reader, count: u16| -> Result<(), String> {
+        for _ in 0..count {
+            match reader.u8()? {
+                0..=6 => (),
+                7 | 8 => {
+                    reader.u16()?;
+                }
+                tag => return Err(format!("[ Error ] Unknown verification type: {}", tag)),
+            }
+        }
+        Ok(())
+    };
+
+    let count = reader.u16()?;
+    let mut frames = Vec::new();
+    for _ in 0..count {
+        let delta = match reader.u8()? {
+            kind @ 0..=63 => kind as usize,
+            kind @ 64..=127 => {
+                skip_types(reader, 1)?;
+                kind as usize - 64
+            }
+            247 => {
+                let delta = reader.u16()?;
+                skip_types(reader, 1)?;
+                delta as usize
+            }
+            248..=251 => reader.u16()? as usize,
+            kind @ 252..=254 => {
+                let delta = reader.u16()?;
+                skip_types(reader, kind as u16 - 251)?;
+                delta as usize
+            }
+            255 => {
+                let delta = reader.u16()?;
+                let locals = reader.u16()?;
+                skip_types(reader, locals)?;
+                let stack = reader.u16()?;
+                if stack != 0 {
+                    return Err("[ Error ] Frame with a non empty stack".to_string());
+                }
+                delta as usize
+            }
+            kind => return Err(format!("[ Error ] Unknown frame type: {}", kind)),
+        };
+        let offset = match frames.last() {
+            Some(previous) => previous + delta + 1,
+            None => delta,
+        };
+        frames.push(offset);
+    }
+    Ok(frames)
+}
+
+// Operand stack effect of one instruction, its length, and where it can go next
+struct Decoded {
+    len: usize,
+    pop: i32,
+    push: i32,
+    target: Option<usize>,
+    falls_through: bool,
+    local: Option<u16>,
+}
+
+fn decode(code: &[u8], at: usize, pool: &Pool) -> Result<Decoded, String> {
+    let byte = |offset: usize| {
+        code.get(at + offset).copied().ok_or(format!(
+            "[ Error ] Instruction at {} runs past the code",
+            at
+        ))
+    };
+    let index = || -> Result<u16, String> { Ok(u16::from_be_bytes([byte(1)?, byte(2)?])) };
+    let simple = |len, pop, push| Decoded {
+        len,
+        pop,
+        push,
+        target: None,
+        falls_through: true,
+        local: None,
+    };
+
+    let opcode = byte(0)?;
+    let decoded = match opcode {
+        // iconst_m1 .. iconst_5
+        0x02..=0x08 => simple(1, 0, 1),
+        0x10 => simple(2, 0, 1),
+        0x11 => simple(3, 0, 1),
+        0x13 => match pool.get(index()?)? {
+            Constant::Integer(_) => simple(3, 0, 1),
+            c => return Err(format!("[ Error ] ldc_w of {:?}", c)),
+        },
+        0x15 | 0x36 => Decoded {
+            local: Some(byte(1)? as u16),
+            ..match opcode {
+                0x15 => simple(2, 0, 1),
+                _ => simple(2, 1, 0),
+            }
+        },
+        // iload_0 .. iload_3, istore_0 .. istore_3
+        0x1a..=0x1d => Decoded {
+            local: Some(opcode as u16 - 0x1a),
+            ..simple(1, 0, 1)
+        },
+        0x3b..=0x3e => Decoded {
+            local: Some(opcode as u16 - 0x3b),
+            ..simple(1, 1, 0)
+        },
+        0x59 => simple(1, 1, 2),
+        0x60 | 0x64 | 0x68 | 0x6c => simple(1, 2, 1),
+        // ifeq .. ifle, if_icmpeq .. if_icmple, goto
+        0x99..=0xa4 | 0xa7 => {
+            let offset = index()? as i16 as i64;
+            let target = usize::try_from(at as i64 + offset)
+                .map_err(|_| format!("[ Error ] Branch at {} before the code", at))?;
+            let pop = match opcode {
+                0x99..=0x9e => 1,
+                0x9f..=0xa4 => 2,
+                _ => 0,
+            };
+            Decoded {
+                target: Some(target),
+                falls_through: opcode != 0xa7,
+                ..simple(3, pop, 0)
+            }
+        }
+        0xac => Decoded {
+            falls_through: false,
+            ..simple(1, 1, 0)
+        },
+        0xb1 => Decoded {
+            falls_through: false,
+            ..simple(1, 0, 0)
+        },
+        0xb2 => simple(3, 0, slots(pool.member(index()?, true)?)?),
+        0xb3 => simple(3, slots(pool.member(index()?, true)?)?, 0),
+        // invokevirtual and invokespecial also pop the receiver
+        0xb6..=0xb8 => {
+            let (args, result) = method_slots(pool.member(index()?, false)?)?;
+            let receiver = (opcode != 0xb8) as i32;
+            simple(3, args + receiver, result)
+        }
+        0xbb => {
+            pool.class(index()?)?;
+            simple(3, 0, 1)
+        }
+        op => return Err(format!("[ Error ] Unknown opcode {:#04x} at {}", op, at)),
+    };
+    Ok(decoded)
+}
+
+fn check_code(method: &Method, pool: &Pool) -> Result<(), String> {
+    let code = &method.code;
+    let mut decoded = HashMap::new();
+    let mut at = 0;
+    while at < code.len() {
+        let inst = decode(code, at, pool)?;
+        at += inst.len;
+        decoded.insert(at - inst.len, inst);
+    }
+
+    for (&at, inst) in &decoded {
+        if let Some(local) = inst.local {
+            if local >= method.max_locals {
+                return Err(format!(
+                    "[ Error ] Local {} at {} past max_locals",
+                    local, at
+                ));
+            }
+        }
+        if let Some(target) = inst.target {
+            if !decoded.contains_key(&target) {
+                return Err(format!("[ Error ] Branch at {} into an instruction", at));
+            }
+            if !method.frames.contains(&target) {
+                return Err(format!("[ Error ] No frame at branch target {}", target));
+            }
+        }
+        let next = at + inst.len;
+        if !inst.falls_through && next < code.len() && !method.frames.contains(&next) {
+            return Err(format!("[ Error ] No frame after {}", at));
+        }
+    }
+    for frame in &method.frames {
+        if !decoded.contains_key(frame) {
+            return Err(format!(
+                "[ Error ] Frame at {} is not an instruction",
+                frame
+            ));
+        }
+    }
+
+    // Walk every path, frames have an empty stack so the depth there has to be 0
+    let mut depths: HashMap<usize, i32> = HashMap::new();
+    let mut work = vec![(0, 0)];
+    for &frame in &method.frames {
+        work.push((frame, 0));
+    }
+    while let Some((at, depth)) = work.pop() {
+        if let Some(&seen) = depths.get(&at) {
+            if seen != depth {
+                return Err(format!(
+                    "[ Error ] Stack depth {} and {} at {}",
+                    seen, depth, at
+                ));
+            }
+            continue;
+        }
+        if method.frames.contains(&at) && depth != 0 {
+            return Err(format!("[ Error ] Stack depth {} at frame {}", depth, at));
+        }
+        depths.insert(at, depth);
+        let inst = decoded
+            .get(&at)
+            .ok_or(format!("[ Error ] Control falls off the code at {}", at))?;
+        if depth < inst.pop {
+            return Err(format!("[ Error ] Stack underflow at {}", at));
+        }
+        let depth = depth - inst.pop + inst.push;
+        if depth > method.max_stack as i32 {
+            return Err(format!(
+                "[ Error ] Stack depth {} at {} past max_stack",
+                depth, at
+            ));
+        }
+        if let Some(target) = inst.target {
+            work.push((target, depth));
+        }
+        if inst.falls_through {
+            work.push((at + inst.len, depth));
+        }
+    }
+    Ok(())
+}
+
+fn read_method(reader: &mut Reader, pool: &Pool) -> Result<Method, String> {
+    reader.u16()?;
+    let name = pool.utf8(reader.u16()?)?.to_string();
+    let descriptor = pool.utf8(reader.u16()?)?.to_string();
+    method_slots(&descriptor)?;
+
+    let mut method = None;
+    for _ in 0..reader.u16()? {
+        let attribute = pool.utf8(reader.u16()?)?.to_string();
+        let len = reader.u32()? as usize;
+        let mut body = Reader {
+            bytes: reader.take(len)?,
+            at: 0,
+        };
+        if attribute != "Code" {
+            continue;
+        }
+
+        let max_stack = body.u16()?;
+        let max_locals = body.u16()?;
+        let code_len = body.u32()? as usize;
+        let code = body.take(code_len)?.to_vec();
+        if body.u16()? != 0 {
+            return Err("[ Error ] Unexpected exception table".to_string());
+        }
+        let mut frames = Vec::new();
+        for _ in 0..body.u16()? {
+            let attribute = pool.utf8(body.u16()?)?.to_string();
+            let len = body.u32()? as usize;
+            let mut table = Reader {
+                bytes: body.take(len)?,
+                at: 0,
+            };
+            if attribute == "StackMapTable" {
+                frames = read_frames(&mut table)?;
+                if table.at != len {
+                    return Err("[ Error ] Trailing bytes in StackMapTable".to_string());
+                }
+            }
+        }
+        if body.at != len {
+            return Err(format!("[ Error ] Trailing bytes in Code of {}", name));
+        }
+        method = Some(Method {
+            name: name.clone(),
+            descriptor: descriptor.clone(),
+            max_stack,
+            max_locals,
+            code,
+            frames,
+        });
+    }
+
+    let method = method.ok_or(format!("[ Error ] Method {} has no Code", name))?;
+    check_code(&method, pool).map_err(|e| format!("{} in {}", e, name))?;
+    Ok(method)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Class, String> {
+    let mut reader = Reader { bytes, at: 0 };
+    if reader.u32()? != 0xcafebabe {
+        return Err("[ Error ] Bad magic".to_string());
+    }
+    reader.u16()?;
+    if reader.u16()? < 50 {
+        return Err("[ Error ] Class file older than StackMapTable".to_string());
+    }
+    let pool = read_pool(&mut reader)?;
+
+    reader.u16()?;
+    let name = pool.class(reader.u16()?)?.to_string();
+    let super_name = pool.class(reader.u16()?)?.to_string();
+    if reader.u16()? != 0 {
+        return Err("[ Error ] Unexpected interfaces".to_string());
+    }
+
+    let mut fields = Vec::new();
+    for _ in 0..reader.u16()? {
+        reader.u16()?;
+        let name = pool.utf8(reader.u16()?)?.to_string();
+        let descriptor = pool.utf8(reader.u16()?)?.to_string();
+        slots(&descriptor)?;
+        for _ in 0..reader.u16()? {
+            reader.u16()?;
+            let len = reader.u32()? as usize;
+            reader.take(len)?;
+        }
+        fields.push((name, descriptor));
+    }
+
+    let mut methods = Vec::new();
+    for _ in 0..reader.u16()? {
+        methods.push(read_method(&mut reader, &pool)?);
+    }
+    for _ in 0..reader.u16()? {
+        reader.u16()?;
+        let len = reader.u32()? as usize;
+        reader.take(len)?;
+    }
+    if reader.at != bytes.len() {
+        return Err("[ Error ] Trailing bytes after the class".to_string());
+    }
+
+    Ok(Class {
+        name,
+        super_name,
+        fields,
+        methods,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A class with one static void method holding `code`, and the frames for it
+    fn class(code: &[u8], max_stack: u8, frames: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 52, 0, 9];
+        for name in ["T", "java/lang/Object", "f", "()V", "Code", "StackMapTable"] {
+            bytes.extend_from_slice(&[1, 0, name.len() as u8]);
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        bytes.extend_from_slice(&[7, 0, 1]);
+        bytes.extend_from_slice(&[7, 0, 2]);
+        bytes.extend_from_slice(&[0, 0x21, 0, 7, 0, 8, 0, 0, 0, 0, 0, 1]);
+        bytes.extend_from_slice(&[0, 8, 0, 3, 0, 4, 0, 1, 0, 5]);
+
+        let mut attribute = vec![0, max_stack, 0, 1];
+        attribute.extend_from_slice(&(code.len() as u32).to_be_bytes());
+        attribute.extend_from_slice(code);
+        attribute.extend_from_slice(&[0, 0]);
+        if frames.is_empty() {
+            attribute.extend_from_slice(&[0, 0]);
+        } else {
+            attribute.extend_from_slice(&[0, 1, 0, 6]);
+            attribute.extend_from_slice(&(frames.len() as u32 + 2).to_be_bytes());
+            attribute.extend_from_slice(&[0, frames.len() as u8]);
+            attribute.extend_from_slice(frames);
+        }
+        bytes.extend_from_slice(&(attribute.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&attribute);
+        bytes.extend_from_slice(&[0, 0]);
+        bytes
+    }
+
+    #[test]
+    fn test_class_accepts() {
+        // iconst_1; istore_0; iload_0; ifeq +4; return; [frame] return
+        let code = [0x04, 0x3b, 0x1a, 0x99, 0, 4, 0xb1, 0xb1];
+        let parsed = parse(&class(&code, 1, &[7])).unwrap();
+
+        assert_eq!(parsed.name, "T");
+        assert_eq!(parsed.methods[0].name, "f");
+        assert_eq!(parsed.methods[0].frames, [7]);
+        assert_eq!(method_slots("([Ljava/lang/String;II)I"), Ok((3, 1)));
+    }
+
+    #[test]
+    fn test_class_rejects() {
+        let cases: Vec<(&[u8], u8, &[u8])> = vec![
+            // No frame at the branch target
+            (&[0x04, 0x99, 0, 4, 0xb1, 0xb1], 1, &[]),
+            // Branch into the middle of an instruction
+            (&[0x04, 0x99, 0, 2, 0xb1], 1, &[3]),
+            // Stack past max_stack
+            (&[0x04, 0x04, 0x60, 0x3b, 0xb1], 1, &[]),
+            // Underflow
+            (&[0x60, 0xb1], 2, &[]),
+            // Local past max_locals
+            (&[0x04, 0x3c, 0xb1], 1, &[]),
+            // Falls off the end
+            (&[0x04, 0x3b], 1, &[]),
+            // Code after a return without a frame
+            (&[0xb1, 0xb1], 0, &[]),
+            // Unknown opcode
+            (&[0xc8, 0xb1], 0, &[]),
+        ];
+        for (code, max_stack, frames) in cases {
+            assert!(
+                parse(&class(code, max_stack, frames)).is_err(),
+                "{:?}",
+                code
+            );
+        }
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::compiler::backend::x86::{AluOp, Cond, Inst, Mem, Operand, Reg, ShiftOp, Size};
+
+// Static x86-64 Linux executable written without an assembler or linker. The instruction stream
+// is encoded straight into machine code, every jump, call and RIP relative operand is a rel32
+// that gets patched once the final address of each label is known.
+//
+// File layout:
+//   0x0000  ELF header and the two program headers
+//   0x1000  .text, loaded read/execute at 0x401000
+//   page    .data then .bss, loaded read/write at the matching address
+//   after   .shstrtab and the section headers
+const BASE: u64 = 0x400000;
+const PAGE: u64 = 0x1000;
+const ENTRY: &str = "_start";
+
+const EHDR_SIZE: u16 = 64;
+const PHDR_SIZE: u16 = 56;
+const SHDR_SIZE: u16 = 64;
+const SHSTRTAB: &[u8] = b"\0.text\0.data\0.bss\0.shstrtab\0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+    Bss,
+}
+
+// A rel32 at `at` in .text, relative to the end of the instruction it belongs to
+struct Fixup {
+    at: usize,
+    end: usize,
+    label: String,
+    addend: i32,
+}
+
+// The r/m half of a ModRM byte
+enum Rm<'a> {
+    Reg(Reg),
+    Mem(&'a Mem),
+}
+
+#[derive(Default)]
+pub struct Image {
+    text: Vec<u8>,
+    data: Vec<u8>,
+    bss: usize,
+    labels: HashMap<String, (Section, usize)>,
+    fixups: Vec<Fixup>,
+}
+
+impl Reg {
+    fn number(self) -> u8 {
+        match self {
+            Reg::Ax => 0,
+            Reg::Cx => 1,
+            Reg::Dx => 2,
+            Reg::Bx => 3,
+            Reg::Sp => 4,
+            Reg::Bp => 5,
+            Reg::Si => 6,
+            Reg::Di => 7,
+            Reg::R8 => 8,
+            Reg::R9 => 9,
+            Reg::R10 => 10,
+            Reg::R11 => 11,
+            Reg::R12 => 12,
+            Reg::R13 => 13,
+            Reg::R14 => 14,
+            Reg::R15 => 15,
+        }
+    }
+}
+
+impl AluOp {
+    // The /digit of the immediate forms, the register forms are this times 8
+    fn extension(self) -> u8 {
+        match self {
+            AluOp::Add => 0,
+            AluOp::Sub => 5,
+            AluOp::Xor => 6,
+            AluOp::Cmp => 7,
+            AluOp::And => 4,
+        }
+    }
+}
+
+impl ShiftOp {
+    // The /digit of the shift group
+    fn extension(self) -> u8 {
+        match self {
+            ShiftOp::Shl => 4,
+            ShiftOp::Sar => 7,
+        }
+    }
+}
+
+impl Cond {
+    fn code(self) -> u8 {
+        match self {
+            Cond::A => 0x7,
+            Cond::S => 0x8,
+            Cond::NS => 0x9,
+            Cond::E => 0x4,
+            Cond::NE => 0x5,
+            Cond::L => 0xc,
+            Cond::GE => 0xd,
+            Cond::LE => 0xe,
+            Cond::G => 0xf,
+        }
+    }
+}
+
+fn imm(size: Size, value: i64) -> Vec<u8> {
+    match size {
+        Size::Byte => vec![value as u8],
+        Size::Word => (value as i16).to_le_bytes().to_vec(),
+        _ => (value as i32).to_le_bytes().to_vec(),
+    }
+}
+
+fn fits_i8(value: i64) -> bool {
+    (-128..=127).contains(&value)
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Image {
+            text: Vec::new(),
+            data: Vec::new(),
+            bss: 0,
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    fn define(&mut self, name: &str, section: Section, offset: usize) -> Result<(), String> {
+        match self.labels.insert(name.to_string(), (section, offset)) {
+            Some(_) => Err(format!("[ Error ] Label defined twice: {}", name)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn dword(&mut self, name: &str, value: i32) -> Result<(), String> {
+        self.define(name, Section::Data, self.data.len())?;
+        self.data.extend_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    // Zero filled bytes that take no room in the file
+    pub fn reserve(&mut self, name: &str, len: usize) -> Result<(), String> {
+        self.define(name, Section::Bss, self.bss)?;
+        self.bss += len;
+        Ok(())
+    }
+
+    pub fn assemble(&mut self, insts: &[Inst]) -> Result<(), String> {
+        for inst in insts {
+            self.encode(inst)?;
+        }
+        Ok(())
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    // Prefixes, opcode, ModRM and whatever follows it for an instruction with an r/m operand.
+    // `reg` is either a register number or the opcode extension.
+    fn modrm(&mut self, size: Size, opcode: &[u8], reg: u8, rm: Rm, imm: &[u8]) {
+        if size == Size::Word {
+            self.text.push(0x66);
+        }
+
+        let mut rex = 0x40;
+        if size == Size::Qword {
+            rex |= 0x8;
+        }
+        if reg & 0x8 != 0 {
+            rex |= 0x4;
+        }
+        let base = match &rm {
+            Rm::Reg(r) | Rm::Mem(Mem::Base(r, _)) => Some(r.number()),
+            Rm::Mem(Mem::Label(_, _)) => None,
+        };
+        if base.is_some_and(|b| b & 0x8 != 0) {
+            rex |= 0x1;
+        }
+        // spl, bpl, sil and dil can only be encoded with a REX prefix
+        let byte_reg = |n: u8| (4..8).contains(&n);
+        let needs_rex = size == Size::Byte
+            && (byte_reg(reg) || matches!(rm, Rm::Reg(r) if byte_reg(r.number())));
+        if rex != 0x40 || needs_rex {
+            self.text.push(rex);
+        }
+        self.text.extend_from_slice(opcode);
+
+        let reg = (reg & 0x7) << 3;
+        match rm {
+            Rm::Reg(r) => self.text.push(0xc0 | reg | (r.number() & 0x7)),
+            Rm::Mem(Mem::Label(label, addend)) => {
+                self.text.push(reg | 0x5);
+                let at = self.text.len();
+                self.text.extend_from_slice(&[0; 4]);
+                self.fixups.push(Fixup {
+                    at,
+                    end: at + 4 + imm.len(),
+                    label: label.clone(),
+                    addend: *addend,
+                });
+            }
+            Rm::Mem(Mem::Base(r, disp)) => {
+                let low = r.number() & 0x7;
+                // rbp and r13 have no form without a displacement
+                let mode = match *disp {
+                    0 if low != 5 => 0x00,
+                    d if fits_i8(d as i64) => 0x40,
+                    _ => 0x80,
+                };
+                self.text.push(mode | reg | low);
+                // rsp and r12 always need a SIB byte
+                if low == 4 {
+                    self.text.push(0x24);
+                }
+                match mode {
+                    0x40 => self.text.push(*disp as u8),
+                    0x80 => self.text.extend_from_slice(&disp.to_le_bytes()),
+                    _ => (),
+                }
+            }
+        }
+        self.text.extend_from_slice(imm);
+    }
+
+    // jmp, jcc and call all end in a rel32 to a label
+    fn relative(&mut self, opcode: &[u8], label: &str) {
+        self.text.extend_from_slice(opcode);
+        let at = self.text.len();
+        self.text.extend_from_slice(&[0; 4]);
+        self.fixups.push(Fixup {
+            at,
+            end: at + 4,
+            label: label.to_string(),
+            addend: 0,
+        });
+    }
+
+    fn rm(operand: &Operand) -> Option<Rm<'_>> {
+        match operand {
+            Operand::Reg(reg) => Some(Rm::Reg(*reg)),
+            Operand::Mem(mem) => Some(Rm::Mem(mem)),
+            Operand::Imm(_) => None,
+        }
+    }
+
+    fn encode(&mut self, inst: &Inst) -> Result<(), String> {
+        let byte = |size: Size, op: u8| if size == Size::Byte { op - 1 } else { op };
+        let unsupported = || Err(format!("[ Error ] Cannot encode: {:?}", inst));
+
+        match inst {
+            Inst::Label(name) => self.define(name, Section::Text, self.text.len())?,
+            Inst::Mov(size, Operand::Reg(dst), Operand::Imm(value)) if *size != Size::Qword => {
+                // Short form with the register in the opcode
+                if *size == Size::Word {
+                    self.text.push(0x66);
+                }
+                let n = dst.number();
+                let needs_rex = n & 0x8 != 0 || (*size == Size::Byte && n >= 4);
+                if needs_rex {
+                    self.text.push(0x40 | (n >> 3));
+                }
+                let op = if *size == Size::Byte { 0xb0 } else { 0xb8 };
+                self.text.push(op + (n & 0x7));
+                self.text.extend_from_slice(&imm(*size, *value));
+            }
+            Inst::Mov(size, dst, Operand::Imm(value)) => {
+                let rm = Image::rm(dst).unwrap();
+                self.modrm(*size, &[byte(*size, 0xc7)], 0, rm, &imm(*size, *value));
+            }
+            Inst::Mov(size, dst, Operand::Reg(src)) => {
+                let rm = Image::rm(dst).unwrap();
+                self.modrm(*size, &[byte(*size, 0x89)], src.number(), rm, &[]);
+            }
+            Inst::Mov(size, Operand::Reg(dst), Operand::Mem(src)) => {
+                self.modrm(*size, &[byte(*size, 0x8b)], dst.number(), Rm::Mem(src), &[]);
+            }
+            Inst::Movzx(reg, mem) => {
+                self.modrm(Size::Dword, &[0x0f, 0xb6], reg.number(), Rm::Mem(mem), &[])
+            }
+            Inst::Lea(reg, mem) => {
+                self.modrm(Size::Qword, &[0x8d], reg.number(), Rm::Mem(mem), &[])
+            }
+            Inst::Alu(op, size, dst, Operand::Imm(value)) => {
+                let rm = Image::rm(dst).unwrap();
+                if *size == Size::Byte {
+                    self.modrm(*size, &[0x80], op.extension(), rm, &imm(*size, *value));
+                } else if fits_i8(*value) {
+                    self.modrm(*size, &[0x83], op.extension(), rm, &[*value as u8]);
+                } else {
+                    self.modrm(*size, &[0x81], op.extension(), rm, &imm(*size, *value));
+                }
+            }
+            Inst::Alu(op, size, dst, Operand::Reg(src)) => {
+                let rm = Image::rm(dst).unwrap();
+                let opcode = byte(*size, op.extension() * 8 + 1);
+                self.modrm(*size, &[opcode], src.number(), rm, &[]);
+            }
+            Inst::Alu(op, size, Operand::Reg(dst), Operand::Mem(src)) => {
+                let opcode = byte(*size, op.extension() * 8 + 3);
+                self.modrm(*size, &[opcode], dst.number(), Rm::Mem(src), &[]);
+            }
+            Inst::Test(size, a, b) => {
+                self.modrm(*size, &[byte(*size, 0x85)], b.number(), Rm::Reg(*a), &[])
+            }
+            Inst::Imul(size, reg, Operand::Imm(value)) => {
+                if fits_i8(*value) {
+                    self.modrm(*size, &[0x6b], reg.number(), Rm::Reg(*reg), &[*value as u8]);
+                } else {
+                    let value = imm(*size, *value);
+                    self.modrm(*size, &[0x69], reg.number(), Rm::Reg(*reg), &value);
+                }
+            }
+            Inst::Imul(size, reg, src) => {
+                let rm = Image::rm(src).unwrap();
+                self.modrm(*size, &[0x0f, 0xaf], reg.number(), rm, &[]);
+            }
+            Inst::Idiv(size, src) | Inst::Div(size, src) => {
+                let ext = if matches!(inst, Inst::Idiv(..)) { 7 } else { 6 };
+                match Image::rm(src) {
+                    Some(rm) => self.modrm(*size, &[byte(*size, 0xf7)], ext, rm, &[]),
+                    None => return unsupported(),
+                }
+            }
+            Inst::Neg(size, reg) => self.modrm(*size, &[byte(*size, 0xf7)], 3, Rm::Reg(*reg), &[]),
+            Inst::Shift(op, size, dst, count) => match Image::rm(dst) {
+                Some(rm) => self.modrm(*size, &[byte(*size, 0xc1)], op.extension(), rm, &[*count]),
+                None => return unsupported(),
+            },
+            Inst::Inc(size, dst) | Inst::Dec(size, dst) => {
+                let ext = if matches!(inst, Inst::Inc(..)) { 0 } else { 1 };
+                match Image::rm(dst) {
+                    Some(rm) => self.modrm(*size, &[byte(*size, 0xff)], ext, rm, &[]),
+                    None => return unsupported(),
+                }
+            }
+            Inst::Cdq => self.text.push(0x99),
+            Inst::Jmp(label) => self.relative(&[0xe9], label),
+            Inst::Jcc(cond, label) => self.relative(&[0x0f, 0x80 | cond.code()], label),
+            Inst::Call(label) => self.relative(&[0xe8], label),
+            Inst::Ret => self.text.push(0xc3),
+            Inst::Syscall => self.text.extend_from_slice(&[0x0f, 0x05]),
+            Inst::Nop => self.text.push(0x90),
+            _ => return unsupported(),
+        }
+        Ok(())
+    }
+
+    fn text_addr(&self) -> u64 {
+        BASE + PAGE
+    }
+
+    fn data_offset(&self) -> u64 {
+        (PAGE + self.text.len() as u64).div_ceil(PAGE) * PAGE
+    }
+
+    fn data_addr(&self) -> u64 {
+        BASE + self.data_offset()
+    }
+
+    fn address(&self, label: &str) -> Result<u64, String> {
+        match self.labels.get(label) {
+            Some((Section::Text, offset)) => Ok(self.text_addr() + *offset as u64),
+            Some((Section::Data, offset)) => Ok(self.data_addr() + *offset as u64),
+            Some((Section::Bss, offset)) => {
+                Ok(self.data_addr() + (self.data.len() + offset) as u64)
+            }
+            None => Err(format!("[ Error ] Undefined label: {}", label)),
+        }
+    }
+
+    // Patch every rel32 now that the layout is fixed
+    pub fn link(&mut self) -> Result<(), String> {
+        for fixup in &self.fixups {
+            let target = self.address(&fixup.label)? as i64 + fixup.addend as i64;
+            let next = (self.text_addr() + fixup.end as u64) as i64;
+            let rel = i32::try_from(target - next)
+                .map_err(|_| format!("[ Error ] Jump out of range: {}", fixup.label))?;
+            self.text[fixup.at..fixup.at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let entry = self
+            .address(ENTRY)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let data_offset = self.data_offset();
+        let strtab_offset = data_offset + self.data.len() as u64;
+        let shoff = (strtab_offset + SHSTRTAB.len() as u64).div_ceil(8) * 8;
+
+        let mut file: Vec<u8> = Vec::new();
+        let u16 = |file: &mut Vec<u8>, v: u16| file.extend_from_slice(&v.to_le_bytes());
+        let u32 = |file: &mut Vec<u8>, v: u32| file.extend_from_slice(&v.to_le_bytes());
+        let u64 = |file: &mut Vec<u8>, v: u64| file.extend_from_slice(&v.to_le_bytes());
+
+        // ELF header: 64-bit, little endian, System V, executable for x86-64
+        file.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        file.extend_from_slice(&[0; 8]);
+        u16(&mut file, 2);
+        u16(&mut file, 0x3e);
+        u32(&mut file, 1);
+        u64(&mut file, entry);
+        u64(&mut file, EHDR_SIZE as u64);
+        u64(&mut file, shoff);
+        u32(&mut file, 0);
+        u16(&mut file, EHDR_SIZE);
+        u16(&mut file, PHDR_SIZE);
+        u16(&mut file, 2);
+        u16(&mut file, SHDR_SIZE);
+        u16(&mut file, 5);
+        u16(&mut file, 4);
+
+        // PT_LOAD segments: type, flags, offset, vaddr, paddr, filesz, memsz, align
+        let segments = [
+            (5, PAGE, self.text_addr(), self.text.len(), self.text.len()),
+            (
+                6,
+                data_offset,
+                self.data_addr(),
+                self.data.len(),
+                self.data.len() + self.bss,
+            ),
+        ];
+        for (flags, offset, addr, filesz, memsz) in segments {
+            u32(&mut file, 1);
+            u32(&mut file, flags);
+            u64(&mut file, offset);
+            u64(&mut file, addr);
+            u64(&mut file, addr);
+            u64(&mut file, filesz as u64);
+            u64(&mut file, memsz as u64);
+            u64(&mut file, PAGE);
+        }
+
+        file.resize(PAGE as usize, 0);
+        file.extend_from_slice(&self.text);
+        file.resize(data_offset as usize, 0);
+        file.extend_from_slice(&self.data);
+        file.extend_from_slice(SHSTRTAB);
+        file.resize(shoff as usize, 0);
+
+        // Section headers: name, type, flags, addr, offset, size, link, info, align, entsize
+        let bss_addr = self.data_addr() + self.data.len() as u64;
+        let sections = [
+            (0, 0, 0, 0, 0, 0, 0),
+            (1, 1, 6, self.text_addr(), PAGE, self.text.len(), 16),
+            (7, 1, 3, self.data_addr(), data_offset, self.data.len(), 4),
+            (13, 8, 3, bss_addr, strtab_offset, self.bss, 1),
+            (18, 3, 0, 0, strtab_offset, SHSTRTAB.len(), 1),
+        ];
+        for (name, kind, flags, addr, offset, size, align) in sections {
+            u32(&mut file, name);
+            u32(&mut file, kind);
+            u64(&mut file, flags);
+            u64(&mut file, addr);
+            u64(&mut file, offset);
+            u64(&mut file, size as u64);
+            u32(&mut file, 0);
+            u32(&mut file, 0);
+            u64(&mut file, align);
+            u64(&mut file, 0);
+        }
+
+        out.write_all(&file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(inst: Inst) -> Vec<u8> {
+        let mut image = Image::new();
+        image.assemble(&[inst]).unwrap();
+        image.text
+    }
+
+    #[test]
+    fn test_encode_registers() {
+        let eax = Operand::Reg(Reg::Ax);
+        let r9d = Operand::Reg(Reg::R9);
+
+        assert_eq!(
+            encode(Inst::Mov(Size::Dword, eax.clone(), Operand::Imm(60))),
+            [0xb8, 60, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(Inst::Mov(Size::Dword, r9d.clone(), Operand::Imm(10))),
+            [0x41, 0xb9, 10, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(Inst::Mov(Size::Dword, eax.clone(), r9d.clone())),
+            [0x44, 0x89, 0xc8]
+        );
+        assert_eq!(
+            encode(Inst::Alu(AluOp::Xor, Size::Dword, r9d.clone(), r9d)),
+            [0x45, 0x31, 0xc9]
+        );
+        assert_eq!(
+            encode(Inst::Alu(AluOp::Sub, Size::Dword, eax, Operand::Imm(48))),
+            [0x83, 0xe8, 48]
+        );
+        assert_eq!(
+            encode(Inst::Imul(Size::Dword, Reg::R9, Operand::Imm(10))),
+            [0x45, 0x6b, 0xc9, 10]
+        );
+        assert_eq!(
+            encode(Inst::Dec(Size::Qword, Operand::Reg(Reg::Si))),
+            [0x48, 0xff, 0xce]
+        );
+        assert_eq!(encode(Inst::Neg(Size::Dword, Reg::Ax)), [0xf7, 0xd8]);
+        assert_eq!(
+            encode(Inst::Shift(
+                ShiftOp::Sar,
+                Size::Dword,
+                Operand::Reg(Reg::Ax),
+                3
+            )),
+            [0xc1, 0xf8, 3]
+        );
+        assert_eq!(
+            encode(Inst::Shift(
+                ShiftOp::Shl,
+                Size::Dword,
+                Operand::Reg(Reg::R12),
+                1
+            )),
+            [0x41, 0xc1, 0xe4, 1]
+        );
+        assert_eq!(
+            encode(Inst::Alu(
+                AluOp::And,
+                Size::Dword,
+                Operand::Reg(Reg::Dx),
+                Operand::Imm(7)
+            )),
+            [0x83, 0xe2, 7]
+        );
+        assert_eq!(
+            encode(Inst::Test(Size::Dword, Reg::R8, Reg::R8)),
+            [0x45, 0x85, 0xc0]
+        );
+        assert_eq!(encode(Inst::Cdq), [0x99]);
+        assert_eq!(encode(Inst::Syscall), [0x0f, 0x05]);
+    }
+
+    #[test]
+    fn test_encode_memory() {
+        // [rsi] needs no displacement, [rsp] needs a SIB byte and [rbp] a zero disp8
+        assert_eq!(
+            encode(Inst::Mov(
+                Size::Byte,
+                Operand::Mem(Mem::Base(Reg::Si, 0)),
+                Operand::Reg(Reg::Dx)
+            )),
+            [0x88, 0x16]
+        );
+        assert_eq!(
+            encode(Inst::Movzx(Reg::Cx, Mem::Base(Reg::Sp, 0))),
+            [0x0f, 0xb6, 0x0c, 0x24]
+        );
+        assert_eq!(
+            encode(Inst::Mov(
+                Size::Dword,
+                Operand::Reg(Reg::Ax),
+                Operand::Mem(Mem::Base(Reg::Bp, 0))
+            )),
+            [0x8b, 0x45, 0x00]
+        );
+        assert_eq!(
+            encode(Inst::Mov(
+                Size::Byte,
+                Operand::Mem(Mem::Base(Reg::Si, 0)),
+                Operand::Reg(Reg::Di)
+            )),
+            [0x40, 0x88, 0x3e]
+        );
+    }
+
+    #[test]
+    fn test_link_relative() {
+        // The rel32 of a RIP relative store counts from after the immediate
+        let mut image = Image::new();
+        image.dword("a", 0).unwrap();
+        image
+            .assemble(&[
+                Inst::Label(String::from("_start")),
+                Inst::Mov(
+                    Size::Dword,
+                    Operand::Mem(Mem::Label(String::from("a"), 0)),
+                    Operand::Imm(7),
+                ),
+                Inst::Jmp(String::from("_start")),
+            ])
+            .unwrap();
+        image.link().unwrap();
+
+        // data sits on the page after .text: 0x402000 - (0x401000 + 10)
+        assert_eq!(
+            image.text(),
+            [0xc7, 0x05, 0xf6, 0x0f, 0x00, 0x00, 7, 0, 0, 0, 0xe9, 0xf1, 0xff, 0xff, 0xff]
+        );
+
+        let mut image = Image::new();
+        image
+            .assemble(&[Inst::Call(String::from("missing"))])
+            .unwrap();
+        assert!(image.link().is_err());
+    }
+}
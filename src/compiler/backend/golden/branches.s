@@ -0,0 +1,33 @@
+	.data
+a:	.word 0
+
+	.text
+	.globl main
+main:
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 7
+	bne t1, t2, L1
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 7
+	beq t1, t2, L1
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 7
+	bge t2, t1, L1
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 7
+	bge t1, t2, L1
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 7
+	blt t1, t2, L1
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 7
+	blt t2, t1, L1
+L1:
+	li a7, 10
+	ecall
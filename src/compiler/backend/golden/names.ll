@@ -0,0 +1,27 @@
+; Small17 program, link with a runtime that defines small17_get and small17_put
+
+@v.main = internal global i32 0
+
+declare i32 @small17_get()
+declare void @small17_put(i32)
+
+define internal void @p.main() {
+entry:
+  %t.1 = load i32, i32* @v.main
+  call void @small17_put(i32 %t.1)
+  ret void
+}
+
+define i32 @main() {
+entry:
+  %t.1 = call i32 @small17_get()
+  store i32 %t.1, i32* @v.main
+  br label %l.entry
+l.entry:
+  call void @p.main()
+  %t.2 = load i32, i32* @v.main
+  %t.3 = icmp sgt i32 %t.2, 0
+  br i1 %t.3, label %next.1, label %l.entry
+next.1:
+  ret i32 0
+}
@@ -0,0 +1,73 @@
+; Small17 program, link with a runtime that defines small17_get and small17_put
+
+@v.a = internal global i16 0
+@v.b = internal global i16 0
+@v.c = internal global i16 0
+
+declare i32 @small17_get()
+declare void @small17_put(i32)
+
+define internal void @p.show() {
+entry:
+  %temp_1 = alloca i16
+  %temp_2 = alloca i16
+  %t.1 = load i16, i16* @v.a
+  %t.2 = load i16, i16* @v.b
+  %t.3 = add i16 %t.1, %t.2
+  store i16 %t.3, i16* %temp_1
+  %t.4 = load i16, i16* %temp_1
+  %t.5 = mul i16 %t.4, 3
+  store i16 %t.5, i16* %temp_2
+  %t.6 = load i16, i16* %temp_2
+  store i16 %t.6, i16* @v.c
+  %t.7 = load i16, i16* @v.c
+  %t.8 = sext i16 %t.7 to i32
+  call void @small17_put(i32 %t.8)
+  ret void
+}
+
+define i32 @main() {
+entry:
+  %temp_3 = alloca i16
+  %temp_4 = alloca i16
+  %temp_5 = alloca i16
+  %t.1 = call i32 @small17_get()
+  %t.2 = trunc i32 %t.1 to i16
+  store i16 %t.2, i16* @v.a
+  %t.3 = call i32 @small17_get()
+  %t.4 = trunc i32 %t.3 to i16
+  store i16 %t.4, i16* @v.b
+  br label %l.L1
+l.L1:
+  %t.5 = load i16, i16* @v.a
+  %t.6 = icmp sgt i16 %t.5, 0
+  br i1 %t.6, label %next.1, label %l.L2
+next.1:
+  %t.7 = load i16, i16* @v.a
+  %t.8 = sub i16 %t.7, 1
+  store i16 %t.8, i16* %temp_3
+  %t.9 = load i16, i16* %temp_3
+  store i16 %t.9, i16* @v.a
+  %t.10 = load i16, i16* @v.b
+  %t.11 = mul i16 %t.10, 2
+  store i16 %t.11, i16* %temp_4
+  %t.12 = load i16, i16* %temp_4
+  store i16 %t.12, i16* @v.b
+  br label %l.L1
+l.L2:
+  %t.13 = load i16, i16* @v.a
+  %t.14 = load i16, i16* @v.b
+  %t.15 = icmp slt i16 %t.13, %t.14
+  br i1 %t.15, label %next.2, label %l.L3
+next.2:
+  call void @p.show()
+  br label %l.L3
+l.L3:
+  %t.16 = load i16, i16* @v.b
+  %t.17 = sdiv i16 %t.16, 2
+  store i16 %t.17, i16* %temp_5
+  %t.18 = load i16, i16* %temp_5
+  %t.19 = sext i16 %t.18 to i32
+  call void @small17_put(i32 %t.19)
+  ret i32 0
+}
@@ -0,0 +1,100 @@
+	.data
+a:	.word 0
+b:	.word 0
+c:	.word 0
+temp_1:	.word 0
+temp_2:	.word 0
+temp_3:	.word 0
+temp_4:	.word 0
+temp_5:	.word 0
+
+	.text
+	.globl main
+main:
+	li a7, 5
+	ecall
+	la t0, a
+	sw a0, 0(t0)
+	li a7, 5
+	ecall
+	la t0, b
+	sw a0, 0(t0)
+L1:
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 0
+	bge t2, t1, L2
+	la t0, a
+	lw t1, 0(t0)
+	li t2, 1
+	sub t1, t1, t2
+	la t0, temp_3
+	sw t1, 0(t0)
+	la t0, temp_3
+	lw t1, 0(t0)
+	la t0, a
+	sw t1, 0(t0)
+	la t0, b
+	lw t1, 0(t0)
+	li t2, 2
+	mul t1, t1, t2
+	la t0, temp_4
+	sw t1, 0(t0)
+	la t0, temp_4
+	lw t1, 0(t0)
+	la t0, b
+	sw t1, 0(t0)
+	j L1
+L2:
+	la t0, a
+	lw t1, 0(t0)
+	la t0, b
+	lw t2, 0(t0)
+	bge t1, t2, L3
+	call show
+L3:
+	la t0, b
+	lw t1, 0(t0)
+	li t2, 2
+	div t1, t1, t2
+	la t0, temp_5
+	sw t1, 0(t0)
+	la t0, temp_5
+	lw a0, 0(t0)
+	li a7, 1
+	ecall
+	li a0, 10
+	li a7, 11
+	ecall
+	li a7, 10
+	ecall
+show:
+	addi sp, sp, -16
+	sw ra, 12(sp)
+	la t0, a
+	lw t1, 0(t0)
+	la t0, b
+	lw t2, 0(t0)
+	add t1, t1, t2
+	la t0, temp_1
+	sw t1, 0(t0)
+	la t0, temp_1
+	lw t1, 0(t0)
+	li t2, 3
+	mul t1, t1, t2
+	la t0, temp_2
+	sw t1, 0(t0)
+	la t0, temp_2
+	lw t1, 0(t0)
+	la t0, c
+	sw t1, 0(t0)
+	la t0, c
+	lw a0, 0(t0)
+	li a7, 1
+	ecall
+	li a0, 10
+	li a7, 11
+	ecall
+	lw ra, 12(sp)
+	addi sp, sp, 16
+	ret
@@ -0,0 +1,19 @@
+; Small17 program, link with a runtime that defines small17_get and small17_put
+
+@v.a = internal global i32 0
+
+declare i32 @small17_get()
+declare void @small17_put(i32)
+
+define i32 @main() {
+entry:
+  br label %l.L1
+l.L1:
+  %t.1 = call i32 @small17_get()
+  store i32 %t.1, i32* @v.a
+  br label %l.L1
+next.1:
+  %t.2 = load i32, i32* @v.a
+  call void @small17_put(i32 %t.2)
+  ret i32 0
+}
@@ -0,0 +1,810 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::compiler::backend::{Backend, Relation, Symbol, SymbolClass};
+use crate::compiler::syntax::Operand;
+
+// JVM class file (Java 8, major version 52). Variables are private static int fields, temps are
+// int locals of the method using them, procedures are private static void methods and the main
+// program is `public static void main(String[])`. PUT is System.out.println(int), GET calls a
+// generated `$get` that reads the next int from a Scanner on System.in, or 0 at EOF.
+//
+// Every branch target and every instruction after a goto or return gets a StackMapTable frame.
+// The operand stack is always empty between quads and temps are zeroed on entry, so all frames
+// of a method are identical: the parameters followed by one int per temp. The front end has no
+// IF or WHILE, so only IR read with ir::parse has the branches that need a StackMapTable.
+const MAGIC: u32 = 0xcafebabe;
+const MAJOR: u16 = 52;
+
+const ACC_PUBLIC: u16 = 0x0001;
+const ACC_PRIVATE: u16 = 0x0002;
+const ACC_STATIC: u16 = 0x0008;
+const ACC_SUPER: u16 = 0x0020;
+
+const SCANNER: &str = "java/util/Scanner";
+const SCANNER_FIELD: &str = "$scanner";
+const GET: &str = "$get";
+
+// Opcodes, named as in the JVM specification
+const ICONST_0: u8 = 0x03;
+const BIPUSH: u8 = 0x10;
+const SIPUSH: u8 = 0x11;
+const LDC_W: u8 = 0x13;
+const ILOAD: u8 = 0x15;
+const ILOAD_0: u8 = 0x1a;
+const ISTORE: u8 = 0x36;
+const ISTORE_0: u8 = 0x3b;
+const DUP: u8 = 0x59;
+const IADD: u8 = 0x60;
+const ISUB: u8 = 0x64;
+const IMUL: u8 = 0x68;
+const IDIV: u8 = 0x6c;
+const IFEQ: u8 = 0x99;
+const IF_ICMPEQ: u8 = 0x9f;
+const IF_ICMPNE: u8 = 0xa0;
+const IF_ICMPLT: u8 = 0xa1;
+const IF_ICMPGE: u8 = 0xa2;
+const IF_ICMPGT: u8 = 0xa3;
+const IF_ICMPLE: u8 = 0xa4;
+const GOTO: u8 = 0xa7;
+const IRETURN: u8 = 0xac;
+const RETURN: u8 = 0xb1;
+const GETSTATIC: u8 = 0xb2;
+const PUTSTATIC: u8 = 0xb3;
+const INVOKEVIRTUAL: u8 = 0xb6;
+const INVOKESPECIAL: u8 = 0xb7;
+const INVOKESTATIC: u8 = 0xb8;
+const NEW: u8 = 0xbb;
+
+// Verification type tags used in stack map frames
+const ITEM_INTEGER: u8 = 1;
+const ITEM_OBJECT: u8 = 7;
+
+pub struct Jvm {
+    class: String,
+    pool: Pool,
+    symbols: Vec<Symbol>,
+    fields: Vec<String>,
+    main: Method,
+    procedures: Vec<Method>,
+    in_procedure: bool,
+    uses_get: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Constant {
+    Utf8(String),
+    Integer(i32),
+    Class(u16),
+    NameAndType(u16, u16),
+    Fieldref(u16, u16),
+    Methodref(u16, u16),
+}
+
+#[derive(Default)]
+struct Pool {
+    constants: Vec<Constant>,
+}
+
+// Frame types of the parameters, the only locals besides temps
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Params {
+    None,
+    Args,
+}
+
+struct Method {
+    access: u16,
+    name: String,
+    descriptor: String,
+    params: Params,
+    temps: Vec<String>,
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    // Branch instruction offset and the label it jumps to
+    fixups: Vec<(usize, String)>,
+    frames: Vec<usize>,
+    depth: i32,
+    max_stack: i32,
+    // The last instruction was a goto or return
+    terminated: bool,
+}
+
+fn u16_be(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn u32_be(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// iload/istore, or their one byte form for the first four slots
+fn local(opcode: u8, short: u8, slot: u8) -> Vec<u8> {
+    match slot {
+        0..=3 => vec![short + slot],
+        _ => vec![opcode, slot],
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Pool {
+    fn add(&mut self, constant: Constant) -> u16 {
+        let index = match self.constants.iter().position(|c| *c == constant) {
+            Some(index) => index,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            }
+        };
+        index as u16 + 1
+    }
+
+    fn utf8(&mut self, value: &str) -> u16 {
+        self.add(Constant::Utf8(value.to_string()))
+    }
+
+    fn class(&mut self, name: &str) -> u16 {
+        let name = self.utf8(name);
+        self.add(Constant::Class(name))
+    }
+
+    fn name_and_type(&mut self, name: &str, descriptor: &str) -> u16 {
+        let name = self.utf8(name);
+        let descriptor = self.utf8(descriptor);
+        self.add(Constant::NameAndType(name, descriptor))
+    }
+
+    fn field(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let class = self.class(class);
+        let name_and_type = self.name_and_type(name, descriptor);
+        self.add(Constant::Fieldref(class, name_and_type))
+    }
+
+    fn method(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let class = self.class(class);
+        let name_and_type = self.name_and_type(name, descriptor);
+        self.add(Constant::Methodref(class, name_and_type))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        u16_be(out, self.constants.len() as u16 + 1);
+        for constant in &self.constants {
+            match constant {
+                Constant::Utf8(value) => {
+                    // Modified UTF-8 only differs from UTF-8 for NUL and supplementary characters
+                    out.push(1);
+                    u16_be(out, value.len() as u16);
+                    out.extend_from_slice(value.as_bytes());
+                }
+                Constant::Integer(value) => {
+                    out.push(3);
+                    out.extend_from_slice(&value.to_be_bytes());
+                }
+                Constant::Class(name) => {
+                    out.push(7);
+                    u16_be(out, *name);
+                }
+                Constant::Fieldref(class, name_and_type) => {
+                    out.push(9);
+                    u16_be(out, *class);
+                    u16_be(out, *name_and_type);
+                }
+                Constant::Methodref(class, name_and_type) => {
+                    out.push(10);
+                    u16_be(out, *class);
+                    u16_be(out, *name_and_type);
+                }
+                Constant::NameAndType(name, descriptor) => {
+                    out.push(12);
+                    u16_be(out, *name);
+                    u16_be(out, *descriptor);
+                }
+            }
+        }
+    }
+}
+
+impl Method {
+    fn new(access: u16, name: &str, descriptor: &str, params: Params) -> Self {
+        Method {
+            access,
+            name: name.to_string(),
+            descriptor: descriptor.to_string(),
+            params,
+            temps: Vec::new(),
+            code: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            frames: Vec::new(),
+            depth: 0,
+            max_stack: 0,
+            terminated: false,
+        }
+    }
+
+    // `delta` is how much the instruction grows the operand stack
+    fn op(&mut self, bytes: &[u8], delta: i32) {
+        // Nothing falls through into code after a goto or return, the verifier needs a frame
+        if self.terminated {
+            self.frames.push(self.code.len());
+            self.terminated = false;
+        }
+        self.code.extend_from_slice(bytes);
+        self.depth += delta;
+        self.max_stack = self.max_stack.max(self.depth);
+    }
+
+    fn indexed(&mut self, opcode: u8, index: u16, delta: i32) {
+        let [high, low] = index.to_be_bytes();
+        self.op(&[opcode, high, low], delta);
+    }
+
+    fn push_int(&mut self, pool: &mut Pool, value: i32) {
+        match value {
+            -1..=5 => self.op(&[(ICONST_0 as i32 + value) as u8], 1),
+            -128..=127 => self.op(&[BIPUSH, value as u8], 1),
+            -32768..=32767 => {
+                let [high, low] = (value as i16).to_be_bytes();
+                self.op(&[SIPUSH, high, low], 1);
+            }
+            _ => {
+                let index = pool.add(Constant::Integer(value));
+                self.indexed(LDC_W, index, 1);
+            }
+        }
+    }
+
+    fn slot(&mut self, temp: &str) -> u8 {
+        let index = match self.temps.iter().position(|t| t == temp) {
+            Some(index) => index,
+            None => {
+                self.temps.push(temp.to_string());
+                self.temps.len() - 1
+            }
+        };
+        (self.first_temp() + index) as u8
+    }
+
+    fn first_temp(&self) -> usize {
+        match self.params {
+            Params::None => 0,
+            Params::Args => 1,
+        }
+    }
+
+    fn label(&mut self, label: &str) {
+        self.labels.insert(label.to_string(), self.code.len());
+        self.frames.push(self.code.len());
+        self.terminated = false;
+    }
+
+    fn branch(&mut self, opcode: u8, label: &str, delta: i32) {
+        self.op(&[opcode, 0, 0], delta);
+        self.fixups.push((self.code.len() - 3, label.to_string()));
+    }
+
+    fn terminate(&mut self, bytes: &[u8], delta: i32) {
+        self.op(bytes, delta);
+        self.terminated = true;
+    }
+
+    fn frame_locals(&self, pool: &mut Pool, out: &mut Vec<u8>) {
+        let params = match self.params {
+            Params::None => 0,
+            Params::Args => 1,
+        };
+        u16_be(out, (params + self.temps.len()) as u16);
+        if self.params == Params::Args {
+            out.push(ITEM_OBJECT);
+            u16_be(out, pool.class("[Ljava/lang/String;"));
+        }
+        for _ in &self.temps {
+            out.push(ITEM_INTEGER);
+        }
+    }
+
+    // method_info with its Code attribute, temps are zeroed in front of the collected code
+    fn write(&mut self, pool: &mut Pool, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.terminated {
+            self.terminate(&[RETURN], 0);
+        }
+        for (at, label) in &self.fixups {
+            let target = self
+                .labels
+                .get(label)
+                .ok_or_else(|| invalid(format!("[ Error ] Undefined label: {}", label)))?;
+            let offset = i16::try_from(*target as i64 - *at as i64)
+                .map_err(|_| invalid(format!("[ Error ] Branch out of range: {}", label)))?;
+            self.code[at + 1..at + 3].copy_from_slice(&offset.to_be_bytes());
+        }
+
+        let mut code = Vec::new();
+        for index in 0..self.temps.len() {
+            code.push(ICONST_0);
+            code.extend_from_slice(&local(ISTORE, ISTORE_0, (self.first_temp() + index) as u8));
+        }
+        let prologue = code.len();
+        code.extend_from_slice(&self.code);
+        let max_stack = self.max_stack.max(!self.temps.is_empty() as i32);
+
+        let mut frames = self.frames.clone();
+        frames.sort_unstable();
+        frames.dedup();
+        let mut table = Vec::new();
+        u16_be(&mut table, frames.len() as u16);
+        let mut previous: Option<usize> = None;
+        for offset in frames.iter().map(|frame| frame + prologue) {
+            match previous {
+                // full_frame, everything after it only needs same_frame
+                None => {
+                    table.push(255);
+                    u16_be(&mut table, offset as u16);
+                    self.frame_locals(pool, &mut table);
+                    u16_be(&mut table, 0);
+                }
+                Some(previous) => match offset - previous - 1 {
+                    delta @ 0..=63 => table.push(delta as u8),
+                    delta => {
+                        table.push(251);
+                        u16_be(&mut table, delta as u16);
+                    }
+                },
+            }
+            previous = Some(offset);
+        }
+
+        let mut attribute = Vec::new();
+        u16_be(&mut attribute, max_stack as u16);
+        u16_be(
+            &mut attribute,
+            (self.first_temp() + self.temps.len()) as u16,
+        );
+        u32_be(&mut attribute, code.len() as u32);
+        attribute.extend_from_slice(&code);
+        u16_be(&mut attribute, 0);
+        if frames.is_empty() {
+            u16_be(&mut attribute, 0);
+        } else {
+            u16_be(&mut attribute, 1);
+            u16_be(&mut attribute, pool.utf8("StackMapTable"));
+            u32_be(&mut attribute, table.len() as u32);
+            attribute.extend_from_slice(&table);
+        }
+
+        u16_be(out, self.access);
+        u16_be(out, pool.utf8(&self.name));
+        u16_be(out, pool.utf8(&self.descriptor));
+        u16_be(out, 1);
+        u16_be(out, pool.utf8("Code"));
+        u32_be(out, attribute.len() as u32);
+        out.extend_from_slice(&attribute);
+        Ok(())
+    }
+}
+
+impl Jvm {
+    pub fn new(class: &str) -> Self {
+        Jvm {
+            class: class.to_string(),
+            pool: Pool::default(),
+            symbols: Vec::new(),
+            fields: Vec::new(),
+            main: Method::new(
+                ACC_PUBLIC | ACC_STATIC,
+                "main",
+                "([Ljava/lang/String;)V",
+                Params::Args,
+            ),
+            procedures: Vec::new(),
+            in_procedure: false,
+            uses_get: false,
+        }
+    }
+
+    // The method quads currently go to and the pool its instructions refer to
+    fn current(&mut self) -> (&mut Method, &mut Pool) {
+        let method = match self.procedures.last_mut() {
+            Some(method) if self.in_procedure => method,
+            _ => &mut self.main,
+        };
+        (method, &mut self.pool)
+    }
+
+    fn is_temp(&self, name: &str) -> bool {
+        self.symbols
+            .iter()
+            .any(|sym| sym.name == name && sym.class == SymbolClass::Temp)
+    }
+
+    fn field(&mut self, name: &str) -> u16 {
+        if !self.fields.iter().any(|field| field == name) {
+            self.fields.push(name.to_string());
+        }
+        let class = self.class.clone();
+        self.pool.field(&class, name, "I")
+    }
+
+    fn load(&mut self, operand: &Operand) {
+        let name = operand.to_string();
+        if let Some(value) = operand.constant() {
+            let (method, pool) = self.current();
+            method.push_int(pool, value);
+        } else if self.is_temp(&name) {
+            let (method, _) = self.current();
+            let slot = method.slot(&name);
+            method.op(&local(ILOAD, ILOAD_0, slot), 1);
+        } else {
+            let field = self.field(&name);
+            self.current().0.indexed(GETSTATIC, field, 1);
+        }
+    }
+
+    fn store(&mut self, operand: &Operand) {
+        let name = operand.to_string();
+        if self.is_temp(&name) {
+            let (method, _) = self.current();
+            let slot = method.slot(&name);
+            method.op(&local(ISTORE, ISTORE_0, slot), -1);
+        } else {
+            let field = self.field(&name);
+            self.current().0.indexed(PUTSTATIC, field, -1);
+        }
+    }
+
+    fn binary(&mut self, opcode: u8, left: &Operand, right: &Operand, dest: &Operand) {
+        self.load(left);
+        self.load(right);
+        self.current().0.op(&[opcode], -1);
+        self.store(dest);
+    }
+
+    // if_icmp<cond> jumps when the condition holds
+    fn compare(rel: Relation) -> u8 {
+        match rel {
+            Relation::Equal => IF_ICMPEQ,
+            Relation::NEqual => IF_ICMPNE,
+            Relation::LessThan => IF_ICMPLT,
+            Relation::GEqual => IF_ICMPGE,
+            Relation::GreaterThan => IF_ICMPGT,
+            Relation::LEqual => IF_ICMPLE,
+        }
+    }
+
+    // static { $scanner = new Scanner(System.in); } plus the initial value of every variable
+    fn clinit(&mut self) -> Method {
+        let mut method = Method::new(ACC_STATIC, "<clinit>", "()V", Params::None);
+        let pool = &mut self.pool;
+        if self.uses_get {
+            method.indexed(NEW, pool.class(SCANNER), 1);
+            method.op(&[DUP], 1);
+            let stdin = pool.field("java/lang/System", "in", "Ljava/io/InputStream;");
+            method.indexed(GETSTATIC, stdin, 1);
+            let init = pool.method(SCANNER, "<init>", "(Ljava/io/InputStream;)V");
+            method.indexed(INVOKESPECIAL, init, -2);
+            let scanner = pool.field(&self.class, SCANNER_FIELD, "Ljava/util/Scanner;");
+            method.indexed(PUTSTATIC, scanner, -1);
+        }
+        for field in &self.fields {
+            let value = self
+                .symbols
+                .iter()
+                .find(|sym| &sym.name == field)
+                .map_or(0, |sym| sym.value);
+            if value != 0 {
+                method.push_int(pool, value);
+                let field = pool.field(&self.class, field, "I");
+                method.indexed(PUTSTATIC, field, -1);
+            }
+        }
+        method
+    }
+
+    // private static int $get() { return $scanner.hasNextInt() ? $scanner.nextInt() : 0; }
+    fn get_method(&mut self) -> Method {
+        let mut method = Method::new(ACC_PRIVATE | ACC_STATIC, GET, "()I", Params::None);
+        let pool = &mut self.pool;
+        let scanner = pool.field(&self.class, SCANNER_FIELD, "Ljava/util/Scanner;");
+        method.indexed(GETSTATIC, scanner, 1);
+        method.indexed(INVOKEVIRTUAL, pool.method(SCANNER, "hasNextInt", "()Z"), 0);
+        method.branch(IFEQ, "eof", -1);
+        method.indexed(GETSTATIC, scanner, 1);
+        method.indexed(INVOKEVIRTUAL, pool.method(SCANNER, "nextInt", "()I"), 0);
+        method.terminate(&[IRETURN], -1);
+        method.label("eof");
+        method.op(&[ICONST_0], 1);
+        method.terminate(&[IRETURN], -1);
+        method
+    }
+}
+
+impl Backend for Jvm {
+    fn extension(&self) -> &str {
+        "class"
+    }
+
+    fn prologue(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Fields are written at the end once every variable the quads touch is known
+    fn data(&mut self, _out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()> {
+        self.symbols = symbols.to_vec();
+        self.fields = symbols
+            .iter()
+            .filter(|sym| sym.class == SymbolClass::Identifier)
+            .map(|sym| sym.name.clone())
+            .collect();
+        Ok(())
+    }
+
+    fn text(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary(IADD, left, right, dest);
+        Ok(())
+    }
+
+    fn sub(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary(ISUB, left, right, dest);
+        Ok(())
+    }
+
+    fn mul(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary(IMUL, left, right, dest);
+        Ok(())
+    }
+
+    fn div(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary(IDIV, left, right, dest);
+        Ok(())
+    }
+
+    fn assign(&mut self, _out: &mut dyn Write, src: &Operand, dest: &Operand) -> io::Result<()> {
+        self.load(src);
+        self.store(dest);
+        Ok(())
+    }
+
+    fn get(&mut self, _out: &mut dyn Write, dest: &Operand) -> io::Result<()> {
+        self.uses_get = true;
+        let class = self.class.clone();
+        let (method, pool) = self.current();
+        method.indexed(INVOKESTATIC, pool.method(&class, GET, "()I"), 1);
+        self.store(dest);
+        Ok(())
+    }
+
+    fn put(&mut self, _out: &mut dyn Write, src: &Operand) -> io::Result<()> {
+        let (method, pool) = self.current();
+        let stdout = pool.field("java/lang/System", "out", "Ljava/io/PrintStream;");
+        method.indexed(GETSTATIC, stdout, 1);
+        self.load(src);
+        let (method, pool) = self.current();
+        let println = pool.method("java/io/PrintStream", "println", "(I)V");
+        method.indexed(INVOKEVIRTUAL, println, -2);
+        Ok(())
+    }
+
+    fn label(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.current().0.label(label);
+        Ok(())
+    }
+
+    fn jump(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        let (method, _) = self.current();
+        method.branch(GOTO, label, 0);
+        method.terminated = true;
+        Ok(())
+    }
+
+    fn branch(
+        &mut self,
+        _out: &mut dyn Write,
+        rel: Relation,
+        left: &Operand,
+        right: &Operand,
+        target: &str,
+    ) -> io::Result<()> {
+        self.load(left);
+        self.load(right);
+        let opcode = Jvm::compare(rel.negate());
+        self.current().0.branch(opcode, target, -2);
+        Ok(())
+    }
+
+    fn procedure(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.procedures.push(Method::new(
+            ACC_PRIVATE | ACC_STATIC,
+            name,
+            "()V",
+            Params::None,
+        ));
+        self.in_procedure = true;
+        Ok(())
+    }
+
+    fn ret(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        self.current().0.terminate(&[RETURN], 0);
+        self.in_procedure = false;
+        Ok(())
+    }
+
+    fn call(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        let class = self.class.clone();
+        let (method, pool) = self.current();
+        method.indexed(INVOKESTATIC, pool.method(&class, name, "()V"), 0);
+        Ok(())
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let mut methods = Vec::new();
+        let values = self.fields.iter().any(|field| {
+            self.symbols
+                .iter()
+                .any(|sym| &sym.name == field && sym.value != 0)
+        });
+        if self.uses_get || values {
+            methods.push(self.clinit());
+        }
+        if self.uses_get {
+            methods.push(self.get_method());
+        }
+        methods.append(&mut self.procedures);
+        methods.push(std::mem::replace(
+            &mut self.main,
+            Method::new(0, "", "", Params::None),
+        ));
+
+        // Methods first, writing them can still add to the constant pool
+        let mut body = Vec::new();
+        let this = self.pool.class(&self.class);
+        let object = self.pool.class("java/lang/Object");
+        u16_be(&mut body, ACC_PUBLIC | ACC_SUPER);
+        u16_be(&mut body, this);
+        u16_be(&mut body, object);
+        u16_be(&mut body, 0);
+
+        let mut fields: Vec<(String, &str)> = self
+            .fields
+            .iter()
+            .map(|field| (field.clone(), "I"))
+            .collect();
+        if self.uses_get {
+            fields.push((SCANNER_FIELD.to_string(), "Ljava/util/Scanner;"));
+        }
+        u16_be(&mut body, fields.len() as u16);
+        for (name, descriptor) in fields {
+            u16_be(&mut body, ACC_PRIVATE | ACC_STATIC);
+            u16_be(&mut body, self.pool.utf8(&name));
+            u16_be(&mut body, self.pool.utf8(descriptor));
+            u16_be(&mut body, 0);
+        }
+
+        u16_be(&mut body, methods.len() as u16);
+        for method in &mut methods {
+            method.write(&mut self.pool, &mut body)?;
+        }
+        u16_be(&mut body, 0);
+
+        let mut class = Vec::new();
+        u32_be(&mut class, MAGIC);
+        u16_be(&mut class, 0);
+        u16_be(&mut class, MAJOR);
+        self.pool.write(&mut class);
+        class.extend_from_slice(&body);
+        out.write_all(&class)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::{class_check, sample};
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::ir;
+    use crate::compiler::syntax::Quad;
+
+    fn generate(quads: Vec<Quad>, symbols: Vec<Symbol>) -> Vec<u8> {
+        let backend = Box::new(Jvm::new("code"));
+        let mut gen = Generator::with_writer(quads, symbols, backend, Vec::new());
+        gen.consume_quads().unwrap();
+        gen.into_inner()
+    }
+
+    #[test]
+    fn test_jvm_class() {
+        let bytes = generate(sample::quads(), sample::symbols());
+        let class = class_check::parse(&bytes).unwrap();
+
+        assert_eq!(class.name, "code");
+        assert_eq!(class.super_name, "java/lang/Object");
+        assert_eq!(
+            class.fields,
+            [
+                ("a".to_string(), "I".to_string()),
+                ("b".to_string(), "I".to_string()),
+                ("c".to_string(), "I".to_string()),
+                ("$scanner".to_string(), "Ljava/util/Scanner;".to_string()),
+            ]
+        );
+        let methods: Vec<(&str, &str)> = class
+            .methods
+            .iter()
+            .map(|method| (method.name.as_str(), method.descriptor.as_str()))
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                ("<clinit>", "()V"),
+                ("$get", "()I"),
+                ("show", "()V"),
+                ("main", "([Ljava/lang/String;)V"),
+            ]
+        );
+
+        // main zeroes temp_3..temp_5 in slots 1..3, then GET(a) is invokestatic $get; putstatic a
+        let main = &class.methods[3];
+        assert_eq!((main.max_stack, main.max_locals), (2, 4));
+        assert_eq!(&main.code[..6], [0x03, 0x3c, 0x03, 0x3d, 0x03, 0x3e]);
+        assert_eq!((main.code[6], main.code[9]), (0xb8, 0xb3));
+        // Frames at L1, L2 and L3
+        assert_eq!(main.frames, [18, 48, 60]);
+    }
+
+    #[test]
+    fn test_jvm_constants_and_dead_code() {
+        // Literals of every size, and a PUT nothing jumps to that follows a goto
+        let quads = ir::parse(
+            "    put -1\n\
+             \x20   put 100\n\
+             \x20   put 1000\n\
+             \x20   put 100000\n\
+             \x20   jmp @L1\n\
+             \x20   put 5\n\
+             L1:\n",
+        )
+        .unwrap();
+
+        let class = class_check::parse(&generate(quads, Vec::new())).unwrap();
+
+        assert_eq!(class.methods.len(), 1);
+        let main = &class.methods[0];
+        assert_eq!(main.max_stack, 2);
+        // getstatic, push, invokevirtual per PUT: iconst_m1, bipush, sipush, ldc_w
+        assert_eq!(main.code[3], 0x02);
+        assert_eq!(&main.code[10..12], [0x10, 100]);
+        assert_eq!(&main.code[18..21], [0x11, 0x03, 0xe8]);
+        assert_eq!(main.code[27], 0x13);
+        // The goto ends at 36 where the dead PUT needs a frame of its own, L1 follows at 43
+        assert_eq!(main.frames, [36, 43]);
+    }
+}
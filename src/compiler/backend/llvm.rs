@@ -0,0 +1,467 @@
+use std::io::{self, Write};
+
+use crate::compiler::backend::{Backend, Relation, Symbol, SymbolClass};
+use crate::compiler::syntax::Operand;
+
+const GET: &str = "small17_get";
+const PUT: &str = "small17_put";
+
+// LLVM IR text. Variables are internal globals, temps are allocas in the entry block of the
+// function using them, so the IR never needs phi nodes and `opt -passes=mem2reg` can promote
+// them. The runtime functions always pass an i32, the program itself works in i16 or i32.
+// Pointers are written typed (i16*) which every LLVM from 3.x on parses. A branch is an icmp and
+// a br to its label or a fresh next.N block, and only IR text has branches while the front end
+// stops at straight-line code.
+//
+// Names the program chose get a namespace of their own: variables are @v.name, procedures @p.name
+// and labels l.name. None can then be @main, a runtime function or a block the backend makes up.
+pub struct Llvm {
+    width: Width,
+    symbols: Vec<Symbol>,
+    // Variables the quads use that are not in the symbol table still need a global
+    globals: Vec<String>,
+    main: Function,
+    procedures: Vec<Function>,
+    in_procedure: bool,
+    uses_get: bool,
+    uses_put: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    I16,
+    I32,
+}
+
+// Body of one function, written out once the allocas it needs are known
+struct Function {
+    header: String,
+    locals: Vec<String>,
+    body: Vec<String>,
+    values: usize,
+    blocks: usize,
+    // The current block already ends in a br or ret
+    terminated: bool,
+}
+
+impl Width {
+    fn name(self) -> &'static str {
+        match self {
+            Width::I16 => "i16",
+            Width::I32 => "i32",
+        }
+    }
+}
+
+impl Function {
+    fn new(header: String) -> Self {
+        Function {
+            header,
+            locals: Vec::new(),
+            body: Vec::new(),
+            values: 0,
+            blocks: 0,
+            terminated: false,
+        }
+    }
+
+    fn value(&mut self) -> String {
+        self.values += 1;
+        format!("%t.{}", self.values)
+    }
+
+    fn block(&mut self) -> String {
+        self.blocks += 1;
+        format!("next.{}", self.blocks)
+    }
+
+    // Code after a br that no label starts is unreachable, it still needs a block of its own
+    fn inst(&mut self, inst: String) {
+        if self.terminated {
+            let block = self.block();
+            self.label(&block);
+        }
+        self.body.push(format!("  {}", inst));
+    }
+
+    fn label(&mut self, label: &str) {
+        if !self.terminated {
+            self.body.push(format!("  br label %{}", label));
+        }
+        self.body.push(format!("{}:", label));
+        self.terminated = false;
+    }
+
+    fn terminate(&mut self, inst: String) {
+        self.inst(inst);
+        self.terminated = true;
+    }
+
+    fn write(&mut self, out: &mut dyn Write, ty: &str, ret: &str) -> io::Result<()> {
+        if !self.terminated {
+            self.terminate(ret.to_string());
+        }
+        out.write_fmt(format_args!("\n{} {{\nentry:\n", self.header))?;
+        for local in &self.locals {
+            out.write_fmt(format_args!("  %{} = alloca {}\n", local, ty))?;
+        }
+        for line in &self.body {
+            out.write_fmt(format_args!("{}\n", line))?;
+        }
+        out.write_fmt(format_args!("}}\n"))
+    }
+}
+
+impl Llvm {
+    pub fn new() -> Self {
+        Llvm::with_width(Width::I16)
+    }
+
+    pub fn with_width(width: Width) -> Self {
+        Llvm {
+            width,
+            symbols: Vec::new(),
+            globals: Vec::new(),
+            main: Function::new(String::from("define i32 @main()")),
+            procedures: Vec::new(),
+            in_procedure: false,
+            uses_get: false,
+            uses_put: false,
+        }
+    }
+
+    fn current(&mut self) -> &mut Function {
+        match self.procedures.last_mut() {
+            Some(function) if self.in_procedure => function,
+            _ => &mut self.main,
+        }
+    }
+
+    fn is_temp(&self, name: &str) -> bool {
+        self.symbols
+            .iter()
+            .any(|sym| sym.name == name && sym.class == SymbolClass::Temp)
+    }
+
+    // Pointer to the storage of a variable or temp
+    fn address(&mut self, operand: &Operand) -> String {
+        let name = operand.to_string();
+        if self.is_temp(&name) {
+            let function = self.current();
+            if !function.locals.contains(&name) {
+                function.locals.push(name.clone());
+            }
+            format!("%{}", name)
+        } else {
+            if !self.globals.contains(&name) {
+                self.globals.push(name.clone());
+            }
+            format!("@v.{}", name)
+        }
+    }
+
+    // Literals are used as constants, anything else is loaded first
+    fn load(&mut self, operand: &Operand) -> String {
+        if let Some(value) = operand.constant() {
+            return value.to_string();
+        }
+        let ty = self.width.name();
+        let address = self.address(operand);
+        let function = self.current();
+        let value = function.value();
+        function.inst(format!("{} = load {}, {}* {}", value, ty, ty, address));
+        value
+    }
+
+    fn store(&mut self, value: &str, operand: &Operand) {
+        let ty = self.width.name();
+        let address = self.address(operand);
+        self.current()
+            .inst(format!("store {} {}, {}* {}", ty, value, ty, address));
+    }
+
+    fn binary(&mut self, op: &str, left: &Operand, right: &Operand, dest: &Operand) {
+        let ty = self.width.name();
+        let left = self.load(left);
+        let right = self.load(right);
+        let function = self.current();
+        let value = function.value();
+        function.inst(format!("{} = {} {} {}, {}", value, op, ty, left, right));
+        self.store(&value, dest);
+    }
+
+    fn predicate(rel: Relation) -> &'static str {
+        match rel {
+            Relation::Equal => "eq",
+            Relation::NEqual => "ne",
+            Relation::GreaterThan => "sgt",
+            Relation::LessThan => "slt",
+            Relation::GEqual => "sge",
+            Relation::LEqual => "sle",
+        }
+    }
+}
+
+impl Default for Llvm {
+    fn default() -> Self {
+        Llvm::new()
+    }
+}
+
+impl Backend for Llvm {
+    fn extension(&self) -> &str {
+        "ll"
+    }
+
+    fn prologue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "; Small17 program, link with a runtime that defines {} and {}\n",
+            GET, PUT
+        ))
+    }
+
+    // Globals are written at the end once every variable the quads touch is known
+    fn data(&mut self, _out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()> {
+        self.symbols = symbols.to_vec();
+        self.globals = symbols
+            .iter()
+            .filter(|sym| sym.class == SymbolClass::Identifier)
+            .map(|sym| sym.name.clone())
+            .collect();
+        Ok(())
+    }
+
+    fn text(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary("add", left, right, dest);
+        Ok(())
+    }
+
+    fn sub(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary("sub", left, right, dest);
+        Ok(())
+    }
+
+    fn mul(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary("mul", left, right, dest);
+        Ok(())
+    }
+
+    fn div(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.binary("sdiv", left, right, dest);
+        Ok(())
+    }
+
+    fn assign(&mut self, _out: &mut dyn Write, src: &Operand, dest: &Operand) -> io::Result<()> {
+        let value = self.load(src);
+        self.store(&value, dest);
+        Ok(())
+    }
+
+    fn get(&mut self, _out: &mut dyn Write, dest: &Operand) -> io::Result<()> {
+        self.uses_get = true;
+        let width = self.width;
+        let function = self.current();
+        let mut value = function.value();
+        function.inst(format!("{} = call i32 @{}()", value, GET));
+        if width == Width::I16 {
+            let wide = value;
+            value = function.value();
+            function.inst(format!("{} = trunc i32 {} to i16", value, wide));
+        }
+        self.store(&value, dest);
+        Ok(())
+    }
+
+    fn put(&mut self, _out: &mut dyn Write, src: &Operand) -> io::Result<()> {
+        self.uses_put = true;
+        let width = self.width;
+        let mut value = self.load(src);
+        let function = self.current();
+        if width == Width::I16 {
+            let narrow = value;
+            value = function.value();
+            function.inst(format!("{} = sext i16 {} to i32", value, narrow));
+        }
+        function.inst(format!("call void @{}(i32 {})", PUT, value));
+        Ok(())
+    }
+
+    fn label(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.current().label(&format!("l.{}", label));
+        Ok(())
+    }
+
+    fn jump(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.current().terminate(format!("br label %l.{}", label));
+        Ok(())
+    }
+
+    // The quad jumps when the relation does not hold, so a true icmp falls through
+    fn branch(
+        &mut self,
+        _out: &mut dyn Write,
+        rel: Relation,
+        left: &Operand,
+        right: &Operand,
+        target: &str,
+    ) -> io::Result<()> {
+        let ty = self.width.name();
+        let left = self.load(left);
+        let right = self.load(right);
+        let function = self.current();
+        let cond = function.value();
+        function.inst(format!(
+            "{} = icmp {} {} {}, {}",
+            cond,
+            Llvm::predicate(rel),
+            ty,
+            left,
+            right
+        ));
+        let next = function.block();
+        function.terminate(format!(
+            "br i1 {}, label %{}, label %l.{}",
+            cond, next, target
+        ));
+        function.label(&next);
+        Ok(())
+    }
+
+    fn procedure(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.procedures
+            .push(Function::new(format!("define internal void @p.{}()", name)));
+        self.in_procedure = true;
+        Ok(())
+    }
+
+    fn ret(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        self.current().terminate(String::from("ret void"));
+        self.in_procedure = false;
+        Ok(())
+    }
+
+    fn call(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.current().inst(format!("call void @p.{}()", name));
+        Ok(())
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let ty = self.width.name();
+        if !self.globals.is_empty() {
+            out.write_fmt(format_args!("\n"))?;
+        }
+        for global in &self.globals {
+            let value = self
+                .symbols
+                .iter()
+                .find(|sym| &sym.name == global)
+                .map_or(0, |sym| sym.value);
+            out.write_fmt(format_args!(
+                "@v.{} = internal global {} {}\n",
+                global, ty, value
+            ))?;
+        }
+
+        if self.uses_get || self.uses_put {
+            out.write_fmt(format_args!("\n"))?;
+        }
+        if self.uses_get {
+            out.write_fmt(format_args!("declare i32 @{}()\n", GET))?;
+        }
+        if self.uses_put {
+            out.write_fmt(format_args!("declare void @{}(i32)\n", PUT))?;
+        }
+
+        for procedure in &mut self.procedures {
+            procedure.write(out, ty, "ret void")?;
+        }
+        self.main.write(out, ty, "ret i32 0")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::ir;
+    use crate::compiler::syntax::QuadList;
+
+    fn generate(quads: QuadList, symbols: Vec<Symbol>, width: Width) -> String {
+        let backend = Box::new(Llvm::with_width(width));
+        let mut gen = Generator::with_writer(quads, symbols, backend, Vec::new());
+        gen.consume_quads().unwrap();
+        String::from_utf8(gen.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_llvm_output() {
+        let ir = generate(sample::quads(), sample::symbols(), Width::I16);
+
+        assert_eq!(ir, include_str!("golden/sample.ll"));
+    }
+
+    #[test]
+    fn test_llvm_i32_unreachable() {
+        // Nothing jumps to the PUT after JMP, it still has to start a block of its own
+        let quads = ir::parse(
+            "L1:\n\
+             \x20   get $a\n\
+             \x20   jmp @L1\n\
+             \x20   put $a\n",
+        )
+        .unwrap();
+
+        let ir = generate(quads, Vec::new(), Width::I32);
+
+        assert_eq!(ir, include_str!("golden/unreachable.ll"));
+    }
+
+    #[test]
+    fn test_llvm_names() {
+        // A variable and a procedure called main, and a label called entry, keep to their own
+        // namespaces
+        let quads = ir::parse(
+            "proc @main\n\
+             \x20   put $main\n\
+             \x20   ret\n\
+             \x20   get $main\n\
+             entry:\n\
+             \x20   call @main\n\
+             \x20   br.gt $main, 0, @entry\n",
+        )
+        .unwrap();
+
+        let ir = generate(quads, Vec::new(), Width::I32);
+
+        assert_eq!(ir, include_str!("golden/names.ll"));
+    }
+}
@@ -0,0 +1,342 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::compiler::syntax::{Operand, Quad, MAX_SHIFT};
+
+pub mod c;
+#[cfg(test)]
+pub mod class_check;
+pub mod elf;
+pub mod jvm;
+pub mod llvm;
+pub mod nasm;
+pub mod peephole;
+pub mod riscv;
+pub mod structured;
+pub mod wat;
+#[cfg(test)]
+pub mod wat_check;
+pub mod x86;
+pub mod x86_64;
+
+// A single entry from the symbol table that the generated program needs storage for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub class: SymbolClass,
+    pub value: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolClass {
+    Identifier,
+    Literal,
+    Temp,
+}
+
+// Selects which Backend the Generator is handed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    I386,
+    X86_64,
+    Gas,
+    C,
+    Wasm,
+    Llvm,
+    Elf,
+    Jvm,
+    RiscV,
+}
+
+// Comparison carried by a conditional branch quad
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Relation {
+    Equal,
+    NEqual,
+    GreaterThan,
+    LessThan,
+    GEqual,
+    LEqual,
+}
+
+// Each target implements these hooks, the Generator walks the quads and decides which one to call.
+// Hooks are called in the order: allocate, prologue, data, text, one hook per quad, epilogue.
+pub trait Backend {
+    // Extension used when the Generator picks the output file name, IE code.asm
+    fn extension(&self) -> &str;
+
+    // Whether the output is a program that can be run as is and needs the execute bit
+    fn executable(&self) -> bool {
+        false
+    }
+
+    // Sees every quad before any code is generated, for backends that decide up front where each
+    // value lives
+    fn allocate(&mut self, _quads: &[Quad]) {}
+
+    // Anything that has to come before the data declarations
+    fn prologue(&mut self, out: &mut dyn Write) -> io::Result<()>;
+
+    // Storage for every variable, literal and temp in the symbol table
+    fn data(&mut self, out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()>;
+
+    // Start of the executable code, the program entry point
+    fn text(&mut self, out: &mut dyn Write) -> io::Result<()>;
+
+    fn add(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()>;
+    fn sub(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()>;
+    fn mul(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()>;
+    fn div(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()>;
+    fn assign(&mut self, out: &mut dyn Write, src: &Operand, dest: &Operand) -> io::Result<()>;
+
+    // left * 2^bits and left / 2^bits, bits is a constant and the shift right rounds towards zero
+    // the same as div. By default these multiply and divide, targets with cheaper shifts
+    // override them.
+    fn shl(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        bits: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.mul(out, left, &Operand::Const(1 << shift_count(bits)?), dest)
+    }
+    fn shr(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        bits: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.div(out, left, &Operand::Const(1 << shift_count(bits)?), dest)
+    }
+
+    fn get(&mut self, out: &mut dyn Write, dest: &Operand) -> io::Result<()>;
+    fn put(&mut self, out: &mut dyn Write, src: &Operand) -> io::Result<()>;
+
+    fn label(&mut self, out: &mut dyn Write, label: &str) -> io::Result<()>;
+    fn jump(&mut self, out: &mut dyn Write, label: &str) -> io::Result<()>;
+
+    // Jump to target when `left rel right` does NOT hold, otherwise fall through
+    fn branch(
+        &mut self,
+        out: &mut dyn Write,
+        rel: Relation,
+        left: &Operand,
+        right: &Operand,
+        target: &str,
+    ) -> io::Result<()>;
+
+    // A procedure body runs from its PROCEDURE quad up to the matching RET, bodies do not nest
+    fn procedure(&mut self, out: &mut dyn Write, name: &str) -> io::Result<()>;
+    fn ret(&mut self, out: &mut dyn Write) -> io::Result<()>;
+    fn call(&mut self, out: &mut dyn Write, name: &str) -> io::Result<()>;
+
+    // Anything that has to come after the last quad, runtime routines, exit, etc
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()>;
+}
+
+// Number of bits a Shl or Shr moves by, ir::typed only lets a Const from 0 to MAX_SHIFT through
+pub fn shift_count(bits: &Operand) -> io::Result<u32> {
+    match bits.constant() {
+        Some(count @ 0..=MAX_SHIFT) => Ok(count as u32),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("[ Error ] Bad shift count: {}", bits),
+        )),
+    }
+}
+
+impl Target {
+    pub fn backend(self) -> Box<dyn Backend> {
+        match self {
+            Target::I386 => Box::new(nasm::Nasm::new()),
+            Target::X86_64 | Target::Gas | Target::Elf => Box::new(self.x86_64().unwrap()),
+            Target::C => Box::new(structured::Structured::new(c::C)),
+            Target::Wasm => Box::new(structured::Structured::new(wat::Wat)),
+            Target::Llvm => Box::new(llvm::Llvm::new()),
+            // The Generator writes code.class, the class name has to match the file
+            Target::Jvm => Box::new(jvm::Jvm::new("code")),
+            Target::RiscV => Box::new(riscv::RiscV::new()),
+        }
+    }
+
+    // The backend of the x86-64 targets, which can be told to keep values in registers
+    pub fn x86_64(self) -> Option<x86_64::X86_64> {
+        match self {
+            Target::X86_64 => Some(x86_64::X86_64::new()),
+            Target::Gas => Some(x86_64::X86_64::with_dialect(x86::Dialect::Gas)),
+            Target::Elf => Some(x86_64::X86_64::elf()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i386" => Ok(Target::I386),
+            "x86-64" | "x86_64" => Ok(Target::X86_64),
+            "gas" => Ok(Target::Gas),
+            "c" => Ok(Target::C),
+            "wasm" | "wat" => Ok(Target::Wasm),
+            "llvm" | "ll" => Ok(Target::Llvm),
+            "elf" => Ok(Target::Elf),
+            "jvm" | "class" => Ok(Target::Jvm),
+            "riscv" | "rv32" => Ok(Target::RiscV),
+            e => Err(format!("[ Error ] Unknown target: {}", e)),
+        }
+    }
+}
+
+impl From<&str> for SymbolClass {
+    fn from(class: &str) -> Self {
+        match class {
+            "Identifier" => SymbolClass::Identifier,
+            "Literal" => SymbolClass::Literal,
+            "Temp" => SymbolClass::Temp,
+            e => panic!("[ Error ] Could not parse symbol class: {}", e),
+        }
+    }
+}
+
+impl Relation {
+    pub fn from_op(op: &str) -> Option<Self> {
+        match op {
+            "==" => Some(Relation::Equal),
+            "!=" => Some(Relation::NEqual),
+            ">" => Some(Relation::GreaterThan),
+            "<" => Some(Relation::LessThan),
+            ">=" => Some(Relation::GEqual),
+            "<=" => Some(Relation::LEqual),
+            _ => None,
+        }
+    }
+
+    pub fn op(self) -> &'static str {
+        match self {
+            Relation::Equal => "==",
+            Relation::NEqual => "!=",
+            Relation::GreaterThan => ">",
+            Relation::LessThan => "<",
+            Relation::GEqual => ">=",
+            Relation::LEqual => "<=",
+        }
+    }
+
+    // The relation that holds exactly when this one does not
+    pub fn negate(self) -> Self {
+        match self {
+            Relation::Equal => Relation::NEqual,
+            Relation::NEqual => Relation::Equal,
+            Relation::GreaterThan => Relation::LEqual,
+            Relation::LessThan => Relation::GEqual,
+            Relation::GEqual => Relation::LessThan,
+            Relation::LEqual => Relation::GreaterThan,
+        }
+    }
+}
+
+// Quads shared by the backend tests, a procedure, a WHILE loop, an IF and plenty of temps:
+//
+//   PROCEDURE show; c = (a + b) * 3; PUT(c);
+//   GET(a); GET(b);
+//   WHILE a > 0 { a = a - 1; b = b * 2; }
+//   IF a < b { CALL show; }
+//   PUT(b / 2);
+//
+// With input 3 and 5 it prints 120 then 20. Syntax has no polish for IF, WHILE or PROCEDURE yet,
+// so the program is written as the IR it would lower to and read with ir::parse. The branches,
+// loops and calls the backends and passes handle only ever reach them this way.
+#[cfg(test)]
+pub mod sample {
+    use super::{Symbol, SymbolClass};
+    use crate::compiler::ir;
+    use crate::compiler::syntax::{Operand, QuadList};
+
+    pub const TEXT: &str = "proc @show\n\
+                            \x20   add %temp_1, $a, $b\n\
+                            \x20   mul %temp_2, %temp_1, 3\n\
+                            \x20   copy $c, %temp_2\n\
+                            \x20   put $c\n\
+                            \x20   ret\n\
+                            \x20   get $a\n\
+                            \x20   get $b\n\
+                            L1:\n\
+                            \x20   br.gt $a, 0, @L2\n\
+                            \x20   sub %temp_3, $a, 1\n\
+                            \x20   copy $a, %temp_3\n\
+                            \x20   mul %temp_4, $b, 2\n\
+                            \x20   copy $b, %temp_4\n\
+                            \x20   jmp @L1\n\
+                            L2:\n\
+                            \x20   br.lt $a, $b, @L3\n\
+                            \x20   call @show\n\
+                            L3:\n\
+                            \x20   div %temp_5, $b, 2\n\
+                            \x20   put %temp_5\n";
+
+    // Operands for the tests of the backends and passes
+    pub fn var(name: &str) -> Operand {
+        Operand::var(name)
+    }
+
+    pub fn temp(id: usize) -> Operand {
+        Operand::temp(id)
+    }
+
+    pub fn num(value: i32) -> Operand {
+        Operand::Const(value)
+    }
+
+    pub fn quads() -> QuadList {
+        ir::parse(TEXT).unwrap()
+    }
+
+    pub fn symbols() -> Vec<Symbol> {
+        let symbol = |name: &str, class: SymbolClass| Symbol {
+            name: name.to_string(),
+            class,
+            value: 0,
+        };
+
+        let mut symbols = vec![
+            symbol("a", SymbolClass::Identifier),
+            symbol("b", SymbolClass::Identifier),
+            symbol("c", SymbolClass::Identifier),
+        ];
+        for lit in ["0", "1", "2", "3"] {
+            symbols.push(symbol(lit, SymbolClass::Literal));
+        }
+        for id in 1..=5 {
+            symbols.push(symbol(&Operand::temp(id).to_string(), SymbolClass::Temp));
+        }
+        symbols
+    }
+}
@@ -0,0 +1,230 @@
+use std::io::{self, Write};
+
+use crate::compiler::backend::{shift_count, Backend, Relation, Symbol, SymbolClass};
+use crate::compiler::syntax::Operand;
+
+// 32-bit NASM output, arithmetic is done 16 bits at a time through ax and I/O goes through int 80h
+#[derive(Default)]
+pub struct Nasm {
+    io_flag: bool,
+    procedure: String,
+}
+
+impl Nasm {
+    pub fn new() -> Self {
+        Nasm {
+            io_flag: false,
+            procedure: String::new(),
+        }
+    }
+
+    // Condition code of the jump taken when `rel` holds
+    fn jump_code(rel: Relation) -> &'static str {
+        match rel {
+            Relation::Equal => "je",
+            Relation::NEqual => "jne",
+            Relation::GreaterThan => "jg",
+            Relation::LessThan => "jl",
+            Relation::GEqual => "jge",
+            Relation::LEqual => "jle",
+        }
+    }
+
+    // A literal is an immediate, anything else is the word stored under its name
+    fn source(operand: &Operand) -> String {
+        match operand {
+            Operand::Const(value) => value.to_string(),
+            operand => format!("[{}]", operand),
+        }
+    }
+}
+
+impl Backend for Nasm {
+    fn extension(&self) -> &str {
+        "asm"
+    }
+
+    fn prologue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "sys_exit equ 1\nsys_read equ 3\nsys_write equ 4\nstdin equ 0\nstdout equ 1\n.DATA\n"
+        ))
+    }
+
+    fn data(&mut self, out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()> {
+        for symbol in symbols {
+            if symbol.class != SymbolClass::Literal {
+                out.write_fmt(format_args!("{:<5} DW {}\n", symbol.name, symbol.value))?;
+            }
+        }
+        out.write_fmt(format_args!(
+            "section .bss\n\tblen equ 6\n\tbuffer resb blen\n"
+        ))
+    }
+
+    fn text(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "section .text\n\tglobal _start\n_start: nop\n"
+        ))
+    }
+
+    fn add(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tadd ax,{}\n\tmov [{}],ax\n",
+            Nasm::source(left),
+            Nasm::source(right),
+            dest
+        ))
+    }
+
+    fn sub(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tsub ax,{}\n\tmov [{}],ax\n",
+            Nasm::source(left),
+            Nasm::source(right),
+            dest
+        ))
+    }
+
+    fn mul(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tmov bx,{}\n\tmul bx\n\tmov [{}],ax\n",
+            Nasm::source(left),
+            Nasm::source(right),
+            dest
+        ))
+    }
+
+    fn div(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov dx,0\n\tmov ax,{}\n\tmov bx,{}\n\tdiv bx\n\tmov [{}],ax\n",
+            Nasm::source(left),
+            Nasm::source(right),
+            dest
+        ))
+    }
+
+    // div bx is unsigned, so a logical shift right divides the same way
+    fn shl(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        bits: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tshl ax,{}\n\tmov [{}],ax\n",
+            Nasm::source(left),
+            shift_count(bits)?,
+            dest
+        ))
+    }
+
+    fn shr(
+        &mut self,
+        out: &mut dyn Write,
+        left: &Operand,
+        bits: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tshr ax,{}\n\tmov [{}],ax\n",
+            Nasm::source(left),
+            shift_count(bits)?,
+            dest
+        ))
+    }
+
+    fn assign(&mut self, out: &mut dyn Write, src: &Operand, dest: &Operand) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tmov [{}],ax\n",
+            Nasm::source(src),
+            dest
+        ))
+    }
+
+    fn get(&mut self, out: &mut dyn Write, _dest: &Operand) -> io::Result<()> {
+        self.io_flag = true;
+        out.write_fmt(format_args!("call GetInput\n"))
+    }
+
+    fn put(&mut self, out: &mut dyn Write, _src: &Operand) -> io::Result<()> {
+        self.io_flag = true;
+        out.write_fmt(format_args!("call Print\n"))
+    }
+
+    fn label(&mut self, out: &mut dyn Write, label: &str) -> io::Result<()> {
+        out.write_fmt(format_args!("{}:\n", label))
+    }
+
+    fn jump(&mut self, out: &mut dyn Write, label: &str) -> io::Result<()> {
+        out.write_fmt(format_args!("\tjmp {}\n", label))
+    }
+
+    fn branch(
+        &mut self,
+        out: &mut dyn Write,
+        rel: Relation,
+        left: &Operand,
+        right: &Operand,
+        target: &str,
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "\tmov ax,{}\n\tcmp ax,{}\n\t{} {}\n",
+            Nasm::source(left),
+            Nasm::source(right),
+            Nasm::jump_code(rel.negate()),
+            target
+        ))
+    }
+
+    // Procedures are emitted where they appear, so jump over the body
+    fn procedure(&mut self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.procedure = name.to_string();
+        out.write_fmt(format_args!("\tjmp {}_end\n{}:\n", name, name))
+    }
+
+    fn ret(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_fmt(format_args!("\tret\n{}_end:\n", self.procedure))
+    }
+
+    fn call(&mut self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        out.write_fmt(format_args!("\tcall {}\n", name))
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        if !self.io_flag {
+            return Ok(());
+        }
+
+        out.write_fmt(format_args!(
+            "GetInput:\nmov eax, 3\nmov ebx, 2\nmov ecx, buffer\nmov edx, blen\nint 80h\n"
+        ))?;
+        out.write_fmt(format_args!(
+            "Print:\npush ax\npush dx\nmov eax, 4\nmov ebx, 1\nmov ecx"
+        ))
+    }
+}
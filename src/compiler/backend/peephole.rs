@@ -0,0 +1,415 @@
+use crate::compiler::backend::x86::{Inst, Mem, Operand};
+
+// Peephole optimisation of an x86 instruction stream.
+//
+// Every quad is translated on its own, so the stream repeats itself where two quads meet: a result
+// stored to a temp is loaded straight back, and a jump lands on the instruction after it or on
+// another jump. Each rule looks at the instructions starting at one position and may replace some
+// of them. The rules are tried at every position until none applies anywhere.
+//
+// Values are 32-bit, so nothing reads the upper half of a register a dword move writes and a move
+// of a register to itself does nothing.
+pub struct Rule {
+    pub name: &'static str,
+    pub apply: fn(&[Inst], usize) -> Option<Rewrite>,
+}
+
+// Replace `len` instructions with `with`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    pub len: usize,
+    pub with: Vec<Inst>,
+}
+
+pub const RULES: [Rule; 6] = [
+    Rule {
+        name: "self-move",
+        apply: self_move,
+    },
+    Rule {
+        name: "reload",
+        apply: reload,
+    },
+    Rule {
+        name: "dead-store",
+        apply: dead_store,
+    },
+    Rule {
+        name: "jump-to-next",
+        apply: jump_to_next,
+    },
+    Rule {
+        name: "jump-chain",
+        apply: jump_chain,
+    },
+    Rule {
+        name: "unreachable",
+        apply: unreachable,
+    },
+];
+
+pub fn optimise(code: Vec<Inst>) -> Vec<Inst> {
+    optimise_with(code, &RULES)
+}
+
+pub fn optimise_with(mut code: Vec<Inst>, rules: &[Rule]) -> Vec<Inst> {
+    // Every rewrite either shortens the stream or points a jump at its final target, so this ends
+    loop {
+        let mut changed = false;
+        let mut at = 0;
+        while at < code.len() {
+            match rules.iter().find_map(|rule| (rule.apply)(&code, at)) {
+                Some(Rewrite { len, with }) => {
+                    code.splice(at..at + len, with);
+                    changed = true;
+                }
+                None => at += 1,
+            }
+        }
+        if !changed {
+            return code;
+        }
+    }
+}
+
+fn remove(len: usize) -> Option<Rewrite> {
+    Some(Rewrite {
+        len,
+        with: Vec::new(),
+    })
+}
+
+// `mov eax,eax`
+fn self_move(code: &[Inst], at: usize) -> Option<Rewrite> {
+    match &code[at] {
+        Inst::Mov(_, dst, src) if dst == src => remove(1),
+        _ => None,
+    }
+}
+
+// Whether writing `a` can change what `b` reads, a register is also the base of an address
+fn overlaps(a: &Operand, b: &Operand) -> bool {
+    match (a, b) {
+        (Operand::Reg(reg), Operand::Mem(Mem::Base(base, _))) => reg == base,
+        (Operand::Mem(Mem::Base(base, _)), Operand::Reg(reg)) => reg == base,
+        _ => a == b,
+    }
+}
+
+// `mov [x],eax` then `mov eax,[x]`, or the same move twice, the second finds the value in place
+fn reload(code: &[Inst], at: usize) -> Option<Rewrite> {
+    match (&code[at], code.get(at + 1)?) {
+        (Inst::Mov(size, dst, src), Inst::Mov(next, next_dst, next_src))
+            if size == next && !overlaps(dst, src) =>
+        {
+            let swapped = next_dst == src && next_src == dst;
+            let repeated = next_dst == dst && next_src == src;
+            match swapped || repeated {
+                true => Some(Rewrite {
+                    len: 2,
+                    with: vec![code[at].clone()],
+                }),
+                false => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// `mov [x],eax` then `mov [x],ecx`, the first value is never read
+fn dead_store(code: &[Inst], at: usize) -> Option<Rewrite> {
+    match (&code[at], code.get(at + 1)?) {
+        (
+            Inst::Mov(size, Operand::Mem(dst @ Mem::Label(..)), _),
+            Inst::Mov(next, Operand::Mem(next_dst), next_src),
+        ) if size == next && dst == next_dst && !matches!(next_src, Operand::Mem(_)) => remove(1),
+        _ => None,
+    }
+}
+
+fn target(inst: &Inst) -> Option<&str> {
+    match inst {
+        Inst::Jmp(label) | Inst::Jcc(_, label) => Some(label),
+        _ => None,
+    }
+}
+
+// `jmp L` straight before `L:`, possibly among other labels
+fn jump_to_next(code: &[Inst], at: usize) -> Option<Rewrite> {
+    let label = target(&code[at])?;
+    let lands = code[at + 1..]
+        .iter()
+        .map_while(|inst| match inst {
+            Inst::Label(name) => Some(name),
+            _ => None,
+        })
+        .any(|name| name == label);
+    match lands {
+        true => remove(1),
+        false => None,
+    }
+}
+
+// First instruction that runs after jumping to `label`
+fn landing<'a>(code: &'a [Inst], label: &str) -> Option<&'a Inst> {
+    let start = code
+        .iter()
+        .position(|inst| matches!(inst, Inst::Label(name) if name == label))?;
+    code[start..]
+        .iter()
+        .find(|inst| !matches!(inst, Inst::Label(_)))
+}
+
+// `jmp L1` where L1 holds `jmp L2` goes to L2 directly, a cycle of jumps is left alone
+fn jump_chain(code: &[Inst], at: usize) -> Option<Rewrite> {
+    let first = target(&code[at])?;
+    let mut label = first;
+    let mut seen = vec![first];
+    while let Some(Inst::Jmp(next)) = landing(code, label) {
+        if seen.contains(&next.as_str()) {
+            return None;
+        }
+        seen.push(next);
+        label = next;
+    }
+    if label == first {
+        return None;
+    }
+    let with = match &code[at] {
+        Inst::Jcc(cond, _) => Inst::Jcc(*cond, label.to_string()),
+        _ => Inst::Jmp(label.to_string()),
+    };
+    Some(Rewrite {
+        len: 1,
+        with: vec![with],
+    })
+}
+
+// Nothing after a `jmp` or `ret` runs before the next label
+fn unreachable(code: &[Inst], at: usize) -> Option<Rewrite> {
+    match (&code[at], code.get(at + 1)?) {
+        (Inst::Jmp(_) | Inst::Ret, Inst::Label(_)) => None,
+        (Inst::Jmp(_) | Inst::Ret, _) => Some(Rewrite {
+            len: 2,
+            with: vec![code[at].clone()],
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::x86::{AluOp, Cond, Reg, Size};
+
+    fn mov(dst: Operand, src: Operand) -> Inst {
+        Inst::Mov(Size::Dword, dst, src)
+    }
+
+    fn reg(reg: Reg) -> Operand {
+        Operand::Reg(reg)
+    }
+
+    // A variable in the data section
+    fn mem(name: &str) -> Operand {
+        Operand::Mem(Mem::Label(name.to_string(), 0))
+    }
+
+    fn label(name: &str) -> Inst {
+        Inst::Label(name.to_string())
+    }
+
+    fn jmp(name: &str) -> Inst {
+        Inst::Jmp(name.to_string())
+    }
+
+    fn add(dst: Operand, src: Operand) -> Inst {
+        Inst::Alu(AluOp::Add, Size::Dword, dst, src)
+    }
+
+    // Only the named rule, so each test shows what that one does by itself
+    fn only(name: &str, code: Vec<Inst>) -> Vec<Inst> {
+        let rule = RULES.iter().position(|rule| rule.name == name).unwrap();
+        optimise_with(code, &RULES[rule..=rule])
+    }
+
+    #[test]
+    fn test_self_move() {
+        let eax = reg(Reg::Ax);
+        let code = vec![
+            mov(eax.clone(), eax.clone()),
+            mov(reg(Reg::Bx), eax.clone()),
+            Inst::Mov(Size::Byte, reg(Reg::Cx), reg(Reg::Cx)),
+        ];
+        assert_eq!(only("self-move", code), [mov(reg(Reg::Bx), eax)]);
+    }
+
+    #[test]
+    fn test_reload() {
+        let eax = reg(Reg::Ax);
+        let code = vec![
+            // The temp is stored and loaded straight back
+            mov(mem("temp_1"), eax.clone()),
+            mov(eax.clone(), mem("temp_1")),
+            // A load repeated
+            mov(eax.clone(), mem("a")),
+            mov(eax.clone(), mem("a")),
+            // Another size, or a register used as the address, have to stay
+            mov(mem("b"), eax.clone()),
+            Inst::Mov(Size::Byte, eax.clone(), mem("b")),
+            mov(reg(Reg::Si), Operand::Mem(Mem::Base(Reg::Si, 0))),
+            mov(reg(Reg::Si), Operand::Mem(Mem::Base(Reg::Si, 0))),
+        ];
+        assert_eq!(
+            only("reload", code),
+            [
+                mov(mem("temp_1"), eax.clone()),
+                mov(eax.clone(), mem("a")),
+                mov(mem("b"), eax.clone()),
+                Inst::Mov(Size::Byte, eax, mem("b")),
+                mov(reg(Reg::Si), Operand::Mem(Mem::Base(Reg::Si, 0))),
+                mov(reg(Reg::Si), Operand::Mem(Mem::Base(Reg::Si, 0))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dead_store() {
+        let (eax, ecx) = (reg(Reg::Ax), reg(Reg::Cx));
+        let code = vec![
+            mov(mem("x"), eax.clone()),
+            mov(mem("x"), ecx.clone()),
+            // Read in between
+            mov(mem("y"), eax.clone()),
+            add(ecx.clone(), mem("y")),
+            mov(mem("y"), ecx.clone()),
+            // Through a register the address is not known
+            mov(Operand::Mem(Mem::Base(Reg::Si, 0)), eax.clone()),
+            mov(Operand::Mem(Mem::Base(Reg::Si, 0)), ecx.clone()),
+        ];
+        assert_eq!(
+            only("dead-store", code),
+            [
+                mov(mem("x"), ecx.clone()),
+                mov(mem("y"), eax.clone()),
+                add(ecx.clone(), mem("y")),
+                mov(mem("y"), ecx.clone()),
+                mov(Operand::Mem(Mem::Base(Reg::Si, 0)), eax),
+                mov(Operand::Mem(Mem::Base(Reg::Si, 0)), ecx),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_to_next() {
+        let code = vec![
+            jmp("L2"),
+            label("L1"),
+            label("L2"),
+            Inst::Jcc(Cond::LE, "L3".to_string()),
+            label("L3"),
+            jmp("L1"),
+            Inst::Ret,
+            label("L1b"),
+        ];
+        assert_eq!(
+            only("jump-to-next", code),
+            [
+                label("L1"),
+                label("L2"),
+                label("L3"),
+                jmp("L1"),
+                Inst::Ret,
+                label("L1b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_chain() {
+        let code = vec![
+            Inst::Jcc(Cond::E, "L1".to_string()),
+            jmp("L2"),
+            label("L1"),
+            jmp("L2"),
+            label("L2"),
+            label("L3"),
+            jmp("L4"),
+            label("L4"),
+            Inst::Ret,
+            // A loop of jumps has nowhere to go
+            label("L5"),
+            jmp("L6"),
+            label("L6"),
+            jmp("L5"),
+        ];
+        assert_eq!(
+            only("jump-chain", code),
+            [
+                Inst::Jcc(Cond::E, "L4".to_string()),
+                jmp("L4"),
+                label("L1"),
+                jmp("L4"),
+                label("L2"),
+                label("L3"),
+                jmp("L4"),
+                label("L4"),
+                Inst::Ret,
+                label("L5"),
+                jmp("L6"),
+                label("L6"),
+                jmp("L5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let eax = reg(Reg::Ax);
+        let code = vec![
+            jmp("L1"),
+            mov(eax.clone(), mem("a")),
+            Inst::Ret,
+            label("L1"),
+            Inst::Ret,
+            add(eax.clone(), eax),
+        ];
+        assert_eq!(
+            only("unreachable", code),
+            [jmp("L1"), label("L1"), Inst::Ret]
+        );
+    }
+
+    #[test]
+    fn test_optimise() {
+        // What `x = a + b; PUT(x)` and an IF with an empty ELSE come out as
+        let (eax, ebx) = (reg(Reg::Ax), reg(Reg::Bx));
+        let code = vec![
+            mov(eax.clone(), mem("a")),
+            add(eax.clone(), mem("b")),
+            mov(mem("temp_1"), eax.clone()),
+            mov(eax.clone(), mem("temp_1")),
+            mov(mem("x"), eax.clone()),
+            mov(eax.clone(), mem("x")),
+            mov(ebx.clone(), ebx.clone()),
+            jmp("L1"),
+            mov(eax.clone(), mem("y")),
+            label("L1"),
+            jmp("L2"),
+            label("L2"),
+            Inst::Call("rt_put_int".to_string()),
+        ];
+        assert_eq!(
+            optimise(code),
+            [
+                mov(eax.clone(), mem("a")),
+                add(eax.clone(), mem("b")),
+                mov(mem("temp_1"), eax.clone()),
+                mov(mem("x"), eax),
+                label("L1"),
+                label("L2"),
+                Inst::Call("rt_put_int".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::io::{self, Write};
+
+use crate::compiler::backend::{Backend, Relation, Symbol, SymbolClass};
+use crate::compiler::syntax::Operand;
+
+// Simulator ecall numbers, the same in RARS and Venus
+const PRINT_INT: i32 = 1;
+const READ_INT: i32 = 5;
+const EXIT: i32 = 10;
+const PRINT_CHAR: i32 = 11;
+
+// RV32IM assembly in the GNU syntax RARS and Venus also accept. Variables and temps are words in
+// .data reached through la, t0 holds the address and t1/t2 the operands. Procedures save ra on
+// the stack so they can call each other and are placed after the exit of the main program.
+#[derive(Default)]
+pub struct RiscV {
+    symbols: Vec<Symbol>,
+    // Variables the quads use that are not in the symbol table still need a word
+    words: Vec<String>,
+    main: Vec<String>,
+    procedures: Vec<String>,
+    in_procedure: bool,
+}
+
+impl RiscV {
+    pub fn new() -> Self {
+        RiscV {
+            symbols: Vec::new(),
+            words: Vec::new(),
+            main: Vec::new(),
+            procedures: Vec::new(),
+            in_procedure: false,
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        if self.in_procedure {
+            self.procedures.push(line);
+        } else {
+            self.main.push(line);
+        }
+    }
+
+    fn word(&mut self, name: &str) {
+        if !self.words.iter().any(|word| word == name) {
+            self.words.push(name.to_string());
+        }
+    }
+
+    fn load(&mut self, reg: &str, operand: &Operand) {
+        if let Some(value) = operand.constant() {
+            self.emit(format!("\tli {}, {}", reg, value));
+        } else {
+            self.word(&operand.to_string());
+            self.emit(format!("\tla t0, {}", operand));
+            self.emit(format!("\tlw {}, 0(t0)", reg));
+        }
+    }
+
+    fn store(&mut self, reg: &str, operand: &Operand) {
+        self.word(&operand.to_string());
+        self.emit(format!("\tla t0, {}", operand));
+        self.emit(format!("\tsw {}, 0(t0)", reg));
+    }
+
+    fn arith(&mut self, op: &str, left: &Operand, right: &Operand, dest: &Operand) {
+        self.load("t1", left);
+        self.load("t2", right);
+        self.emit(format!("\t{} t1, t1, t2", op));
+        self.store("t1", dest);
+    }
+
+    fn ecall(&mut self, service: i32) {
+        self.emit(format!("\tli a7, {}", service));
+        self.emit(String::from("\tecall"));
+    }
+
+    // Branch taken when `t1 rel t2` holds, only the base instructions so > and <= swap operands
+    fn branch_code(rel: Relation) -> &'static str {
+        match rel {
+            Relation::Equal => "beq t1, t2",
+            Relation::NEqual => "bne t1, t2",
+            Relation::LessThan => "blt t1, t2",
+            Relation::GEqual => "bge t1, t2",
+            Relation::GreaterThan => "blt t2, t1",
+            Relation::LEqual => "bge t2, t1",
+        }
+    }
+}
+
+impl Backend for RiscV {
+    fn extension(&self) -> &str {
+        "s"
+    }
+
+    fn prologue(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Words are written at the end once every variable the quads touch is known
+    fn data(&mut self, _out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()> {
+        self.symbols = symbols.to_vec();
+        for symbol in symbols {
+            if symbol.class != SymbolClass::Literal {
+                self.word(&symbol.name);
+            }
+        }
+        Ok(())
+    }
+
+    fn text(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.arith("add", left, right, dest);
+        Ok(())
+    }
+
+    fn sub(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.arith("sub", left, right, dest);
+        Ok(())
+    }
+
+    fn mul(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.arith("mul", left, right, dest);
+        Ok(())
+    }
+
+    fn div(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.arith("div", left, right, dest);
+        Ok(())
+    }
+
+    fn assign(&mut self, _out: &mut dyn Write, src: &Operand, dest: &Operand) -> io::Result<()> {
+        self.load("t1", src);
+        self.store("t1", dest);
+        Ok(())
+    }
+
+    fn get(&mut self, _out: &mut dyn Write, dest: &Operand) -> io::Result<()> {
+        self.ecall(READ_INT);
+        self.store("a0", dest);
+        Ok(())
+    }
+
+    fn put(&mut self, _out: &mut dyn Write, src: &Operand) -> io::Result<()> {
+        self.load("a0", src);
+        self.ecall(PRINT_INT);
+        self.emit(String::from("\tli a0, 10"));
+        self.ecall(PRINT_CHAR);
+        Ok(())
+    }
+
+    fn label(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.emit(format!("{}:", label));
+        Ok(())
+    }
+
+    fn jump(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.emit(format!("\tj {}", label));
+        Ok(())
+    }
+
+    fn branch(
+        &mut self,
+        _out: &mut dyn Write,
+        rel: Relation,
+        left: &Operand,
+        right: &Operand,
+        target: &str,
+    ) -> io::Result<()> {
+        self.load("t1", left);
+        self.load("t2", right);
+        self.emit(format!(
+            "\t{}, {}",
+            RiscV::branch_code(rel.negate()),
+            target
+        ));
+        Ok(())
+    }
+
+    fn procedure(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.in_procedure = true;
+        self.emit(format!("{}:", name));
+        self.emit(String::from("\taddi sp, sp, -16"));
+        self.emit(String::from("\tsw ra, 12(sp)"));
+        Ok(())
+    }
+
+    fn ret(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        self.emit(String::from("\tlw ra, 12(sp)"));
+        self.emit(String::from("\taddi sp, sp, 16"));
+        self.emit(String::from("\tret"));
+        self.in_procedure = false;
+        Ok(())
+    }
+
+    fn call(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.emit(format!("\tcall {}", name));
+        Ok(())
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.ecall(EXIT);
+
+        out.write_fmt(format_args!("\t.data\n"))?;
+        for word in &self.words {
+            let value = self
+                .symbols
+                .iter()
+                .find(|symbol| &symbol.name == word)
+                .map_or(0, |symbol| symbol.value);
+            out.write_fmt(format_args!("{}:\t.word {}\n", word, value))?;
+        }
+        out.write_fmt(format_args!("\n\t.text\n\t.globl main\nmain:\n"))?;
+        for line in self.main.iter().chain(&self.procedures) {
+            out.write_fmt(format_args!("{}\n", line))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::syntax::{Quad, QuadList};
+
+    fn generate(quads: QuadList, symbols: Vec<Symbol>) -> String {
+        let backend = Box::new(RiscV::new());
+        let mut gen = Generator::with_writer(quads, symbols, backend, Vec::new());
+        gen.consume_quads().unwrap();
+        String::from_utf8(gen.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_riscv_output() {
+        let asm = generate(sample::quads(), sample::symbols());
+
+        assert_eq!(asm, include_str!("golden/sample.s"));
+    }
+
+    #[test]
+    fn test_riscv_branches() {
+        // One branch per relation, each jumps to L1 when `a rel 7` does not hold
+        let mut quads: QuadList = [
+            Relation::Equal,
+            Relation::NEqual,
+            Relation::GreaterThan,
+            Relation::LessThan,
+            Relation::GEqual,
+            Relation::LEqual,
+        ]
+        .into_iter()
+        .map(|rel| Quad::branch(rel, Operand::var("a"), Operand::Const(7), "L1"))
+        .collect();
+        quads.push(Quad::label("L1"));
+
+        let asm = generate(quads, Vec::new());
+
+        assert_eq!(asm, include_str!("golden/branches.s"));
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::compiler::backend::{Backend, Relation, Symbol, SymbolClass};
+use crate::compiler::syntax::Operand;
+
+// Targets with structured control flow or expression syntax (C, WebAssembly) cannot be written a
+// quad at a time. Structured collects every quad into one statement list per function and hands
+// the whole Program to a Render once the last quad has been seen.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Var(String),
+    Const(i64),
+    Bin(char, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stmt {
+    Assign(String, Expr),
+    Get(String),
+    Put(Expr),
+    Call(String),
+    Label(String),
+    Goto(String),
+    // Jump to the label when the comparison does not hold
+    Branch(Relation, Expr, Expr, String),
+}
+
+// Statements after IF and WHILE have been recovered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Stmt(Stmt),
+    If(Relation, Expr, Expr, Vec<Node>),
+    // The loop keeps the labels of its first statement and of the statement after it
+    While(String, String, Relation, Expr, Expr, Vec<Node>),
+}
+
+#[derive(Default)]
+pub struct Program {
+    pub symbols: Vec<Symbol>,
+    pub main: Vec<Stmt>,
+    pub procedures: Vec<(String, Vec<Stmt>)>,
+    in_procedure: bool,
+}
+
+pub trait Render {
+    fn extension(&self) -> &str;
+    fn render(&self, out: &mut dyn Write, program: &Program) -> io::Result<()>;
+}
+
+pub struct Structured<R: Render> {
+    program: Program,
+    render: R,
+}
+
+impl Expr {
+    pub fn from_operand(operand: &Operand) -> Self {
+        match operand.constant() {
+            Some(value) => Expr::Const(value as i64),
+            None => Expr::Var(operand.to_string()),
+        }
+    }
+
+    pub fn reads(&self, names: &mut Vec<String>) {
+        match self {
+            Expr::Var(name) => names.push(name.clone()),
+            Expr::Const(_) => (),
+            Expr::Bin(_, left, right) => {
+                left.reads(names);
+                right.reads(names);
+            }
+        }
+    }
+
+    // Replace folded temps with the expression they were assigned
+    fn substitute(self, pending: &mut HashMap<String, Expr>) -> Self {
+        match self {
+            Expr::Var(name) => match pending.remove(&name) {
+                Some(expr) => expr,
+                None => Expr::Var(name),
+            },
+            Expr::Bin(op, left, right) => Expr::Bin(
+                op,
+                Box::new(left.substitute(pending)),
+                Box::new(right.substitute(pending)),
+            ),
+            e => e,
+        }
+    }
+}
+
+impl Stmt {
+    pub fn reads(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        match self {
+            Stmt::Assign(_, expr) | Stmt::Put(expr) => expr.reads(&mut names),
+            Stmt::Branch(_, left, right, _) => {
+                left.reads(&mut names);
+                right.reads(&mut names);
+            }
+            _ => (),
+        }
+        names
+    }
+
+    pub fn target(&self) -> Option<&String> {
+        match self {
+            Stmt::Goto(label) | Stmt::Branch(_, _, _, label) => Some(label),
+            _ => None,
+        }
+    }
+}
+
+impl Program {
+    fn push(&mut self, stmt: Stmt) {
+        match self.procedures.last_mut() {
+            Some((_, body)) if self.in_procedure => body.push(stmt),
+            _ => self.main.push(stmt),
+        }
+    }
+
+    fn binary(&mut self, op: char, left: &Operand, right: &Operand, dest: &Operand) {
+        self.push(Stmt::Assign(
+            dest.to_string(),
+            Expr::Bin(
+                op,
+                Box::new(Expr::from_operand(left)),
+                Box::new(Expr::from_operand(right)),
+            ),
+        ));
+    }
+
+    pub fn is_temp(&self, name: &str) -> bool {
+        self.symbols
+            .iter()
+            .any(|sym| sym.name == name && sym.class == SymbolClass::Temp)
+    }
+
+    pub fn value(&self, name: &str) -> i32 {
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == name)
+            .map_or(0, |sym| sym.value)
+    }
+
+    // Every variable the program touches, including ones missing from the symbol table
+    pub fn globals(&self) -> Vec<String> {
+        let mut globals: Vec<String> = self
+            .symbols
+            .iter()
+            .filter(|sym| sym.class == SymbolClass::Identifier)
+            .map(|sym| sym.name.clone())
+            .collect();
+
+        let bodies = self.procedures.iter().map(|(_, body)| body);
+        for stmt in std::iter::once(&self.main).chain(bodies).flatten() {
+            let mut names = stmt.reads();
+            if let Stmt::Assign(dest, _) | Stmt::Get(dest) = stmt {
+                names.push(dest.clone());
+            }
+            for name in names {
+                if !self.is_temp(&name) && !globals.contains(&name) {
+                    globals.push(name);
+                }
+            }
+        }
+        globals
+    }
+
+    // Temps still assigned in a body after folding, these become locals of the function
+    pub fn locals(&self, body: &[Stmt]) -> Vec<String> {
+        let mut locals: Vec<String> = Vec::new();
+        for stmt in body {
+            if let Stmt::Assign(dest, _) = stmt {
+                if self.is_temp(dest) && !locals.contains(dest) {
+                    locals.push(dest.clone());
+                }
+            }
+        }
+        locals
+    }
+
+    // Fold every temp that is assigned once and read once into the statement that reads it.
+    // Only temp assignments may sit between the two, so no variable it reads can have changed.
+    pub fn fold_temps(&self, body: &[Stmt]) -> Vec<Stmt> {
+        let mut reads: HashMap<String, usize> = HashMap::new();
+        let mut writes: HashMap<String, usize> = HashMap::new();
+        for stmt in body {
+            for name in stmt.reads() {
+                *reads.entry(name).or_insert(0) += 1;
+            }
+            if let Stmt::Assign(dest, _) | Stmt::Get(dest) = stmt {
+                *writes.entry(dest.clone()).or_insert(0) += 1;
+            }
+        }
+        let foldable = |name: &String| {
+            self.is_temp(name) && reads.get(name) == Some(&1) && writes.get(name) == Some(&1)
+        };
+
+        let mut folded = Vec::new();
+        let mut pending: HashMap<String, Expr> = HashMap::new();
+        let mut order: Vec<String> = Vec::new();
+        for stmt in body.iter().cloned() {
+            let stmt = match stmt {
+                Stmt::Assign(dest, expr) if foldable(&dest) => {
+                    let expr = expr.substitute(&mut pending);
+                    pending.insert(dest.clone(), expr);
+                    order.push(dest);
+                    continue;
+                }
+                Stmt::Assign(dest, expr) => Stmt::Assign(dest, expr.substitute(&mut pending)),
+                Stmt::Put(expr) => Stmt::Put(expr.substitute(&mut pending)),
+                Stmt::Branch(rel, left, right, label) => {
+                    let left = left.substitute(&mut pending);
+                    Stmt::Branch(rel, left, right.substitute(&mut pending), label)
+                }
+                stmt => stmt,
+            };
+
+            // Anything left over is read later on, keep it as a plain assignment
+            for name in order.drain(..) {
+                if let Some(expr) = pending.remove(&name) {
+                    folded.push(Stmt::Assign(name, expr));
+                }
+            }
+            folded.push(stmt);
+        }
+        for name in order {
+            if let Some(expr) = pending.remove(&name) {
+                folded.push(Stmt::Assign(name, expr));
+            }
+        }
+        folded
+    }
+}
+
+// How many jumps and branches target each label
+pub fn label_refs(body: &[Stmt]) -> HashMap<String, usize> {
+    let mut refs: HashMap<String, usize> = HashMap::new();
+    for stmt in body {
+        if let Some(label) = stmt.target() {
+            *refs.entry(label.clone()).or_insert(0) += 1;
+        }
+    }
+    refs
+}
+
+// Recover the shapes IF and WHILE are lowered to:
+//   IF:    branch L1, body, L1:
+//   WHILE: L1:, branch L2, body, jump L1, L2:
+// as long as nothing else jumps to the labels involved.
+pub fn structure(stmts: &[Stmt], refs: &HashMap<String, usize>) -> Vec<Node> {
+    let single = |label: &String| refs.get(label) == Some(&1);
+    let label_at = |index: usize| match stmts.get(index) {
+        Some(Stmt::Label(label)) => Some(label),
+        _ => None,
+    };
+
+    let mut nodes = Vec::new();
+    let mut i = 0;
+    while i < stmts.len() {
+        if let (Some(top), Some(Stmt::Branch(rel, left, right, end))) =
+            (label_at(i), stmts.get(i + 1))
+        {
+            let close = (i + 2..stmts.len()).find(|&j| label_at(j) == Some(end));
+            if let Some(j) = close {
+                if single(top)
+                    && single(end)
+                    && j >= i + 3
+                    && stmts[j - 1] == Stmt::Goto(top.clone())
+                {
+                    let body = structure(&stmts[i + 2..j - 1], refs);
+                    nodes.push(Node::While(
+                        top.clone(),
+                        end.clone(),
+                        *rel,
+                        left.clone(),
+                        right.clone(),
+                        body,
+                    ));
+                    i = j + 1;
+                    continue;
+                }
+            }
+        }
+
+        if let Some(Stmt::Branch(rel, left, right, end)) = stmts.get(i) {
+            let close = (i + 1..stmts.len()).find(|&j| label_at(j) == Some(end));
+            if let Some(j) = close {
+                if single(end) {
+                    let body = structure(&stmts[i + 1..j], refs);
+                    nodes.push(Node::If(*rel, left.clone(), right.clone(), body));
+                    i = j + 1;
+                    continue;
+                }
+            }
+        }
+
+        // Labels nothing jumps to any more are dropped
+        match &stmts[i] {
+            Stmt::Label(label) if !refs.contains_key(label) => (),
+            stmt => nodes.push(Node::Stmt(stmt.clone())),
+        }
+        i += 1;
+    }
+    nodes
+}
+
+// True when every jump was recovered as an if or while
+pub fn is_structured(nodes: &[Node]) -> bool {
+    nodes.iter().all(|node| match node {
+        Node::Stmt(Stmt::Label(_)) | Node::Stmt(Stmt::Goto(_)) => false,
+        Node::Stmt(Stmt::Branch(..)) => false,
+        Node::Stmt(_) => true,
+        Node::If(_, _, _, body) | Node::While(_, _, _, _, _, body) => is_structured(body),
+    })
+}
+
+impl<R: Render> Structured<R> {
+    pub fn new(render: R) -> Self {
+        Structured {
+            program: Program::default(),
+            render,
+        }
+    }
+}
+
+impl<R: Render> Backend for Structured<R> {
+    fn extension(&self) -> &str {
+        self.render.extension()
+    }
+
+    fn prologue(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Declarations are written at the end once every variable the quads touch is known
+    fn data(&mut self, _out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()> {
+        self.program.symbols = symbols.to_vec();
+        Ok(())
+    }
+
+    fn text(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.program.binary('+', left, right, dest);
+        Ok(())
+    }
+
+    fn sub(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.program.binary('-', left, right, dest);
+        Ok(())
+    }
+
+    fn mul(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.program.binary('*', left, right, dest);
+        Ok(())
+    }
+
+    fn div(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &Operand,
+        right: &Operand,
+        dest: &Operand,
+    ) -> io::Result<()> {
+        self.program.binary('/', left, right, dest);
+        Ok(())
+    }
+
+    fn assign(&mut self, _out: &mut dyn Write, src: &Operand, dest: &Operand) -> io::Result<()> {
+        self.program
+            .push(Stmt::Assign(dest.to_string(), Expr::from_operand(src)));
+        Ok(())
+    }
+
+    fn get(&mut self, _out: &mut dyn Write, dest: &Operand) -> io::Result<()> {
+        self.program.push(Stmt::Get(dest.to_string()));
+        Ok(())
+    }
+
+    fn put(&mut self, _out: &mut dyn Write, src: &Operand) -> io::Result<()> {
+        self.program.push(Stmt::Put(Expr::from_operand(src)));
+        Ok(())
+    }
+
+    fn label(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.program.push(Stmt::Label(label.to_string()));
+        Ok(())
+    }
+
+    fn jump(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.program.push(Stmt::Goto(label.to_string()));
+        Ok(())
+    }
+
+    fn branch(
+        &mut self,
+        _out: &mut dyn Write,
+        rel: Relation,
+        left: &Operand,
+        right: &Operand,
+        target: &str,
+    ) -> io::Result<()> {
+        self.program.push(Stmt::Branch(
+            rel,
+            Expr::from_operand(left),
+            Expr::from_operand(right),
+            target.to_string(),
+        ));
+        Ok(())
+    }
+
+    fn procedure(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.program.procedures.push((name.to_string(), Vec::new()));
+        self.program.in_procedure = true;
+        Ok(())
+    }
+
+    fn ret(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        self.program.in_procedure = false;
+        Ok(())
+    }
+
+    fn call(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.program.push(Stmt::Call(name.to_string()));
+        Ok(())
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.render.render(out, &self.program)
+    }
+}
@@ -0,0 +1,345 @@
+use std::io::{self, Write};
+
+use crate::compiler::backend::structured::{self, Expr, Node, Program, Render, Stmt};
+use crate::compiler::backend::Relation;
+
+// WebAssembly text format. Variables are i32 globals, temps are locals of the function they are
+// used in and GET/PUT call the host functions env.get and env.put. IF and WHILE become if and
+// block/loop, a function with jumps that cannot be structured runs as a br_table dispatch loop.
+pub struct Wat;
+
+// Flat instructions, indented by how deeply blocks are nested
+struct Body<'a> {
+    program: &'a Program,
+    out: &'a mut dyn Write,
+    depth: usize,
+}
+
+impl Body<'_> {
+    fn line(&mut self, inst: &str) -> io::Result<()> {
+        let indent = "  ".repeat(self.depth);
+        self.out.write_fmt(format_args!("{}{}\n", indent, inst))
+    }
+
+    fn open(&mut self, inst: &str) -> io::Result<()> {
+        self.line(inst)?;
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.depth -= 1;
+        self.line("end")
+    }
+
+    fn scope(&self, name: &str) -> &str {
+        if self.program.is_temp(name) {
+            "local"
+        } else {
+            "global"
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> io::Result<()> {
+        match expr {
+            Expr::Var(name) => self.line(&format!("{}.get ${}", self.scope(name), name)),
+            Expr::Const(value) => self.line(&format!("i32.const {}", value)),
+            Expr::Bin(op, left, right) => {
+                self.expr(left)?;
+                self.expr(right)?;
+                self.line(match op {
+                    '+' => "i32.add",
+                    '-' => "i32.sub",
+                    '*' => "i32.mul",
+                    _ => "i32.div_s",
+                })
+            }
+        }
+    }
+
+    fn compare(&mut self, rel: Relation, left: &Expr, right: &Expr) -> io::Result<()> {
+        self.expr(left)?;
+        self.expr(right)?;
+        self.line(Wat::operator(rel))
+    }
+
+    // Statements without control flow, the same in structured and dispatch functions
+    fn simple(&mut self, stmt: &Stmt) -> io::Result<()> {
+        match stmt {
+            Stmt::Assign(dest, expr) => {
+                self.expr(expr)?;
+                self.line(&format!("{}.set ${}", self.scope(dest), dest))
+            }
+            Stmt::Get(dest) => {
+                self.line("call $get")?;
+                self.line(&format!("{}.set ${}", self.scope(dest), dest))
+            }
+            Stmt::Put(expr) => {
+                self.expr(expr)?;
+                self.line("call $put")
+            }
+            Stmt::Call(name) => self.line(&format!("call ${}", name)),
+            e => panic!("[ Error ] Unexpected jump in WAT body: {:?}", e),
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) -> io::Result<()> {
+        for node in nodes {
+            match node {
+                Node::Stmt(stmt) => self.simple(stmt)?,
+                Node::If(rel, left, right, body) => {
+                    self.compare(*rel, left, right)?;
+                    self.open("if")?;
+                    self.nodes(body)?;
+                    self.end()?;
+                }
+                Node::While(top, end, rel, left, right, body) => {
+                    self.open(&format!("block ${}", end))?;
+                    self.open(&format!("loop ${}", top))?;
+                    self.compare(rel.negate(), left, right)?;
+                    self.line(&format!("br_if ${}", end))?;
+                    self.nodes(body)?;
+                    self.line(&format!("br ${}", top))?;
+                    self.end()?;
+                    self.end()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Every label starts a new case, $pc holds the case to run next. The cases are nested blocks
+    // inside one loop, so br_table lands at the start of a case by leaving the blocks around it
+    // and falling off the end of a case runs the next one.
+    fn dispatch(&mut self, body: &[Stmt]) -> io::Result<()> {
+        let mut cases: Vec<(String, Vec<&Stmt>)> = vec![("entry".to_string(), Vec::new())];
+        for stmt in body {
+            match stmt {
+                Stmt::Label(label) => cases.push((label.clone(), Vec::new())),
+                stmt => cases.last_mut().unwrap().1.push(stmt),
+            }
+        }
+        let index = |label: &String| cases.iter().position(|(name, _)| name == label).unwrap();
+
+        self.open("loop $dispatch")?;
+        for (name, _) in cases.iter().rev() {
+            self.open(&format!("block ${}", name))?;
+        }
+        self.line("local.get $pc")?;
+        let targets: Vec<String> = cases.iter().map(|(name, _)| format!("${}", name)).collect();
+        self.line(&format!("br_table {}", targets.join(" ")))?;
+
+        for (_, stmts) in &cases {
+            self.end()?;
+            for stmt in stmts {
+                match stmt {
+                    Stmt::Goto(label) => {
+                        self.line(&format!("i32.const {}", index(label)))?;
+                        self.line("local.set $pc")?;
+                        self.line("br $dispatch")?;
+                    }
+                    Stmt::Branch(rel, left, right, label) => {
+                        self.compare(rel.negate(), left, right)?;
+                        self.open("if")?;
+                        self.line(&format!("i32.const {}", index(label)))?;
+                        self.line("local.set $pc")?;
+                        self.line("br $dispatch")?;
+                        self.end()?;
+                    }
+                    stmt => self.simple(stmt)?,
+                }
+            }
+        }
+        self.end()
+    }
+}
+
+impl Wat {
+    fn operator(rel: Relation) -> &'static str {
+        match rel {
+            Relation::Equal => "i32.eq",
+            Relation::NEqual => "i32.ne",
+            Relation::GreaterThan => "i32.gt_s",
+            Relation::LessThan => "i32.lt_s",
+            Relation::GEqual => "i32.ge_s",
+            Relation::LEqual => "i32.le_s",
+        }
+    }
+
+    fn write_function(
+        out: &mut dyn Write,
+        program: &Program,
+        header: &str,
+        body: &[Stmt],
+    ) -> io::Result<()> {
+        out.write_fmt(format_args!("  ({}\n", header))?;
+
+        let refs = structured::label_refs(body);
+        let nodes = structured::structure(body, &refs);
+        let structured = structured::is_structured(&nodes);
+        for local in program.locals(body) {
+            out.write_fmt(format_args!("    (local ${} i32)\n", local))?;
+        }
+        if !structured {
+            out.write_fmt(format_args!("    (local $pc i32)\n"))?;
+        }
+
+        let mut writer = Body {
+            program,
+            out,
+            depth: 2,
+        };
+        if structured {
+            writer.nodes(&nodes)?;
+        } else {
+            writer.dispatch(body)?;
+        }
+        out.write_fmt(format_args!("  )\n"))
+    }
+}
+
+impl Render for Wat {
+    fn extension(&self) -> &str {
+        "wat"
+    }
+
+    fn render(&self, out: &mut dyn Write, program: &Program) -> io::Result<()> {
+        out.write_fmt(format_args!(
+            "(module\n  \
+             (import \"env\" \"get\" (func $get (result i32)))\n  \
+             (import \"env\" \"put\" (func $put (param i32)))\n"
+        ))?;
+
+        for global in program.globals() {
+            out.write_fmt(format_args!(
+                "  (global ${} (mut i32) (i32.const {}))\n",
+                global,
+                program.value(&global)
+            ))?;
+        }
+        for (name, body) in &program.procedures {
+            Wat::write_function(out, program, &format!("func ${}", name), body)?;
+        }
+        Wat::write_function(out, program, "func $main (export \"main\")", &program.main)?;
+        out.write_fmt(format_args!(")\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::var;
+    use crate::compiler::backend::structured::Structured;
+    use crate::compiler::backend::{sample, wat_check, Symbol, SymbolClass};
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::syntax::{Quad, QuadList};
+
+    fn generate(quads: QuadList, symbols: Vec<Symbol>) -> String {
+        let backend = Box::new(Structured::new(Wat));
+        let mut gen = Generator::with_writer(quads, symbols, backend, Vec::new());
+        gen.consume_quads().unwrap();
+        String::from_utf8(gen.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_wat_output() {
+        let module = generate(sample::quads(), sample::symbols());
+
+        assert_eq!(
+            module,
+            "(module\n\
+             \x20 (import \"env\" \"get\" (func $get (result i32)))\n\
+             \x20 (import \"env\" \"put\" (func $put (param i32)))\n\
+             \x20 (global $a (mut i32) (i32.const 0))\n\
+             \x20 (global $b (mut i32) (i32.const 0))\n\
+             \x20 (global $c (mut i32) (i32.const 0))\n\
+             \x20 (func $show\n\
+             \x20   (local $temp_1 i32)\n\
+             \x20   (local $temp_2 i32)\n\
+             \x20   global.get $a\n\
+             \x20   global.get $b\n\
+             \x20   i32.add\n\
+             \x20   local.set $temp_1\n\
+             \x20   local.get $temp_1\n\
+             \x20   i32.const 3\n\
+             \x20   i32.mul\n\
+             \x20   local.set $temp_2\n\
+             \x20   local.get $temp_2\n\
+             \x20   global.set $c\n\
+             \x20   global.get $c\n\
+             \x20   call $put\n\
+             \x20 )\n\
+             \x20 (func $main (export \"main\")\n\
+             \x20   (local $temp_3 i32)\n\
+             \x20   (local $temp_4 i32)\n\
+             \x20   (local $temp_5 i32)\n\
+             \x20   call $get\n\
+             \x20   global.set $a\n\
+             \x20   call $get\n\
+             \x20   global.set $b\n\
+             \x20   block $L2\n\
+             \x20     loop $L1\n\
+             \x20       global.get $a\n\
+             \x20       i32.const 0\n\
+             \x20       i32.le_s\n\
+             \x20       br_if $L2\n\
+             \x20       global.get $a\n\
+             \x20       i32.const 1\n\
+             \x20       i32.sub\n\
+             \x20       local.set $temp_3\n\
+             \x20       local.get $temp_3\n\
+             \x20       global.set $a\n\
+             \x20       global.get $b\n\
+             \x20       i32.const 2\n\
+             \x20       i32.mul\n\
+             \x20       local.set $temp_4\n\
+             \x20       local.get $temp_4\n\
+             \x20       global.set $b\n\
+             \x20       br $L1\n\
+             \x20     end\n\
+             \x20   end\n\
+             \x20   global.get $a\n\
+             \x20   global.get $b\n\
+             \x20   i32.lt_s\n\
+             \x20   if\n\
+             \x20     call $show\n\
+             \x20   end\n\
+             \x20   global.get $b\n\
+             \x20   i32.const 2\n\
+             \x20   i32.div_s\n\
+             \x20   local.set $temp_5\n\
+             \x20   local.get $temp_5\n\
+             \x20   call $put\n\
+             \x20 )\n\
+             )\n"
+        );
+        wat_check::check(&module).unwrap();
+        wat_check::round_trip(&module).unwrap();
+    }
+
+    #[test]
+    fn test_wat_dispatch() {
+        // L1 is both branched to and jumped to, so main has to fall back to the dispatch loop
+        let quads = vec![
+            Quad::get(var("a")),
+            Quad::label("L1"),
+            Quad::put(var("a")),
+            Quad::branch(Relation::Equal, var("a"), var("b"), "L1"),
+            Quad::jump("L1"),
+        ];
+        let symbols = vec![Symbol {
+            name: "b".to_string(),
+            class: SymbolClass::Identifier,
+            value: 4,
+        }];
+
+        let module = generate(quads, symbols);
+
+        assert!(module.contains("(global $b (mut i32) (i32.const 4))\n"));
+        assert!(module.contains("(global $a (mut i32) (i32.const 0))\n"));
+        assert!(module.contains("    (local $pc i32)\n    loop $dispatch\n"));
+        assert!(module.contains("br_table $entry $L1\n"));
+        wat_check::check(&module).unwrap();
+        wat_check::round_trip(&module).unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Well-formedness checker for the subset of WAT the Wat backend writes, so tests do not need an
+// external toolchain. Checks the module fields, that every name used is declared, that blocks
+// are balanced, that branches only target enclosing labels and that the operand stack has the
+// right height for every instruction, at every end and at the end of every function.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sexp {
+    Atom(String),
+    Str(String),
+    List(Vec<Sexp>),
+}
+
+struct Func {
+    params: usize,
+    results: usize,
+}
+
+struct Frame {
+    label: Option<String>,
+    height: usize,
+    // After br or br_table the rest of the block is never run, its stack is not checked
+    unreachable: bool,
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sexp::Atom(atom) => write!(f, "{}", atom),
+            Sexp::Str(string) => write!(f, "\"{}\"", string),
+            Sexp::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Sexp>, String> {
+    let mut stack: Vec<Vec<Sexp>> = vec![Vec::new()];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => stack.push(Vec::new()),
+            ')' => {
+                let list = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.push(Sexp::List(list)),
+                    None => return Err("unbalanced )".to_string()),
+                }
+            }
+            ';' if chars.peek() == Some(&';') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => string.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                stack.last_mut().unwrap().push(Sexp::Str(string));
+            }
+            c if c.is_whitespace() => (),
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' || c == ';' {
+                        break;
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                stack.last_mut().unwrap().push(Sexp::Atom(atom));
+            }
+        }
+    }
+    if stack.len() != 1 {
+        return Err("unbalanced (".to_string());
+    }
+    Ok(stack.pop().unwrap())
+}
+
+// Printing the parsed module and parsing it again has to give back the same tree
+pub fn round_trip(text: &str) -> Result<(), String> {
+    let parsed = parse(text)?;
+    let printed: Vec<String> = parsed.iter().map(|sexp| sexp.to_string()).collect();
+    if parse(&printed.join("\n"))? == parsed {
+        Ok(())
+    } else {
+        Err("module does not round trip".to_string())
+    }
+}
+
+fn atom(sexp: Option<&Sexp>) -> Option<&str> {
+    match sexp {
+        Some(Sexp::Atom(atom)) => Some(atom),
+        _ => None,
+    }
+}
+
+fn id(sexp: Option<&Sexp>) -> Result<String, String> {
+    match atom(sexp) {
+        Some(name) if name.len() > 1 && name.starts_with('$') => Ok(name.to_string()),
+        _ => Err(format!("expected an identifier, found {:?}", sexp)),
+    }
+}
+
+fn list<'a>(sexp: &'a Sexp, head: &str) -> Option<&'a [Sexp]> {
+    match sexp {
+        Sexp::List(items) if atom(items.first()) == Some(head) => Some(&items[1..]),
+        _ => None,
+    }
+}
+
+fn is_i32(sexp: Option<&Sexp>) -> bool {
+    atom(sexp) == Some("i32")
+}
+
+// (func $name (param i32)* (result i32)?) from an import, or the start of a func field
+fn signature(items: &[Sexp]) -> Result<(Func, usize), String> {
+    let mut func = Func {
+        params: 0,
+        results: 0,
+    };
+    let mut i = 0;
+    while let Some(item) = items.get(i) {
+        if let Some(export) = list(item, "export") {
+            match export {
+                [Sexp::Str(_)] => (),
+                e => return Err(format!("bad export {:?}", e)),
+            }
+        } else if let Some(param) = list(item, "param") {
+            if param.is_empty() || !param.iter().all(|t| is_i32(Some(t))) {
+                return Err(format!("bad param {:?}", param));
+            }
+            func.params += param.len();
+        } else if let Some(result) = list(item, "result") {
+            if result.len() != 1 || !is_i32(result.first()) {
+                return Err(format!("bad result {:?}", result));
+            }
+            func.results += 1;
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    Ok((func, i))
+}
+
+pub fn check(text: &str) -> Result<(), String> {
+    let parsed = parse(text)?;
+    let fields = match parsed.as_slice() {
+        [module] => list(module, "module").ok_or("expected (module ...)")?,
+        _ => return Err("expected exactly one module".to_string()),
+    };
+
+    // Everything a function body may refer to, declared anywhere in the module
+    let mut funcs: HashMap<String, Func> = HashMap::new();
+    let mut globals: Vec<String> = Vec::new();
+    for field in fields {
+        if let Some(import) = list(field, "import") {
+            let func = match import {
+                [Sexp::Str(_), Sexp::Str(_), func] => list(func, "func").ok_or("bad import")?,
+                e => return Err(format!("bad import {:?}", e)),
+            };
+            let name = id(func.first())?;
+            let (sig, used) = signature(&func[1..])?;
+            if used + 1 != func.len() {
+                return Err(format!("bad import signature for {}", name));
+            }
+            if funcs.insert(name.clone(), sig).is_some() {
+                return Err(format!("duplicate function {}", name));
+            }
+        } else if let Some(global) = list(field, "global") {
+            let name = id(global.first())?;
+            let mutable = global.get(1).and_then(|t| list(t, "mut"));
+            let init = global.get(2).and_then(|t| list(t, "i32.const"));
+            match (mutable, init) {
+                (Some([t]), Some([Sexp::Atom(value)]))
+                    if is_i32(Some(t)) && value.parse::<i32>().is_ok() && global.len() == 3 => {}
+                _ => return Err(format!("bad global {}", name)),
+            }
+            if globals.contains(&name) {
+                return Err(format!("duplicate global {}", name));
+            }
+            globals.push(name);
+        } else if let Some(func) = list(field, "func") {
+            let name = id(func.first())?;
+            let (sig, _) = signature(&func[1..])?;
+            if funcs.insert(name.clone(), sig).is_some() {
+                return Err(format!("duplicate function {}", name));
+            }
+        } else {
+            return Err(format!("unknown module field {}", field));
+        }
+    }
+
+    for field in fields {
+        if let Some(func) = list(field, "func") {
+            check_func(func, &funcs, &globals)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_func(
+    func: &[Sexp],
+    funcs: &HashMap<String, Func>,
+    globals: &[String],
+) -> Result<(), String> {
+    let name = id(func.first())?;
+    let (sig, used) = signature(&func[1..])?;
+    let mut rest = &func[used + 1..];
+
+    let mut locals: Vec<String> = Vec::new();
+    while let Some(local) = rest.first().and_then(|t| list(t, "local")) {
+        let local_name = id(local.first())?;
+        if local.len() != 2 || !is_i32(local.get(1)) || locals.contains(&local_name) {
+            return Err(format!("bad local {} in {}", local_name, name));
+        }
+        locals.push(local_name);
+        rest = &rest[1..];
+    }
+
+    let mut frames = vec![Frame {
+        label: None,
+        height: 0,
+        unreachable: false,
+    }];
+    let mut height = 0;
+    let mut insts = rest.iter();
+    let immediate = |inst: Option<&Sexp>| -> Result<String, String> {
+        match atom(inst) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("missing immediate in {}", name)),
+        }
+    };
+
+    while let Some(inst) = insts.next() {
+        let op = match inst {
+            Sexp::Atom(op) => op.as_str(),
+            e => return Err(format!("folded instructions are not used, found {}", e)),
+        };
+        // How many operands the instruction takes and how many results it leaves
+        let (pops, pushes) = match op {
+            "i32.const" => {
+                immediate(insts.next())?
+                    .parse::<i32>()
+                    .map_err(|e| e.to_string())?;
+                (0, 1)
+            }
+            "global.get" | "global.set" | "local.get" | "local.set" => {
+                let var = immediate(insts.next())?;
+                let declared = if op.starts_with("global") {
+                    globals.contains(&var)
+                } else {
+                    locals.contains(&var)
+                };
+                if !declared {
+                    return Err(format!("{} is not declared in {}", var, name));
+                }
+                if op.ends_with("get") {
+                    (0, 1)
+                } else {
+                    (1, 0)
+                }
+            }
+            "i32.add" | "i32.sub" | "i32.mul" | "i32.div_s" | "i32.eq" | "i32.ne" | "i32.gt_s"
+            | "i32.lt_s" | "i32.ge_s" | "i32.le_s" => (2, 1),
+            "i32.eqz" => (1, 1),
+            "drop" => (1, 0),
+            "call" => {
+                let callee = immediate(insts.next())?;
+                match funcs.get(&callee) {
+                    Some(sig) => (sig.params, sig.results),
+                    None => return Err(format!("{} is not declared", callee)),
+                }
+            }
+            "block" | "loop" | "if" => {
+                let pops = if op == "if" { 1 } else { 0 };
+                let frame = frames.last().unwrap();
+                if !frame.unreachable && height < frame.height + pops {
+                    return Err(format!("stack underflow at {} in {}", op, name));
+                }
+                height -= pops.min(height);
+                let label = match atom(insts.as_slice().first()) {
+                    Some(label) if label.starts_with('$') => {
+                        insts.next();
+                        Some(label.to_string())
+                    }
+                    _ => None,
+                };
+                frames.push(Frame {
+                    label,
+                    height,
+                    unreachable: false,
+                });
+                continue;
+            }
+            "end" => {
+                let frame = frames.pop().unwrap();
+                if frames.is_empty() {
+                    return Err(format!("end without a block in {}", name));
+                }
+                if !frame.unreachable && height != frame.height {
+                    return Err(format!("block leaves {} values in {}", height, name));
+                }
+                height = frame.height;
+                continue;
+            }
+            "br" | "br_if" | "br_table" => {
+                let mut targets = vec![immediate(insts.next())?];
+                if op == "br_table" {
+                    while let Some(target) = atom(insts.as_slice().first()) {
+                        if !target.starts_with('$') && target.parse::<usize>().is_err() {
+                            break;
+                        }
+                        targets.push(target.to_string());
+                        insts.next();
+                    }
+                }
+                for target in targets {
+                    let depth = target.parse::<usize>().ok();
+                    let found = frames[1..].iter().rev().enumerate().any(|(i, frame)| {
+                        depth == Some(i) || frame.label.as_deref() == Some(target.as_str())
+                    });
+                    if !found {
+                        return Err(format!("branch to {} is not in scope in {}", target, name));
+                    }
+                }
+                if op == "br_if" {
+                    (1, 0)
+                } else {
+                    let pops = if op == "br_table" { 1 } else { 0 };
+                    let frame = frames.last_mut().unwrap();
+                    if !frame.unreachable && height < frame.height + pops {
+                        return Err(format!("stack underflow at {} in {}", op, name));
+                    }
+                    frame.unreachable = true;
+                    continue;
+                }
+            }
+            e => return Err(format!("unknown instruction {} in {}", e, name)),
+        };
+
+        let frame = frames.last().unwrap();
+        if height < frame.height + pops {
+            if !frame.unreachable {
+                return Err(format!("stack underflow at {} in {}", op, name));
+            }
+            height = frame.height;
+        } else {
+            height -= pops;
+        }
+        height += pushes;
+    }
+
+    if frames.len() != 1 {
+        return Err(format!(
+            "{} blocks are not closed in {}",
+            frames.len() - 1,
+            name
+        ));
+    }
+    if !frames[0].unreachable && height != sig.results {
+        return Err(format!("{} leaves {} values on the stack", name, height));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HEADER: &str = "(module (import \"env\" \"put\" (func $put (param i32))) \
+                          (global $a (mut i32) (i32.const 0))";
+
+    #[test]
+    fn test_check_accepts() {
+        let module = format!(
+            "{} (func $main (local $t i32) block $out loop $top global.get $a i32.eqz \
+             br_if $out global.get $a i32.const 1 i32.sub local.set $t local.get $t \
+             call $put br $top end end))",
+            HEADER
+        );
+        assert_eq!(check(&module), Ok(()));
+        assert_eq!(round_trip(&module), Ok(()));
+    }
+
+    #[test]
+    fn test_check_rejects() {
+        let bad = [
+            "(func $main global.get $b drop)",
+            "(func $main block $out br $top end)",
+            "(func $main i32.const 1)",
+            "(func $main block i32.const 1 end)",
+            "(func $main i32.add drop)",
+            "(func $main loop $top)",
+            "(func $main call $get)",
+            "(func $main (local $t i64))",
+            "(func $main i32.const 1 i64.extend_i32_s drop)",
+        ];
+        for body in bad {
+            let module = format!("{} {})", HEADER, body);
+            assert!(check(&module).is_err(), "{}", body);
+        }
+        assert!(parse("(module (func $main)").is_err());
+        assert!(parse("(module))").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use std::fmt;
+
+// Structured x86 instructions, the x86 backends build these up and print them at the end so the
+// same stream can be inspected before it is written out.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Ax,
+    Cx,
+    Dx,
+    Bx,
+    Sp,
+    Bp,
+    Si,
+    Di,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mem {
+    // Data label plus displacement, RIP relative in 64-bit code
+    Label(String, i32),
+    // Register plus displacement
+    Base(Reg, i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem(Mem),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Cmp,
+    Xor,
+    And,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp {
+    Shl,
+    // Arithmetic, the sign bit is copied in from the left
+    Sar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    E,
+    NE,
+    G,
+    L,
+    GE,
+    LE,
+    A,
+    S,
+    NS,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inst {
+    Label(String),
+    Mov(Size, Operand, Operand),
+    // Zero extend a byte from memory into a 32-bit register
+    Movzx(Reg, Mem),
+    // Load the address of a data label into a 64-bit register
+    Lea(Reg, Mem),
+    Alu(AluOp, Size, Operand, Operand),
+    Test(Size, Reg, Reg),
+    Imul(Size, Reg, Operand),
+    Idiv(Size, Operand),
+    Div(Size, Operand),
+    Neg(Size, Reg),
+    // Shift by an immediate count
+    Shift(ShiftOp, Size, Operand, u8),
+    Inc(Size, Operand),
+    Dec(Size, Operand),
+    Cdq,
+    Jmp(String),
+    Jcc(Cond, String),
+    Call(String),
+    Ret,
+    Syscall,
+    Nop,
+}
+
+impl Reg {
+    pub fn name(self, size: Size) -> String {
+        let legacy = |base: &str| match size {
+            Size::Byte => format!("{}l", base),
+            Size::Word => format!("{}x", base),
+            Size::Dword => format!("e{}x", base),
+            Size::Qword => format!("r{}x", base),
+        };
+        let index = |base: &str| match size {
+            Size::Byte => format!("{}l", base),
+            Size::Word => base.to_string(),
+            Size::Dword => format!("e{}", base),
+            Size::Qword => format!("r{}", base),
+        };
+        let extended = |num: u8| match size {
+            Size::Byte => format!("r{}b", num),
+            Size::Word => format!("r{}w", num),
+            Size::Dword => format!("r{}d", num),
+            Size::Qword => format!("r{}", num),
+        };
+
+        match self {
+            Reg::Ax => legacy("a"),
+            Reg::Cx => legacy("c"),
+            Reg::Dx => legacy("d"),
+            Reg::Bx => legacy("b"),
+            Reg::Sp => index("sp"),
+            Reg::Bp => index("bp"),
+            Reg::Si => index("si"),
+            Reg::Di => index("di"),
+            Reg::R8 => extended(8),
+            Reg::R9 => extended(9),
+            Reg::R10 => extended(10),
+            Reg::R11 => extended(11),
+            Reg::R12 => extended(12),
+            Reg::R13 => extended(13),
+            Reg::R14 => extended(14),
+            Reg::R15 => extended(15),
+        }
+    }
+}
+
+impl Size {
+    fn suffix(self) -> char {
+        match self {
+            Size::Byte => 'b',
+            Size::Word => 'w',
+            Size::Dword => 'l',
+            Size::Qword => 'q',
+        }
+    }
+
+    fn keyword(self) -> &'static str {
+        match self {
+            Size::Byte => "byte",
+            Size::Word => "word",
+            Size::Dword => "dword",
+            Size::Qword => "qword",
+        }
+    }
+}
+
+impl AluOp {
+    fn mnemonic(self) -> &'static str {
+        match self {
+            AluOp::Add => "add",
+            AluOp::Sub => "sub",
+            AluOp::Cmp => "cmp",
+            AluOp::Xor => "xor",
+            AluOp::And => "and",
+        }
+    }
+}
+
+impl ShiftOp {
+    fn mnemonic(self) -> &'static str {
+        match self {
+            ShiftOp::Shl => "shl",
+            ShiftOp::Sar => "sar",
+        }
+    }
+}
+
+impl Cond {
+    fn suffix(self) -> &'static str {
+        match self {
+            Cond::E => "e",
+            Cond::NE => "ne",
+            Cond::G => "g",
+            Cond::L => "l",
+            Cond::GE => "ge",
+            Cond::LE => "le",
+            Cond::A => "a",
+            Cond::S => "s",
+            Cond::NS => "ns",
+        }
+    }
+}
+
+// Assembler syntax the x86 backends print their instruction stream and directives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Nasm,
+    Gas,
+}
+
+impl Dialect {
+    pub fn inst(self, inst: &Inst) -> String {
+        match self {
+            Dialect::Nasm => Intel { inst, rip: true }.to_string(),
+            Dialect::Gas => Att { inst }.to_string(),
+        }
+    }
+
+    pub fn global(self, name: &str) -> String {
+        match self {
+            Dialect::Nasm => format!("\tglobal {}", name),
+            Dialect::Gas => format!("\t.globl {}", name),
+        }
+    }
+
+    pub fn section(self, name: &str) -> String {
+        match self {
+            Dialect::Nasm => format!("section {}", name),
+            Dialect::Gas => format!("\t{}", name),
+        }
+    }
+
+    pub fn dword(self, name: &str, value: i32) -> String {
+        match self {
+            Dialect::Nasm => format!("{:<5} dd {}", name, value),
+            Dialect::Gas => format!("{}:\t.long {}", name, value),
+        }
+    }
+
+    // Uninitialised bytes, only ever used in the bss section
+    pub fn reserve(self, name: &str, len: i32) -> String {
+        match self {
+            Dialect::Nasm => format!("{} resb {}", name, len),
+            Dialect::Gas => format!("\t.lcomm {}, {}", name, len),
+        }
+    }
+}
+
+// NASM spelling of an instruction, `rip` selects `[rel label]` addressing for 64-bit code
+pub struct Intel<'a> {
+    pub inst: &'a Inst,
+    pub rip: bool,
+}
+
+impl<'a> Intel<'a> {
+    fn mem(&self, mem: &Mem) -> String {
+        let disp = |d: i32| match d {
+            0 => String::new(),
+            d if d > 0 => format!("+{}", d),
+            d => format!("{}", d),
+        };
+
+        match mem {
+            Mem::Label(name, d) if self.rip => format!("[rel {}{}]", name, disp(*d)),
+            Mem::Label(name, d) => format!("[{}{}]", name, disp(*d)),
+            Mem::Base(reg, d) => format!("[{}{}]", reg.name(Size::Qword), disp(*d)),
+        }
+    }
+
+    fn operand(&self, size: Size, operand: &Operand) -> String {
+        match operand {
+            Operand::Reg(reg) => reg.name(size),
+            Operand::Imm(value) => value.to_string(),
+            Operand::Mem(mem) => self.mem(mem),
+        }
+    }
+
+    // Memory operands only need a size keyword when nothing else pins the size down
+    fn sized(&self, size: Size, operand: &Operand) -> String {
+        match operand {
+            Operand::Mem(_) => format!("{} {}", size.keyword(), self.operand(size, operand)),
+            _ => self.operand(size, operand),
+        }
+    }
+
+    fn pair(&self, size: Size, dst: &Operand, src: &Operand) -> String {
+        match (dst, src) {
+            (Operand::Mem(_), Operand::Imm(_)) => {
+                format!("{},{}", self.sized(size, dst), self.operand(size, src))
+            }
+            _ => format!("{},{}", self.operand(size, dst), self.operand(size, src)),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Intel<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inst {
+            Inst::Label(name) => write!(f, "{}:", name),
+            Inst::Mov(size, dst, src) => write!(f, "\tmov {}", self.pair(*size, dst, src)),
+            Inst::Movzx(reg, mem) => write!(
+                f,
+                "\tmovzx {},byte {}",
+                reg.name(Size::Dword),
+                self.mem(mem)
+            ),
+            Inst::Lea(reg, mem) => write!(f, "\tlea {},{}", reg.name(Size::Qword), self.mem(mem)),
+            Inst::Alu(op, size, dst, src) => {
+                write!(f, "\t{} {}", op.mnemonic(), self.pair(*size, dst, src))
+            }
+            Inst::Test(size, a, b) => write!(f, "\ttest {},{}", a.name(*size), b.name(*size)),
+            Inst::Imul(size, reg, src) => {
+                write!(f, "\timul {},{}", reg.name(*size), self.operand(*size, src))
+            }
+            Inst::Idiv(size, src) => write!(f, "\tidiv {}", self.sized(*size, src)),
+            Inst::Div(size, src) => write!(f, "\tdiv {}", self.sized(*size, src)),
+            Inst::Neg(size, reg) => write!(f, "\tneg {}", reg.name(*size)),
+            Inst::Shift(op, size, dst, count) => {
+                write!(
+                    f,
+                    "\t{} {},{}",
+                    op.mnemonic(),
+                    self.sized(*size, dst),
+                    count
+                )
+            }
+            Inst::Inc(size, dst) => write!(f, "\tinc {}", self.sized(*size, dst)),
+            Inst::Dec(size, dst) => write!(f, "\tdec {}", self.sized(*size, dst)),
+            Inst::Cdq => write!(f, "\tcdq"),
+            Inst::Jmp(label) => write!(f, "\tjmp {}", label),
+            Inst::Jcc(cond, label) => write!(f, "\tj{} {}", cond.suffix(), label),
+            Inst::Call(label) => write!(f, "\tcall {}", label),
+            Inst::Ret => write!(f, "\tret"),
+            Inst::Syscall => write!(f, "\tsyscall"),
+            Inst::Nop => write!(f, "\tnop"),
+        }
+    }
+}
+
+// GNU as spelling of an instruction, source operand first with size suffixes on the mnemonic
+pub struct Att<'a> {
+    pub inst: &'a Inst,
+}
+
+impl<'a> Att<'a> {
+    fn mem(&self, mem: &Mem) -> String {
+        let disp = |d: i32| match d {
+            0 => String::new(),
+            d if d > 0 => format!("+{}", d),
+            d => format!("{}", d),
+        };
+
+        match mem {
+            Mem::Label(name, d) => format!("{}{}(%rip)", name, disp(*d)),
+            Mem::Base(reg, 0) => format!("(%{})", reg.name(Size::Qword)),
+            Mem::Base(reg, d) => format!("{}(%{})", d, reg.name(Size::Qword)),
+        }
+    }
+
+    fn operand(&self, size: Size, operand: &Operand) -> String {
+        match operand {
+            Operand::Reg(reg) => format!("%{}", reg.name(size)),
+            Operand::Imm(value) => format!("${}", value),
+            Operand::Mem(mem) => self.mem(mem),
+        }
+    }
+
+    fn pair(&self, size: Size, dst: &Operand, src: &Operand) -> String {
+        format!("{}, {}", self.operand(size, src), self.operand(size, dst))
+    }
+}
+
+impl<'a> fmt::Display for Att<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inst {
+            Inst::Label(name) => write!(f, "{}:", name),
+            Inst::Mov(size, dst, src) => {
+                write!(f, "\tmov{} {}", size.suffix(), self.pair(*size, dst, src))
+            }
+            Inst::Movzx(reg, mem) => {
+                write!(f, "\tmovzbl {}, %{}", self.mem(mem), reg.name(Size::Dword))
+            }
+            Inst::Lea(reg, mem) => {
+                write!(f, "\tleaq {}, %{}", self.mem(mem), reg.name(Size::Qword))
+            }
+            Inst::Alu(op, size, dst, src) => write!(
+                f,
+                "\t{}{} {}",
+                op.mnemonic(),
+                size.suffix(),
+                self.pair(*size, dst, src)
+            ),
+            Inst::Test(size, a, b) => write!(
+                f,
+                "\ttest{} %{}, %{}",
+                size.suffix(),
+                b.name(*size),
+                a.name(*size)
+            ),
+            Inst::Imul(size, reg, src) => write!(
+                f,
+                "\timul{} {}, %{}",
+                size.suffix(),
+                self.operand(*size, src),
+                reg.name(*size)
+            ),
+            Inst::Idiv(size, src) => {
+                write!(f, "\tidiv{} {}", size.suffix(), self.operand(*size, src))
+            }
+            Inst::Div(size, src) => {
+                write!(f, "\tdiv{} {}", size.suffix(), self.operand(*size, src))
+            }
+            Inst::Neg(size, reg) => write!(f, "\tneg{} %{}", size.suffix(), reg.name(*size)),
+            Inst::Shift(op, size, dst, count) => write!(
+                f,
+                "\t{}{} ${}, {}",
+                op.mnemonic(),
+                size.suffix(),
+                count,
+                self.operand(*size, dst)
+            ),
+            Inst::Inc(size, dst) => {
+                write!(f, "\tinc{} {}", size.suffix(), self.operand(*size, dst))
+            }
+            Inst::Dec(size, dst) => {
+                write!(f, "\tdec{} {}", size.suffix(), self.operand(*size, dst))
+            }
+            Inst::Cdq => write!(f, "\tcltd"),
+            Inst::Jmp(label) => write!(f, "\tjmp {}", label),
+            Inst::Jcc(cond, label) => write!(f, "\tj{} {}", cond.suffix(), label),
+            Inst::Call(label) => write!(f, "\tcall {}", label),
+            Inst::Ret => write!(f, "\tret"),
+            Inst::Syscall => write!(f, "\tsyscall"),
+            Inst::Nop => write!(f, "\tnop"),
+        }
+    }
+}
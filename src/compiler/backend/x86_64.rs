@@ -0,0 +1,955 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::compiler::backend::elf::Image;
+use crate::compiler::backend::peephole;
+use crate::compiler::backend::x86::{AluOp, Cond, Dialect, Inst, Mem, Operand, Reg, ShiftOp, Size};
+use crate::compiler::backend::{shift_count, Backend, Relation, Symbol, SymbolClass};
+use crate::compiler::opt::regalloc;
+use crate::compiler::syntax::{self, Quad};
+
+const SYS_READ: i64 = 0;
+const SYS_WRITE: i64 = 1;
+const SYS_EXIT: i64 = 60;
+const BUFFER: &str = "rt_buffer";
+const BUFFER_LEN: i32 = 32;
+// Registers the allocator hands out, neither syscall nor the runtime routines touch them
+const REGISTERS: [Reg; 6] = [Reg::Bx, Reg::R10, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+// 64-bit Linux output for either NASM or GNU as. Variables are 32-bit integers addressed RIP
+// relative, I/O goes through the syscall instruction. The runtime routines and their buffer are
+// rt_get_int, rt_put_int and rt_buffer.
+//
+// Names the program chose are written with a prefix, v_ for variables, l_ for labels and p_ for
+// procedures, so neither assembler can read one as a register or keyword such as rax, rel or
+// byte. The lexer does not take `_` in an identifier, so none can be a runtime name either.
+pub struct X86_64 {
+    dialect: Dialect,
+    // Encode straight into an executable instead of printing assembly
+    elf: bool,
+    symbols: Vec<Symbol>,
+    code: Vec<Inst>,
+    // Procedure bodies are collected here and placed after the exit of the main program
+    procedures: Vec<Inst>,
+    in_procedure: bool,
+    uses_get: bool,
+    uses_put: bool,
+    // Keep temps and variables in REGISTERS instead of going through memory for every quad
+    allocate: bool,
+    registers: HashMap<String, Reg>,
+    // Clean up the finished instruction stream with the peephole rules
+    peephole: bool,
+}
+
+fn data_label(name: &str) -> String {
+    format!("v_{}", name)
+}
+
+fn jump_label(name: &str) -> String {
+    format!("l_{}", name)
+}
+
+fn proc_label(name: &str) -> String {
+    format!("p_{}", name)
+}
+
+impl X86_64 {
+    pub fn new() -> Self {
+        X86_64::with_dialect(Dialect::Nasm)
+    }
+
+    pub fn with_dialect(dialect: Dialect) -> Self {
+        X86_64 {
+            dialect,
+            elf: false,
+            symbols: Vec::new(),
+            code: Vec::new(),
+            procedures: Vec::new(),
+            in_procedure: false,
+            uses_get: false,
+            uses_put: false,
+            allocate: false,
+            registers: HashMap::new(),
+            peephole: false,
+        }
+    }
+
+    pub fn with_registers(self) -> Self {
+        X86_64 {
+            allocate: true,
+            ..self
+        }
+    }
+
+    pub fn with_peephole(self) -> Self {
+        X86_64 {
+            peephole: true,
+            ..self
+        }
+    }
+
+    pub fn elf() -> Self {
+        X86_64 {
+            elf: true,
+            ..X86_64::new()
+        }
+    }
+
+    // Literals become immediates, allocated names are registers, everything else lives in the
+    // data section
+    fn operand(&self, value: &syntax::Operand) -> Operand {
+        if let Some(constant) = value.constant() {
+            return Operand::Imm(constant as i64);
+        }
+        let name = value.to_string();
+        match self.registers.get(&name) {
+            Some(reg) => Operand::Reg(*reg),
+            None => Operand::Mem(Mem::Label(data_label(&name), 0)),
+        }
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        if self.in_procedure {
+            self.procedures.push(inst);
+        } else {
+            self.code.push(inst);
+        }
+    }
+
+    fn mov(&mut self, dst: Operand, src: Operand) {
+        if dst != src {
+            self.emit(Inst::Mov(Size::Dword, dst, src));
+        }
+    }
+
+    fn load(&mut self, reg: Reg, value: &syntax::Operand) {
+        let src = self.operand(value);
+        self.mov(Operand::Reg(reg), src);
+    }
+
+    fn store(&mut self, reg: Reg, value: &syntax::Operand) {
+        let dst = self.operand(value);
+        self.mov(dst, Operand::Reg(reg));
+    }
+
+    // A destination in a register is computed in place, unless the right operand lives there
+    fn alu(
+        &mut self,
+        op: AluOp,
+        left: &syntax::Operand,
+        right: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) {
+        let (left, right, dst) = (self.operand(left), self.operand(right), self.operand(dest));
+        match dst {
+            Operand::Reg(reg) if right != dst => {
+                self.mov(dst, left);
+                self.emit(Inst::Alu(op, Size::Dword, Operand::Reg(reg), right));
+            }
+            _ => {
+                self.mov(Operand::Reg(Reg::Ax), left);
+                self.emit(Inst::Alu(op, Size::Dword, Operand::Reg(Reg::Ax), right));
+                self.store(Reg::Ax, dest);
+            }
+        }
+    }
+
+    fn cond(rel: Relation) -> Cond {
+        match rel {
+            Relation::Equal => Cond::E,
+            Relation::NEqual => Cond::NE,
+            Relation::GreaterThan => Cond::G,
+            Relation::LessThan => Cond::L,
+            Relation::GEqual => Cond::GE,
+            Relation::LEqual => Cond::LE,
+        }
+    }
+
+    // Reads stdin a byte at a time up to a newline and parses a signed decimal into eax, so
+    // piped input is never consumed past the current line. Anything that is not a digit or a
+    // leading minus is skipped and EOF ends the number.
+    fn get_routine() -> Vec<Inst> {
+        let label = |name: &str| String::from("rt_get_int") + name;
+        let ecx = Operand::Reg(Reg::Cx);
+        let r8d = Operand::Reg(Reg::R8);
+        let r9d = Operand::Reg(Reg::R9);
+
+        vec![
+            Inst::Label(label("")),
+            Inst::Alu(AluOp::Xor, Size::Dword, r8d.clone(), r8d.clone()),
+            Inst::Alu(AluOp::Xor, Size::Dword, r9d.clone(), r9d.clone()),
+            Inst::Label(label("_next")),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::Ax), Operand::Imm(SYS_READ)),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::Di), Operand::Imm(0)),
+            Inst::Lea(Reg::Si, Mem::Label(String::from(BUFFER), 0)),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::Dx), Operand::Imm(1)),
+            Inst::Syscall,
+            Inst::Test(Size::Dword, Reg::Ax, Reg::Ax),
+            Inst::Jcc(Cond::LE, label("_sign")),
+            Inst::Movzx(Reg::Cx, Mem::Base(Reg::Si, 0)),
+            Inst::Alu(AluOp::Cmp, Size::Dword, ecx.clone(), Operand::Imm(10)),
+            Inst::Jcc(Cond::E, label("_sign")),
+            Inst::Alu(
+                AluOp::Cmp,
+                Size::Dword,
+                ecx.clone(),
+                Operand::Imm('-' as i64),
+            ),
+            Inst::Jcc(Cond::NE, label("_digit")),
+            Inst::Mov(Size::Dword, r8d, Operand::Imm(1)),
+            Inst::Jmp(label("_next")),
+            Inst::Label(label("_digit")),
+            Inst::Alu(
+                AluOp::Sub,
+                Size::Dword,
+                ecx.clone(),
+                Operand::Imm('0' as i64),
+            ),
+            Inst::Alu(AluOp::Cmp, Size::Dword, ecx.clone(), Operand::Imm(9)),
+            Inst::Jcc(Cond::A, label("_next")),
+            Inst::Imul(Size::Dword, Reg::R9, Operand::Imm(10)),
+            Inst::Alu(AluOp::Add, Size::Dword, r9d.clone(), ecx),
+            Inst::Jmp(label("_next")),
+            Inst::Label(label("_sign")),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::Ax), r9d),
+            Inst::Test(Size::Dword, Reg::R8, Reg::R8),
+            Inst::Jcc(Cond::E, label("_done")),
+            Inst::Neg(Size::Dword, Reg::Ax),
+            Inst::Label(label("_done")),
+            Inst::Ret,
+        ]
+    }
+
+    // Writes eax to stdout as a signed decimal followed by a newline
+    fn put_routine() -> Vec<Inst> {
+        let label = |name: &str| String::from("rt_put_int") + name;
+        let eax = Operand::Reg(Reg::Ax);
+        let ecx = Operand::Reg(Reg::Cx);
+        let rsi = Operand::Reg(Reg::Si);
+
+        vec![
+            Inst::Label(label("")),
+            Inst::Lea(Reg::Si, Mem::Label(String::from(BUFFER), BUFFER_LEN - 1)),
+            Inst::Mov(
+                Size::Byte,
+                Operand::Mem(Mem::Base(Reg::Si, 0)),
+                Operand::Imm(10),
+            ),
+            Inst::Mov(Size::Dword, ecx.clone(), Operand::Imm(1)),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::R8), eax.clone()),
+            Inst::Test(Size::Dword, Reg::Ax, Reg::Ax),
+            Inst::Jcc(Cond::NS, label("_digit")),
+            Inst::Neg(Size::Dword, Reg::Ax),
+            Inst::Label(label("_digit")),
+            Inst::Alu(
+                AluOp::Xor,
+                Size::Dword,
+                Operand::Reg(Reg::Dx),
+                Operand::Reg(Reg::Dx),
+            ),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::R9), Operand::Imm(10)),
+            Inst::Div(Size::Dword, Operand::Reg(Reg::R9)),
+            Inst::Alu(
+                AluOp::Add,
+                Size::Dword,
+                Operand::Reg(Reg::Dx),
+                Operand::Imm('0' as i64),
+            ),
+            Inst::Dec(Size::Qword, rsi.clone()),
+            Inst::Mov(
+                Size::Byte,
+                Operand::Mem(Mem::Base(Reg::Si, 0)),
+                Operand::Reg(Reg::Dx),
+            ),
+            Inst::Inc(Size::Dword, ecx.clone()),
+            Inst::Test(Size::Dword, Reg::Ax, Reg::Ax),
+            Inst::Jcc(Cond::NE, label("_digit")),
+            Inst::Test(Size::Dword, Reg::R8, Reg::R8),
+            Inst::Jcc(Cond::NS, label("_write")),
+            Inst::Dec(Size::Qword, rsi),
+            Inst::Mov(
+                Size::Byte,
+                Operand::Mem(Mem::Base(Reg::Si, 0)),
+                Operand::Imm('-' as i64),
+            ),
+            Inst::Inc(Size::Dword, ecx.clone()),
+            Inst::Label(label("_write")),
+            Inst::Mov(Size::Dword, eax, Operand::Imm(SYS_WRITE)),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::Di), Operand::Imm(1)),
+            Inst::Mov(Size::Dword, Operand::Reg(Reg::Dx), ecx),
+            Inst::Syscall,
+            Inst::Ret,
+        ]
+    }
+}
+
+impl X86_64 {
+    // Same storage the data hook declares for the assemblers, then the encoded code
+    fn write_elf(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut image = Image::new();
+        let linked = self
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.class != SymbolClass::Literal)
+            .try_for_each(|symbol| image.dword(&data_label(&symbol.name), symbol.value))
+            .and_then(|_| image.reserve(BUFFER, BUFFER_LEN as usize))
+            .and_then(|_| image.assemble(&self.code))
+            .and_then(|_| image.link());
+
+        match linked {
+            Ok(()) => image.write(out),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl Default for X86_64 {
+    fn default() -> Self {
+        X86_64::new()
+    }
+}
+
+impl Backend for X86_64 {
+    fn extension(&self) -> &str {
+        if self.elf {
+            return "elf";
+        }
+        match self.dialect {
+            Dialect::Nasm => "asm",
+            Dialect::Gas => "s",
+        }
+    }
+
+    fn executable(&self) -> bool {
+        self.elf
+    }
+
+    fn allocate(&mut self, quads: &[Quad]) {
+        if !self.allocate {
+            return;
+        }
+        self.registers = regalloc::allocate(quads, REGISTERS.len())
+            .into_iter()
+            .map(|(name, index)| (name, REGISTERS[index]))
+            .collect();
+    }
+
+    fn prologue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        if self.elf {
+            return Ok(());
+        }
+        out.write_fmt(format_args!("{}\n", self.dialect.global("_start")))
+    }
+
+    fn data(&mut self, out: &mut dyn Write, symbols: &[Symbol]) -> io::Result<()> {
+        if self.elf {
+            self.symbols = symbols.to_vec();
+            return Ok(());
+        }
+        out.write_fmt(format_args!("{}\n", self.dialect.section(".data")))?;
+        for symbol in symbols {
+            if symbol.class != SymbolClass::Literal {
+                out.write_fmt(format_args!(
+                    "{}\n",
+                    self.dialect.dword(&data_label(&symbol.name), symbol.value)
+                ))?;
+            }
+        }
+        out.write_fmt(format_args!(
+            "{}\n{}\n",
+            self.dialect.section(".bss"),
+            self.dialect.reserve(BUFFER, BUFFER_LEN)
+        ))
+    }
+
+    fn text(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.emit(Inst::Label(String::from("_start")));
+        if self.elf {
+            return Ok(());
+        }
+        out.write_fmt(format_args!("{}\n", self.dialect.section(".text")))
+    }
+
+    fn add(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &syntax::Operand,
+        right: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        self.alu(AluOp::Add, left, right, dest);
+        Ok(())
+    }
+
+    fn sub(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &syntax::Operand,
+        right: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        self.alu(AluOp::Sub, left, right, dest);
+        Ok(())
+    }
+
+    fn mul(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &syntax::Operand,
+        right: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        let (left, right, dst) = (self.operand(left), self.operand(right), self.operand(dest));
+        match dst {
+            Operand::Reg(reg) if right != dst => {
+                self.mov(dst, left);
+                self.emit(Inst::Imul(Size::Dword, reg, right));
+            }
+            _ => {
+                self.mov(Operand::Reg(Reg::Ax), left);
+                self.emit(Inst::Imul(Size::Dword, Reg::Ax, right));
+                self.store(Reg::Ax, dest);
+            }
+        }
+        Ok(())
+    }
+
+    fn div(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &syntax::Operand,
+        right: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        // idiv has no immediate form, so a divisor not already in a register goes through ecx
+        self.load(Reg::Ax, left);
+        let divisor = match self.operand(right) {
+            Operand::Reg(reg) => reg,
+            _ => {
+                self.load(Reg::Cx, right);
+                Reg::Cx
+            }
+        };
+        self.emit(Inst::Cdq);
+        self.emit(Inst::Idiv(Size::Dword, Operand::Reg(divisor)));
+        self.store(Reg::Ax, dest);
+        Ok(())
+    }
+
+    fn shl(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &syntax::Operand,
+        bits: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        let count = shift_count(bits)? as u8;
+        let (left, dst) = (self.operand(left), self.operand(dest));
+        let reg = match dst {
+            Operand::Reg(reg) => reg,
+            _ => Reg::Ax,
+        };
+        self.mov(Operand::Reg(reg), left);
+        self.emit(Inst::Shift(
+            ShiftOp::Shl,
+            Size::Dword,
+            Operand::Reg(reg),
+            count,
+        ));
+        self.store(reg, dest);
+        Ok(())
+    }
+
+    fn shr(
+        &mut self,
+        _out: &mut dyn Write,
+        left: &syntax::Operand,
+        bits: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        // sar rounds down, adding 2^bits - 1 to a negative value first makes it round towards
+        // zero like idiv
+        let count = shift_count(bits)? as u8;
+        self.load(Reg::Ax, left);
+        self.emit(Inst::Cdq);
+        self.emit(Inst::Alu(
+            AluOp::And,
+            Size::Dword,
+            Operand::Reg(Reg::Dx),
+            Operand::Imm((1i64 << count) - 1),
+        ));
+        self.emit(Inst::Alu(
+            AluOp::Add,
+            Size::Dword,
+            Operand::Reg(Reg::Ax),
+            Operand::Reg(Reg::Dx),
+        ));
+        self.emit(Inst::Shift(
+            ShiftOp::Sar,
+            Size::Dword,
+            Operand::Reg(Reg::Ax),
+            count,
+        ));
+        self.store(Reg::Ax, dest);
+        Ok(())
+    }
+
+    fn assign(
+        &mut self,
+        _out: &mut dyn Write,
+        src: &syntax::Operand,
+        dest: &syntax::Operand,
+    ) -> io::Result<()> {
+        match (self.operand(src), self.operand(dest)) {
+            (src, dst @ Operand::Reg(_)) | (src @ Operand::Reg(_), dst) => self.mov(dst, src),
+            _ => {
+                self.load(Reg::Ax, src);
+                self.store(Reg::Ax, dest);
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, _out: &mut dyn Write, dest: &syntax::Operand) -> io::Result<()> {
+        self.uses_get = true;
+        self.emit(Inst::Call(String::from("rt_get_int")));
+        self.store(Reg::Ax, dest);
+        Ok(())
+    }
+
+    fn put(&mut self, _out: &mut dyn Write, src: &syntax::Operand) -> io::Result<()> {
+        self.uses_put = true;
+        self.load(Reg::Ax, src);
+        self.emit(Inst::Call(String::from("rt_put_int")));
+        Ok(())
+    }
+
+    fn label(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.emit(Inst::Label(jump_label(label)));
+        Ok(())
+    }
+
+    fn jump(&mut self, _out: &mut dyn Write, label: &str) -> io::Result<()> {
+        self.emit(Inst::Jmp(jump_label(label)));
+        Ok(())
+    }
+
+    fn branch(
+        &mut self,
+        _out: &mut dyn Write,
+        rel: Relation,
+        left: &syntax::Operand,
+        right: &syntax::Operand,
+        target: &str,
+    ) -> io::Result<()> {
+        // cmp can take a register on either side, two memory operands go through eax
+        let (left, right) = match (self.operand(left), self.operand(right)) {
+            (left @ Operand::Reg(_), right) | (left @ Operand::Mem(_), right @ Operand::Reg(_)) => {
+                (left, right)
+            }
+            (left, right) => {
+                self.mov(Operand::Reg(Reg::Ax), left);
+                (Operand::Reg(Reg::Ax), right)
+            }
+        };
+        self.emit(Inst::Alu(AluOp::Cmp, Size::Dword, left, right));
+        self.emit(Inst::Jcc(X86_64::cond(rel.negate()), jump_label(target)));
+        Ok(())
+    }
+
+    fn procedure(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.in_procedure = true;
+        self.emit(Inst::Label(proc_label(name)));
+        Ok(())
+    }
+
+    fn ret(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        self.emit(Inst::Ret);
+        self.in_procedure = false;
+        Ok(())
+    }
+
+    fn call(&mut self, _out: &mut dyn Write, name: &str) -> io::Result<()> {
+        self.emit(Inst::Call(proc_label(name)));
+        Ok(())
+    }
+
+    fn epilogue(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.code.push(Inst::Mov(
+            Size::Dword,
+            Operand::Reg(Reg::Ax),
+            Operand::Imm(SYS_EXIT),
+        ));
+        self.code.push(Inst::Alu(
+            AluOp::Xor,
+            Size::Dword,
+            Operand::Reg(Reg::Di),
+            Operand::Reg(Reg::Di),
+        ));
+        self.code.push(Inst::Syscall);
+        self.code.append(&mut self.procedures);
+
+        if self.uses_get {
+            self.code.extend(X86_64::get_routine());
+        }
+        if self.uses_put {
+            self.code.extend(X86_64::put_routine());
+        }
+        if self.peephole {
+            self.code = peephole::optimise(std::mem::take(&mut self.code));
+        }
+
+        if self.elf {
+            return self.write_elf(out);
+        }
+        for inst in &self.code {
+            out.write_fmt(format_args!("{}\n", self.dialect.inst(inst)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::syntax::{Opcode, Quad};
+
+    #[test]
+    fn test_x86_64_output() {
+        let quads = vec![
+            Quad::binary(Opcode::Mul, var("a"), num(4), temp(1)),
+            Quad::binary(Opcode::Div, temp(1), var("b"), temp(2)),
+            Quad::assign(temp(2), var("a")),
+        ];
+        let symbols = vec![
+            Symbol {
+                name: String::from("a"),
+                class: SymbolClass::Identifier,
+                value: 0,
+            },
+            Symbol {
+                name: String::from("4"),
+                class: SymbolClass::Literal,
+                value: 0,
+            },
+            Symbol {
+                name: String::from("b"),
+                class: SymbolClass::Identifier,
+                value: 0,
+            },
+            Symbol {
+                name: String::from("temp_1"),
+                class: SymbolClass::Temp,
+                value: 0,
+            },
+            Symbol {
+                name: String::from("temp_2"),
+                class: SymbolClass::Temp,
+                value: 0,
+            },
+        ];
+
+        // Every name the code uses is declared, the literal is an immediate
+        let mut gen = Generator::with_writer(quads, symbols, Box::new(X86_64::new()), Vec::new());
+        gen.consume_quads().unwrap();
+        let asm = String::from_utf8(gen.into_inner()).unwrap();
+
+        assert_eq!(
+            asm,
+            "\tglobal _start\n\
+             section .data\n\
+             v_a   dd 0\n\
+             v_b   dd 0\n\
+             v_temp_1 dd 0\n\
+             v_temp_2 dd 0\n\
+             section .bss\n\
+             rt_buffer resb 32\n\
+             section .text\n\
+             _start:\n\
+             \tmov eax,[rel v_a]\n\
+             \timul eax,4\n\
+             \tmov [rel v_temp_1],eax\n\
+             \tmov eax,[rel v_temp_1]\n\
+             \tmov ecx,[rel v_b]\n\
+             \tcdq\n\
+             \tidiv ecx\n\
+             \tmov [rel v_temp_2],eax\n\
+             \tmov eax,[rel v_temp_2]\n\
+             \tmov [rel v_a],eax\n\
+             \tmov eax,60\n\
+             \txor edi,edi\n\
+             \tsyscall\n"
+        );
+    }
+
+    #[test]
+    fn test_x86_64_names() {
+        // Names an assembler would read as a register or keyword
+        let quads = || {
+            vec![
+                Quad::get(var("rax")),
+                Quad::label("rip"),
+                Quad::binary(Opcode::Add, var("rax"), var("byte"), var("rel")),
+                Quad::assign(var("rel"), var("eax")),
+                Quad::branch(Relation::LessThan, var("eax"), num(0), "rip"),
+                Quad::call("rax"),
+            ]
+        };
+        let symbols: Vec<Symbol> = ["rax", "eax", "rip", "rel", "byte"]
+            .iter()
+            .map(|name| Symbol {
+                name: name.to_string(),
+                class: SymbolClass::Identifier,
+                value: 0,
+            })
+            .collect();
+        let generate = |backend: X86_64| {
+            let mut gen =
+                Generator::with_writer(quads(), symbols.clone(), Box::new(backend), Vec::new());
+            gen.consume_quads().unwrap();
+            String::from_utf8(gen.into_inner()).unwrap()
+        };
+
+        let nasm = generate(X86_64::new());
+        assert!(nasm.contains("v_rax dd 0\nv_eax dd 0\nv_rip dd 0\nv_rel dd 0\nv_byte dd 0\n"));
+        assert!(nasm.contains(
+            "l_rip:\n\
+             \tmov eax,[rel v_rax]\n\
+             \tadd eax,[rel v_byte]\n\
+             \tmov [rel v_rel],eax\n"
+        ));
+        assert!(nasm.contains("\tjge l_rip\n\tcall p_rax\n"));
+
+        let gas = generate(X86_64::with_dialect(Dialect::Gas));
+        assert!(gas.contains("v_rip:\t.long 0\n"));
+        assert!(gas.contains(
+            "l_rip:\n\
+             \tmovl v_rax(%rip), %eax\n\
+             \taddl v_byte(%rip), %eax\n\
+             \tmovl %eax, v_rel(%rip)\n"
+        ));
+        assert!(gas.contains("\tjge l_rip\n\tcall p_rax\n"));
+    }
+
+    #[test]
+    fn test_x86_64_io_routines() {
+        let quads = vec![Quad::put(var("a"))];
+
+        let mut gen =
+            Generator::with_writer(quads, Vec::new(), Box::new(X86_64::new()), Vec::new());
+        gen.consume_quads().unwrap();
+        let asm = String::from_utf8(gen.into_inner()).unwrap();
+
+        assert!(asm.contains("\tcall rt_put_int\n"));
+        assert!(asm.contains("rt_put_int:\n"));
+        assert!(!asm.contains("rt_get_int:\n"));
+    }
+
+    #[test]
+    fn test_gas_output() {
+        let quads = || {
+            vec![
+                Quad::get(var("a")),
+                Quad::binary(Opcode::Sub, var("a"), num(1), temp(1)),
+                Quad::branch(Relation::LessThan, var("a"), temp(1), "L1"),
+                Quad::label("L1"),
+            ]
+        };
+        let symbols = vec![Symbol {
+            name: String::from("a"),
+            class: SymbolClass::Identifier,
+            value: 3,
+        }];
+        let generate = |backend: X86_64| {
+            let mut gen =
+                Generator::with_writer(quads(), symbols.clone(), Box::new(backend), Vec::new());
+            gen.consume_quads().unwrap();
+            String::from_utf8(gen.into_inner()).unwrap()
+        };
+
+        let nasm = generate(X86_64::new());
+        let gas = generate(X86_64::with_dialect(Dialect::Gas));
+
+        assert!(gas.starts_with(
+            "\t.globl _start\n\
+             \t.data\n\
+             v_a:\t.long 3\n\
+             \t.bss\n\
+             \t.lcomm rt_buffer, 32\n\
+             \t.text\n\
+             _start:\n\
+             \tcall rt_get_int\n\
+             \tmovl %eax, v_a(%rip)\n\
+             \tmovl v_a(%rip), %eax\n\
+             \tsubl $1, %eax\n\
+             \tmovl %eax, v_temp_1(%rip)\n\
+             \tmovl v_a(%rip), %eax\n\
+             \tcmpl v_temp_1(%rip), %eax\n\
+             \tjge l_L1\n\
+             l_L1:\n"
+        ));
+        assert!(gas.contains("\tmovzbl (%rsi), %ecx\n"));
+
+        // Same instruction stream, only the spelling differs
+        let code = |asm: &str| {
+            asm.lines()
+                .skip_while(|line| *line != "_start:")
+                .map(|line| line.split_whitespace().next().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+        let (nasm, gas) = (code(&nasm), code(&gas));
+        assert_eq!(nasm.len(), gas.len());
+        for (n, g) in nasm.iter().zip(gas.iter()) {
+            let spelled = match n.as_str() {
+                "cdq" => "cltd",
+                "movzx" => "movzbl",
+                n => n,
+            };
+            assert!(g.starts_with(spelled), "{} {}", n, g);
+        }
+    }
+
+    #[test]
+    fn test_x86_64_registers() {
+        // GET(n); s = 0; WHILE n > 0 { s = s + n; n = n - 1; } PUT(s);
+        let quads = vec![
+            Quad::get(var("n")),
+            Quad::assign(num(0), var("s")),
+            Quad::label("L1"),
+            Quad::branch(Relation::GreaterThan, var("n"), num(0), "L2"),
+            Quad::binary(Opcode::Add, var("s"), var("n"), temp(1)),
+            Quad::assign(temp(1), var("s")),
+            Quad::binary(Opcode::Sub, var("n"), num(1), temp(2)),
+            Quad::assign(temp(2), var("n")),
+            Quad::jump("L1"),
+            Quad::label("L2"),
+            Quad::put(var("s")),
+        ];
+        let code = |quads: Vec<Quad>, backend: X86_64| {
+            let mut gen = Generator::with_writer(quads, Vec::new(), Box::new(backend), Vec::new());
+            gen.consume_quads().unwrap();
+            let asm = String::from_utf8(gen.into_inner()).unwrap();
+            asm.lines()
+                .skip_while(|line| *line != "_start:")
+                .take_while(|line| *line != "\tsyscall")
+                .map(|line| format!("{}\n", line))
+                .collect::<String>()
+        };
+
+        // Nothing in the loop touches memory
+        let registers = code(quads.clone(), X86_64::new().with_registers());
+        assert_eq!(
+            registers,
+            "_start:\n\
+             \tcall rt_get_int\n\
+             \tmov ebx,eax\n\
+             \tmov r10d,0\n\
+             l_L1:\n\
+             \tcmp ebx,0\n\
+             \tjle l_L2\n\
+             \tmov r12d,r10d\n\
+             \tadd r12d,ebx\n\
+             \tmov r10d,r12d\n\
+             \tmov r12d,ebx\n\
+             \tsub r12d,1\n\
+             \tmov ebx,r12d\n\
+             \tjmp l_L1\n\
+             l_L2:\n\
+             \tmov eax,r10d\n\
+             \tcall rt_put_int\n\
+             \tmov eax,60\n\
+             \txor edi,edi\n"
+        );
+        assert!(registers.lines().count() < code(quads, X86_64::new()).lines().count());
+
+        // show reads a and b, so only the temps of the sample get registers
+        let memory = code(sample::quads(), X86_64::new());
+        let registers = code(sample::quads(), X86_64::new().with_registers());
+        assert!(registers.lines().count() < memory.lines().count());
+        assert!(!registers.contains("temp"));
+        assert!(registers.contains("[rel v_a]"));
+    }
+
+    #[test]
+    fn test_x86_64_peephole() {
+        let code = |backend: X86_64| {
+            let mut gen = Generator::with_writer(
+                sample::quads(),
+                sample::symbols(),
+                Box::new(backend),
+                Vec::new(),
+            );
+            gen.consume_quads().unwrap();
+            String::from_utf8(gen.into_inner()).unwrap()
+        };
+
+        // Every temp the sample stores is loaded straight back by the next quad, as is c
+        let plain = code(X86_64::new());
+        let peephole = code(X86_64::new().with_peephole());
+        assert_eq!(plain.lines().count() - peephole.lines().count(), 6);
+        let lines: Vec<&str> = peephole.lines().collect();
+        for pair in lines.windows(2) {
+            if let Some(var) = pair[0].strip_prefix("\tmov [rel ") {
+                let var = var.strip_suffix("],eax").unwrap();
+                assert_ne!(pair[1], format!("\tmov eax,[rel {}]", var));
+            }
+        }
+    }
+
+    #[test]
+    fn test_x86_64_shifts() {
+        // GET(x); PUT(x * 8); PUT(x / 4) after strength reduction
+        let quads = vec![
+            Quad::get(var("x")),
+            Quad::binary(Opcode::Shl, var("x"), num(3), temp(1)),
+            Quad::put(temp(1)),
+            Quad::binary(Opcode::Shr, var("x"), num(2), temp(2)),
+            Quad::put(temp(2)),
+        ];
+
+        let mut gen =
+            Generator::with_writer(quads, Vec::new(), Box::new(X86_64::new()), Vec::new());
+        gen.consume_quads().unwrap();
+        let asm = String::from_utf8(gen.into_inner()).unwrap();
+        let start = asm.find("_start:").unwrap();
+        let end = asm[start..].find("\tmov eax,60").unwrap();
+        // The shift right rounds towards zero like idiv
+        assert_eq!(
+            &asm[start..start + end],
+            "_start:\n\
+             \tcall rt_get_int\n\
+             \tmov [rel v_x],eax\n\
+             \tmov eax,[rel v_x]\n\
+             \tshl eax,3\n\
+             \tmov [rel v_temp_1],eax\n\
+             \tmov eax,[rel v_temp_1]\n\
+             \tcall rt_put_int\n\
+             \tmov eax,[rel v_x]\n\
+             \tcdq\n\
+             \tand edx,3\n\
+             \tadd eax,edx\n\
+             \tsar eax,2\n\
+             \tmov [rel v_temp_2],eax\n\
+             \tmov eax,[rel v_temp_2]\n\
+             \tcall rt_put_int\n"
+        );
+    }
+
+    #[test]
+    fn test_elf_output() {
+        let mut gen = Generator::with_writer(
+            sample::quads(),
+            sample::symbols(),
+            Box::new(X86_64::elf()),
+            Vec::new(),
+        );
+        gen.consume_quads().unwrap();
+        let elf = gen.into_inner();
+
+        // Pins the encoding of every instruction the backend and its runtime emit
+        assert_eq!(elf, include_bytes!("golden/sample.elf"));
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::compiler::backend::Relation;
+use crate::compiler::lexical::{Token, TokenClass};
+
+// Stack machine bytecode in the style of PL/0 P-code. Every variable is a numbered global slot,
+// expressions work on the operand stack and jumps use absolute instruction indexes.
+//
+// Binary file layout, all integers little endian:
+//   magic "S17B", version byte
+//   u16 global count, then per global: u8 name length, name bytes, i32 initial value
+//   u32 instruction count, then per instruction: opcode byte, operand if the opcode has one
+const MAGIC: &[u8; 4] = b"S17B";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Push(i32),
+    Load(u16),
+    Store(u16),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    // Pops right then left and pushes 1 when `left rel right` holds, 0 otherwise
+    Cmp(Relation),
+    Jump(u32),
+    // Pops the condition and jumps when it is 0
+    JumpIfFalse(u32),
+    Call(u32),
+    Ret,
+    // Reads a number from the input into a slot
+    Get(u16),
+    // Pops a value and prints it
+    Put,
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Global {
+    pub name: String,
+    pub value: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program {
+    pub globals: Vec<Global>,
+    pub code: Vec<Op>,
+}
+
+// An operand stack entry while compiling, variables remember the load that pushed them so an
+// assignment or GET can take it back out again
+enum Item {
+    Var(u16, usize),
+    Value,
+}
+
+impl Op {
+    fn opcode(self) -> u8 {
+        match self {
+            Op::Push(_) => 0x01,
+            Op::Load(_) => 0x02,
+            Op::Store(_) => 0x03,
+            Op::Add => 0x10,
+            Op::Sub => 0x11,
+            Op::Mul => 0x12,
+            Op::Div => 0x13,
+            Op::Cmp(Relation::Equal) => 0x20,
+            Op::Cmp(Relation::NEqual) => 0x21,
+            Op::Cmp(Relation::LessThan) => 0x22,
+            Op::Cmp(Relation::LEqual) => 0x23,
+            Op::Cmp(Relation::GreaterThan) => 0x24,
+            Op::Cmp(Relation::GEqual) => 0x25,
+            Op::Jump(_) => 0x30,
+            Op::JumpIfFalse(_) => 0x31,
+            Op::Call(_) => 0x32,
+            Op::Ret => 0x33,
+            Op::Get(_) => 0x40,
+            Op::Put => 0x41,
+            Op::Halt => 0xff,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Push(value) => write!(f, "PUSH {}", value),
+            Op::Load(slot) => write!(f, "LOAD {}", slot),
+            Op::Store(slot) => write!(f, "STORE {}", slot),
+            Op::Add => write!(f, "ADD"),
+            Op::Sub => write!(f, "SUB"),
+            Op::Mul => write!(f, "MUL"),
+            Op::Div => write!(f, "DIV"),
+            Op::Cmp(Relation::Equal) => write!(f, "EQ"),
+            Op::Cmp(Relation::NEqual) => write!(f, "NE"),
+            Op::Cmp(Relation::LessThan) => write!(f, "LT"),
+            Op::Cmp(Relation::LEqual) => write!(f, "LE"),
+            Op::Cmp(Relation::GreaterThan) => write!(f, "GT"),
+            Op::Cmp(Relation::GEqual) => write!(f, "GE"),
+            Op::Jump(addr) => write!(f, "JMP {}", addr),
+            Op::JumpIfFalse(addr) => write!(f, "JPC {}", addr),
+            Op::Call(addr) => write!(f, "CALL {}", addr),
+            Op::Ret => write!(f, "RET"),
+            Op::Get(slot) => write!(f, "GET {}", slot),
+            Op::Put => write!(f, "PUT"),
+            Op::Halt => write!(f, "HALT"),
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (slot, global) in self.globals.iter().enumerate() {
+            writeln!(f, "; {} {} = {}", slot, global.name, global.value)?;
+        }
+        for (addr, op) in self.code.iter().enumerate() {
+            writeln!(f, "{:>4}  {}", addr, op)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("[ Error ] {}", msg))
+}
+
+fn read_bytes<const N: usize>(input: &mut dyn Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl Program {
+    // Postfix from Syntax: operands are pushed, `dest src =` assigns and `x GET` / `x PUT` do I/O.
+    // Only straight-line code is covered, Syntax has no polish for IF, WHILE or PROCEDURE, so the
+    // jumps and calls only come from programs built by hand or read from a file.
+    pub fn from_polish(polish: &[Token]) -> Result<Self, String> {
+        let mut program = Program::default();
+        let mut stack: Vec<Item> = Vec::new();
+        let underflow = |token: &Token| format!("[ Error ] Missing operand for {}", token.name);
+
+        for token in polish {
+            match token.class {
+                TokenClass::Identifier => {
+                    let slot = program.slot(&token.name);
+                    stack.push(Item::Var(slot, program.code.len()));
+                    program.code.push(Op::Load(slot));
+                }
+                TokenClass::Literal => {
+                    let value = token
+                        .name
+                        .parse::<i32>()
+                        .map_err(|_| format!("[ Error ] Bad literal: {}", token.name))?;
+                    stack.push(Item::Value);
+                    program.code.push(Op::Push(value));
+                }
+                TokenClass::Op if token.name == "=" => {
+                    stack.pop().ok_or_else(|| underflow(token))?;
+                    let slot = program.take_var(stack.pop(), token)?;
+                    program.code.push(Op::Store(slot));
+                }
+                TokenClass::Op | TokenClass::RelationOp => {
+                    let op = match token.name.as_str() {
+                        "+" => Op::Add,
+                        "-" => Op::Sub,
+                        "*" => Op::Mul,
+                        "/" => Op::Div,
+                        rel => match Relation::from_op(rel) {
+                            Some(rel) => Op::Cmp(rel),
+                            None => return Err(format!("[ Error ] Unknown operator: {}", rel)),
+                        },
+                    };
+                    stack.pop().ok_or_else(|| underflow(token))?;
+                    stack.pop().ok_or_else(|| underflow(token))?;
+                    stack.push(Item::Value);
+                    program.code.push(op);
+                }
+                TokenClass::ReservedWord if token.name == "GET" => {
+                    let slot = program.take_var(stack.pop(), token)?;
+                    program.code.push(Op::Get(slot));
+                }
+                TokenClass::ReservedWord if token.name == "PUT" => {
+                    stack.pop().ok_or_else(|| underflow(token))?;
+                    program.code.push(Op::Put);
+                }
+                TokenClass::ReservedWord => {
+                    return Err(format!(
+                        "[ Error ] {} can not be compiled to bytecode, only straight-line code can",
+                        token.name
+                    ))
+                }
+                // Statement separators carry no code
+                _ => continue,
+            }
+        }
+
+        program.code.push(Op::Halt);
+        Ok(program)
+    }
+
+    // Slot of a global, added the first time the name is seen
+    pub fn slot(&mut self, name: &str) -> u16 {
+        match self.globals.iter().position(|global| global.name == name) {
+            Some(slot) => slot as u16,
+            None => {
+                self.globals.push(Global {
+                    name: name.to_string(),
+                    value: 0,
+                });
+                (self.globals.len() - 1) as u16
+            }
+        }
+    }
+
+    // The target of = and GET was loaded like any other operand, drop that load again
+    fn take_var(&mut self, item: Option<Item>, token: &Token) -> Result<u16, String> {
+        match item {
+            Some(Item::Var(slot, load)) => {
+                self.code.remove(load);
+                Ok(slot)
+            }
+            _ => Err(format!("[ Error ] {} needs a variable", token.name)),
+        }
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+
+        out.write_all(&(self.globals.len() as u16).to_le_bytes())?;
+        for global in &self.globals {
+            let name = global.name.as_bytes();
+            let len = u8::try_from(name.len()).map_err(|_| invalid("Global name too long"))?;
+            out.write_all(&[len])?;
+            out.write_all(name)?;
+            out.write_all(&global.value.to_le_bytes())?;
+        }
+
+        out.write_all(&(self.code.len() as u32).to_le_bytes())?;
+        for op in &self.code {
+            out.write_all(&[op.opcode()])?;
+            match *op {
+                Op::Push(value) => out.write_all(&value.to_le_bytes())?,
+                Op::Load(slot) | Op::Store(slot) | Op::Get(slot) => {
+                    out.write_all(&slot.to_le_bytes())?
+                }
+                Op::Jump(addr) | Op::JumpIfFalse(addr) | Op::Call(addr) => {
+                    out.write_all(&addr.to_le_bytes())?
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(input: &mut dyn Read) -> io::Result<Self> {
+        if &read_bytes::<4>(input)? != MAGIC {
+            return Err(invalid("Not a Small17 bytecode file"));
+        }
+        if read_bytes::<1>(input)?[0] != VERSION {
+            return Err(invalid("Unsupported bytecode version"));
+        }
+
+        let mut program = Program::default();
+        for _ in 0..u16::from_le_bytes(read_bytes(input)?) {
+            let mut name = vec![0; read_bytes::<1>(input)?[0] as usize];
+            input.read_exact(&mut name)?;
+            program.globals.push(Global {
+                name: String::from_utf8(name).map_err(|_| invalid("Bad global name"))?,
+                value: i32::from_le_bytes(read_bytes(input)?),
+            });
+        }
+
+        for _ in 0..u32::from_le_bytes(read_bytes(input)?) {
+            let slot = |input: &mut dyn Read| read_bytes(input).map(u16::from_le_bytes);
+            let addr = |input: &mut dyn Read| read_bytes(input).map(u32::from_le_bytes);
+            let op = match read_bytes::<1>(input)?[0] {
+                0x01 => Op::Push(i32::from_le_bytes(read_bytes(input)?)),
+                0x02 => Op::Load(slot(input)?),
+                0x03 => Op::Store(slot(input)?),
+                0x10 => Op::Add,
+                0x11 => Op::Sub,
+                0x12 => Op::Mul,
+                0x13 => Op::Div,
+                0x20 => Op::Cmp(Relation::Equal),
+                0x21 => Op::Cmp(Relation::NEqual),
+                0x22 => Op::Cmp(Relation::LessThan),
+                0x23 => Op::Cmp(Relation::LEqual),
+                0x24 => Op::Cmp(Relation::GreaterThan),
+                0x25 => Op::Cmp(Relation::GEqual),
+                0x30 => Op::Jump(addr(input)?),
+                0x31 => Op::JumpIfFalse(addr(input)?),
+                0x32 => Op::Call(addr(input)?),
+                0x33 => Op::Ret,
+                0x40 => Op::Get(slot(input)?),
+                0x41 => Op::Put,
+                0xff => Op::Halt,
+                e => return Err(invalid(&format!("Unknown opcode {:#04x}", e))),
+            };
+            program.code.push(op);
+        }
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn polish(source: &str) -> Vec<Token> {
+        source
+            .split_whitespace()
+            .map(|name| {
+                let class = match name {
+                    "+" | "-" | "*" | "/" | "=" => TokenClass::Op,
+                    "<" | ">" | "==" | "!=" | "<=" | ">=" => TokenClass::RelationOp,
+                    "GET" | "PUT" | "WHILE" => TokenClass::ReservedWord,
+                    ";" => TokenClass::Delimiter,
+                    n if n.parse::<i32>().is_ok() => TokenClass::Literal,
+                    _ => TokenClass::Identifier,
+                };
+                Token::new(name, class)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_from_polish() {
+        // GET(a); b = a * (a - 1); PUT(b / 2);
+        let program = Program::from_polish(&polish("a GET ; b a a 1 - * = ; b 2 / PUT ;")).unwrap();
+
+        assert_eq!(
+            program.to_string(),
+            "; 0 a = 0\n\
+             ; 1 b = 0\n\
+             \x20  0  GET 0\n\
+             \x20  1  LOAD 0\n\
+             \x20  2  LOAD 0\n\
+             \x20  3  PUSH 1\n\
+             \x20  4  SUB\n\
+             \x20  5  MUL\n\
+             \x20  6  STORE 1\n\
+             \x20  7  LOAD 1\n\
+             \x20  8  PUSH 2\n\
+             \x20  9  DIV\n\
+             \x20 10  PUT\n\
+             \x20 11  HALT\n"
+        );
+    }
+
+    #[test]
+    fn test_from_polish_errors() {
+        assert!(Program::from_polish(&polish("1 2 =")).is_err());
+        assert!(Program::from_polish(&polish("4 GET")).is_err());
+        assert!(Program::from_polish(&polish("a +")).is_err());
+        assert_eq!(
+            Program::from_polish(&polish("a 0 > WHILE")),
+            Err(String::from(
+                "[ Error ] WHILE can not be compiled to bytecode, only straight-line code can"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_serialise() {
+        let mut program = Program::from_polish(&polish("a GET a 3 > PUT")).unwrap();
+        program.globals[0].value = -2;
+        program.code.insert(0, Op::Call(4));
+        program.code.push(Op::JumpIfFalse(1));
+        program.code.push(Op::Ret);
+
+        let mut bytes = Vec::new();
+        program.write(&mut bytes).unwrap();
+
+        assert_eq!(
+            bytes,
+            [
+                b'S', b'1', b'7', b'B', 1, 1, 0, 1, b'a', 0xfe, 0xff, 0xff, 0xff, 9, 0, 0, 0, 0x32,
+                4, 0, 0, 0, 0x40, 0, 0, 0x02, 0, 0, 0x01, 3, 0, 0, 0, 0x24, 0x41, 0xff, 0x31, 1, 0,
+                0, 0, 0x33
+            ]
+        );
+        assert_eq!(Program::read(&mut bytes.as_slice()).unwrap(), program);
+        assert!(Program::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(Program::read(&mut &b"ELF"[..]).is_err());
+    }
+}
@@ -1,239 +1,327 @@
 use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{self, Write};
 use std::path::Path;
 use std::vec::IntoIter;
 
-use crate::compiler::lexical::TokenClass;
-use crate::compiler::syntax::{Quad, QuadList};
+use crate::compiler::backend::{Backend, Symbol};
+use crate::compiler::ir;
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList};
 
 type Result<T> = std::result::Result<T, GeneratorErr>;
 
+// The quad the backend failed on, None when writing the header or epilogue failed
 #[derive(Debug, PartialEq, Eq)]
-pub struct GeneratorErr(Quad);
+pub struct GeneratorErr(Option<Box<Quad>>);
 
-struct Generator {
+pub struct Generator<W: Write> {
     quads: IntoIter<Quad>,
-    asm_file: File,
+    symbols: Vec<Symbol>,
+    backend: Box<dyn Backend>,
+    out: W,
 }
 
-impl<'a> fmt::Display for GeneratorErr {
+impl fmt::Display for GeneratorErr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.0)
+        match &self.0 {
+            Some(quad) => write!(
+                f,
+                "[ Error ] Could not generate code for: {}",
+                ir::to_text(std::slice::from_ref(quad)).trim()
+            ),
+            None => write!(f, "[ Error ] Could not write the program"),
+        }
     }
 }
 
-impl Generator {
-    fn new(quads: QuadList) -> Self {
-        if Path::new("code.asm").exists() {
-            fs::remove_file("code.asm").unwrap();
+// Only a Var or Temp can be written to
+fn stored(operand: &Operand) -> bool {
+    operand.storage().is_some()
+}
+
+impl Generator<File> {
+    // Generate into a new file at path, replacing whatever was there
+    pub fn create(
+        quads: QuadList,
+        symbols: Vec<Symbol>,
+        backend: Box<dyn Backend>,
+        path: &Path,
+    ) -> io::Result<Self> {
+        if path.exists() {
+            fs::remove_file(path)?;
         }
 
-        let mut file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open("code.asm")
-            .unwrap();
+        let mut options = OpenOptions::new();
+        options.create_new(true).write(true);
+        #[cfg(unix)]
+        if backend.executable() {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o755);
+        }
+        let file = options.open(path)?;
 
-        file.write_fmt(format_args!(
-            "sys_exit equ 1\nsys_read equ 3\nsys_write equ 4\nstdin equ 0\nstdout equ 1\n.DATA\n"
-        ))
-        .unwrap();
-        Generator::init_asm_file(&mut file).unwrap();
+        Ok(Generator::with_writer(quads, symbols, backend, file))
+    }
+}
 
+impl<W: Write> Generator<W> {
+    pub fn with_writer(
+        quads: QuadList,
+        symbols: Vec<Symbol>,
+        backend: Box<dyn Backend>,
+        out: W,
+    ) -> Self {
         Generator {
             quads: quads.into_iter(),
-            asm_file: file,
+            symbols,
+            backend,
+            out,
         }
     }
 
-    fn init_asm_file(asm_file: &mut File) -> Result<()> {
-        // open symbol table
-        // init variables
-        // Table driven?
-        let sym_file = File::open("symbols");
-        match sym_file {
-            Ok(file) => {
-                let buf = BufReader::new(file);
-                let mut lines = buf.lines();
-
-                while let Some(result) = lines.next() {
-                    let mut line_vec: Vec<&str>;
-                    if let Ok(line) = result {
-                        line_vec = Vec::from_iter(line.split_whitespace());
-                        if line_vec[1].eq("Identifier") {
-                            asm_file
-                                .write_fmt(format_args!("{:<5} DW {}\n", line_vec[0], line_vec[2]))
-                                .unwrap();
-                        }
-                    }
-                }
-                asm_file
-                    .write_fmt(format_args!(
-                        "section .bss\n\tblen equ 6\n\tbuffer resb blen\nsection .text\n\tglobal _start\n_start: nop\n"
-                    ))
-                    .unwrap();
-                Ok(())
-            }
-            Err(e) => panic!("{}", e),
-        }
+    pub fn into_inner(self) -> W {
+        self.out
     }
 
     pub fn consume_quads(&mut self) -> Result<()> {
-        // let label_loc = 0;
-        // let fix_up: Vec<(i32, &str)> = Vec::new();
-
-        // Match on the different operators
-        // output the assembly to a file
-        let mut io_flag = false;
-        while let Some(quad) = self.quads.next() {
-            match quad.op.class {
-                TokenClass::ReservedWord => match quad.op.name.as_str() {
-                    "GET" => {
-                        io_flag = true;
-                        let res = self.asm_file.write_fmt(format_args!("call GetInput\n"));
-
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
-                    }
-
-                    "PUT" => {
-                        io_flag = true;
-                        let res = self.asm_file.write_fmt(format_args!("call Print\n"));
-
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
-                    }
-
-                    _ => todo!(),
-                },
-
-                TokenClass::Op => match quad.op.name.as_str() {
-                    "+" => {
-                        let res = self.asm_file.write_fmt(format_args!(
-                            "\tmov ax,[{}]\n\tadd ax,[{}]\n\tmov [{}],ax\n",
-                            quad.param_two.name, quad.param_one.name, quad.temp.name
-                        ));
-
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
-                    }
-
-                    "-" => {
-                        let res = self.asm_file.write_fmt(format_args!(
-                            "\tmov ax,[{}]\n\tsub ax,[{}]\n\tmov [{}],ax\n",
-                            quad.param_two.name, quad.param_one.name, quad.temp.name
-                        ));
-
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
-                    }
-
-                    "/" => {
-                        let res = self.asm_file.write_fmt(format_args!(
-                            "\tmov dx,0\n\tmov ax,[{}]\n\tmov bx,[{}]\n\tdiv bx\n\tmov [{}],ax\n",
-                            quad.param_two.name, quad.param_one.name, quad.temp.name
-                        ));
-
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
-                    }
-
-                    "*" => {
-                        let res = self.asm_file.write_fmt(format_args!(
-                            "\tmov ax,[{}]\n\tmov bx,[{}]\n\tmul bx\n\tmov [{}],ax\n",
-                            quad.param_two.name, quad.param_one.name, quad.temp.name
-                        ));
+        let out: &mut dyn Write = &mut self.out;
+
+        self.backend.allocate(self.quads.as_slice());
+        let header = self
+            .backend
+            .prologue(out)
+            .and_then(|_| self.backend.data(out, &self.symbols))
+            .and_then(|_| self.backend.text(out));
+        if header.is_err() {
+            return Err(GeneratorErr(None));
+        }
 
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
+        // Match on the different opcodes, handing each one to the backend. A quad whose operands
+        // do not fit its opcode is an error rather than something to guess at.
+        for quad in self.quads.by_ref() {
+            let res = match (quad.op, &quad.left, &quad.right, &quad.dest) {
+                (op, Some(left), Some(right), Some(dest)) if op.is_arith() && stored(dest) => {
+                    match op {
+                        Opcode::Add => self.backend.add(out, left, right, dest),
+                        Opcode::Sub => self.backend.sub(out, left, right, dest),
+                        Opcode::Mul => self.backend.mul(out, left, right, dest),
+                        Opcode::Div => self.backend.div(out, left, right, dest),
+                        Opcode::Shl => self.backend.shl(out, left, right, dest),
+                        _ => self.backend.shr(out, left, right, dest),
                     }
+                }
+                (Opcode::Assign, Some(src), None, Some(dest)) if stored(dest) => {
+                    self.backend.assign(out, src, dest)
+                }
 
-                    "=" => {
-                        let res = self.asm_file.write_fmt(format_args!(
-                            "\tmov ax,[{}]\n\tmov [{}],ax\n",
-                            quad.param_one.name, quad.param_two.name
-                        ));
+                // Conditional jump to the label in dest when the comparison fails
+                (Opcode::Branch(rel), Some(left), Some(right), Some(Operand::Label(target))) => {
+                    self.backend.branch(out, rel, left, right, target)
+                }
 
-                        if res.is_err() {
-                            return Err(GeneratorErr(quad));
-                        }
-                    }
+                (Opcode::Get, None, None, Some(dest)) if stored(dest) => {
+                    self.backend.get(out, dest)
+                }
+                (Opcode::Put, Some(src), None, None) => self.backend.put(out, src),
+                (Opcode::Label, None, None, Some(Operand::Label(name))) => {
+                    self.backend.label(out, name)
+                }
+                (Opcode::Jump, None, None, Some(Operand::Label(name))) => {
+                    self.backend.jump(out, name)
+                }
+                (Opcode::Procedure, None, None, Some(Operand::Label(name))) => {
+                    self.backend.procedure(out, name)
+                }
+                (Opcode::Call, None, None, Some(Operand::Label(name))) => {
+                    self.backend.call(out, name)
+                }
+                (Opcode::Ret, None, None, None) => self.backend.ret(out),
 
-                    e => {
-                        if Path::new("code.asm").exists() {
-                            fs::remove_file("code.asm").unwrap();
-                        }
-                        panic!("[ Error ] Not a valid operator, {}", e)
-                    }
-                },
-                TokenClass::BoolExp => todo!(),
-                TokenClass::RelationOp => todo!(),
-                TokenClass::Unknown => todo!(),
-                _ => {
-                    if Path::new("code.asm").exists() {
-                        fs::remove_file("code.asm").unwrap();
-                    }
+                _ => return Err(GeneratorErr(Some(Box::new(quad)))),
+            };
 
-                    panic!("[ Error ] Some how this made it past syntax analysis?")
-                }
+            if res.is_err() {
+                return Err(GeneratorErr(Some(Box::new(quad))));
             }
         }
 
-        if io_flag {
-            self.write_io();
+        if self.backend.epilogue(out).is_err() {
+            return Err(GeneratorErr(None));
         }
 
         Ok(())
     }
-
-    fn write_io(&mut self) {
-        self.asm_file
-            .write_fmt(format_args!(
-                "GetInput:\nmov eax, 3\nmov ebx, 2\nmov ecx, buffer\nmov edx, blen\nint 80h\n"
-            ))
-            .unwrap();
-
-        self.asm_file.write_fmt(format_args!("Print:\npush ax\npush dx\nmov eax, 4\nmov ebx, 1\nmov ecx")).unwrap();
-    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::compiler::syntax::Syntax;
+    use crate::compiler::backend::nasm::Nasm;
+    use crate::compiler::backend::sample::{temp, var};
+    use crate::compiler::backend::{Relation, SymbolClass, Target};
+    use crate::compiler::ir;
+    use crate::{compile, Options};
+
+    // test1.java without its prompts, the lexer has no string literals. Its names are declared,
+    // as every name has to be, and PUT(ans) is added so the result is used.
+    const PROGRAM1: &str =
+        "VAR a, b, c, bob, jane, ans;\nGET(a);\nGET(b);\nGET(c);\nGET(bob);\nGET(jane);\n\n\
+                            ans = a * ((bob + jane - 10) / 2 * 4) / (b + c);\nPUT(ans);\n";
 
     #[test]
     fn test_program1() {
-        let mut syn = Syntax::new("test1.java", true);
-        syn.create_symbol_table("symbols1");
-        syn.complete_analysis();
-        syn.consume_polish().unwrap();
-
-        let mut gen = Generator::new(syn.quads);
-        let res = gen.consume_quads();
-        let check_res = res.is_ok();
-
-        assert!(check_res);
+        let output = compile(PROGRAM1, &Options::default()).unwrap();
+
+        assert_eq!(
+            ir::to_text(&output.quads),
+            "    get $a\n\
+             \x20   get $b\n\
+             \x20   get $c\n\
+             \x20   get $bob\n\
+             \x20   get $jane\n\
+             \x20   add %temp_1, $bob, $jane\n\
+             \x20   sub %temp_2, %temp_1, 10\n\
+             \x20   div %temp_3, %temp_2, 2\n\
+             \x20   mul %temp_4, %temp_3, 4\n\
+             \x20   mul %temp_5, $a, %temp_4\n\
+             \x20   add %temp_6, $b, $c\n\
+             \x20   div %temp_7, %temp_5, %temp_6\n\
+             \x20   copy $ans, %temp_7\n\
+             \x20   put $ans\n"
+        );
+        // Every variable and temp has storage and literals are immediates
+        let asm = String::from_utf8(output.code).unwrap();
+        for name in ["a", "b", "c", "bob", "jane", "ans", "temp_1", "temp_7"] {
+            assert!(
+                asm.contains(&format!("\n{:<5} DW 0\n", name)),
+                "{} is not declared",
+                name
+            );
+        }
+        assert!(asm.contains("\tmov ax,[temp_1]\n\tsub ax,10\n\tmov [temp_2],ax\n"));
     }
 
     #[test]
     fn test_program2() {
-        let mut syn = Syntax::new("test2.java", true);
-        syn.create_symbol_table("symbols2");
-        syn.complete_analysis();
-        syn.consume_polish().unwrap();
+        // test2.java compares with > and branches with IF, neither of which the front end has
+        let error = |source: &str| {
+            let diagnostics = compile(source, &Options::default()).unwrap_err();
+            diagnostics.errors[0].to_string()
+        };
+
+        assert_eq!(
+            error(include_str!("../../test2.java")),
+            "error: unexpected character `\"`"
+        );
+        assert_eq!(
+            error("GET(x);\nGET(y);\nIF x THEN PUT(x);\n"),
+            "error: `IF` is not supported"
+        );
+    }
 
-        let mut gen = Generator::new(syn.quads);
-        let res = gen.consume_quads();
-        let check_res = res.is_ok();
+    #[test]
+    fn test_nasm_output() {
+        let quads = vec![
+            Quad::get(var("a")),
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(1)),
+            Quad::assign(temp(1), var("c")),
+            Quad::branch(Relation::GreaterThan, var("a"), var("b"), "L1"),
+            Quad::label("L1"),
+        ];
+        let symbols = vec![
+            Symbol {
+                name: String::from("a"),
+                class: SymbolClass::Identifier,
+                value: 0,
+            },
+            Symbol {
+                name: String::from("temp_1"),
+                class: SymbolClass::Temp,
+                value: 0,
+            },
+        ];
+
+        let mut gen = Generator::with_writer(quads, symbols, Box::new(Nasm::new()), Vec::new());
+        gen.consume_quads().unwrap();
+        let asm = String::from_utf8(gen.into_inner()).unwrap();
+
+        assert_eq!(
+            asm,
+            "sys_exit equ 1\nsys_read equ 3\nsys_write equ 4\nstdin equ 0\nstdout equ 1\n.DATA\n\
+             a     DW 0\n\
+             temp_1 DW 0\n\
+             section .bss\n\tblen equ 6\n\tbuffer resb blen\n\
+             section .text\n\tglobal _start\n_start: nop\n\
+             call GetInput\n\
+             \tmov ax,[a]\n\tadd ax,[b]\n\tmov [temp_1],ax\n\
+             \tmov ax,[temp_1]\n\tmov [c],ax\n\
+             \tmov ax,[a]\n\tcmp ax,[b]\n\tjle L1\n\
+             L1:\n\
+             GetInput:\nmov eax, 3\nmov ebx, 2\nmov ecx, buffer\nmov edx, blen\nint 80h\n\
+             Print:\npush ax\npush dx\nmov eax, 4\nmov ebx, 1\nmov ecx"
+        );
+    }
 
-        assert!(check_res);
+    #[test]
+    fn test_malformed_quad() {
+        // Nothing can be stored into a literal, and a jump needs a label
+        let bad = [
+            Quad::assign(Operand::var("a"), Operand::Const(1)),
+            Quad {
+                op: Opcode::Jump,
+                left: None,
+                right: None,
+                dest: Some(Operand::var("L1")),
+            },
+            Quad {
+                op: Opcode::Add,
+                left: Some(Operand::var("a")),
+                right: None,
+                dest: Some(Operand::temp(1)),
+            },
+        ];
+
+        for quad in bad {
+            let mut gen = Generator::with_writer(
+                vec![quad.clone()],
+                Vec::new(),
+                Box::new(Nasm::new()),
+                Vec::new(),
+            );
+            assert_eq!(gen.consume_quads(), Err(GeneratorErr(Some(Box::new(quad)))));
+        }
+    }
+
+    #[test]
+    fn test_bad_shift_count() {
+        // A count ir::typed turns away is an error from every backend, not a panic or an overflow
+        let targets = [
+            Target::I386,
+            Target::X86_64,
+            Target::Gas,
+            Target::C,
+            Target::Wasm,
+            Target::Llvm,
+            Target::Elf,
+            Target::Jvm,
+            Target::RiscV,
+        ];
+        for target in targets {
+            for (op, count) in [(Opcode::Shl, 16), (Opcode::Shr, 40), (Opcode::Shl, -1)] {
+                let quad = Quad::binary(
+                    op,
+                    Operand::var("a"),
+                    Operand::Const(count),
+                    Operand::temp(1),
+                );
+                let mut gen = Generator::with_writer(
+                    vec![quad.clone()],
+                    Vec::new(),
+                    target.backend(),
+                    Vec::new(),
+                );
+                assert_eq!(gen.consume_quads(), Err(GeneratorErr(Some(Box::new(quad)))));
+            }
+        }
     }
 }
@@ -0,0 +1,388 @@
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+// Byte offsets into the source, end is one past the last byte
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+// A span with the text written under it, the primary one is underlined with ^ and the rest with -
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+// An error or warning as rustc reports them:
+//
+//   error: mismatched closing delimiter: `}`
+//    --> input.java:3:9
+//     |
+//   2 | GET(a;
+//     |    - opened here
+//   3 | PUT(a) }
+//     |        ^ expected `)`
+//     |
+//     = help: ...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub help: Vec<String>,
+}
+
+// When to colour the output, auto colours a terminal unless NO_COLOR is set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+// Renders diagnostics against the source they were found in
+pub struct Emitter<'a> {
+    pub file: &'a str,
+    pub source: &'a str,
+    pub color: bool,
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+impl Level {
+    fn style(self) -> &'static str {
+        match self {
+            Level::Error => RED,
+            Level::Warning => YELLOW,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error(message: &str) -> Self {
+        Diagnostic::new(Level::Error, message)
+    }
+
+    pub fn warning(message: &str) -> Self {
+        Diagnostic::new(Level::Warning, message)
+    }
+
+    fn new(level: Level, message: &str) -> Self {
+        Diagnostic {
+            level,
+            message: message.to_string(),
+            labels: Vec::new(),
+            help: Vec::new(),
+        }
+    }
+
+    // Where the problem is, its location is the one in the header
+    pub fn primary(mut self, span: Span, message: &str) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.to_string(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn label(mut self, span: Span, message: &str) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.to_string(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn help(mut self, message: &str) -> Self {
+        self.help.push(message.to_string());
+        self
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.labels
+            .iter()
+            .find(|label| label.primary)
+            .map(|label| label.span)
+    }
+}
+
+// Errors from the stages that still report a plain string, which carry no location
+impl From<String> for Diagnostic {
+    fn from(error: String) -> Self {
+        let message = error.strip_prefix("[ Error ] ").unwrap_or(&error);
+        Diagnostic::error(message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.level, self.message)
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            e => Err(format!("[ Error ] Unknown colour setting: {}", e)),
+        }
+    }
+}
+
+impl Color {
+    // Whether to colour what is written to stderr
+    pub fn enabled(self) -> bool {
+        match self {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal(),
+        }
+    }
+}
+
+impl<'a> Emitter<'a> {
+    pub fn new(file: &'a str, source: &'a str, color: Color) -> Self {
+        Emitter {
+            file,
+            source,
+            color: color.enabled(),
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let level = diagnostic.level;
+        let mut out = format!(
+            "{}{}",
+            self.paint(level.style(), &level.to_string()),
+            self.paint(BOLD, &format!(": {}", diagnostic.message))
+        );
+        out.push('\n');
+
+        // Labels by line, a line shows every label on it in the order they start
+        let mut labels: Vec<(usize, usize, &Label)> = diagnostic
+            .labels
+            .iter()
+            .map(|label| {
+                let (line, col) = self.locate(label.span.start);
+                (line, col, label)
+            })
+            .collect();
+        labels.sort_by_key(|(line, col, _)| (*line, *col));
+        let width = labels
+            .last()
+            .map_or(0, |(line, _, _)| line.to_string().len());
+        let gutter = " ".repeat(width);
+        let bar = |text: &str| format!("{} {}", gutter, self.paint(BLUE, text));
+
+        if let Some(span) = diagnostic.span() {
+            let (line, col) = self.locate(span.start);
+            out += &format!(
+                "{}{} {}:{}:{}\n",
+                gutter,
+                self.paint(BLUE, "-->"),
+                self.file,
+                line,
+                col
+            );
+            out += &bar("|");
+            out.push('\n');
+        }
+
+        let mut shown: Option<usize> = None;
+        for (line, col, label) in &labels {
+            let text = self.line(*line);
+            if shown != Some(*line) {
+                match shown {
+                    Some(prev) if line - prev == 2 => out += &self.source_line(prev + 1, width),
+                    Some(prev) if line - prev > 2 => {
+                        out += &format!("{}\n", self.paint(BLUE, "..."))
+                    }
+                    _ => (),
+                }
+                out += &self.source_line(*line, width);
+                shown = Some(*line);
+            }
+
+            // Keep tabs so the marks line up with the source however wide the terminal shows them
+            let pad: String = text
+                .chars()
+                .take(col - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let rest = text.chars().count().saturating_sub(col - 1);
+            let length = self.source[label.span.start..label.span.end.min(self.source.len())]
+                .chars()
+                .count()
+                .min(rest)
+                .max(1);
+            let (mark, style) = match label.primary {
+                true => ("^", level.style()),
+                false => ("-", BLUE),
+            };
+            let marks = format!("{} {}", mark.repeat(length), label.message);
+            out += &format!(
+                "{} {}{}\n",
+                bar("|"),
+                pad,
+                self.paint(style, marks.trim_end())
+            );
+        }
+
+        if !diagnostic.help.is_empty() {
+            if !labels.is_empty() {
+                out += &bar("|");
+                out.push('\n');
+            }
+            for help in &diagnostic.help {
+                out += &format!("{} {} {}\n", bar("="), self.paint(BOLD, "help:"), help);
+            }
+        }
+        out
+    }
+
+    // Every diagnostic followed by a blank line, the way rustc separates them
+    pub fn render_all(&self, diagnostics: &[Diagnostic]) -> String {
+        diagnostics
+            .iter()
+            .map(|diagnostic| self.render(diagnostic) + "\n")
+            .collect()
+    }
+
+    // 1-based line and column, in characters, of a byte offset. The end of the source is just past
+    // its last character.
+    pub fn locate(&self, offset: usize) -> (usize, usize) {
+        let before = &self.source[..offset.min(self.source.len())];
+        let line = before.matches('\n').count() + 1;
+        let start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        (line, before[start..].chars().count() + 1)
+    }
+
+    fn line(&self, line: usize) -> &str {
+        self.source.split('\n').nth(line - 1).unwrap_or("")
+    }
+
+    fn source_line(&self, line: usize, width: usize) -> String {
+        let text = self.line(line).trim_end_matches('\r');
+        let number = format!("{:>width$} |", line, width = width);
+        format!("{} {}", self.paint(BLUE, &number), text)
+            .trim_end()
+            .to_string()
+            + "\n"
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{}{}{}", style, text, RESET),
+            false => text.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "CLASS Pgm1 {\nVAR a;\nGET(a;\nPUT(a) }\n";
+
+    #[test]
+    fn test_render_labels() {
+        let emitter = Emitter::new("input.java", SOURCE, Color::Never);
+        let diagnostic = Diagnostic::error("mismatched closing delimiter: `}`")
+            .primary(Span::new(34, 35), "expected `)`")
+            .label(Span::new(23, 24), "opened here")
+            .help("close it with `)`");
+
+        assert_eq!(emitter.locate(34), (4, 8));
+        assert_eq!(emitter.locate(SOURCE.len()), (5, 1));
+        assert_eq!(
+            emitter.render(&diagnostic),
+            "error: mismatched closing delimiter: `}`\n\
+             \x20--> input.java:4:8\n\
+             \x20 |\n\
+             3 | GET(a;\n\
+             \x20 |    - opened here\n\
+             4 | PUT(a) }\n\
+             \x20 |        ^ expected `)`\n\
+             \x20 |\n\
+             \x20 = help: close it with `)`\n"
+        );
+    }
+
+    #[test]
+    fn test_render_lines() {
+        let emitter = Emitter::new("input.java", SOURCE, Color::Never);
+        let diagnostic = Diagnostic::warning("unused variable: `a`")
+            .primary(Span::new(31, 32), "used here")
+            .label(Span::new(6, 10), "in this class");
+
+        // Lines between two labels are skipped, one line between them is shown
+        assert_eq!(
+            emitter.render(&diagnostic),
+            "warning: unused variable: `a`\n\
+             \x20--> input.java:4:5\n\
+             \x20 |\n\
+             1 | CLASS Pgm1 {\n\
+             \x20 |       ---- in this class\n\
+             ...\n\
+             4 | PUT(a) }\n\
+             \x20 |     ^ used here\n"
+        );
+        assert_eq!(
+            emitter.render(&Diagnostic::from(String::from(
+                "[ Error ] Could not write the program"
+            ))),
+            "error: Could not write the program\n"
+        );
+    }
+
+    #[test]
+    fn test_render_color() {
+        let emitter = Emitter::new("input.java", SOURCE, Color::Always);
+        let rendered = emitter.render(&Diagnostic::error("oops").primary(Span::new(0, 5), "here"));
+
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m\x1b[1m: oops\x1b[0m\n"));
+        assert!(rendered.contains("\x1b[1;31m^^^^^ here\x1b[0m"));
+        assert_eq!("never".parse(), Ok(Color::Never));
+        assert_eq!(
+            "blue".parse::<Color>(),
+            Err(String::from("[ Error ] Unknown colour setting: blue"))
+        );
+    }
+}
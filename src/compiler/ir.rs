@@ -0,0 +1,606 @@
+use std::io::{self, Write};
+
+use std::collections::HashMap;
+
+use crate::compiler::backend::Relation;
+use crate::compiler::opt::cfg::{self, Cfg};
+use crate::compiler::opt::ssa::Phi;
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList, MAX_SHIFT};
+
+// Textual three-address IR, so the quads can be dumped after consume_polish or any pass and read
+// back in front of the Generator. One quad per line, the destination comes first:
+//
+//   ; a comment runs to the end of the line, blank lines are skipped
+//   proc @show             PROCEDURE show
+//   L1:                    LABEL L1
+//       add %temp_1, $a, 4  temp_1 = a + 4, also sub mul div shl shr
+//       copy $a, %temp_1    a = temp_1
+//       br.gt $a, 7, @L2   falls through when a > 7 holds, else jumps to L2, also eq ne lt ge le
+//       get $a             GET(a)
+//       put $a             PUT(a), or a "string" with \" \\ \n \t escapes
+//       jmp @L1
+//       call @show
+//       ret
+//
+// Operands are typed by their sigil: `$` a variable, `%` a temp, `@` a label and a bare integer
+// a constant. write and parse are inverses, parse(write(quads)) gives back the same quads and
+// write(parse(text)) gives back text that was written by write.
+//
+// In SSA form names carry a version, `$n.2`, and a block that control flow joins at starts with
+// its phis straight after its label, one argument per predecessor in the order Block::preds has
+// them. Cfg prints this form and parse_ssa reads it back.
+//
+//   L1:
+//       phi $n.2, $n.1, $n.3   n.2 is n.1 coming from the first predecessor, n.3 from the second
+
+pub fn write(quads: &[Quad], out: &mut dyn Write) -> io::Result<()> {
+    for quad in quads {
+        out.write_fmt(format_args!("{}\n", line(quad)))?;
+    }
+    Ok(())
+}
+
+pub fn to_text(quads: &[Quad]) -> String {
+    quads.iter().map(|quad| line(quad) + "\n").collect()
+}
+
+pub fn parse(text: &str) -> Result<QuadList, String> {
+    let mut quads = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let quad = parse_line(line).map_err(|e| format!("[ Error ] Line {}: {}", index + 1, e))?;
+        if let Some(quad) = quad {
+            quads.push(quad);
+        }
+    }
+    Ok(quads)
+}
+
+// Functions in SSA form as Cfg prints them, the phis are given back to the blocks they head
+pub fn parse_ssa(text: &str) -> Result<Vec<Cfg>, String> {
+    let mut quads = Vec::new();
+    let mut phis: HashMap<String, Vec<Phi>> = HashMap::new();
+    // Label of the block whose phis are still being read
+    let mut head: Option<String> = None;
+    for (index, line) in text.lines().enumerate() {
+        let error = |e: String| format!("[ Error ] Line {}: {}", index + 1, e);
+        if split(line).map_err(error)?.first().map(String::as_str) == Some("phi") {
+            let phi = parse_phi(line).map_err(error)?;
+            let label = head.as_ref().ok_or(error(format!(
+                "Phi outside the head of a block: {}",
+                line.trim()
+            )))?;
+            phis.entry(label.clone()).or_default().push(phi);
+            continue;
+        }
+        if let Some(quad) = parse_line(line).map_err(error)? {
+            head = quad
+                .target_name()
+                .filter(|_| quad.op == Opcode::Label)
+                .map(String::from);
+            quads.push(quad);
+        }
+    }
+
+    let mut cfgs = cfg::build(quads);
+    for block in cfgs.iter_mut().flat_map(|cfg| &mut cfg.blocks) {
+        let label = match block.quads.first() {
+            Some(quad) if quad.op == Opcode::Label => quad.target_name().unwrap(),
+            _ => continue,
+        };
+        if let Some(phis) = phis.remove(label) {
+            if let Some(phi) = phis.iter().find(|phi| phi.args.len() != block.preds.len()) {
+                return Err(format!(
+                    "[ Error ] {} has {} arguments, {} has {} predecessors",
+                    phi_line(phi).trim(),
+                    phi.args.len(),
+                    label,
+                    block.preds.len()
+                ));
+            }
+            block.phis = phis;
+        }
+    }
+    Ok(cfgs)
+}
+
+// Whether every operand a quad has is one its opcode takes, the shapes the Generator dispatches on
+pub fn typed(quad: &Quad) -> Result<(), String> {
+    let value = |operand: &Option<Operand>| {
+        matches!(
+            operand,
+            Some(Operand::Var(_) | Operand::Temp(_) | Operand::Const(_))
+        )
+    };
+    let stored =
+        |operand: &Option<Operand>| matches!(operand, Some(Operand::Var(_) | Operand::Temp(_)));
+    let label = |operand: &Option<Operand>| matches!(operand, Some(Operand::Label(_)));
+    let none = |operand: &Option<Operand>| operand.is_none();
+
+    let ok = match quad.op {
+        Opcode::Shl | Opcode::Shr => {
+            value(&quad.left)
+                && matches!(quad.right, Some(Operand::Const(0..=MAX_SHIFT)))
+                && stored(&quad.dest)
+        }
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div => {
+            value(&quad.left) && value(&quad.right) && stored(&quad.dest)
+        }
+        Opcode::Assign => value(&quad.left) && none(&quad.right) && stored(&quad.dest),
+        Opcode::Branch(_) => value(&quad.left) && value(&quad.right) && label(&quad.dest),
+        Opcode::Get => none(&quad.left) && none(&quad.right) && stored(&quad.dest),
+        Opcode::Put => {
+            (value(&quad.left) || matches!(quad.left, Some(Operand::Str(_))))
+                && none(&quad.right)
+                && none(&quad.dest)
+        }
+        Opcode::Label | Opcode::Jump | Opcode::Procedure | Opcode::Call => {
+            none(&quad.left) && none(&quad.right) && label(&quad.dest)
+        }
+        Opcode::Ret => none(&quad.left) && none(&quad.right) && none(&quad.dest),
+    };
+    match ok {
+        true => Ok(()),
+        false => Err(format!(
+            "Operands do not fit {}: {}",
+            mnemonic(quad.op),
+            line(quad).trim()
+        )),
+    }
+}
+
+pub fn line(quad: &Quad) -> String {
+    let operands: Vec<String> = match quad.op {
+        Opcode::Label => return format!("{}:", label_name(&quad.dest)),
+        Opcode::Procedure => return format!("proc {}", field(&quad.dest)),
+        op if op.is_arith() || op == Opcode::Assign || op == Opcode::Get => {
+            [&quad.dest, &quad.left, &quad.right]
+                .into_iter()
+                .filter(|operand| operand.is_some())
+                .map(field)
+                .collect()
+        }
+        _ => [&quad.left, &quad.right, &quad.dest]
+            .into_iter()
+            .filter(|operand| operand.is_some())
+            .map(field)
+            .collect(),
+    };
+    match operands.is_empty() {
+        true => format!("    {}", mnemonic(quad.op)),
+        false => format!("    {} {}", mnemonic(quad.op), operands.join(", ")),
+    }
+}
+
+pub fn phi_line(phi: &Phi) -> String {
+    let operands: Vec<String> = std::iter::once(&phi.dest)
+        .chain(&phi.args)
+        .map(|operand| field(&Some(operand.clone())))
+        .collect();
+    format!("    phi {}", operands.join(", "))
+}
+
+fn label_name(operand: &Option<Operand>) -> String {
+    match operand {
+        Some(Operand::Label(name)) => name.clone(),
+        _ => field(operand),
+    }
+}
+
+fn field(operand: &Option<Operand>) -> String {
+    match operand {
+        Some(Operand::Var(name)) => format!("${}", name),
+        Some(Operand::Temp(name)) => format!("%{}", name),
+        Some(Operand::Label(name)) => format!("@{}", name),
+        Some(Operand::Const(value)) => value.to_string(),
+        Some(Operand::Str(string)) => {
+            let escaped = string
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\t', "\\t");
+            format!("\"{}\"", escaped)
+        }
+        None => String::from("_"),
+    }
+}
+
+fn mnemonic(op: Opcode) -> &'static str {
+    match op {
+        Opcode::Add => "add",
+        Opcode::Sub => "sub",
+        Opcode::Mul => "mul",
+        Opcode::Div => "div",
+        Opcode::Shl => "shl",
+        Opcode::Shr => "shr",
+        Opcode::Assign => "copy",
+        Opcode::Branch(Relation::Equal) => "br.eq",
+        Opcode::Branch(Relation::NEqual) => "br.ne",
+        Opcode::Branch(Relation::GreaterThan) => "br.gt",
+        Opcode::Branch(Relation::LessThan) => "br.lt",
+        Opcode::Branch(Relation::GEqual) => "br.ge",
+        Opcode::Branch(Relation::LEqual) => "br.le",
+        Opcode::Get => "get",
+        Opcode::Put => "put",
+        Opcode::Label => "label",
+        Opcode::Jump => "jmp",
+        Opcode::Procedure => "proc",
+        Opcode::Ret => "ret",
+        Opcode::Call => "call",
+    }
+}
+
+fn opcode(mnemonic: &str) -> Option<Opcode> {
+    match mnemonic {
+        "add" => Some(Opcode::Add),
+        "sub" => Some(Opcode::Sub),
+        "mul" => Some(Opcode::Mul),
+        "div" => Some(Opcode::Div),
+        "shl" => Some(Opcode::Shl),
+        "shr" => Some(Opcode::Shr),
+        "copy" => Some(Opcode::Assign),
+        "br.eq" => Some(Opcode::Branch(Relation::Equal)),
+        "br.ne" => Some(Opcode::Branch(Relation::NEqual)),
+        "br.gt" => Some(Opcode::Branch(Relation::GreaterThan)),
+        "br.lt" => Some(Opcode::Branch(Relation::LessThan)),
+        "br.ge" => Some(Opcode::Branch(Relation::GEqual)),
+        "br.le" => Some(Opcode::Branch(Relation::LEqual)),
+        "get" => Some(Opcode::Get),
+        "put" => Some(Opcode::Put),
+        "jmp" => Some(Opcode::Jump),
+        "proc" => Some(Opcode::Procedure),
+        "ret" => Some(Opcode::Ret),
+        "call" => Some(Opcode::Call),
+        _ => None,
+    }
+}
+
+// The quad on one line, None for a line with only a comment or whitespace
+fn parse_line(line: &str) -> Result<Option<Quad>, String> {
+    let fields = split(line)?;
+    let Some((first, rest)) = fields.split_first() else {
+        return Ok(None);
+    };
+    if let Some(name) = first.strip_suffix(':') {
+        if !rest.is_empty() || !is_name(name) {
+            return Err(format!("Bad label: {}", line.trim()));
+        }
+        return Ok(Some(Quad::label(name)));
+    }
+    let op = opcode(first).ok_or(format!("Unknown opcode: {}", first))?;
+    let mut operands = rest
+        .iter()
+        .map(|text| operand(text).map(Some))
+        .collect::<Result<Vec<_>, _>>()?;
+    let arity = match op {
+        op if op.is_arith() || matches!(op, Opcode::Branch(_)) => 3,
+        Opcode::Assign => 2,
+        Opcode::Ret => 0,
+        _ => 1,
+    };
+    if operands.len() != arity {
+        return Err(format!(
+            "{} takes {} operands, found {}",
+            first,
+            arity,
+            operands.len()
+        ));
+    }
+    operands.resize(3, None);
+    let [a, b, c]: [Option<Operand>; 3] = operands.try_into().unwrap();
+    let (left, right, dest) = match op {
+        op if op.is_arith() || op == Opcode::Assign || op == Opcode::Get => (b, c, a),
+        Opcode::Branch(_) => (a, b, c),
+        Opcode::Put => (a, None, None),
+        _ => (None, None, a),
+    };
+    let quad = Quad {
+        op,
+        left,
+        right,
+        dest,
+    };
+    typed(&quad)?;
+    Ok(Some(quad))
+}
+
+fn parse_phi(line: &str) -> Result<Phi, String> {
+    let fields = split(line)?;
+    let operands = fields[1..]
+        .iter()
+        .map(|text| operand(text))
+        .collect::<Result<Vec<_>, _>>()?;
+    match operands.split_first() {
+        Some((dest @ (Operand::Var(_) | Operand::Temp(_)), args))
+            if !args.is_empty()
+                && args.iter().all(|arg| {
+                    matches!(arg, Operand::Var(_) | Operand::Temp(_) | Operand::Const(_))
+                }) =>
+        {
+            Ok(Phi {
+                dest: dest.clone(),
+                args: args.to_vec(),
+            })
+        }
+        _ => Err(format!("Operands do not fit phi: {}", line.trim())),
+    }
+}
+
+// Mnemonic and comma separated operands of a line, without its comment
+fn split(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars();
+    let mut quoted = false;
+    // Whitespace ended the current field, only a comma may follow
+    let mut ended = false;
+    // A comma was seen and the operand after it is still to come
+    let mut pending = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                field.push(c);
+                field.push(chars.next().ok_or("Unterminated string")?);
+            }
+            '"' if quoted => {
+                quoted = false;
+                field.push(c);
+            }
+            _ if quoted => field.push(c),
+            ';' => break,
+            ',' if !fields.is_empty() => {
+                fields.push(std::mem::take(&mut field));
+                ended = false;
+                pending = true;
+            }
+            c if c.is_whitespace() => {
+                if fields.is_empty() && !field.is_empty() {
+                    fields.push(std::mem::take(&mut field));
+                } else if !field.is_empty() {
+                    ended = true;
+                }
+            }
+            _ if ended => return Err(format!("Missing comma: {}", line.trim())),
+            c => {
+                quoted = c == '"';
+                pending = false;
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(String::from("Unterminated string"));
+    }
+    if !field.is_empty() || pending {
+        fields.push(field);
+    }
+    Ok(fields)
+}
+
+fn operand(text: &str) -> Result<Operand, String> {
+    let named = |name: &str, make: fn(String) -> Operand| match is_name(name) {
+        true => Ok(make(name.to_string())),
+        false => Err(format!("Bad operand: {}", text)),
+    };
+    match text.chars().next() {
+        Some('$') => named(&text[1..], Operand::Var),
+        Some('%') => named(&text[1..], Operand::Temp),
+        Some('@') => named(&text[1..], Operand::Label),
+        Some('"') if text.len() > 1 && text.ends_with('"') => unescape(&text[1..text.len() - 1]),
+        _ => text
+            .parse::<i32>()
+            .map(Operand::Const)
+            .map_err(|_| format!("Bad operand: {}", text)),
+    }
+}
+
+fn unescape(text: &str) -> Result<Operand, String> {
+    let mut string = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some(c @ ('"' | '\\')) => string.push(c),
+                _ => return Err(format!("Bad escape in: \"{}\"", text)),
+            },
+            '"' => return Err(format!("Bad string: \"{}\"", text)),
+            c => string.push(c),
+        }
+    }
+    Ok(Operand::Str(string))
+}
+
+// Identifiers, temps and the `name.N` versions of SSA
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::riscv::RiscV;
+    use crate::compiler::backend::sample;
+    use crate::compiler::codegen::Generator;
+    use crate::compiler::opt::ssa;
+
+    #[test]
+    fn test_ir_round_trip() {
+        let quads = sample::quads();
+        let text = to_text(&quads);
+
+        assert_eq!(parse(&text).unwrap(), quads);
+        assert_eq!(to_text(&parse(&text).unwrap()), text);
+        let mut out = Vec::new();
+        write(&quads, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), text);
+
+        // Reloaded quads generate the same code as the ones dumped
+        let mut gen = Generator::with_writer(
+            parse(&text).unwrap(),
+            sample::symbols(),
+            Box::new(RiscV::new()),
+            Vec::new(),
+        );
+        gen.consume_quads().unwrap();
+        assert_eq!(
+            String::from_utf8(gen.into_inner()).unwrap(),
+            include_str!("backend/golden/sample.s")
+        );
+    }
+
+    #[test]
+    fn test_ir_parse() {
+        let text = "; GET(a); WHILE a > 0 { a = a - 1; } PUT(\"done; \\\"ok\\\"\\n\");\n\
+                    \n\
+                    proc @show\n\
+                    \x20   put $a\n\
+                    \x20   ret\n\
+                    \tget $a   ; read a\n\
+                    L1:\n\
+                    \x20   br.gt $a,0 , @L2\n\
+                    \x20   sub %temp_1, $a, 1\n\
+                    \x20   copy $a, %temp_1\n\
+                    \x20   shr %temp_2, $a, 2\n\
+                    \x20   mul %temp_3, $a, -2\n\
+                    \x20   jmp @L1\n\
+                    L2:\n\
+                    \x20   call @show\n\
+                    \x20   put \"done; \\\"ok\\\"\\n\"\n";
+        let quads = parse(text).unwrap();
+
+        assert_eq!(
+            quads,
+            vec![
+                Quad::procedure("show"),
+                Quad::put(Operand::var("a")),
+                Quad::ret(),
+                Quad::get(Operand::var("a")),
+                Quad::label("L1"),
+                Quad::branch(
+                    Relation::GreaterThan,
+                    Operand::var("a"),
+                    Operand::Const(0),
+                    "L2"
+                ),
+                Quad::binary(
+                    Opcode::Sub,
+                    Operand::var("a"),
+                    Operand::Const(1),
+                    Operand::temp(1)
+                ),
+                Quad::assign(Operand::temp(1), Operand::var("a")),
+                Quad::binary(
+                    Opcode::Shr,
+                    Operand::var("a"),
+                    Operand::Const(2),
+                    Operand::temp(2)
+                ),
+                Quad::binary(
+                    Opcode::Mul,
+                    Operand::var("a"),
+                    Operand::Const(-2),
+                    Operand::temp(3)
+                ),
+                Quad::jump("L1"),
+                Quad::label("L2"),
+                Quad::call("show"),
+                Quad::put(Operand::Str(String::from("done; \"ok\"\n"))),
+            ]
+        );
+        // Comments and spacing are not kept, the rest comes back as written
+        assert_eq!(
+            to_text(&quads[3..6]),
+            "    get $a\nL1:\n    br.gt $a, 0, @L2\n"
+        );
+    }
+
+    #[test]
+    fn test_ir_errors() {
+        let error = |text: &str| parse(text).unwrap_err();
+
+        assert_eq!(
+            error("    get $a\n    mov $a, 1\n"),
+            "[ Error ] Line 2: Unknown opcode: mov"
+        );
+        assert_eq!(
+            error("add %temp_1, $a"),
+            "[ Error ] Line 1: add takes 3 operands, found 2"
+        );
+        assert_eq!(
+            error("copy 4, $a"),
+            "[ Error ] Line 1: Operands do not fit copy: copy 4, $a"
+        );
+        assert_eq!(
+            error("shl %temp_1, $a, $b"),
+            "[ Error ] Line 1: Operands do not fit shl: shl %temp_1, $a, $b"
+        );
+        // A shift moves by 0 to 15 bits
+        assert_eq!(
+            error("shr %temp_1, $a, -2"),
+            "[ Error ] Line 1: Operands do not fit shr: shr %temp_1, $a, -2"
+        );
+        assert_eq!(
+            error("shl %temp_1, $a, 16"),
+            "[ Error ] Line 1: Operands do not fit shl: shl %temp_1, $a, 16"
+        );
+        assert!(parse("shl %temp_1, $a, 15").is_ok());
+        assert_eq!(error("jmp L1"), "[ Error ] Line 1: Bad operand: L1");
+        assert_eq!(error("put $"), "[ Error ] Line 1: Bad operand: $");
+        assert_eq!(error("add %temp_1, $a,"), "[ Error ] Line 1: Bad operand: ");
+        assert_eq!(
+            error("copy $a $b"),
+            "[ Error ] Line 1: Missing comma: copy $a $b"
+        );
+        assert_eq!(error("L1: ret"), "[ Error ] Line 1: Bad label: L1: ret");
+        assert_eq!(error("put \"a"), "[ Error ] Line 1: Unterminated string");
+    }
+
+    #[test]
+    fn test_ir_ssa() {
+        // GET(x); IF x > 0 { x = 1; } PUT(x); and the sample, which has a procedure
+        let text = "; main\n\
+                    \x20   get $x.1\n\
+                    \x20   br.gt $x.1, 0, @L1\n\
+                    \x20   copy $x.2, 1\n\
+                    L1:\n\
+                    \x20   phi $x.3, $x.1, $x.2\n\
+                    \x20   put $x.3\n";
+        let cfgs = parse_ssa(text).unwrap();
+        let join = &cfgs[0].blocks[3];
+        assert_eq!(join.preds, [1, 2]);
+        assert_eq!(
+            join.phis,
+            [Phi {
+                dest: Operand::var("x.3"),
+                args: vec![Operand::var("x.1"), Operand::var("x.2")],
+            }]
+        );
+        assert_eq!(parse_ssa(&cfgs[0].to_string()), Ok(cfgs));
+
+        let ssa = ssa::to_ssa(sample::quads());
+        let text: String = ssa.iter().map(|cfg| cfg.to_string()).collect();
+        assert_eq!(parse_ssa(&text), Ok(ssa));
+
+        let error = |text: &str| parse_ssa(text).unwrap_err();
+        assert_eq!(
+            error("    get $x\n    phi $x.2, $x.1\n"),
+            "[ Error ] Line 2: Phi outside the head of a block: phi $x.2, $x.1"
+        );
+        assert_eq!(
+            error("L1:\n    phi $x.2, @L1\n"),
+            "[ Error ] Line 2: Operands do not fit phi: phi $x.2, @L1"
+        );
+        assert_eq!(
+            error("L1:\n    phi $x.2, $x.1, $x.3\n    put $x.2\n"),
+            "[ Error ] phi $x.2, $x.1, $x.3 has 2 arguments, L1 has 1 predecessors"
+        );
+        assert_eq!(
+            parse("phi $x.2, $x.1"),
+            Err(String::from("[ Error ] Line 1: Unknown opcode: phi"))
+        );
+    }
+}
@@ -1,10 +1,9 @@
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, Write};
+use std::fs;
+use std::io;
 use std::iter::Peekable;
-use std::path::Path;
 use std::vec::IntoIter;
 
-use crate::compiler::syntax::Syntax;
+use crate::compiler::diagnostics::{Diagnostic, Span};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Terminal {
@@ -55,6 +54,7 @@ pub enum TokenClass {
     Term,
     Mop,
     Fac,
+    Label,
     Unknown,
 }
 
@@ -62,6 +62,8 @@ pub enum TokenClass {
 pub struct Token {
     pub name: String,
     pub class: TokenClass,
+    // Where the token was read from, empty for one made up by a later stage
+    pub span: Span,
 }
 
 impl From<&str> for TokenClass {
@@ -81,8 +83,13 @@ impl From<&str> for TokenClass {
 
 pub struct Tokenize {
     pub characters: Peekable<IntoIter<char>>,
+    // Byte offset of the next character
+    pub offset: usize,
 }
 
+const SCANNER_FSA: &str = include_str!("fsa_tables/scanner_fsa");
+const SYMBOL_FSA: &str = include_str!("fsa_tables/symbol_fsa");
+
 const RESERVED_WORDS: [&str; 12] = [
     "CONST",
     "IF",
@@ -99,10 +106,19 @@ const RESERVED_WORDS: [&str; 12] = [
 ];
 
 impl Token {
+    pub fn new(name: &str, class: TokenClass) -> Self {
+        Token {
+            name: name.to_string(),
+            class,
+            span: Span::default(),
+        }
+    }
+
     pub fn empty() -> Self {
         Token {
             name: String::from("Empty"),
             class: TokenClass::Unknown,
+            span: Span::default(),
         }
     }
 
@@ -110,61 +126,37 @@ impl Token {
         Token {
             name: String::from("Terminator"),
             class: TokenClass::Delimiter,
+            span: Span::default(),
         }
     }
-
-    pub fn temp_gen(id: i32) -> Self {
-        let string = String::from("temp") + &id.to_string();
-        let token = Token {
-            name: string,
-            class: TokenClass::Identifier,
-        };
-        Syntax::token_to_table(&token.name, "Temp", &0, &0);
-
-        token
-    }
 }
 
 impl Tokenize {
-    pub fn create_scanner(filename: &str) -> io::Result<Self> {
-        let contents = &fs::read_to_string(filename)
-            .expect("[ ERROR ] Something went wrong reading the file]");
-
-        Ok(Tokenize {
-            characters: contents.chars().collect::<Vec<_>>().into_iter().peekable(),
-        })
-    }
-
-    pub fn token_to_file(token: Token) {
-        // let file_res: io::Result<File>;
-        let path = Path::new("tokens");
-        if !path.exists() {
-            File::create("tokens").expect("[ Error ] Something went wrong creating file.");
+    pub fn new(source: &str) -> Self {
+        Tokenize {
+            characters: source.chars().collect::<Vec<_>>().into_iter().peekable(),
+            offset: 0,
         }
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open("tokens")
-            .unwrap();
+    pub fn create_scanner(filename: &str) -> io::Result<Self> {
+        let contents = &fs::read_to_string(filename)?;
 
-        if let Err(e) = writeln!(file, "{} {:?}", token.name, token.class) {
-            eprintln!("{}, could not write to file.", e);
-        }
+        Ok(Tokenize::new(contents))
     }
 
-    // Using a predefined state table located in a file to perform row col look up
+    // Using a predefined state table built into the binary to perform row col look up
     // determining our current state
     pub fn table_lookup(state: usize, col: usize, fsa: &str) -> usize {
-        let table = fs::File::open("src/compiler/".to_owned() + fsa)
-            .expect("[ ERROR ] Something went wrong reading the file.");
+        let table = match fsa {
+            "fsa_tables/scanner_fsa" => SCANNER_FSA,
+            "fsa_tables/symbol_fsa" => SYMBOL_FSA,
+            e => panic!("[ Error ] No such state table: {}", e),
+        };
 
-        let file_reader = io::BufReader::new(table).lines().nth(state).unwrap();
-        if let Ok(line) = file_reader {
+        if let Some(line) = table.lines().nth(state) {
             return line
                 .split_whitespace()
-                .collect::<Vec<&str>>()
-                .into_iter()
                 .nth(col)
                 .unwrap()
                 .parse::<usize>()
@@ -174,6 +166,17 @@ impl Tokenize {
     }
 }
 
+// The scanner has no column for the character, so it can neither start nor continue a token
+pub fn unexpected(token: &Token) -> Diagnostic {
+    let diagnostic = Diagnostic::error(&format!("unexpected character `{}`", token.name))
+        .primary(token.span, "not part of any token");
+    match token.name.as_str() {
+        "\"" => diagnostic.help("string literals are not supported"),
+        "<" | ">" | "!" => diagnostic.help("the only operators are +, -, *, / and ="),
+        _ => diagnostic,
+    }
+}
+
 // NOTE: The great thing about the From<T> for U trait is that we get the opposite type conversion "for
 // free" as well. IE Into<U> for T
 impl From<TokenClass> for usize {
@@ -217,7 +220,7 @@ impl From<&char> for Terminal {
         match ch {
             c if c.is_alphabetic() => Terminal::Letter,
 
-            c if c.is_digit(10) => Terminal::Digit,
+            c if c.is_ascii_digit() => Terminal::Digit,
 
             character if character.is_whitespace() => Terminal::Whitespace,
 
@@ -248,6 +251,7 @@ impl Iterator for Tokenize {
         let mut token = Token {
             name: String::from(""),
             class: TokenClass::Unknown,
+            span: Span::default(),
         };
 
         let mut curr_state: usize = 0;
@@ -263,8 +267,24 @@ impl Iterator for Tokenize {
                 return None;
             }
 
-            // Check what terminal we have
-            let terminal = Terminal::from(&character);
+            // A token starts at the first character pushed to its name, whitespace and comments
+            // before it are cleared
+            if token.name.is_empty() {
+                token.span.start = self.offset;
+            }
+            self.offset += character.len_utf8();
+
+            // Check what terminal we have, the scanner has no column for an unknown character so
+            // outside a comment it becomes an Unknown token for the caller to report
+            let terminal = match Terminal::from(&character) {
+                Terminal::Unknown if curr_state == 14 || curr_state == 15 => Terminal::Letter,
+                Terminal::Unknown => {
+                    token.name.push(character);
+                    token.span.end = self.offset;
+                    return Some(token);
+                }
+                terminal => terminal,
+            };
 
             curr_state =
                 Tokenize::table_lookup(curr_state, usize::from(terminal), "fsa_tables/scanner_fsa");
@@ -277,16 +297,15 @@ impl Iterator for Tokenize {
                 1 => {
                     token.name.push(character);
 
-                    // Handling the case where we find a delimiter after a letter
-                    let peeked = self.characters.peek().unwrap();
+                    // Handling the case where we find a delimiter after a letter or digit, or the
+                    // end of the input
+                    let peeked = self.characters.peek().unwrap_or(&' ');
                     match Terminal::from(peeked) {
                         Terminal::Letter => continue,
                         Terminal::Digit => continue,
                         _ => {
-                            if terminal == Terminal::Letter {
-                                token.class = TokenClass::Identifier;
-                                break;
-                            }
+                            token.class = TokenClass::Identifier;
+                            break;
                         }
                     }
                 }
@@ -294,8 +313,8 @@ impl Iterator for Tokenize {
                 3 => {
                     token.name.push(character);
 
-                    // Handling the case where we find a delimiter after a digit
-                    let peeked = self.characters.peek().unwrap();
+                    // Handling the case where we find a delimiter after a digit, or the end of the input
+                    let peeked = self.characters.peek().unwrap_or(&' ');
                     match Terminal::from(peeked) {
                         Terminal::Letter => continue,
                         Terminal::Digit => continue,
@@ -405,6 +424,7 @@ impl Iterator for Tokenize {
         if RESERVED_WORDS.contains(&token.name.as_str()) {
             token.class = TokenClass::ReservedWord;
         }
+        token.span.end = token.span.start + token.name.len();
 
         // Send out token wrapped in option. Will return None to detonte end of Iter
         Some(token)
@@ -1,5 +1,12 @@
+pub mod backend;
+pub mod bytecode;
 pub mod codegen;
+pub mod diagnostics;
+pub mod ir;
 pub mod lexical;
+pub mod opt;
 pub mod precedence;
+pub mod semantic;
 pub mod syntax;
 pub mod tableindex;
+pub mod vm;
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::compiler::ir;
+use crate::compiler::opt::ssa::Phi;
+use crate::compiler::syntax::{Opcode, Quad, QuadList};
+
+// Index of the empty block every function starts in, its exit is the last block
+pub const ENTRY: usize = 0;
+
+// Control flow graph of one function, the main program or a PROCEDURE up to its RET. Blocks
+// start at a LABEL or after a jump, branch or RET, and end at the next one. CALL returns to the
+// quad after it, so it does not end a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    // Procedure name, None for the main program
+    pub name: Option<String>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Block {
+    // Only filled in while the function is in SSA form, one argument per predecessor
+    pub phis: Vec<Phi>,
+    pub quads: QuadList,
+    pub succs: Vec<usize>,
+    pub preds: Vec<usize>,
+}
+
+// Label a quad jumps to, and whether it can also fall through to the next quad
+fn jump(quad: &Quad) -> Option<(&str, bool)> {
+    match quad.op {
+        Opcode::Branch(_) => Some((quad.target_name()?, true)),
+        Opcode::Jump => Some((quad.target_name()?, false)),
+        _ => None,
+    }
+}
+
+// One graph per function, procedures in the order they appear and then the main program
+pub fn build(quads: QuadList) -> Vec<Cfg> {
+    let mut cfgs = Vec::new();
+    let mut main = Vec::new();
+    let mut procedure: Option<(String, QuadList)> = None;
+    for quad in quads {
+        match procedure.as_mut() {
+            Some((_, body)) => {
+                let ends = quad.op == Opcode::Ret;
+                body.push(quad);
+                if ends {
+                    let (name, body) = procedure.take().unwrap();
+                    cfgs.push(Cfg::new(Some(name), body));
+                }
+            }
+            None if quad.op == Opcode::Procedure => {
+                procedure = Some((quad.target_name().unwrap().to_string(), vec![quad]));
+            }
+            None => main.push(quad),
+        }
+    }
+    if let Some((name, body)) = procedure {
+        panic!(
+            "[ Error ] Procedure {} has no RET: {} quads",
+            name,
+            body.len()
+        );
+    }
+    cfgs.push(Cfg::new(None, main));
+    cfgs
+}
+
+// Quads back in the order build split them, the inverse of build
+pub fn flatten(cfgs: Vec<Cfg>) -> QuadList {
+    cfgs.into_iter().flat_map(Cfg::into_quads).collect()
+}
+
+// Every function as a cluster of one Graphviz digraph
+pub fn write_dot(cfgs: &[Cfg], out: &mut dyn Write) -> io::Result<()> {
+    out.write_fmt(format_args!(
+        "digraph quads {{\n\tnode [shape=box, fontname=monospace];\n"
+    ))?;
+    for (index, cfg) in cfgs.iter().enumerate() {
+        cfg.write_cluster(index, out)?;
+    }
+    out.write_fmt(format_args!("}}\n"))
+}
+
+impl Cfg {
+    pub fn new(name: Option<String>, quads: QuadList) -> Self {
+        let mut blocks = vec![Block::default()];
+        let mut block = Block::default();
+        for quad in quads {
+            if quad.op == Opcode::Label && !block.quads.is_empty() {
+                blocks.push(std::mem::take(&mut block));
+            }
+            let ends = jump(&quad).is_some() || quad.op == Opcode::Ret;
+            block.quads.push(quad);
+            if ends {
+                blocks.push(std::mem::take(&mut block));
+            }
+        }
+        if !block.quads.is_empty() {
+            blocks.push(block);
+        }
+        blocks.push(Block::default());
+
+        let exit = blocks.len() - 1;
+        let labels: HashMap<String, usize> = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, block)| match block.quads.first() {
+                Some(quad) if quad.op == Opcode::Label => {
+                    Some((quad.target_name().unwrap().to_string(), index))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut edges = vec![(ENTRY, 1)];
+        for (index, block) in blocks.iter().enumerate().take(exit).skip(1) {
+            let last = block.quads.last().unwrap();
+            let (target, falls) = match jump(last) {
+                Some((label, falls)) => match labels.get(label) {
+                    Some(&target) => (Some(target), falls),
+                    None => panic!("[ Error ] Jump to undefined label: {}", label),
+                },
+                None => (None, last.op != Opcode::Ret),
+            };
+            // The last block falls through to the exit
+            if falls {
+                edges.push((index, index + 1));
+            }
+            if let Some(target) = target {
+                edges.push((index, target));
+            }
+            if last.op == Opcode::Ret {
+                edges.push((index, exit));
+            }
+        }
+        for (from, to) in edges {
+            if !blocks[from].succs.contains(&to) {
+                blocks[from].succs.push(to);
+                blocks[to].preds.push(from);
+            }
+        }
+
+        Cfg { name, blocks }
+    }
+
+    pub fn exit(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    pub fn into_quads(self) -> QuadList {
+        self.blocks
+            .into_iter()
+            .flat_map(|block| block.quads)
+            .collect()
+    }
+
+    fn write_cluster(&self, index: usize, out: &mut dyn Write) -> io::Result<()> {
+        let name = self.name.as_deref().unwrap_or("main");
+        out.write_fmt(format_args!(
+            "\tsubgraph cluster_{} {{\n\t\tlabel=\"{}\";\n",
+            index,
+            escape(name)
+        ))?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            let label = match id {
+                ENTRY => String::from("ENTRY"),
+                _ if id == self.exit() => String::from("EXIT"),
+                _ => block
+                    .lines()
+                    .iter()
+                    .fold(format!("B{}\\l", id), |label, line| {
+                        label + &escape(line.trim()) + "\\l"
+                    }),
+            };
+            let shape = match id {
+                _ if block.quads.is_empty() => ", shape=oval",
+                _ => "",
+            };
+            out.write_fmt(format_args!(
+                "\t\tf{}b{} [label=\"{}\"{}];\n",
+                index, id, label, shape
+            ))?;
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for succ in &block.succs {
+                out.write_fmt(format_args!(
+                    "\t\tf{}b{} -> f{}b{};\n",
+                    index, id, index, succ
+                ))?;
+            }
+        }
+        out.write_fmt(format_args!("\t}}\n"))
+    }
+}
+
+impl Block {
+    // The block in the textual format of ir, its phis come straight after its label
+    pub fn lines(&self) -> Vec<String> {
+        let split = match self.quads.first() {
+            Some(quad) if quad.op == Opcode::Label => 1,
+            _ => 0,
+        };
+        let (label, rest) = self.quads.split_at(split);
+        label
+            .iter()
+            .map(ir::line)
+            .chain(self.phis.iter().map(ir::phi_line))
+            .chain(rest.iter().map(ir::line))
+            .collect()
+    }
+}
+
+// Text dump of the blocks that ir::parse_ssa reads back, the function and block names are comments
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "; {}", self.name.as_deref().unwrap_or("main"))?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "; B{}", id)?;
+            for line in block.lines() {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::syntax::Operand;
+
+    #[test]
+    fn test_cfg_sample() {
+        let cfgs = build(sample::quads());
+
+        assert_eq!(cfgs.len(), 2);
+        let show = &cfgs[0];
+        assert_eq!(show.name.as_deref(), Some("show"));
+        assert_eq!(show.blocks.len(), 3);
+        assert_eq!(show.blocks[1].quads.len(), 6);
+        assert_eq!(show.blocks[1].succs, [2]);
+
+        // GET GET | L1 branch | loop body JMP | L2 branch | CALL | L3 / PUT
+        let main = &cfgs[1];
+        assert_eq!(main.name, None);
+        let sizes: Vec<usize> = main.blocks.iter().map(|block| block.quads.len()).collect();
+        assert_eq!(sizes, [0, 2, 2, 5, 2, 1, 3, 0]);
+        let succs: Vec<&[usize]> = main.blocks.iter().map(|block| &block.succs[..]).collect();
+        assert_eq!(
+            succs,
+            [&[1][..], &[2], &[3, 4], &[2], &[5, 6], &[6], &[7], &[]]
+        );
+        let preds: Vec<&[usize]> = main.blocks.iter().map(|block| &block.preds[..]).collect();
+        assert_eq!(
+            preds,
+            [&[][..], &[0], &[1, 3], &[2], &[2], &[4], &[4, 5], &[6]]
+        );
+        assert_eq!(main.exit(), 7);
+
+        assert_eq!(flatten(cfgs), sample::quads());
+    }
+
+    #[test]
+    fn test_cfg_dot() {
+        // PUT(a); JMP L1; PUT(b); LABEL L1;
+        let quads = vec![
+            Quad::put(Operand::var("a")),
+            Quad::jump("L1"),
+            Quad::put(Operand::var("b")),
+            Quad::label("L1"),
+        ];
+        let cfgs = build(quads);
+
+        // The PUT after the jump is unreachable, it still gets a block
+        assert_eq!(cfgs[0].blocks[2].preds, []);
+        let mut dot = Vec::new();
+        write_dot(&cfgs, &mut dot).unwrap();
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph quads {\n\
+             \tnode [shape=box, fontname=monospace];\n\
+             \tsubgraph cluster_0 {\n\
+             \t\tlabel=\"main\";\n\
+             \t\tf0b0 [label=\"ENTRY\", shape=oval];\n\
+             \t\tf0b1 [label=\"B1\\lput $a\\ljmp @L1\\l\"];\n\
+             \t\tf0b2 [label=\"B2\\lput $b\\l\"];\n\
+             \t\tf0b3 [label=\"B3\\lL1:\\l\"];\n\
+             \t\tf0b4 [label=\"EXIT\", shape=oval];\n\
+             \t\tf0b0 -> f0b1;\n\
+             \t\tf0b1 -> f0b3;\n\
+             \t\tf0b2 -> f0b3;\n\
+             \t\tf0b3 -> f0b4;\n\
+             \t}\n\
+             }\n"
+        );
+    }
+}
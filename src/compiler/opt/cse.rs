@@ -0,0 +1,476 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::backend::Relation;
+use crate::compiler::opt::{reads, temps};
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList};
+
+// Common subexpression elimination over a DAG per basic block.
+//
+// Leaves are literals and the value a variable holds in memory, interior nodes are operations
+// on other nodes, with `+` and `*` looked up in either operand order. A temp names the node
+// computing it, as consume_polish writes every temp once. Assigning or reading into a variable
+// kills its leaf, later reads of the variable get a new one, so an expression over the old value
+// is never merged with one over the new. The quads are regenerated in statement order with each
+// node computed once, into the first temp that named it, right before its first use. A node still
+// needing a killed leaf is computed before the write, unless it also reads a leaf made after it, in
+// which case only its operands over the old value are.
+pub fn cse(quads: QuadList) -> QuadList {
+    let temps = temps(&quads);
+    let blocks = blocks(quads);
+
+    // Which blocks read each name, a temp another block reads has to be stored under its name
+    let mut readers: HashMap<String, HashSet<usize>> = HashMap::new();
+    for (index, block) in blocks.iter().enumerate() {
+        for operand in block.iter().flat_map(reads) {
+            readers
+                .entry(operand.to_string())
+                .or_default()
+                .insert(index);
+        }
+    }
+
+    let mut out = Vec::new();
+    for (index, block) in blocks.into_iter().enumerate() {
+        let live_out: HashSet<String> = readers
+            .iter()
+            .filter(|(_, blocks)| blocks.iter().any(|&reader| reader != index))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut dag = Dag::new(&temps);
+        for quad in block {
+            dag.add(quad);
+        }
+        out.extend(dag.finish(&live_out));
+    }
+    out
+}
+
+// Splits before LABEL and PROCEDURE and after anything that jumps
+fn blocks(quads: QuadList) -> Vec<QuadList> {
+    let mut blocks = Vec::new();
+    let mut block = Vec::new();
+    for quad in quads {
+        let starts = matches!(quad.op, Opcode::Label | Opcode::Procedure);
+        if starts && !block.is_empty() {
+            blocks.push(std::mem::take(&mut block));
+        }
+        let ends = is_terminator(&quad);
+        block.push(quad);
+        if ends {
+            blocks.push(std::mem::take(&mut block));
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn is_terminator(quad: &Quad) -> bool {
+    matches!(
+        quad.op,
+        Opcode::Branch(_) | Opcode::Jump | Opcode::Call | Opcode::Ret
+    )
+}
+
+enum Kind {
+    Leaf(Operand),
+    Op(Opcode, usize, usize),
+}
+
+struct Node {
+    kind: Kind,
+    // Temps naming this node, the first one holds it once computed
+    temps: Vec<String>,
+    value: Option<Operand>,
+    // Statement a variable's leaf was read after, its value only exists from there on
+    birth: usize,
+}
+
+#[derive(Clone)]
+enum Stmt {
+    // `var = node`, with the leaf the assignment kills
+    Assign(Operand, usize, Option<usize>),
+    Get(Operand, Option<usize>),
+    Put(usize),
+    Branch(Relation, usize, usize, String),
+    Other(Quad),
+}
+
+struct Dag<'a> {
+    temps: &'a HashSet<String>,
+    nodes: Vec<Node>,
+    // Current leaf of each variable and literal
+    leaves: HashMap<Operand, usize>,
+    // Node each temp defined in the block names
+    names: HashMap<String, usize>,
+    ops: HashMap<(Opcode, usize, usize), usize>,
+    stmts: Vec<Stmt>,
+    // Temps read before the block defines them, the next pass through the block needs them
+    carried: HashSet<String>,
+    out: QuadList,
+}
+
+impl<'a> Dag<'a> {
+    fn new(temps: &'a HashSet<String>) -> Self {
+        Dag {
+            temps,
+            nodes: Vec::new(),
+            leaves: HashMap::new(),
+            names: HashMap::new(),
+            ops: HashMap::new(),
+            stmts: Vec::new(),
+            carried: HashSet::new(),
+            out: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, kind: Kind) -> usize {
+        let birth = match kind {
+            Kind::Leaf(Operand::Var(_)) => self.stmts.len(),
+            _ => 0,
+        };
+        self.nodes.push(Node {
+            kind,
+            temps: Vec::new(),
+            value: None,
+            birth,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn node(&mut self, operand: &Operand) -> usize {
+        let name = operand.to_string();
+        if let Some(&node) = self.names.get(&name) {
+            return node;
+        }
+        if let Some(&node) = self.leaves.get(operand) {
+            return node;
+        }
+        if self.temps.contains(&name) {
+            self.carried.insert(name);
+        }
+        let node = self.push(Kind::Leaf(operand.clone()));
+        self.leaves.insert(operand.clone(), node);
+        node
+    }
+
+    fn op(&mut self, op: Opcode, left: usize, right: usize) -> usize {
+        let key = match op {
+            Opcode::Add | Opcode::Mul => (op, left.min(right), left.max(right)),
+            _ => (op, left, right),
+        };
+        match self.ops.get(&key) {
+            Some(&node) => node,
+            None => {
+                let node = self.push(Kind::Op(op, left, right));
+                self.ops.insert(key, node);
+                node
+            }
+        }
+    }
+
+    fn add(&mut self, quad: Quad) {
+        match (quad.op, quad.left, quad.right, quad.dest) {
+            (Opcode::Assign, Some(src), _, Some(var)) => {
+                let node = self.node(&src);
+                let killed = self.leaves.remove(&var);
+                self.names.remove(&var.to_string());
+                self.stmts.push(Stmt::Assign(var, node, killed));
+            }
+            (op, Some(left), Some(right), Some(temp)) if op.is_arith() => {
+                let left = self.node(&left);
+                let right = self.node(&right);
+                let node = self.op(op, left, right);
+                self.nodes[node].temps.push(temp.to_string());
+                self.names.insert(temp.to_string(), node);
+            }
+            (Opcode::Branch(rel), Some(left), Some(right), Some(Operand::Label(label))) => {
+                let left = self.node(&left);
+                let right = self.node(&right);
+                self.stmts.push(Stmt::Branch(rel, left, right, label));
+            }
+            (Opcode::Put, Some(src), _, _) => {
+                let node = self.node(&src);
+                self.stmts.push(Stmt::Put(node));
+            }
+            (Opcode::Get, _, _, Some(var)) => {
+                let killed = self.leaves.remove(&var);
+                self.stmts.push(Stmt::Get(var, killed));
+            }
+            (op, left, right, dest) => self.stmts.push(Stmt::Other(Quad {
+                op,
+                left,
+                right,
+                dest,
+            })),
+        }
+    }
+
+    // Operand holding the node's value, computing it first if needed
+    fn emit(&mut self, node: usize) -> Operand {
+        if let Some(value) = &self.nodes[node].value {
+            return value.clone();
+        }
+        let value = match &self.nodes[node].kind {
+            Kind::Leaf(operand) => operand.clone(),
+            Kind::Op(op, left, right) => {
+                let (op, left, right) = (*op, *left, *right);
+                let left = self.emit(left);
+                let right = self.emit(right);
+                let temp = Operand::Temp(self.nodes[node].temps[0].clone());
+                self.out.push(Quad::binary(op, left, right, temp.clone()));
+                temp
+            }
+        };
+        self.nodes[node].value = Some(value.clone());
+        value
+    }
+
+    fn depends(&self, node: usize, leaf: usize) -> bool {
+        match self.nodes[node].kind {
+            _ if node == leaf => true,
+            Kind::Leaf(_) => false,
+            Kind::Op(_, left, right) => self.depends(left, leaf) || self.depends(right, leaf),
+        }
+    }
+
+    // Nodes still to be used by the statements from `from` on and by later blocks
+    fn pending(&self, from: usize, live_out: &HashSet<String>) -> Vec<usize> {
+        let mut nodes: Vec<usize> = self.stmts[from..]
+            .iter()
+            .flat_map(|stmt| match stmt {
+                Stmt::Assign(_, node, _) | Stmt::Put(node) => vec![*node],
+                Stmt::Branch(_, left, right, _) => vec![*left, *right],
+                Stmt::Get(..) | Stmt::Other(_) => Vec::new(),
+            })
+            .collect();
+        nodes.extend(self.live_names(live_out).into_iter().map(|(_, node)| node));
+        nodes
+    }
+
+    // Temps defined here that another block, or the next pass through this one, reads
+    fn live_names(&self, live_out: &HashSet<String>) -> Vec<(String, usize)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(node, n)| n.temps.iter().map(move |temp| (temp.clone(), node)))
+            .filter(|(temp, node)| {
+                self.names.get(temp) == Some(node)
+                    && (live_out.contains(temp) || self.carried.contains(temp))
+            })
+            .collect()
+    }
+
+    // Whether every leaf under the node holds its value before statement `at`
+    fn ready(&self, node: usize, at: usize) -> bool {
+        match self.nodes[node].kind {
+            Kind::Leaf(_) => self.nodes[node].birth <= at,
+            Kind::Op(_, left, right) => self.ready(left, at) && self.ready(right, at),
+        }
+    }
+
+    // Statement `at` writes the variable of `leaf`, compute whatever still needs its old value
+    fn kill(&mut self, leaf: Option<usize>, at: usize, live_out: &HashSet<String>) {
+        let Some(leaf) = leaf else {
+            return;
+        };
+        for node in self.pending(at + 1, live_out) {
+            self.flush(node, leaf, at);
+        }
+    }
+
+    // A node also reading a value written at or after `at` can not move above it, its operands
+    // over the old leaf are computed instead and it is left to its first use
+    fn flush(&mut self, node: usize, leaf: usize, at: usize) {
+        if self.nodes[node].value.is_some() || node == leaf || !self.depends(node, leaf) {
+            return;
+        }
+        if self.ready(node, at) {
+            self.emit(node);
+        } else if let Kind::Op(_, left, right) = self.nodes[node].kind {
+            self.flush(left, leaf, at);
+            self.flush(right, leaf, at);
+        }
+    }
+
+    fn store_live(&mut self, live_out: &HashSet<String>) {
+        for (temp, node) in self.live_names(live_out) {
+            let value = self.emit(node);
+            if value.to_string() != temp {
+                self.out.push(Quad::assign(value, Operand::Temp(temp)));
+            }
+        }
+    }
+
+    fn finish(mut self, live_out: &HashSet<String>) -> QuadList {
+        let terminated = match self.stmts.last() {
+            Some(Stmt::Branch(..)) => true,
+            Some(Stmt::Other(quad)) => is_terminator(quad),
+            _ => false,
+        };
+        let count = self.stmts.len();
+        for index in 0..count {
+            // Temps other blocks read are stored before the jump out
+            if terminated && index + 1 == count {
+                self.store_live(live_out);
+            }
+            match self.stmts[index].clone() {
+                Stmt::Assign(var, node, killed) => {
+                    let value = self.emit(node);
+                    self.kill(killed, index, live_out);
+                    self.out.push(Quad::assign(value, var));
+                }
+                Stmt::Get(var, killed) => {
+                    self.kill(killed, index, live_out);
+                    self.out.push(Quad::get(var));
+                }
+                Stmt::Put(node) => {
+                    let value = self.emit(node);
+                    self.out.push(Quad::put(value));
+                }
+                Stmt::Branch(rel, left, right, label) => {
+                    let left = self.emit(left);
+                    let right = self.emit(right);
+                    self.out.push(Quad::branch(rel, left, right, &label));
+                }
+                Stmt::Other(quad) => self.out.push(quad),
+            }
+        }
+        if !terminated {
+            self.store_live(live_out);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::ir;
+
+    #[test]
+    fn test_cse_commutative() {
+        // x = a * b + a * b; y = a * b - b * a; z = a - b - (b - a);
+        let quads = vec![
+            Quad::binary(Opcode::Mul, var("a"), var("b"), temp(1)),
+            Quad::binary(Opcode::Mul, var("a"), var("b"), temp(2)),
+            Quad::binary(Opcode::Add, temp(1), temp(2), temp(3)),
+            Quad::assign(temp(3), var("x")),
+            Quad::binary(Opcode::Mul, var("a"), var("b"), temp(4)),
+            Quad::binary(Opcode::Mul, var("b"), var("a"), temp(5)),
+            Quad::binary(Opcode::Sub, temp(4), temp(5), temp(6)),
+            Quad::assign(temp(6), var("y")),
+            Quad::binary(Opcode::Sub, var("a"), var("b"), temp(7)),
+            Quad::binary(Opcode::Sub, var("b"), var("a"), temp(8)),
+            Quad::binary(Opcode::Sub, temp(7), temp(8), temp(9)),
+            Quad::assign(temp(9), var("z")),
+        ];
+
+        assert_eq!(
+            ir::to_text(&cse(quads)),
+            "    mul %temp_1, $a, $b\n\
+             \x20   add %temp_3, %temp_1, %temp_1\n\
+             \x20   copy $x, %temp_3\n\
+             \x20   sub %temp_6, %temp_1, %temp_1\n\
+             \x20   copy $y, %temp_6\n\
+             \x20   sub %temp_7, $a, $b\n\
+             \x20   sub %temp_8, $b, $a\n\
+             \x20   sub %temp_9, %temp_7, %temp_8\n\
+             \x20   copy $z, %temp_9\n"
+        );
+    }
+
+    #[test]
+    fn test_cse_kills() {
+        // a + b after a is assigned and after GET(b) is a new value, PUT(temp_1) still needs the
+        // sum from before a = 1 so it is computed ahead of the assignment
+        let quads = vec![
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(1)),
+            Quad::assign(num(1), var("a")),
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(2)),
+            Quad::put(temp(1)),
+            Quad::put(temp(2)),
+            Quad::get(var("b")),
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(3)),
+            Quad::put(temp(3)),
+            Quad::binary(Opcode::Add, var("b"), var("a"), temp(4)),
+            Quad::put(temp(4)),
+        ];
+
+        assert_eq!(
+            ir::to_text(&cse(quads)),
+            "    add %temp_1, $a, $b\n\
+             \x20   copy $a, 1\n\
+             \x20   put %temp_1\n\
+             \x20   add %temp_2, $a, $b\n\
+             \x20   put %temp_2\n\
+             \x20   get $b\n\
+             \x20   add %temp_3, $a, $b\n\
+             \x20   put %temp_3\n\
+             \x20   put %temp_3\n"
+        );
+    }
+
+    #[test]
+    fn test_cse_kill_reread() {
+        // GET(a); GET(b); x = a * b; a = 7; PUT(x + b * a); PUT(a * b + b * a);
+        // b * a reads the new a, so only a * b over the old one moves above the assignment
+        let quads = vec![
+            Quad::get(var("a")),
+            Quad::get(var("b")),
+            Quad::binary(Opcode::Mul, var("a"), var("b"), temp(1)),
+            Quad::assign(temp(1), var("x")),
+            Quad::assign(num(7), var("a")),
+            Quad::binary(Opcode::Mul, var("b"), var("a"), temp(2)),
+            Quad::binary(Opcode::Add, temp(1), temp(2), temp(3)),
+            Quad::put(temp(3)),
+            Quad::binary(Opcode::Mul, var("a"), var("b"), temp(4)),
+            Quad::binary(Opcode::Mul, var("b"), var("a"), temp(5)),
+            Quad::binary(Opcode::Add, temp(4), temp(5), temp(6)),
+            Quad::put(temp(6)),
+        ];
+
+        assert_eq!(
+            ir::to_text(&cse(quads)),
+            "    get $a\n\
+             \x20   get $b\n\
+             \x20   mul %temp_1, $a, $b\n\
+             \x20   copy $x, %temp_1\n\
+             \x20   copy $a, 7\n\
+             \x20   mul %temp_2, $b, $a\n\
+             \x20   add %temp_3, %temp_1, %temp_2\n\
+             \x20   put %temp_3\n\
+             \x20   add %temp_6, %temp_2, %temp_2\n\
+             \x20   put %temp_6\n"
+        );
+    }
+
+    #[test]
+    fn test_cse_blocks() {
+        // Nothing is shared across the label, temp_2 is read after the jump so it is stored
+        let quads = vec![
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(1)),
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(2)),
+            Quad::put(temp(1)),
+            Quad::jump("L1"),
+            Quad::label("L1"),
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(3)),
+            Quad::put(temp(3)),
+            Quad::put(temp(2)),
+        ];
+
+        assert_eq!(
+            ir::to_text(&cse(quads)),
+            "    add %temp_1, $a, $b\n\
+             \x20   put %temp_1\n\
+             \x20   copy %temp_2, %temp_1\n\
+             \x20   jmp @L1\n\
+             L1:\n\
+             \x20   add %temp_3, $a, $b\n\
+             \x20   put %temp_3\n\
+             \x20   put %temp_2\n"
+        );
+    }
+}
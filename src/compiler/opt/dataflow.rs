@@ -0,0 +1,488 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use crate::compiler::ir;
+use crate::compiler::opt::cfg::{Cfg, ENTRY};
+use crate::compiler::opt::{reads, temps, writes};
+use crate::compiler::syntax::{Opcode, Operand, Quad};
+
+// Iterative dataflow analysis over a Cfg. An Analysis says which way facts flow, how the facts of
+// several predecessors (or successors) meet and what each quad does to a fact, solve then goes
+// round the blocks until nothing changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    // Fact at the entry of a forward analysis or at the exit of a backward one
+    fn boundary(&self, cfg: &Cfg) -> Self::Fact;
+
+    // Starting fact of every other block, meeting it with any fact gives that fact
+    fn top(&self) -> Self::Fact;
+
+    fn meet(&self, into: &mut Self::Fact, other: &Self::Fact);
+
+    // Updates the fact on one side of the quad at (block, index) into the fact on the other side
+    fn transfer(&self, at: (usize, usize), quad: &Quad, fact: &mut Self::Fact);
+}
+
+// Facts at the start and end of every block, in program order whatever the direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Solution<F> {
+    pub before: Vec<F>,
+    pub after: Vec<F>,
+}
+
+pub fn solve<A: Analysis>(cfg: &Cfg, analysis: &A) -> Solution<A::Fact> {
+    let count = cfg.blocks.len();
+    let forward = analysis.direction() == Direction::Forward;
+    let mut before = vec![analysis.top(); count];
+    let mut after = vec![analysis.top(); count];
+    let order: Vec<usize> = match forward {
+        true => (0..count).collect(),
+        false => (0..count).rev().collect(),
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in &order {
+            let block = &cfg.blocks[id];
+            let (edges, from, to) = match forward {
+                true => (&block.preds, &mut after, &mut before),
+                false => (&block.succs, &mut before, &mut after),
+            };
+            let mut fact = match (forward, id) {
+                (true, ENTRY) => analysis.boundary(cfg),
+                (false, id) if id == cfg.exit() => analysis.boundary(cfg),
+                _ => {
+                    let mut fact = analysis.top();
+                    for &edge in edges {
+                        analysis.meet(&mut fact, &from[edge]);
+                    }
+                    fact
+                }
+            };
+            to[id] = fact.clone();
+
+            let quads = block.quads.iter().enumerate();
+            match forward {
+                true => {
+                    quads.for_each(|(index, quad)| analysis.transfer((id, index), quad, &mut fact))
+                }
+                false => quads
+                    .rev()
+                    .for_each(|(index, quad)| analysis.transfer((id, index), quad, &mut fact)),
+            }
+            if from[id] != fact {
+                from[id] = fact;
+                changed = true;
+            }
+        }
+    }
+    Solution { before, after }
+}
+
+impl<F: Clone> Solution<F> {
+    // Fact at every quad of a block, before it for a forward analysis and after it for a backward
+    pub fn quads<A: Analysis<Fact = F>>(&self, cfg: &Cfg, analysis: &A, id: usize) -> Vec<F> {
+        let quads = &cfg.blocks[id].quads;
+        let mut facts = Vec::with_capacity(quads.len());
+        match analysis.direction() {
+            Direction::Forward => {
+                let mut fact = self.before[id].clone();
+                for (index, quad) in quads.iter().enumerate() {
+                    facts.push(fact.clone());
+                    analysis.transfer((id, index), quad, &mut fact);
+                }
+            }
+            Direction::Backward => {
+                let mut fact = self.after[id].clone();
+                for (index, quad) in quads.iter().enumerate().rev() {
+                    facts.push(fact.clone());
+                    analysis.transfer((id, index), quad, &mut fact);
+                }
+                facts.reverse();
+            }
+        }
+        facts
+    }
+}
+
+// Variables a procedure reads before writing and may write, counting the procedures it calls
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub reads: BTreeSet<String>,
+    pub writes: BTreeSet<String>,
+}
+
+pub fn summaries(cfgs: &[Cfg]) -> HashMap<String, Summary> {
+    let temps = temps(
+        cfgs.iter()
+            .flat_map(|cfg| cfg.blocks.iter().flat_map(|b| &b.quads)),
+    );
+    let mut summaries: HashMap<String, Summary> = cfgs
+        .iter()
+        .filter_map(|cfg| cfg.name.clone())
+        .map(|name| (name, Summary::default()))
+        .collect();
+
+    // Both only grow, so this stops once no procedure learns anything from its callees
+    loop {
+        let mut changed = false;
+        for cfg in cfgs {
+            let Some(name) = &cfg.name else {
+                continue;
+            };
+            let liveness = Liveness {
+                temps: temps.clone(),
+                exit: BTreeSet::new(),
+                summaries: summaries.clone(),
+            };
+            let reads = solve(cfg, &liveness).before[ENTRY].clone();
+            let mut written = BTreeSet::new();
+            for quad in cfg.blocks.iter().flat_map(|block| &block.quads) {
+                if let Some(dest) = writes(quad).and_then(Operand::storage) {
+                    if !temps.contains(dest) {
+                        written.insert(dest.to_string());
+                    }
+                }
+                if let Some(callee) = call(quad).and_then(|callee| summaries.get(callee)) {
+                    written.extend(callee.writes.iter().cloned());
+                }
+            }
+
+            let summary = Summary {
+                reads,
+                writes: written,
+            };
+            if summaries[name] != summary {
+                summaries.insert(name.clone(), summary);
+                changed = true;
+            }
+        }
+        if !changed {
+            return summaries;
+        }
+    }
+}
+
+fn call(quad: &Quad) -> Option<&str> {
+    match quad.op {
+        Opcode::Call => quad.target_name(),
+        _ => None,
+    }
+}
+
+// Names any procedure reads or writes, these have to stay in their global storage
+pub fn shared(cfgs: &[Cfg]) -> HashSet<String> {
+    cfgs.iter()
+        .filter(|cfg| cfg.name.is_some())
+        .flat_map(|cfg| cfg.blocks.iter().flat_map(|block| &block.quads))
+        .flat_map(|quad| reads(quad).into_iter().chain(writes(quad)))
+        .filter_map(Operand::storage)
+        .map(String::from)
+        .collect()
+}
+
+fn variables(cfgs: &[Cfg]) -> BTreeSet<String> {
+    let quads: Vec<&Quad> = cfgs
+        .iter()
+        .flat_map(|cfg| cfg.blocks.iter().flat_map(|block| &block.quads))
+        .collect();
+    let temps = temps(quads.iter().copied());
+    quads
+        .iter()
+        .flat_map(|quad| reads(quad).into_iter().chain(writes(quad)))
+        .filter_map(Operand::storage)
+        .filter(|name| !temps.contains(*name))
+        .map(String::from)
+        .collect()
+}
+
+// Live variables, backward. At the exit of the main program nothing is live, at the exit of a
+// procedure every variable is since the caller may read it. A CALL reads what the procedure does.
+pub struct Liveness {
+    temps: HashSet<String>,
+    exit: BTreeSet<String>,
+    summaries: HashMap<String, Summary>,
+}
+
+impl Liveness {
+    pub fn new(cfgs: &[Cfg]) -> Self {
+        Liveness {
+            temps: temps(
+                cfgs.iter()
+                    .flat_map(|cfg| cfg.blocks.iter().flat_map(|b| &b.quads)),
+            ),
+            exit: variables(cfgs),
+            summaries: summaries(cfgs),
+        }
+    }
+
+    pub fn is_temp(&self, name: &str) -> bool {
+        self.temps.contains(name)
+    }
+}
+
+impl Analysis for Liveness {
+    type Fact = BTreeSet<String>;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self, cfg: &Cfg) -> Self::Fact {
+        match cfg.name {
+            Some(_) => self.exit.clone(),
+            None => BTreeSet::new(),
+        }
+    }
+
+    fn top(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().cloned());
+    }
+
+    fn transfer(&self, _at: (usize, usize), quad: &Quad, fact: &mut Self::Fact) {
+        if let Some(dest) = writes(quad).and_then(Operand::storage) {
+            fact.remove(dest);
+        }
+        for name in reads(quad).into_iter().filter_map(Operand::storage) {
+            fact.insert(name.to_string());
+        }
+        if let Some(callee) = call(quad).and_then(|callee| self.summaries.get(callee)) {
+            fact.extend(callee.reads.iter().cloned());
+        }
+    }
+}
+
+// A definition of a variable or temp, at (block, index) or None for the value it had before the
+// function started. In the main program that means no GET or assignment has run yet.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Def {
+    pub name: String,
+    pub at: Option<(usize, usize)>,
+}
+
+// Reaching definitions, forward. A CALL defines every variable the procedure may write.
+pub struct ReachingDefs {
+    variables: BTreeSet<String>,
+    summaries: HashMap<String, Summary>,
+}
+
+impl ReachingDefs {
+    pub fn new(cfgs: &[Cfg]) -> Self {
+        ReachingDefs {
+            variables: variables(cfgs),
+            summaries: summaries(cfgs),
+        }
+    }
+}
+
+impl Analysis for ReachingDefs {
+    type Fact = BTreeSet<Def>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, _cfg: &Cfg) -> Self::Fact {
+        self.variables
+            .iter()
+            .map(|name| Def {
+                name: name.clone(),
+                at: None,
+            })
+            .collect()
+    }
+
+    fn top(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, into: &mut Self::Fact, other: &Self::Fact) {
+        into.extend(other.iter().cloned());
+    }
+
+    fn transfer(&self, at: (usize, usize), quad: &Quad, fact: &mut Self::Fact) {
+        let mut defined: Vec<&str> = writes(quad)
+            .and_then(Operand::storage)
+            .into_iter()
+            .collect();
+        if let Some(callee) = call(quad).and_then(|callee| self.summaries.get(callee)) {
+            defined.extend(callee.writes.iter().map(String::as_str));
+        }
+        for name in defined {
+            fact.retain(|def| def.name != name);
+            fact.insert(Def {
+                name: name.to_string(),
+                at: Some(at),
+            });
+        }
+    }
+}
+
+// A variable of the main program read while no GET or assignment may have run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub name: String,
+    pub quad: Quad,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[ Warning ] {} may be used before GET or assignment: {}",
+            self.name,
+            ir::line(&self.quad).trim()
+        )
+    }
+}
+
+// One warning per variable, at the first quad reading it uninitialised
+pub fn uninitialised(cfgs: &[Cfg]) -> Vec<Warning> {
+    let Some(main) = cfgs.iter().find(|cfg| cfg.name.is_none()) else {
+        return Vec::new();
+    };
+    let analysis = ReachingDefs::new(cfgs);
+    let summaries = summaries(cfgs);
+    let solution = solve(main, &analysis);
+
+    let mut warnings: Vec<Warning> = Vec::new();
+    for (id, block) in main.blocks.iter().enumerate() {
+        let facts = solution.quads(main, &analysis, id);
+        for (quad, fact) in block.quads.iter().zip(facts) {
+            let mut used: Vec<String> = reads(quad)
+                .into_iter()
+                .filter_map(Operand::storage)
+                .map(String::from)
+                .collect();
+            if let Some(callee) = call(quad).and_then(|callee| summaries.get(callee)) {
+                used.extend(callee.reads.iter().cloned());
+            }
+            for name in used {
+                let undefined = Def {
+                    name: name.clone(),
+                    at: None,
+                };
+                if fact.contains(&undefined) && !warnings.iter().any(|w| w.name == name) {
+                    warnings.push(Warning {
+                        name,
+                        quad: quad.clone(),
+                    });
+                }
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::{num, var};
+    use crate::compiler::backend::{sample, Relation};
+    use crate::compiler::opt::cfg;
+
+    fn set(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_liveness() {
+        let cfgs = cfg::build(sample::quads());
+        let liveness = Liveness::new(&cfgs);
+        let (show, main) = (&cfgs[0], &cfgs[1]);
+
+        // show reads a and b before writing c, a caller may read all three afterwards
+        let solution = solve(show, &liveness);
+        assert_eq!(solution.before[1], set(&["a", "b"]));
+        assert_eq!(solution.after[1], set(&["a", "b", "c"]));
+
+        let solution = solve(main, &liveness);
+        assert_eq!(solution.before[ENTRY], set(&[]));
+        assert_eq!(solution.before[2], set(&["a", "b"]));
+        assert_eq!(solution.before[5], set(&["a", "b"]));
+        assert_eq!(solution.before[6], set(&["b"]));
+        assert_eq!(solution.after[7], set(&[]));
+
+        // After each quad of the loop body: a - 1 into temp_3, back into a, b * 2 into temp_4...
+        let facts = solution.quads(main, &liveness, 3);
+        assert_eq!(facts[0], set(&["b", "temp_3"]));
+        assert_eq!(facts[1], set(&["a", "b"]));
+        assert_eq!(facts[2], set(&["a", "temp_4"]));
+        assert_eq!(facts[4], set(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_reaching_defs() {
+        let cfgs = cfg::build(sample::quads());
+        let main = &cfgs[1];
+        let analysis = ReachingDefs::new(&cfgs);
+        let solution = solve(main, &analysis);
+
+        // At the loop head a comes from GET or the loop body, c is still undefined
+        let at_head: Vec<&Def> = solution.before[2]
+            .iter()
+            .filter(|def| def.name == "a" || def.name == "c")
+            .collect();
+        let def = |name: &str, at: Option<(usize, usize)>| Def {
+            name: name.to_string(),
+            at,
+        };
+        assert_eq!(
+            at_head,
+            [
+                &def("a", Some((1, 0))),
+                &def("a", Some((3, 1))),
+                &def("c", None)
+            ]
+        );
+        // CALL show defines c, after the if c may or may not be defined
+        assert!(!solution.after[5].contains(&def("c", None)));
+        assert!(solution.before[6].contains(&def("c", Some((5, 0)))));
+        assert!(solution.before[6].contains(&def("c", None)));
+    }
+
+    #[test]
+    fn test_uninitialised() {
+        assert_eq!(uninitialised(&cfg::build(sample::quads())), []);
+
+        // PUT(x); GET(y); IF y < 1 { x = 1 } PUT(x); CALL p where p reads z
+        let quads = vec![
+            Quad::procedure("p"),
+            Quad::put(var("z")),
+            Quad::ret(),
+            Quad::put(var("x")),
+            Quad::get(var("y")),
+            Quad::branch(Relation::LessThan, var("y"), num(1), "L1"),
+            Quad::assign(num(1), var("x")),
+            Quad::label("L1"),
+            Quad::put(var("y")),
+            Quad::call("p"),
+        ];
+
+        let warnings: Vec<String> = uninitialised(&cfg::build(quads))
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "[ Warning ] x may be used before GET or assignment: put $x",
+                "[ Warning ] z may be used before GET or assignment: call @p",
+            ]
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::compiler::opt::cfg::{self, Cfg, ENTRY};
+use crate::compiler::opt::dataflow::{solve, Liveness};
+use crate::compiler::opt::writes;
+use crate::compiler::syntax::{Opcode, Quad, QuadList};
+
+// Dead code and dead store elimination.
+//
+// Blocks no path from the entry of their function reaches are dropped, these are left behind when
+// fold turns a branch into a JMP. Then every quad that only stores to a variable or temp that is
+// not live afterwards is dropped, and liveness is solved again since that can make the quads
+// computing its operands dead too. GET still consumes input and a division can still trap, so
+// those stay even when their result is never read.
+pub fn dce(quads: QuadList) -> QuadList {
+    let mut cfgs: Vec<Cfg> = cfg::build(quads)
+        .into_iter()
+        .map(remove_unreachable)
+        .collect();
+    loop {
+        let liveness = Liveness::new(&cfgs);
+        let mut changed = false;
+        cfgs = cfgs
+            .into_iter()
+            .map(|cfg| {
+                let solution = solve(&cfg, &liveness);
+                let live: Vec<_> = (0..cfg.blocks.len())
+                    .map(|id| solution.quads(&cfg, &liveness, id))
+                    .collect();
+                let name = cfg.name.clone();
+                let mut quads = Vec::new();
+                for (block, live) in cfg.blocks.into_iter().zip(live) {
+                    for (quad, live) in block.quads.into_iter().zip(live) {
+                        match writes(&quad) {
+                            Some(dest) if !live.contains(&dest.to_string()) && is_pure(&quad) => {
+                                changed = true
+                            }
+                            _ => quads.push(quad),
+                        }
+                    }
+                }
+                Cfg::new(name, quads)
+            })
+            .collect();
+        if !changed {
+            return cfg::flatten(cfgs);
+        }
+    }
+}
+
+// Whether dropping the quad can only change which values are stored
+fn is_pure(quad: &Quad) -> bool {
+    match quad.op {
+        Opcode::Div => !matches!(
+            quad.right.as_ref().and_then(|right| right.constant()),
+            None | Some(0)
+        ),
+        op => op.is_arith() || op == Opcode::Assign,
+    }
+}
+
+fn remove_unreachable(cfg: Cfg) -> Cfg {
+    let mut reached = vec![false; cfg.blocks.len()];
+    let mut stack = vec![ENTRY];
+    while let Some(id) = stack.pop() {
+        if !reached[id] {
+            reached[id] = true;
+            stack.extend(&cfg.blocks[id].succs);
+        }
+    }
+    let name = cfg.name;
+    let quads = cfg
+        .blocks
+        .into_iter()
+        .zip(reached)
+        .filter(|(_, reached)| *reached)
+        .flat_map(|(block, _)| block.quads)
+        .collect();
+    Cfg::new(name, quads)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::ir;
+
+    #[test]
+    fn test_dce_sample() {
+        assert_eq!(dce(sample::quads()), sample::quads());
+    }
+
+    #[test]
+    fn test_dce_stores() {
+        let quads = vec![
+            // x = 1; x = y + 2; GET(z); PUT(x); w = z * 3; v = z / y; u = z / 4;
+            Quad::assign(num(1), var("x")),
+            Quad::binary(Opcode::Add, var("y"), num(2), temp(1)),
+            Quad::assign(temp(1), var("x")),
+            Quad::get(var("z")),
+            Quad::put(var("x")),
+            Quad::binary(Opcode::Mul, var("z"), num(3), temp(2)),
+            Quad::assign(temp(2), var("w")),
+            Quad::binary(Opcode::Div, var("z"), var("y"), temp(3)),
+            Quad::assign(temp(3), var("v")),
+            Quad::binary(Opcode::Div, var("z"), num(4), temp(4)),
+            Quad::assign(temp(4), var("u")),
+        ];
+
+        assert_eq!(
+            ir::to_text(&dce(quads)),
+            "    add %temp_1, $y, 2\n\
+             \x20   copy $x, %temp_1\n\
+             \x20   get $z\n\
+             \x20   put $x\n\
+             \x20   div %temp_3, $z, $y\n"
+        );
+    }
+
+    #[test]
+    fn test_dce_blocks() {
+        let quads = vec![
+            // A procedure's stores may be read by its caller
+            Quad::procedure("p"),
+            Quad::put(var("a")),
+            Quad::assign(num(2), var("b")),
+            Quad::ret(),
+            // What fold leaves of IF 1 > 2 { PUT(b) } ELSE { PUT(c) }
+            Quad::jump("L1"),
+            Quad::put(var("b")),
+            Quad::jump("L2"),
+            Quad::label("L1"),
+            Quad::put(var("c")),
+            Quad::label("L2"),
+            // The call reads a, it may or may not overwrite b but the first store to b is dead
+            Quad::assign(num(3), var("a")),
+            Quad::assign(num(4), var("b")),
+            Quad::assign(num(5), var("b")),
+            Quad::call("p"),
+            Quad::put(var("b")),
+        ];
+
+        assert_eq!(
+            ir::to_text(&dce(quads)),
+            "proc @p\n\
+             \x20   put $a\n\
+             \x20   copy $b, 2\n\
+             \x20   ret\n\
+             \x20   jmp @L1\n\
+             L1:\n\
+             \x20   put $c\n\
+             L2:\n\
+             \x20   copy $a, 3\n\
+             \x20   copy $b, 5\n\
+             \x20   call @p\n\
+             \x20   put $b\n"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::backend::Relation;
+use crate::compiler::opt::{reads, temps};
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList};
+
+// Constant folding, algebraic identities and constant propagation inside basic blocks.
+//
+// A result is only folded when it fits in 16 bits, the width of the narrowest backends (C and
+// LLVM use int16_t), so the literal is what every target would have computed. Division by zero is
+// left for the program to hit at run time. Temps are the destinations of arithmetic quads, they
+// are written once and read inside the same statement, so besides literals a temp can also stand
+// for the variable it copies. Folded temps nothing reads any more are dropped at the end, other
+// arithmetic is kept even when unused since a division can still trap.
+pub fn fold(quads: QuadList) -> QuadList {
+    let temps = temps(&quads);
+
+    // What a name can be replaced with at this point of the block
+    let mut values: HashMap<String, Operand> = HashMap::new();
+    let mut folded = Vec::new();
+    for mut quad in quads {
+        match quad.op {
+            Opcode::Assign => {
+                substitute(&values, &mut quad.left);
+                let dest = quad.dest.as_ref().unwrap().to_string();
+                kill(&mut values, &dest);
+                let src = quad.left.clone().unwrap();
+                if src.constant().is_some() || temps.contains(&dest) {
+                    values.insert(dest, src);
+                }
+                folded.push(quad);
+            }
+            op if op.is_arith() => {
+                substitute(&values, &mut quad.left);
+                substitute(&values, &mut quad.right);
+                let dest = quad.dest.clone().unwrap();
+                kill(&mut values, &dest.to_string());
+                match simplify(&quad) {
+                    Some(value) => {
+                        values.insert(dest.to_string(), value.clone());
+                        folded.push(Quad::assign(value, dest));
+                    }
+                    None => folded.push(quad),
+                }
+            }
+            Opcode::Branch(rel) => {
+                substitute(&values, &mut quad.left);
+                substitute(&values, &mut quad.right);
+                match (literal(&quad.left), literal(&quad.right)) {
+                    // Falls through when the relation holds, otherwise always jumps
+                    (Some(left), Some(right)) => {
+                        if !holds(rel, left, right) {
+                            folded.push(Quad::jump(quad.target_name().unwrap()));
+                        }
+                    }
+                    _ => folded.push(quad),
+                }
+            }
+            Opcode::Put => {
+                substitute(&values, &mut quad.left);
+                folded.push(quad);
+            }
+            Opcode::Get => {
+                kill(&mut values, &quad.dest.as_ref().unwrap().to_string());
+                folded.push(quad);
+            }
+            // Labels start a block, procedures can change any variable
+            _ => {
+                values.clear();
+                folded.push(quad);
+            }
+        }
+    }
+    remove_unused(folded, &temps)
+}
+
+fn literal(operand: &Option<Operand>) -> Option<i32> {
+    operand.as_ref()?.constant()
+}
+
+fn substitute(values: &HashMap<String, Operand>, operand: &mut Option<Operand>) {
+    let value = operand
+        .as_ref()
+        .and_then(|operand| operand.storage())
+        .and_then(|name| values.get(name));
+    if let Some(value) = value {
+        *operand = Some(value.clone());
+    }
+}
+
+// `name` is written, neither its old value nor temps copying it can be used any more
+fn kill(values: &mut HashMap<String, Operand>, name: &str) {
+    values.remove(name);
+    values.retain(|_, value| value.storage() != Some(name));
+}
+
+// The literal or operand an arithmetic quad computes, if it can be known without running it
+fn simplify(quad: &Quad) -> Option<Operand> {
+    let (left, right) = (quad.left.as_ref()?, quad.right.as_ref()?);
+    match (quad.op, left.constant(), right.constant()) {
+        (op, Some(left), Some(right)) => evaluate(op, left, right).map(Operand::Const),
+        (Opcode::Add, Some(0), _) | (Opcode::Mul, Some(1), _) => Some(right.clone()),
+        (Opcode::Add | Opcode::Sub, _, Some(0)) | (Opcode::Mul | Opcode::Div, _, Some(1)) => {
+            Some(left.clone())
+        }
+        (Opcode::Mul, Some(0), _) | (Opcode::Mul, _, Some(0)) => Some(Operand::Const(0)),
+        _ => None,
+    }
+}
+
+fn evaluate(op: Opcode, left: i32, right: i32) -> Option<i32> {
+    let value = match op {
+        Opcode::Add => left.checked_add(right),
+        Opcode::Sub => left.checked_sub(right),
+        Opcode::Mul => left.checked_mul(right),
+        Opcode::Div => left.checked_div(right),
+        // A shift right divides, rounding towards zero the same way
+        Opcode::Shl => 1i32
+            .checked_shl(u32::try_from(right).ok()?)?
+            .checked_mul(left),
+        Opcode::Shr => left.checked_div(1i32.checked_shl(u32::try_from(right).ok()?)?),
+        _ => None,
+    }?;
+    i16::try_from(value).ok().map(i32::from)
+}
+
+fn holds(rel: Relation, left: i32, right: i32) -> bool {
+    match rel {
+        Relation::Equal => left == right,
+        Relation::NEqual => left != right,
+        Relation::GreaterThan => left > right,
+        Relation::LessThan => left < right,
+        Relation::GEqual => left >= right,
+        Relation::LEqual => left <= right,
+    }
+}
+
+// Drops copies into temps nothing reads, until removing one frees no other
+fn remove_unused(mut quads: QuadList, temps: &HashSet<String>) -> QuadList {
+    loop {
+        let read: HashSet<String> = quads
+            .iter()
+            .flat_map(reads)
+            .map(|operand| operand.to_string())
+            .collect();
+        let before = quads.len();
+        quads.retain(|quad| match (quad.op, &quad.dest) {
+            (Opcode::Assign, Some(dest)) => {
+                let dest = dest.to_string();
+                !temps.contains(&dest) || read.contains(&dest)
+            }
+            _ => true,
+        });
+        if quads.len() == before {
+            return quads;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::ir;
+
+    #[test]
+    fn test_fold_expressions() {
+        // x = 10 / 2 * 4; z = y + 0; w = 1 * y; v = y * 0; PUT(x - 25);
+        let quads = vec![
+            Quad::binary(Opcode::Div, num(10), num(2), temp(1)),
+            Quad::binary(Opcode::Mul, temp(1), num(4), temp(2)),
+            Quad::assign(temp(2), var("x")),
+            Quad::binary(Opcode::Add, var("y"), num(0), temp(3)),
+            Quad::assign(temp(3), var("z")),
+            Quad::binary(Opcode::Mul, num(1), var("y"), temp(4)),
+            Quad::assign(temp(4), var("w")),
+            Quad::binary(Opcode::Mul, var("y"), num(0), temp(5)),
+            Quad::assign(temp(5), var("v")),
+            Quad::binary(Opcode::Sub, var("x"), num(25), temp(6)),
+            Quad::put(temp(6)),
+        ];
+
+        assert_eq!(
+            ir::to_text(&fold(quads)),
+            "    copy $x, 20\n\
+             \x20   copy $z, $y\n\
+             \x20   copy $w, $y\n\
+             \x20   copy $v, 0\n\
+             \x20   put -5\n"
+        );
+    }
+
+    #[test]
+    fn test_fold_blocks() {
+        let quads = vec![
+            // Known before the loop, not at its head
+            Quad::assign(num(5), var("a")),
+            Quad::label("L1"),
+            Quad::branch(Relation::GreaterThan, var("a"), num(0), "L2"),
+            Quad::binary(Opcode::Sub, var("a"), num(1), temp(1)),
+            Quad::assign(temp(1), var("a")),
+            Quad::jump("L1"),
+            Quad::label("L2"),
+            // 2 < 3 always falls through, 3 < 2 always jumps
+            Quad::branch(Relation::LessThan, num(2), num(3), "L3"),
+            Quad::branch(Relation::LessThan, num(3), num(2), "L4"),
+            // GET and CALL forget a
+            Quad::assign(num(7), var("a")),
+            Quad::get(var("a")),
+            Quad::put(var("a")),
+            Quad::assign(num(7), var("a")),
+            Quad::call("show"),
+            Quad::put(var("a")),
+            // Division by zero and 300 * 300 are not folded
+            Quad::binary(Opcode::Div, num(1), num(0), temp(2)),
+            Quad::put(temp(2)),
+            Quad::binary(Opcode::Mul, num(300), num(300), temp(3)),
+            Quad::put(temp(3)),
+        ];
+
+        assert_eq!(
+            ir::to_text(&fold(quads)),
+            "    copy $a, 5\n\
+             L1:\n\
+             \x20   br.gt $a, 0, @L2\n\
+             \x20   sub %temp_1, $a, 1\n\
+             \x20   copy $a, %temp_1\n\
+             \x20   jmp @L1\n\
+             L2:\n\
+             \x20   jmp @L4\n\
+             \x20   copy $a, 7\n\
+             \x20   get $a\n\
+             \x20   put $a\n\
+             \x20   copy $a, 7\n\
+             \x20   call @show\n\
+             \x20   put $a\n\
+             \x20   div %temp_2, 1, 0\n\
+             \x20   put %temp_2\n\
+             \x20   mul %temp_3, 300, 300\n\
+             \x20   put %temp_3\n"
+        );
+    }
+
+    #[test]
+    fn test_fold_shifts() {
+        // PUT(-7 >> 1); PUT(3 << 2); PUT(1 << 15)
+        let quads = vec![
+            Quad::binary(Opcode::Shr, num(-7), num(1), temp(1)),
+            Quad::put(temp(1)),
+            Quad::binary(Opcode::Shl, num(3), num(2), temp(2)),
+            Quad::put(temp(2)),
+            Quad::binary(Opcode::Shl, num(1), num(15), temp(3)),
+            Quad::put(temp(3)),
+        ];
+
+        // A shift right rounds towards zero like a division, 1 << 15 does not fit in 16 bits
+        assert_eq!(
+            ir::to_text(&fold(quads)),
+            "    put -3\n\
+             \x20   put 12\n\
+             \x20   shl %temp_3, 1, 15\n\
+             \x20   put %temp_3\n"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::compiler::opt::cfg::{self, Cfg};
+use crate::compiler::opt::dataflow::{summaries, Summary};
+use crate::compiler::opt::ssa::dominators;
+use crate::compiler::opt::{is_arith, writes};
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList};
+
+// Natural loops and loop-invariant code motion.
+//
+// An edge to a block that dominates its source is a back edge, and its loop is the header it
+// points at plus every block that reaches the source without passing through the header. A WHILE
+// has one back edge, its JMP to the LABEL of the condition. Syntax has no polish for WHILE, so
+// until it does the loops come from IR text.
+//
+// Quads are only moved out of a loop whose header is entered from outside by falling through from
+// the block laid out just before it. Quads placed in front of the header's LABEL then run once on
+// every way into the loop, so that spot is the preheader. A computation is invariant when its
+// operands are literals, names nothing in the loop writes, or temps already hoisted. Its temp is
+// only read by the statement that computed it, so hoisting it is safe even if the loop runs zero
+// times, except for a division that could trap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    // Blocks of the loop, the header included
+    pub body: BTreeSet<usize>,
+}
+
+// Loops of the function ordered by header, back edges to the same header make one loop
+pub fn loops(cfg: &Cfg) -> Vec<Loop> {
+    let idom = dominators(cfg);
+    let mut bodies: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        for &header in block
+            .succs
+            .iter()
+            .filter(|&&succ| dominates(&idom, succ, id))
+        {
+            let body = bodies
+                .entry(header)
+                .or_insert_with(|| BTreeSet::from([header]));
+            let mut stack = vec![id];
+            while let Some(id) = stack.pop() {
+                if body.insert(id) {
+                    stack.extend(&cfg.blocks[id].preds);
+                }
+            }
+        }
+    }
+    bodies
+        .into_iter()
+        .map(|(header, body)| Loop { header, body })
+        .collect()
+}
+
+fn dominates(idom: &[Option<usize>], a: usize, b: usize) -> bool {
+    let mut id = b;
+    loop {
+        if id == a {
+            return true;
+        }
+        match idom[id] {
+            Some(parent) => id = parent,
+            None => return false,
+        }
+    }
+}
+
+// Whether quads put just before the header's LABEL run exactly when the loop is entered
+pub fn has_preheader(cfg: &Cfg, lp: &Loop) -> bool {
+    let header = &cfg.blocks[lp.header];
+    let label = header
+        .quads
+        .first()
+        .filter(|quad| quad.op == Opcode::Label)
+        .and_then(Quad::target_name);
+    let mut outside = header.preds.iter().filter(|pred| !lp.body.contains(pred));
+    // A branch before the header falls through to it, as long as it does not jump there too
+    let falls = match cfg.blocks[lp.header - 1].quads.last() {
+        Some(
+            quad @ Quad {
+                op: Opcode::Branch(_),
+                ..
+            },
+        ) => quad.target_name() != label,
+        Some(quad) => !matches!(quad.op, Opcode::Jump | Opcode::Ret),
+        None => true,
+    };
+    label.is_some() && falls && outside.next() == Some(&(lp.header - 1)) && outside.next().is_none()
+}
+
+// Names the quads of the loop may write, counting the procedures it calls
+pub fn written(cfg: &Cfg, lp: &Loop, summaries: &HashMap<String, Summary>) -> HashSet<String> {
+    let mut written = HashSet::new();
+    for quad in lp.body.iter().flat_map(|&id| &cfg.blocks[id].quads) {
+        if let Some(dest) = writes(quad) {
+            written.insert(dest.to_string());
+        }
+        if quad.op == Opcode::Call {
+            if let Some(summary) = quad.target_name().and_then(|name| summaries.get(name)) {
+                written.extend(summary.writes.iter().cloned());
+            }
+        }
+    }
+    written
+}
+
+// Names written by exactly one quad of the program
+pub fn written_once(quads: &[Quad]) -> HashSet<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for dest in quads.iter().filter_map(writes) {
+        *counts.entry(dest.to_string()).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count == 1)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+// Moves invariant computations out of loops, innermost first, until none is left. A computation
+// hoisted out of an inner loop can be hoisted again out of the loop around it.
+pub fn licm(quads: QuadList) -> QuadList {
+    let mut quads = quads;
+    loop {
+        let once = written_once(&quads);
+        let cfgs = cfg::build(quads.clone());
+        let summaries = summaries(&cfgs);
+        let mut changed = false;
+        quads = cfgs
+            .into_iter()
+            .flat_map(|cfg| match hoist(&cfg, &summaries, &once) {
+                Some(quads) => {
+                    changed = true;
+                    quads
+                }
+                None => cfg.into_quads(),
+            })
+            .collect();
+        if !changed {
+            return quads;
+        }
+    }
+}
+
+// The quads of the function with the invariants of one loop moved to its preheader, None when no
+// loop has any
+fn hoist(
+    cfg: &Cfg,
+    summaries: &HashMap<String, Summary>,
+    once: &HashSet<String>,
+) -> Option<QuadList> {
+    let mut loops = loops(cfg);
+    loops.sort_by_key(|lp| lp.body.len());
+    for lp in loops.iter().filter(|lp| has_preheader(cfg, lp)) {
+        let written = written(cfg, lp, summaries);
+        let mut invariant: HashSet<&str> = HashSet::new();
+        let mut hoisted: HashSet<(usize, usize)> = HashSet::new();
+        for &id in &lp.body {
+            for (index, quad) in cfg.blocks[id].quads.iter().enumerate() {
+                let operands = [&quad.left, &quad.right]
+                    .into_iter()
+                    .flatten()
+                    .all(|operand| match operand {
+                        Operand::Const(_) => true,
+                        Operand::Var(name) | Operand::Temp(name) => {
+                            !written.contains(name) || invariant.contains(name.as_str())
+                        }
+                        _ => false,
+                    });
+                let Some(temp) = quad.dest.as_ref().and_then(Operand::storage) else {
+                    continue;
+                };
+                if is_arith(quad) && once.contains(temp) && operands && !traps(quad) {
+                    invariant.insert(temp);
+                    hoisted.insert((id, index));
+                }
+            }
+        }
+        if hoisted.is_empty() {
+            continue;
+        }
+
+        let mut quads = Vec::new();
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            if id == lp.header {
+                for &id in &lp.body {
+                    quads.extend(
+                        cfg.blocks[id]
+                            .quads
+                            .iter()
+                            .enumerate()
+                            .filter(|(index, _)| hoisted.contains(&(id, *index)))
+                            .map(|(_, quad)| quad.clone()),
+                    );
+                }
+            }
+            quads.extend(
+                block
+                    .quads
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| !hoisted.contains(&(id, *index)))
+                    .map(|(_, quad)| quad.clone()),
+            );
+        }
+        return Some(quads);
+    }
+    None
+}
+
+// A division the loop might never have reached, unless the divisor is a literal other than 0
+fn traps(quad: &Quad) -> bool {
+    quad.op == Opcode::Div
+        && matches!(
+            quad.right.as_ref().and_then(Operand::constant),
+            None | Some(0)
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::ir;
+
+    #[test]
+    fn test_loops() {
+        let cfgs = cfg::build(sample::quads());
+        // show has no loop, main has the WHILE from LABEL L1 to its JMP
+        assert_eq!(loops(&cfgs[0]), []);
+        let main = loops(&cfgs[1]);
+        assert_eq!(
+            main,
+            [Loop {
+                header: 2,
+                body: BTreeSet::from([2, 3])
+            }]
+        );
+        assert!(has_preheader(&cfgs[1], &main[0]));
+
+        // WHILE a > 0 { WHILE b > 0 { b = b - 1 } a = a - 1 }
+        let quads = ir::parse(
+            "L1:\n\
+             \x20   br.gt $a, 0, @L2\n\
+             L3:\n\
+             \x20   br.gt $b, 0, @L4\n\
+             \x20   sub %temp_1, $b, 1\n\
+             \x20   copy $b, %temp_1\n\
+             \x20   jmp @L3\n\
+             L4:\n\
+             \x20   sub %temp_2, $a, 1\n\
+             \x20   copy $a, %temp_2\n\
+             \x20   jmp @L1\n\
+             L2:\n",
+        )
+        .unwrap();
+        let cfgs = cfg::build(quads);
+        let nested = loops(&cfgs[0]);
+        assert_eq!(
+            nested,
+            [
+                Loop {
+                    header: 1,
+                    body: BTreeSet::from([1, 2, 3, 4])
+                },
+                Loop {
+                    header: 2,
+                    body: BTreeSet::from([2, 3])
+                }
+            ]
+        );
+        // The outer header starts the program, the inner one follows the outer condition
+        assert!(nested.iter().all(|lp| has_preheader(&cfgs[0], lp)));
+    }
+
+    #[test]
+    fn test_licm() {
+        // PROCEDURE p { c = c + 1 }
+        // GET(n); WHILE n > 0 { x = a * b + 2; y = a / b; z = c * 2; PUT(x / 4); CALL p;
+        // n = n - 1 }
+        let quads = ir::parse(
+            "proc @p\n\
+             \x20   add %temp_1, $c, 1\n\
+             \x20   copy $c, %temp_1\n\
+             \x20   ret\n\
+             \x20   get $n\n\
+             L1:\n\
+             \x20   br.gt $n, 0, @L2\n\
+             \x20   mul %temp_2, $a, $b\n\
+             \x20   add %temp_3, %temp_2, 2\n\
+             \x20   copy $x, %temp_3\n\
+             \x20   div %temp_4, $a, $b\n\
+             \x20   copy $y, %temp_4\n\
+             \x20   mul %temp_5, $c, 2\n\
+             \x20   copy $z, %temp_5\n\
+             \x20   div %temp_6, $x, 4\n\
+             \x20   put %temp_6\n\
+             \x20   call @p\n\
+             \x20   sub %temp_7, $n, 1\n\
+             \x20   copy $n, %temp_7\n\
+             \x20   jmp @L1\n\
+             L2:\n",
+        )
+        .unwrap();
+
+        // a * b + 2 moves out, a / b might divide by zero, p writes c and x changes every trip
+        assert_eq!(
+            ir::to_text(&licm(quads)),
+            "proc @p\n\
+             \x20   add %temp_1, $c, 1\n\
+             \x20   copy $c, %temp_1\n\
+             \x20   ret\n\
+             \x20   get $n\n\
+             \x20   mul %temp_2, $a, $b\n\
+             \x20   add %temp_3, %temp_2, 2\n\
+             L1:\n\
+             \x20   br.gt $n, 0, @L2\n\
+             \x20   copy $x, %temp_3\n\
+             \x20   div %temp_4, $a, $b\n\
+             \x20   copy $y, %temp_4\n\
+             \x20   mul %temp_5, $c, 2\n\
+             \x20   copy $z, %temp_5\n\
+             \x20   div %temp_6, $x, 4\n\
+             \x20   put %temp_6\n\
+             \x20   call @p\n\
+             \x20   sub %temp_7, $n, 1\n\
+             \x20   copy $n, %temp_7\n\
+             \x20   jmp @L1\n\
+             L2:\n"
+        );
+    }
+
+    #[test]
+    fn test_licm_nested() {
+        // WHILE a > 0 { WHILE b > 0 { PUT(n * 3); b = b - 1 } a = a - 1 }
+        let quads = ir::parse(
+            "L1:\n\
+             \x20   br.gt $a, 0, @L2\n\
+             L3:\n\
+             \x20   br.gt $b, 0, @L4\n\
+             \x20   mul %temp_1, $n, 3\n\
+             \x20   put %temp_1\n\
+             \x20   sub %temp_2, $b, 1\n\
+             \x20   copy $b, %temp_2\n\
+             \x20   jmp @L3\n\
+             L4:\n\
+             \x20   sub %temp_3, $a, 1\n\
+             \x20   copy $a, %temp_3\n\
+             \x20   jmp @L1\n\
+             L2:\n",
+        )
+        .unwrap();
+
+        // Out of the inner loop first, then out of the outer one
+        let hoisted = licm(quads);
+        assert_eq!(
+            ir::to_text(&hoisted[..3]),
+            "    mul %temp_1, $n, 3\n\
+             L1:\n\
+             \x20   br.gt $a, 0, @L2\n"
+        );
+        assert_eq!(hoisted.len(), 14);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use crate::compiler::ir;
+use crate::compiler::opt::{cse, dce, fold, loops, reads, ssa, strength};
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList};
+
+pub type Pass = fn(QuadList) -> QuadList;
+
+// Every pass by the name the pipeline options use
+pub const PASSES: [(&str, Pass); 6] = [
+    ("fold", fold::fold),
+    ("cse", cse::cse),
+    ("licm", loops::licm),
+    ("strength", strength::strength),
+    ("dce", dce::dce),
+    ("ssa", ssa::ssa),
+];
+
+// Runs a pipeline of passes over the quads, verifying them before the first pass and after every
+// one so a pass that breaks the IR is named rather than showing up as bad code later. The IR
+// after any pass can be dumped in the textual format of ir.
+//
+//   --passes=fold,cse,dce   the pipeline, in order, a pass may appear more than once
+//   --dump-after=cse        write the IR after every run of these passes
+//
+// A dump after ssa shows the SSA form, phis and versioned names, before it is taken back out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PassManager {
+    pub pipeline: Vec<&'static str>,
+    pub dump_after: HashSet<&'static str>,
+}
+
+impl PassManager {
+    pub fn new(pipeline: &[&str]) -> Result<Self, String> {
+        Ok(PassManager {
+            pipeline: pipeline
+                .iter()
+                .map(|name| PassManager::pass(name))
+                .collect::<Result<_, _>>()?,
+            dump_after: HashSet::new(),
+        })
+    }
+
+    // Take a pipeline option, false when the argument is not one
+    pub fn option(&mut self, arg: &str) -> Result<bool, String> {
+        let names = |list: &str| {
+            list.split(',')
+                .filter(|name| !name.is_empty())
+                .map(PassManager::pass)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        if let Some(list) = arg.strip_prefix("--passes=") {
+            self.pipeline = names(list)?;
+        } else if let Some(list) = arg.strip_prefix("--dump-after=") {
+            self.dump_after.extend(names(list)?);
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub fn run(&self, quads: QuadList, dump: &mut dyn Write) -> Result<QuadList, String> {
+        if let Some(name) = self
+            .dump_after
+            .iter()
+            .find(|name| !self.pipeline.contains(name))
+        {
+            return Err(format!("[ Error ] Dump after {}, which is not run", name));
+        }
+        verify(&quads).map_err(|e| format!("[ Error ] Before the first pass: {}", e))?;
+        let mut quads = quads;
+        for name in &self.pipeline {
+            let dumped = self.dump_after.contains(name);
+            let mut text = String::new();
+            quads = match *name {
+                "ssa" if dumped => {
+                    let cfgs = ssa::to_ssa(quads);
+                    text = cfgs.iter().map(|cfg| cfg.to_string()).collect();
+                    ssa::from_ssa(cfgs)
+                }
+                _ => {
+                    let (_, pass) = PASSES.iter().find(|(pass, _)| pass == name).unwrap();
+                    pass(quads)
+                }
+            };
+            verify(&quads).map_err(|e| format!("[ Error ] After {}: {}", name, e))?;
+            if dumped {
+                if text.is_empty() {
+                    text = ir::to_text(&quads);
+                }
+                dump.write_fmt(format_args!("; after {}\n{}", name, text))
+                    .map_err(|e| format!("[ Error ] Could not dump the IR: {}", e))?;
+            }
+        }
+        Ok(quads)
+    }
+
+    fn pass(name: &str) -> Result<&'static str, String> {
+        PASSES
+            .iter()
+            .map(|(pass, _)| *pass)
+            .find(|pass| *pass == name)
+            .ok_or(format!("[ Error ] Unknown pass: {}", name))
+    }
+}
+
+// Structural invariants every pass keeps: the operands of each quad fit its opcode, every temp is
+// written by a quad before the first one reading it, every label is defined once and every jump,
+// branch and call has a target.
+pub fn verify(quads: &[Quad]) -> Result<(), String> {
+    let mut labels = HashSet::new();
+    let mut procedures = HashSet::new();
+    for quad in quads {
+        ir::typed(quad)?;
+        let target = quad.target_name().map(String::from);
+        let defined = match quad.op {
+            Opcode::Label => &mut labels,
+            Opcode::Procedure => &mut procedures,
+            _ => continue,
+        };
+        if !defined.insert(target.unwrap()) {
+            return Err(format!("Label defined twice: {}", quad_text(quad)));
+        }
+    }
+
+    let mut defined = HashSet::new();
+    for quad in quads {
+        let unresolved = match quad.op {
+            Opcode::Jump | Opcode::Branch(_) => !labels.contains(quad.target_name().unwrap()),
+            Opcode::Call => !procedures.contains(quad.target_name().unwrap()),
+            _ => false,
+        };
+        if unresolved {
+            return Err(format!("Undefined label: {}", quad_text(quad)));
+        }
+        for operand in reads(quad) {
+            if let Operand::Temp(name) = operand {
+                if !defined.contains(name) {
+                    return Err(format!(
+                        "{} used before it is defined: {}",
+                        name,
+                        quad_text(quad)
+                    ));
+                }
+            }
+        }
+        if let Some(Operand::Temp(name)) = &quad.dest {
+            defined.insert(name.clone());
+        }
+    }
+    Ok(())
+}
+
+fn quad_text(quad: &Quad) -> String {
+    ir::to_text(std::slice::from_ref(quad)).trim().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::backend::{sample, Relation};
+
+    #[test]
+    fn test_manager_options() {
+        let mut manager = PassManager::default();
+
+        assert_eq!(manager.option("--passes=fold,cse,dce"), Ok(true));
+        assert_eq!(manager.option("--dump-after=cse"), Ok(true));
+        assert_eq!(manager.option("input.java"), Ok(false));
+        assert_eq!(manager.pipeline, ["fold", "cse", "dce"]);
+        assert_eq!(manager.dump_after, HashSet::from(["cse"]));
+        assert_eq!(
+            manager.option("--passes=fold,gvn"),
+            Err(String::from("[ Error ] Unknown pass: gvn"))
+        );
+        assert_eq!(
+            PassManager::new(&["fold", "cse", "dce"]).unwrap().pipeline,
+            manager.pipeline
+        );
+    }
+
+    #[test]
+    fn test_manager_pipeline() {
+        let run = |pipeline: &[&str]| {
+            PassManager::new(pipeline)
+                .unwrap()
+                .run(sample::quads(), &mut Vec::new())
+                .unwrap()
+        };
+
+        // All passes are off by default, so the quads match the polish one for one
+        assert_eq!(run(&[]), sample::quads());
+        // Nothing in the sample has two literal operands, an identity, a repeated expression,
+        // a dead store or a loop invariant
+        assert_eq!(run(&["fold", "cse", "licm", "dce"]), sample::quads());
+        // Only b * 2 and b / 2 change, into shifts
+        let changed = sample::quads()
+            .into_iter()
+            .zip(run(&["fold", "cse", "licm", "strength", "dce"]))
+            .filter(|(before, after)| before != after)
+            .count();
+        assert_eq!(changed, 2);
+    }
+
+    #[test]
+    fn test_manager_dump() {
+        // GET(a); b = a * 1; c = a * 1; PUT(c);
+        let quads = vec![
+            Quad::get(var("a")),
+            Quad::binary(Opcode::Mul, var("a"), num(1), temp(1)),
+            Quad::assign(temp(1), var("b")),
+            Quad::binary(Opcode::Mul, var("a"), num(1), temp(2)),
+            Quad::assign(temp(2), var("c")),
+            Quad::put(var("c")),
+        ];
+        let mut manager = PassManager::new(&["fold", "cse", "dce"]).unwrap();
+        manager.option("--dump-after=fold,dce").unwrap();
+        let mut dump = Vec::new();
+
+        let optimised = manager.run(quads, &mut dump).unwrap();
+
+        assert_eq!(
+            optimised,
+            [
+                Quad::get(var("a")),
+                Quad::assign(var("a"), var("c")),
+                Quad::put(var("c")),
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            "; after fold\n\
+             \x20   get $a\n\
+             \x20   copy $b, $a\n\
+             \x20   copy $c, $a\n\
+             \x20   put $c\n\
+             ; after dce\n\
+             \x20   get $a\n\
+             \x20   copy $c, $a\n\
+             \x20   put $c\n"
+        );
+        manager.dump_after.insert("licm");
+        assert_eq!(
+            manager.run(sample::quads(), &mut Vec::new()),
+            Err(String::from("[ Error ] Dump after licm, which is not run"))
+        );
+    }
+
+    #[test]
+    fn test_manager_ssa() {
+        // GET(x); IF x > 0 { x = 1; } PUT(x);
+        let quads = vec![
+            Quad::get(var("x")),
+            Quad::branch(Relation::GreaterThan, var("x"), num(0), "L1"),
+            Quad::assign(num(1), var("x")),
+            Quad::label("L1"),
+            Quad::put(var("x")),
+        ];
+        let mut manager = PassManager::new(&["ssa"]).unwrap();
+        manager.option("--dump-after=ssa").unwrap();
+        let mut dump = Vec::new();
+
+        // The edge from the branch to L1 gets a block for its copy, which goes back to x
+        let quads = manager.run(quads, &mut dump).unwrap();
+        assert_eq!(
+            ir::to_text(&quads),
+            "    get $x\n\
+             \x20   br.gt $x, 0, @L2\n\
+             \x20   copy $x, 1\n\
+             \x20   jmp @L1\n\
+             L2:\n\
+             L1:\n\
+             \x20   put $x\n"
+        );
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            "; after ssa\n\
+             ; main\n\
+             ; B0\n\
+             ; B1\n\
+             \x20   get $x.1\n\
+             \x20   br.gt $x.1, 0, @L1\n\
+             ; B2\n\
+             \x20   copy $x.2, 1\n\
+             ; B3\n\
+             L1:\n\
+             \x20   phi $x.3, $x.1, $x.2\n\
+             \x20   put $x.3\n\
+             ; B4\n"
+        );
+    }
+
+    #[test]
+    fn test_manager_verify() {
+        assert_eq!(verify(&sample::quads()), Ok(()));
+        let error = |quads: Vec<Quad>| verify(&quads).unwrap_err();
+
+        assert_eq!(
+            error(vec![
+                Quad::put(temp(1)),
+                Quad::binary(Opcode::Add, var("a"), num(1), temp(1)),
+            ]),
+            "temp_1 used before it is defined: put %temp_1"
+        );
+        assert_eq!(
+            error(vec![Quad::branch(Relation::Equal, var("a"), num(0), "L1")]),
+            "Undefined label: br.eq $a, 0, @L1"
+        );
+        assert_eq!(
+            error(vec![Quad::label("show"), Quad::call("show")]),
+            "Undefined label: call @show"
+        );
+        assert_eq!(
+            error(vec![Quad::label("L1"), Quad::label("L1")]),
+            "Label defined twice: L1:"
+        );
+        assert_eq!(
+            error(vec![Quad::assign(var("a"), num(1))]),
+            "Operands do not fit copy: copy 1, $a"
+        );
+    }
+}
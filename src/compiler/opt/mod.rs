@@ -0,0 +1,64 @@
+use std::collections::HashSet;
+
+use crate::compiler::syntax::{Opcode, Operand, Quad};
+
+pub mod cfg;
+pub mod cse;
+pub mod dataflow;
+pub mod dce;
+pub mod fold;
+pub mod loops;
+pub mod manager;
+pub mod regalloc;
+pub mod ssa;
+pub mod strength;
+
+// `+ - * / << >>`, the quads that compute into a temp
+pub fn is_arith(quad: &Quad) -> bool {
+    quad.op.is_arith()
+}
+
+// Temps are the destinations of arithmetic quads, consume_polish writes each one once
+pub fn temps<'a>(quads: impl IntoIterator<Item = &'a Quad>) -> HashSet<String> {
+    quads
+        .into_iter()
+        .filter(|quad| is_arith(quad))
+        .filter_map(|quad| quad.dest.as_ref())
+        .map(|dest| dest.to_string())
+        .collect()
+}
+
+// Operands a quad reads, GET and the destination of `=` are writes
+pub fn reads(quad: &Quad) -> Vec<&Operand> {
+    match quad.op {
+        Opcode::Get => Vec::new(),
+        _ => quad.left.iter().chain(&quad.right).collect(),
+    }
+}
+
+// Variable or temp a quad stores to
+pub fn writes(quad: &Quad) -> Option<&Operand> {
+    match quad.op {
+        op if op.is_arith() => quad.dest.as_ref(),
+        Opcode::Assign | Opcode::Get => quad.dest.as_ref(),
+        _ => None,
+    }
+}
+
+// Highest number after `prefix` in any name the quads use, SSA versions included. New labels and
+// temps are numbered after it.
+pub fn highest<'a>(quads: impl IntoIterator<Item = &'a Quad>, prefix: &str) -> usize {
+    quads
+        .into_iter()
+        .flat_map(|quad| [&quad.left, &quad.right, &quad.dest])
+        .flatten()
+        .filter(|operand| operand.constant().is_none())
+        .filter_map(|operand| {
+            ssa::base(&operand.to_string())
+                .strip_prefix(prefix)?
+                .parse::<usize>()
+                .ok()
+        })
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,258 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::compiler::opt::cfg::{self, Cfg, ENTRY};
+use crate::compiler::opt::dataflow::{shared, solve, Liveness};
+use crate::compiler::opt::{reads, temps, writes};
+use crate::compiler::syntax::{Opcode, Operand, Quad};
+
+// Linear scan register allocation over live intervals.
+//
+// Quads of a function are numbered in order and every quad has two points, 2 * n where it reads
+// its operands and 2 * n + 1 where it writes its result, so a value read for the last time can hand
+// its register to the result of the same quad. A live interval runs from the first to the last
+// point its name is read, written or live at, intervals that overlap need different registers.
+//
+// Temps always get a register if one is free. Variables are global and procedures read and write
+// them in memory, so only variables of the main program no procedure mentions are candidates,
+// and only once a GET or assignment has given them a value. A register is never shared with
+// a procedure across a CALL, and procedure values live across a CALL stay in memory since the
+// procedure may call itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interval {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    // Points of the CALL quads the interval is live across
+    pub calls: Vec<usize>,
+}
+
+// Register index 0..registers for every name that lives in one, anything missing stays in memory
+pub fn allocate(quads: &[Quad], registers: usize) -> HashMap<String, usize> {
+    let cfgs = cfg::build(quads.to_vec());
+    let liveness = Liveness::new(&cfgs);
+    let temps = temps(quads);
+    let shared = shared(&cfgs);
+
+    let mut allocation = HashMap::new();
+    // Procedures come first out of cfg::build, so this is complete by the time main is allocated
+    let mut clobbered: BTreeSet<usize> = BTreeSet::new();
+    for cfg in &cfgs {
+        let entry = &solve(cfg, &liveness).before[ENTRY];
+        let candidate = |name: &str| match cfg.name {
+            Some(_) => temps.contains(name),
+            None => temps.contains(name) || (!shared.contains(name) && !entry.contains(name)),
+        };
+        let intervals: Vec<Interval> = intervals(cfg, &liveness)
+            .into_iter()
+            .filter(|interval| candidate(&interval.name))
+            .collect();
+
+        let forbidden = match cfg.name {
+            Some(_) => (0..registers).collect(),
+            None => clobbered.clone(),
+        };
+        let assigned = scan(intervals, registers, &forbidden);
+        if cfg.name.is_some() {
+            clobbered.extend(assigned.values().copied());
+        }
+        allocation.extend(assigned);
+    }
+    allocation
+}
+
+// Live interval of every identifier read or written in the function, ordered by start
+pub fn intervals(cfg: &Cfg, liveness: &Liveness) -> Vec<Interval> {
+    let solution = solve(cfg, liveness);
+    let mut ranges: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut extend = |name: &str, point: usize| {
+        let range = ranges.entry(name.to_string()).or_insert((point, point));
+        range.0 = range.0.min(point);
+        range.1 = range.1.max(point);
+    };
+
+    let mut calls = Vec::new();
+    let mut index = 0;
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let live = solution.quads(cfg, liveness, id);
+        for (quad, live) in block.quads.iter().zip(live) {
+            let point = 2 * index;
+            for name in reads(quad).into_iter().filter_map(Operand::storage) {
+                extend(name, point);
+            }
+            if let Some(dest) = writes(quad).and_then(Operand::storage) {
+                extend(dest, point + 1);
+            }
+            // Live after the quad means still needed when the next one reads
+            for name in &live {
+                extend(name, point + 1);
+                extend(name, point + 2);
+            }
+            if quad.op == Opcode::Call {
+                calls.push(point + 1);
+            }
+            index += 1;
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(name, (start, end))| Interval {
+            calls: calls
+                .iter()
+                .copied()
+                .filter(|&call| start < call && call < end)
+                .collect(),
+            name,
+            start,
+            end,
+        })
+        .collect();
+    intervals.sort_by(|a, b| (a.start, &a.name).cmp(&(b.start, &b.name)));
+    intervals
+}
+
+// Hands out registers in order of start. When none is free the interval that ends last, the
+// current one or an active one, goes to memory.
+fn scan(
+    intervals: Vec<Interval>,
+    registers: usize,
+    forbidden: &BTreeSet<usize>,
+) -> HashMap<String, usize> {
+    let mut assigned: HashMap<String, usize> = HashMap::new();
+    let mut active: Vec<(Interval, usize)> = Vec::new();
+    for interval in intervals {
+        active.retain(|(other, _)| other.end >= interval.start);
+
+        let allowed = |reg: &usize| interval.calls.is_empty() || !forbidden.contains(reg);
+        let free = (0..registers)
+            .filter(allowed)
+            .find(|reg| active.iter().all(|(_, used)| used != reg));
+        if let Some(reg) = free {
+            assigned.insert(interval.name.clone(), reg);
+            active.push((interval, reg));
+            continue;
+        }
+
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, reg))| allowed(reg))
+            .max_by_key(|(_, (other, _))| other.end)
+            .filter(|(_, (other, _))| other.end > interval.end)
+            .map(|(index, _)| index);
+        if let Some(index) = victim {
+            let (spilled, reg) = active.remove(index);
+            assigned.remove(&spilled.name);
+            assigned.insert(interval.name.clone(), reg);
+            active.push((interval, reg));
+        }
+    }
+    assigned
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample;
+    use crate::compiler::backend::sample::{temp, var};
+
+    fn sorted(allocation: &HashMap<String, usize>) -> Vec<(&str, usize)> {
+        let mut sorted: Vec<(&str, usize)> = allocation
+            .iter()
+            .map(|(name, reg)| (name.as_str(), *reg))
+            .collect();
+        sorted.sort();
+        sorted
+    }
+
+    #[test]
+    fn test_intervals() {
+        let cfgs = cfg::build(sample::quads());
+        let liveness = Liveness::new(&cfgs);
+        let main: Vec<(String, usize, usize, Vec<usize>)> = intervals(&cfgs[1], &liveness)
+            .into_iter()
+            .map(|i| (i.name, i.start, i.end, i.calls))
+            .collect();
+
+        // GET a, GET b, LABEL L1, > a 0, a - 1, = a, b * 2, = b, JMP L1, LABEL L2, < a b, CALL show,
+        // LABEL L3, b / 2, PUT
+        let interval = |name: &str, start, end, calls: &[usize]| {
+            (name.to_string(), start, end, calls.to_vec())
+        };
+        assert_eq!(
+            main,
+            [
+                interval("a", 1, 22, &[]),
+                interval("b", 3, 26, &[23]),
+                interval("temp_3", 9, 10, &[]),
+                interval("temp_4", 13, 14, &[]),
+                interval("temp_5", 27, 28, &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allocate_sample() {
+        // a and b are read by show, only the temps are candidates
+        let allocation = allocate(&sample::quads(), 4);
+        assert_eq!(
+            sorted(&allocation),
+            [
+                ("temp_1", 0),
+                ("temp_2", 0),
+                ("temp_3", 0),
+                ("temp_4", 0),
+                ("temp_5", 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allocate_pressure() {
+        // GET a; GET b; x = a + b; y = a - b; PUT(x * y); CALL p; PUT(a) where p writes c
+        let quads = vec![
+            Quad::procedure("p"),
+            Quad::binary(Opcode::Add, var("c"), var("c"), temp(1)),
+            Quad::assign(temp(1), var("c")),
+            Quad::ret(),
+            Quad::get(var("a")),
+            Quad::get(var("b")),
+            Quad::binary(Opcode::Add, var("a"), var("b"), temp(2)),
+            Quad::assign(temp(2), var("x")),
+            Quad::binary(Opcode::Sub, var("a"), var("b"), temp(3)),
+            Quad::assign(temp(3), var("y")),
+            Quad::binary(Opcode::Mul, var("x"), var("y"), temp(4)),
+            Quad::put(temp(4)),
+            Quad::call("p"),
+            Quad::put(var("a")),
+        ];
+
+        // Enough registers for everything, a is live across the call so it avoids temp_1's
+        assert_eq!(
+            sorted(&allocate(&quads, 4)),
+            [
+                ("a", 1),
+                ("b", 0),
+                ("temp_1", 0),
+                ("temp_2", 2),
+                ("temp_3", 0),
+                ("temp_4", 0),
+                ("x", 2),
+                ("y", 0)
+            ]
+        );
+        // With two, a lives longest and is the one left in memory
+        assert_eq!(
+            sorted(&allocate(&quads, 2)),
+            [
+                ("b", 0),
+                ("temp_1", 0),
+                ("temp_2", 1),
+                ("temp_3", 0),
+                ("temp_4", 0),
+                ("x", 1),
+                ("y", 0)
+            ]
+        );
+    }
+}
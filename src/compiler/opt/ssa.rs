@@ -0,0 +1,700 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::slice;
+
+use crate::compiler::ir;
+use crate::compiler::opt::cfg::{self, Cfg, ENTRY};
+use crate::compiler::opt::dataflow::{shared, solve, Liveness};
+use crate::compiler::opt::{highest, reads, temps, writes};
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList, TEMP_PREFIX};
+
+// Static single assignment form over the quads.
+//
+// Every candidate name is split into versions `name.N`, each written by exactly one quad or phi,
+// and `name.0` is the value it had before the function started. A `.` can not appear in an
+// identifier so versions never clash with a real name. Candidates are the temps, and in the main
+// program the variables no procedure mentions, since procedures read and write variables in
+// their global storage behind any CALL. Phis are only placed where the name is live, so every phi
+// is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phi {
+    pub dest: Operand,
+    // Value coming in from each predecessor, in the order of Block::preds
+    pub args: Vec<Operand>,
+}
+
+impl fmt::Display for Phi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", ir::phi_line(self))
+    }
+}
+
+// Name a version belongs to
+pub fn base(name: &str) -> &str {
+    name.split('.').next().unwrap()
+}
+
+fn version(of: &Operand, version: usize) -> Operand {
+    of.renamed(format!("{}.{}", of, version))
+}
+
+// Immediate dominator of every block, None for the entry and blocks it can not reach. Cooper,
+// Harvey and Kennedy's iteration over reverse postorder.
+pub fn dominators(cfg: &Cfg) -> Vec<Option<usize>> {
+    let order = postorder(cfg);
+    let mut rank = vec![usize::MAX; cfg.blocks.len()];
+    for (index, &id) in order.iter().enumerate() {
+        rank[id] = index;
+    }
+
+    let mut idom: Vec<Option<usize>> = vec![None; cfg.blocks.len()];
+    idom[ENTRY] = Some(ENTRY);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in order.iter().rev().filter(|&&id| id != ENTRY) {
+            let mut preds = cfg.blocks[id]
+                .preds
+                .iter()
+                .copied()
+                .filter(|&pred| idom[pred].is_some());
+            let Some(first) = preds.next() else {
+                continue;
+            };
+            let new = preds.fold(first, |mut a, mut b| {
+                while a != b {
+                    while rank[a] < rank[b] {
+                        a = idom[a].unwrap();
+                    }
+                    while rank[b] < rank[a] {
+                        b = idom[b].unwrap();
+                    }
+                }
+                a
+            });
+            if idom[id] != Some(new) {
+                idom[id] = Some(new);
+                changed = true;
+            }
+        }
+    }
+    idom[ENTRY] = None;
+    idom
+}
+
+fn postorder(cfg: &Cfg) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut seen = vec![false; cfg.blocks.len()];
+    let mut stack = vec![(ENTRY, 0)];
+    seen[ENTRY] = true;
+    while let Some((id, next)) = stack.pop() {
+        match cfg.blocks[id].succs.get(next) {
+            Some(&succ) => {
+                stack.push((id, next + 1));
+                if !seen[succ] {
+                    seen[succ] = true;
+                    stack.push((succ, 0));
+                }
+            }
+            None => order.push(id),
+        }
+    }
+    order
+}
+
+// Blocks where the dominance of each block ends, the joins its definitions need a phi at
+pub fn frontiers(cfg: &Cfg, idom: &[Option<usize>]) -> Vec<BTreeSet<usize>> {
+    let mut frontiers = vec![BTreeSet::new(); cfg.blocks.len()];
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        if block.preds.len() < 2 || idom[id].is_none() {
+            continue;
+        }
+        for &pred in &block.preds {
+            let mut runner = Some(pred);
+            while let Some(at) = runner.filter(|&at| Some(at) != idom[id]) {
+                frontiers[at].insert(id);
+                runner = idom[at];
+            }
+        }
+    }
+    frontiers
+}
+
+// Into SSA form and back out, the other passes see the names versioned and coalesced again
+pub fn ssa(quads: QuadList) -> QuadList {
+    from_ssa(to_ssa(quads))
+}
+
+// Every function in SSA form, procedures first as cfg::build orders them
+pub fn to_ssa(quads: QuadList) -> Vec<Cfg> {
+    let cfgs = cfg::build(quads);
+    let liveness = Liveness::new(&cfgs);
+    let temps = temps(
+        cfgs.iter()
+            .flat_map(|cfg| cfg.blocks.iter().flat_map(|b| &b.quads)),
+    );
+    let shared = shared(&cfgs);
+
+    cfgs.into_iter()
+        .map(|cfg| {
+            let candidates: HashSet<String> = cfg
+                .blocks
+                .iter()
+                .flat_map(|block| &block.quads)
+                .flat_map(|quad| reads(quad).into_iter().chain(writes(quad)))
+                .filter_map(Operand::storage)
+                .filter(|name| {
+                    temps.contains(*name) || (cfg.name.is_none() && !shared.contains(*name))
+                })
+                .map(String::from)
+                .collect();
+            construct(cfg, &liveness, &candidates)
+        })
+        .collect()
+}
+
+fn construct(mut cfg: Cfg, liveness: &Liveness, candidates: &HashSet<String>) -> Cfg {
+    let idom = dominators(&cfg);
+    let frontiers = frontiers(&cfg, &idom);
+    let live_in = solve(&cfg, liveness).before;
+
+    let mut defs: BTreeMap<&Operand, BTreeSet<usize>> = BTreeMap::new();
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        for dest in block.quads.iter().filter_map(writes) {
+            if candidates.contains(&dest.to_string()) {
+                defs.entry(dest).or_default().insert(id);
+            }
+        }
+    }
+
+    let mut phis: Vec<Vec<Phi>> = vec![Vec::new(); cfg.blocks.len()];
+    for (operand, blocks) in defs {
+        let mut placed = BTreeSet::new();
+        let mut work: Vec<usize> = blocks.iter().copied().collect();
+        while let Some(id) = work.pop() {
+            for &join in &frontiers[id] {
+                if live_in[join].contains(&operand.to_string()) && placed.insert(join) {
+                    phis[join].push(Phi {
+                        dest: operand.clone(),
+                        args: vec![operand.clone(); cfg.blocks[join].preds.len()],
+                    });
+                    if !blocks.contains(&join) {
+                        work.push(join);
+                    }
+                }
+            }
+        }
+    }
+    for (block, phis) in cfg.blocks.iter_mut().zip(phis) {
+        block.phis = phis;
+    }
+
+    let mut children = vec![Vec::new(); cfg.blocks.len()];
+    for (id, dom) in idom.iter().enumerate() {
+        if let Some(dom) = dom {
+            children[*dom].push(id);
+        }
+    }
+    let mut renamer = Renamer {
+        candidates,
+        stacks: HashMap::new(),
+        counts: HashMap::new(),
+    };
+    renamer.rename(&mut cfg, &children, ENTRY);
+    cfg
+}
+
+struct Renamer<'a> {
+    candidates: &'a HashSet<String>,
+    // Version each name has at this point of the walk down the dominator tree
+    stacks: HashMap<String, Vec<Operand>>,
+    counts: HashMap<String, usize>,
+}
+
+impl<'a> Renamer<'a> {
+    // Version of a name the phi or quad at this point reads
+    fn current(&self, of: &Operand) -> Operand {
+        let name = base(&of.to_string()).to_string();
+        match self.stacks.get(&name).and_then(|stack| stack.last()) {
+            Some(operand) => operand.clone(),
+            None => version(&of.renamed(name), 0),
+        }
+    }
+
+    fn define(&mut self, of: &Operand, pushed: &mut Vec<String>) -> Operand {
+        let name = of.to_string();
+        let count = self.counts.entry(name.clone()).or_insert(0);
+        *count += 1;
+        let operand = version(of, *count);
+        self.stacks
+            .entry(name.clone())
+            .or_default()
+            .push(operand.clone());
+        pushed.push(name);
+        operand
+    }
+
+    fn is_candidate(&self, operand: &Operand) -> bool {
+        operand
+            .storage()
+            .is_some_and(|name| self.candidates.contains(name))
+    }
+
+    fn rename(&mut self, cfg: &mut Cfg, children: &[Vec<usize>], id: usize) {
+        let mut pushed = Vec::new();
+        let mut block = std::mem::take(&mut cfg.blocks[id]);
+        for phi in &mut block.phis {
+            phi.dest = self.define(&phi.dest, &mut pushed);
+        }
+        for quad in &mut block.quads {
+            for operand in [&mut quad.left, &mut quad.right].into_iter().flatten() {
+                if self.is_candidate(operand) {
+                    *operand = self.current(operand);
+                }
+            }
+            if writes(quad).is_some_and(|dest| self.is_candidate(dest)) {
+                let dest = quad.dest.as_mut().unwrap();
+                *dest = self.define(dest, &mut pushed);
+            }
+        }
+        for &succ in &block.succs {
+            let index = cfg.blocks[succ]
+                .preds
+                .iter()
+                .position(|&p| p == id)
+                .unwrap();
+            let phis = match succ == id {
+                true => &mut block.phis,
+                false => &mut cfg.blocks[succ].phis,
+            };
+            for phi in phis {
+                phi.args[index] = self.current(&phi.args[index]);
+            }
+        }
+        cfg.blocks[id] = block;
+
+        for &child in &children[id] {
+            self.rename(cfg, children, child);
+        }
+        for name in pushed {
+            self.stacks.get_mut(&name).unwrap().pop();
+        }
+    }
+}
+
+// Orders a parallel copy, every destination gets the value its source had before any of them ran.
+// A copy is safe to emit once nothing still waiting reads its destination, a cycle is broken by
+// saving one destination in `spare` first.
+pub fn sequentialise(
+    copies: Vec<(Operand, Operand)>,
+    spare: &mut dyn FnMut(&Operand) -> Operand,
+) -> Vec<(Operand, Operand)> {
+    let mut pending: Vec<(Operand, Operand)> = copies
+        .into_iter()
+        .filter(|(dest, src)| dest != src)
+        .collect();
+    let mut ordered = Vec::new();
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(dest, _)| pending.iter().all(|(_, src)| src != dest));
+        match ready {
+            Some(index) => ordered.push(pending.remove(index)),
+            None => {
+                let dest = pending[0].0.clone();
+                let saved = spare(&dest);
+                ordered.push((saved.clone(), dest.clone()));
+                for (_, src) in pending.iter_mut().filter(|(_, src)| *src == dest) {
+                    *src = saved.clone();
+                }
+            }
+        }
+    }
+    ordered
+}
+
+// Back to plain quads. Each phi becomes a copy at the end of every predecessor, an edge from a
+// block with two successors gets a block of its own for them. Versions of a name that are never
+// live at the same time share its storage again, the others become new temps numbered after the
+// highest one in use, and copies between the same name are dropped.
+pub fn from_ssa(cfgs: Vec<Cfg>) -> QuadList {
+    let quads = cfgs
+        .iter()
+        .flat_map(|cfg| cfg.blocks.iter().flat_map(|block| &block.quads));
+    let mut labels = highest(quads.clone(), "L");
+    let mut temps = highest(quads, TEMP_PREFIX);
+
+    cfgs.into_iter()
+        .flat_map(|cfg| {
+            let name = cfg.name.clone();
+            let quads = insert_copies(cfg, &mut labels);
+            coalesce(Cfg::new(name, quads), &mut temps)
+        })
+        .collect()
+}
+
+fn insert_copies(cfg: Cfg, labels: &mut usize) -> QuadList {
+    let count = cfg.blocks.len();
+    let mut spares: HashMap<String, usize> = HashMap::new();
+    let mut sequence = |copies: Vec<(Operand, Operand)>| -> Vec<Quad> {
+        let mut spare = |dest: &Operand| {
+            let name = base(&dest.to_string()).to_string();
+            let count = spares.entry(name.clone()).or_insert(0);
+            *count += 1;
+            dest.renamed(format!("{}.spare{}", name, count))
+        };
+        sequentialise(copies, &mut spare)
+            .into_iter()
+            .map(|(dest, src)| Quad::assign(src, dest))
+            .collect()
+    };
+
+    let mut tails: Vec<Vec<Quad>> = vec![Vec::new(); count];
+    let mut heads: Vec<Vec<Quad>> = vec![Vec::new(); count];
+    let mut targets: Vec<Option<String>> = vec![None; count];
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        if block.phis.is_empty() {
+            continue;
+        }
+        let copies = |index: usize| -> Vec<(Operand, Operand)> {
+            block
+                .phis
+                .iter()
+                .map(|phi| (phi.dest.clone(), phi.args[index].clone()))
+                .collect()
+        };
+        let mut falls = id > 0
+            && !cfg.blocks[id - 1]
+                .quads
+                .last()
+                .is_some_and(|last| matches!(last.op, Opcode::Jump | Opcode::Ret));
+
+        // Edges from a block that branches, fallthrough first so it stays next to the block
+        let mut preds: Vec<(usize, usize)> = block.preds.iter().copied().enumerate().collect();
+        preds.sort_by_key(|&(_, pred)| pred + 1 != id);
+        for (index, pred) in preds {
+            if cfg.blocks[pred].succs.len() == 1 {
+                tails[pred].extend(sequence(copies(index)));
+            } else if pred + 1 == id {
+                heads[id].extend(sequence(copies(index)));
+            } else {
+                *labels += 1;
+                let label = format!("L{}", labels);
+                if falls {
+                    heads[id].push(Quad::jump(block.quads[0].target_name().unwrap()));
+                }
+                heads[id].push(Quad::label(&label));
+                heads[id].extend(sequence(copies(index)));
+                targets[pred] = Some(label);
+                falls = true;
+            }
+        }
+    }
+
+    let mut quads = Vec::new();
+    for (((block, head), tail), target) in cfg.blocks.into_iter().zip(heads).zip(tails).zip(targets)
+    {
+        quads.extend(head);
+        let mut body = block.quads;
+        let jumps = body
+            .last()
+            .is_some_and(|last| matches!(last.op, Opcode::Jump | Opcode::Branch(_)));
+        let last = match jumps {
+            true => body.pop(),
+            false => None,
+        };
+        quads.extend(body);
+        quads.extend(tail);
+        if let Some(mut last) = last {
+            if let Some(target) = target {
+                last.dest = Some(Operand::Label(target));
+            }
+            quads.push(last);
+        }
+    }
+    quads
+}
+
+fn coalesce(cfg: Cfg, temps: &mut usize) -> QuadList {
+    let liveness = Liveness::new(slice::from_ref(&cfg));
+    let solution = solve(&cfg, &liveness);
+
+    // Versions of the same name live at the same time
+    let mut interferes: HashSet<(String, String)> = HashSet::new();
+    let mut versions: BTreeMap<String, BTreeSet<(usize, Operand)>> = BTreeMap::new();
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let live = solution.quads(&cfg, &liveness, id);
+        for (quad, live) in block.quads.iter().zip(live) {
+            let copied = match quad.op {
+                Opcode::Assign => quad.left.as_ref().map(Operand::to_string),
+                _ => None,
+            };
+            let Some(dest) = writes(quad)
+                .map(Operand::to_string)
+                .filter(|dest| dest.contains('.'))
+            else {
+                continue;
+            };
+            for other in &live {
+                if other != &dest && Some(other) != copied.as_ref() && base(other) == base(&dest) {
+                    interferes.insert((dest.clone(), other.clone()));
+                    interferes.insert((other.clone(), dest.clone()));
+                }
+            }
+        }
+        for quad in &block.quads {
+            for operand in reads(quad).into_iter().chain(writes(quad)) {
+                let Some(full) = operand.storage() else {
+                    continue;
+                };
+                if let Some((name, number)) = full.split_once('.') {
+                    // Spares sort after every numbered version
+                    let number = number.parse::<usize>().unwrap_or(usize::MAX);
+                    versions
+                        .entry(name.to_string())
+                        .or_default()
+                        .insert((number, operand.clone()));
+                }
+            }
+        }
+    }
+
+    let mut names: HashMap<String, Operand> = HashMap::new();
+    for (name, versions) in versions {
+        let mut shared: Vec<String> = Vec::new();
+        for (_, version) in versions {
+            let key = version.to_string();
+            let clash = shared
+                .iter()
+                .any(|other| interferes.contains(&(key.clone(), other.clone())));
+            if clash {
+                *temps += 1;
+                names.insert(key, Operand::temp(*temps));
+            } else {
+                names.insert(key.clone(), version.renamed(name.clone()));
+                shared.push(key);
+            }
+        }
+    }
+
+    let rename = |operand: &mut Option<Operand>| {
+        let name = operand.as_ref().and_then(|operand| operand.storage());
+        if let Some(renamed) = name.and_then(|name| names.get(name)) {
+            *operand = Some(renamed.clone());
+        }
+    };
+    cfg.into_quads()
+        .into_iter()
+        .map(|mut quad| {
+            rename(&mut quad.left);
+            rename(&mut quad.right);
+            rename(&mut quad.dest);
+            quad
+        })
+        .filter(|quad| !(quad.op == Opcode::Assign && quad.left == quad.dest))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::backend::{sample, Relation};
+    use crate::compiler::ir;
+
+    // GET(n); s = 0; WHILE n > 0 { s = s + n; n = n - 1; } PUT(s);
+    fn sum() -> QuadList {
+        vec![
+            Quad::get(var("n")),
+            Quad::assign(num(0), var("s")),
+            Quad::label("L1"),
+            Quad::branch(Relation::GreaterThan, var("n"), num(0), "L2"),
+            Quad::binary(Opcode::Add, var("s"), var("n"), temp(1)),
+            Quad::assign(temp(1), var("s")),
+            Quad::binary(Opcode::Sub, var("n"), num(1), temp(2)),
+            Quad::assign(temp(2), var("n")),
+            Quad::jump("L1"),
+            Quad::label("L2"),
+            Quad::put(var("s")),
+        ]
+    }
+
+    #[test]
+    fn test_dominators() {
+        let cfgs = cfg::build(sample::quads());
+        let main = &cfgs[1];
+        let idom = dominators(main);
+        assert_eq!(
+            idom,
+            [
+                None,
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(2),
+                Some(4),
+                Some(4),
+                Some(6)
+            ]
+        );
+
+        let frontiers: Vec<Vec<usize>> = frontiers(main, &idom)
+            .into_iter()
+            .map(|frontier| frontier.into_iter().collect())
+            .collect();
+        let empty: Vec<usize> = Vec::new();
+        assert_eq!(
+            frontiers,
+            [
+                empty.clone(),
+                empty.clone(),
+                vec![2],
+                vec![2],
+                empty.clone(),
+                vec![6],
+                empty.clone(),
+                empty
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ssa_loop() {
+        let ssa = to_ssa(sum());
+        assert_eq!(
+            ssa[0].to_string(),
+            "; main\n\
+             ; B0\n\
+             ; B1\n\
+             \x20   get $n.1\n\
+             \x20   copy $s.1, 0\n\
+             ; B2\n\
+             L1:\n\
+             \x20   phi $n.2, $n.1, $n.3\n\
+             \x20   phi $s.2, $s.1, $s.3\n\
+             \x20   br.gt $n.2, 0, @L2\n\
+             ; B3\n\
+             \x20   add %temp_1.1, $s.2, $n.2\n\
+             \x20   copy $s.3, %temp_1.1\n\
+             \x20   sub %temp_2.1, $n.2, 1\n\
+             \x20   copy $n.3, %temp_2.1\n\
+             \x20   jmp @L1\n\
+             ; B4\n\
+             L2:\n\
+             \x20   put $s.2\n\
+             ; B5\n"
+        );
+        assert_eq!(ir::parse_ssa(&ssa[0].to_string()), Ok(ssa.clone()));
+
+        let mut dot = Vec::new();
+        cfg::write_dot(&ssa, &mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("[label=\"B2\\lL1:\\lphi $n.2, $n.1, $n.3\\lphi $s.2, $s.1, $s.3\\l"));
+
+        assert_eq!(from_ssa(ssa), sum());
+    }
+
+    #[test]
+    fn test_ssa_sample() {
+        // a and b are read by show, only the temps are versioned
+        let ssa = to_ssa(sample::quads());
+        assert!(ssa[1]
+            .to_string()
+            .contains("    sub %temp_3.1, $a, 1\n    copy $a, %temp_3.1\n"));
+        assert!(ssa
+            .iter()
+            .all(|cfg| cfg.blocks.iter().all(|b| b.phis.is_empty())));
+        assert_eq!(from_ssa(ssa), sample::quads());
+    }
+
+    #[test]
+    fn test_ssa_critical_edge() {
+        // GET(x); IF x > 0 { x = 1; } PUT(x);
+        let quads = vec![
+            Quad::get(var("x")),
+            Quad::branch(Relation::GreaterThan, var("x"), num(0), "L1"),
+            Quad::assign(num(1), var("x")),
+            Quad::label("L1"),
+            Quad::put(var("x")),
+        ];
+        let ssa = to_ssa(quads);
+        assert_eq!(
+            ssa[0].blocks[3].phis[0].to_string(),
+            "    phi $x.3, $x.1, $x.2\n"
+        );
+
+        // The edge from the branch straight to L1 gets its own block, the copies on both sides
+        // are between versions that all go back to x
+        assert_eq!(
+            ir::to_text(&from_ssa(ssa)),
+            "    get $x\n\
+             \x20   br.gt $x, 0, @L2\n\
+             \x20   copy $x, 1\n\
+             \x20   jmp @L1\n\
+             L2:\n\
+             L1:\n\
+             \x20   put $x\n"
+        );
+    }
+
+    #[test]
+    fn test_ssa_interference() {
+        // Two versions of y live at once, after copy propagation of y.2 = y.1 into the PUT
+        let quads = vec![
+            Quad::get(var("y")),
+            Quad::binary(Opcode::Add, var("y"), num(1), temp(1)),
+            Quad::assign(temp(1), var("y")),
+            Quad::put(var("y")),
+        ];
+        let mut ssa = to_ssa(quads);
+        ssa[0].blocks[1].quads[3].left = Some(var("y.1"));
+        ssa[0].blocks[1].quads.push(Quad::put(var("y.2")));
+
+        assert_eq!(
+            ir::to_text(&from_ssa(ssa)),
+            "    get $y\n\
+             \x20   add %temp_1, $y, 1\n\
+             \x20   copy %temp_2, %temp_1\n\
+             \x20   put $y\n\
+             \x20   put %temp_2\n"
+        );
+    }
+
+    #[test]
+    fn test_sequentialise() {
+        let copies = |pairs: &[(&str, &str)]| -> Vec<(Operand, Operand)> {
+            pairs
+                .iter()
+                .map(|(dest, src)| (var(dest), var(src)))
+                .collect()
+        };
+        let names = |copies: Vec<(Operand, Operand)>| -> Vec<(String, String)> {
+            copies
+                .into_iter()
+                .map(|(dest, src)| (dest.to_string(), src.to_string()))
+                .collect()
+        };
+        let mut spare = |_: &Operand| var("t");
+
+        // b has to be read before it is overwritten
+        let ordered = sequentialise(copies(&[("b", "a"), ("c", "b"), ("d", "d")]), &mut spare);
+        assert_eq!(
+            names(ordered),
+            [
+                ("c".to_string(), "b".to_string()),
+                ("b".to_string(), "a".to_string())
+            ]
+        );
+
+        // A swap goes through the spare
+        let ordered = sequentialise(copies(&[("a", "b"), ("b", "a")]), &mut spare);
+        assert_eq!(
+            names(ordered),
+            [
+                ("t".to_string(), "a".to_string()),
+                ("a".to_string(), "b".to_string()),
+                ("b".to_string(), "t".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::compiler::opt::cfg::{self, Cfg};
+use crate::compiler::opt::dataflow::{summaries, Summary};
+use crate::compiler::opt::loops::{has_preheader, loops, written_once, Loop};
+use crate::compiler::opt::{highest, temps, writes};
+use crate::compiler::syntax::{Opcode, Operand, Quad, QuadList, MAX_SHIFT, TEMP_PREFIX};
+
+// Strength reduction, cheaper instructions for the same result.
+//
+// A variable whose only change in a loop is `i = i + c` or `i = i - c` is an induction variable,
+// and `i * d` for a literal d then goes up by c * d every trip. Such a multiplication becomes a new
+// variable set to i * d in the preheader and bumped right after every update of i, so the loop
+// adds where it multiplied. Afterwards a multiplication by a power of two becomes a shift left and
+// a division by one a shift right, `<<` and `>>` keep the operand on the left and the number of
+// bits on the right.
+pub fn strength(quads: QuadList) -> QuadList {
+    induction(quads).into_iter().map(shift).collect()
+}
+
+// k for a literal 2^k above 1, as long as a shift can move by k bits
+fn power(operand: &Option<Operand>) -> Option<i32> {
+    match operand.as_ref()?.constant()? {
+        value if value > 1 && value.count_ones() == 1 => {
+            Some(value.trailing_zeros() as i32).filter(|k| *k <= MAX_SHIFT)
+        }
+        _ => None,
+    }
+}
+
+fn shift(quad: Quad) -> Quad {
+    let bits = |bits: i32| Some(Operand::Const(bits));
+    match quad.op {
+        Opcode::Mul => match (power(&quad.right), power(&quad.left)) {
+            (Some(k), _) => Quad {
+                op: Opcode::Shl,
+                right: bits(k),
+                ..quad
+            },
+            (None, Some(k)) => Quad {
+                op: Opcode::Shl,
+                left: quad.right,
+                right: bits(k),
+                dest: quad.dest,
+            },
+            (None, None) => quad,
+        },
+        Opcode::Div => match power(&quad.right) {
+            Some(k) => Quad {
+                op: Opcode::Shr,
+                right: bits(k),
+                ..quad
+            },
+            None => quad,
+        },
+        _ => quad,
+    }
+}
+
+// `i = i + c` or `i = i - c` as the step c, from the arithmetic quad and the copy after it
+fn step(arith: &Quad, copy: &Quad) -> Option<i32> {
+    let i = copy.dest.as_ref();
+    if copy.op != Opcode::Assign || arith.dest != copy.left {
+        return None;
+    }
+    let literal = |operand: &Option<Operand>| operand.as_ref()?.constant();
+    match arith.op {
+        Opcode::Add if arith.left.as_ref() == i => literal(&arith.right),
+        Opcode::Add if arith.right.as_ref() == i => literal(&arith.left),
+        Opcode::Sub if arith.left.as_ref() == i => literal(&arith.right)?.checked_neg(),
+        _ => None,
+    }
+}
+
+// Reduces the multiplications of induction variables, one loop of every function at a time until
+// no loop has any
+fn induction(quads: QuadList) -> QuadList {
+    let mut quads = quads;
+    loop {
+        let once = written_once(&quads);
+        let temps = temps(&quads);
+        let mut fresh = highest(&quads, TEMP_PREFIX);
+        let cfgs = cfg::build(quads.clone());
+        let summaries = summaries(&cfgs);
+        let context = Context {
+            summaries: &summaries,
+            once: &once,
+            temps: &temps,
+        };
+        let mut changed = false;
+        quads = cfgs
+            .into_iter()
+            .flat_map(|cfg| {
+                for lp in loops(&cfg).iter().filter(|lp| has_preheader(&cfg, lp)) {
+                    if let Some(quads) = reduce(&cfg, lp, &context, &mut fresh) {
+                        changed = true;
+                        return quads;
+                    }
+                }
+                cfg.into_quads()
+            })
+            .collect();
+        if !changed {
+            return quads;
+        }
+    }
+}
+
+struct Context<'a> {
+    summaries: &'a HashMap<String, Summary>,
+    once: &'a HashSet<String>,
+    temps: &'a HashSet<String>,
+}
+
+fn reduce(cfg: &Cfg, lp: &Loop, context: &Context, fresh: &mut usize) -> Option<QuadList> {
+    let quads: Vec<((usize, usize), &Quad)> = lp
+        .body
+        .iter()
+        .flat_map(|&id| {
+            cfg.blocks[id]
+                .quads
+                .iter()
+                .enumerate()
+                .map(move |(index, quad)| ((id, index), quad))
+        })
+        .collect();
+
+    // The one update of every induction variable, where its copy is and the step
+    let mut updates: HashMap<&str, Vec<(usize, usize)>> = HashMap::new();
+    for &(at, quad) in &quads {
+        if let Some(dest) = writes(quad).and_then(Operand::storage) {
+            updates.entry(dest).or_default().push(at);
+        }
+    }
+    let called: HashSet<&str> = quads
+        .iter()
+        .filter(|(_, quad)| quad.op == Opcode::Call)
+        .filter_map(|(_, quad)| context.summaries.get(quad.target_name()?))
+        .flat_map(|summary| summary.writes.iter().map(String::as_str))
+        .collect();
+    let mut steps: HashMap<&str, ((usize, usize), i32)> = HashMap::new();
+    for (name, at) in updates {
+        let (id, index) = match at[..] {
+            [(id, index)] if index > 0 => (id, index),
+            _ => continue,
+        };
+        let arith = &cfg.blocks[id].quads[index - 1];
+        if context.temps.contains(name)
+            || called.contains(name)
+            || !arith
+                .dest
+                .as_ref()
+                .is_some_and(|temp| context.once.contains(&temp.to_string()))
+        {
+            continue;
+        }
+        if let Some(step) = step(arith, &cfg.blocks[id].quads[index]) {
+            steps.insert(name, ((id, index), step));
+        }
+    }
+
+    // Multiplications of them by a literal whose temp is read nowhere else
+    let mut reduced: BTreeMap<(&str, i32), Vec<(usize, usize)>> = BTreeMap::new();
+    for &(at, quad) in &quads {
+        let (Opcode::Mul, Some(left), Some(right), Some(temp)) =
+            (quad.op, &quad.left, &quad.right, &quad.dest)
+        else {
+            continue;
+        };
+        if !context.once.contains(&temp.to_string()) {
+            continue;
+        }
+        for (i, d) in [(left, right), (right, left)] {
+            match (i, steps.get(i.to_string().as_str()), d.constant()) {
+                (Operand::Var(i) | Operand::Temp(i), Some((_, step)), Some(factor))
+                    if step
+                        .checked_mul(factor)
+                        .is_some_and(|bump| i16::try_from(bump).is_ok()) =>
+                {
+                    reduced.entry((i.as_str(), factor)).or_default().push(at);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    if reduced.is_empty() {
+        return None;
+    }
+
+    let mut temp = || {
+        *fresh += 1;
+        Operand::temp(*fresh)
+    };
+
+    // Quads to put in the preheader, after an index and in place of the multiplications' temps
+    let mut preheader = Vec::new();
+    let mut after: HashMap<(usize, usize), Vec<Quad>> = HashMap::new();
+    let mut dropped: HashSet<(usize, usize)> = HashSet::new();
+    let mut renamed: HashMap<String, Operand> = HashMap::new();
+    for ((i, factor), ats) in reduced {
+        let (update, step) = steps[i];
+        let (j, start, bump) = (temp(), temp(), temp());
+        preheader.push(Quad::binary(
+            Opcode::Mul,
+            Operand::var(i),
+            Operand::Const(factor),
+            start.clone(),
+        ));
+        preheader.push(Quad::assign(start, j.clone()));
+        let next = after.entry(update).or_default();
+        next.push(Quad::binary(
+            Opcode::Add,
+            j.clone(),
+            Operand::Const(step * factor),
+            bump.clone(),
+        ));
+        next.push(Quad::assign(bump, j.clone()));
+        for (id, index) in ats {
+            let name = cfg.blocks[id].quads[index]
+                .dest
+                .as_ref()
+                .unwrap()
+                .to_string();
+            renamed.insert(name, j.clone());
+            dropped.insert((id, index));
+        }
+    }
+
+    let mut reduced = Vec::new();
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        if id == lp.header {
+            reduced.append(&mut preheader);
+        }
+        for (index, quad) in block.quads.iter().enumerate() {
+            if dropped.contains(&(id, index)) {
+                continue;
+            }
+            let mut quad = quad.clone();
+            for operand in [&mut quad.left, &mut quad.right].into_iter().flatten() {
+                if let Some(j) = renamed.get(&operand.to_string()) {
+                    *operand = j.clone();
+                }
+            }
+            reduced.push(quad);
+            reduced.extend(after.remove(&(id, index)).unwrap_or_default());
+        }
+    }
+    Some(reduced)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::backend::sample::{num, temp, var};
+    use crate::compiler::backend::{sample, Relation};
+    use crate::compiler::ir;
+
+    #[test]
+    fn test_shifts() {
+        let quads = vec![
+            // PUT(x * 8); PUT(4 * x); PUT(x / 16); PUT(2 / x); PUT(x * 6); PUT(x / -4);
+            // PUT(x * 65536), a shift moves by 15 bits at most
+            Quad::binary(Opcode::Mul, var("x"), num(8), temp(1)),
+            Quad::put(temp(1)),
+            Quad::binary(Opcode::Mul, num(4), var("x"), temp(2)),
+            Quad::put(temp(2)),
+            Quad::binary(Opcode::Div, var("x"), num(16), temp(3)),
+            Quad::put(temp(3)),
+            Quad::binary(Opcode::Div, num(2), var("x"), temp(4)),
+            Quad::put(temp(4)),
+            Quad::binary(Opcode::Mul, var("x"), num(6), temp(5)),
+            Quad::put(temp(5)),
+            Quad::binary(Opcode::Div, var("x"), num(-4), temp(6)),
+            Quad::put(temp(6)),
+            Quad::binary(Opcode::Mul, var("x"), num(65536), temp(7)),
+            Quad::put(temp(7)),
+        ];
+
+        assert_eq!(
+            ir::to_text(&strength(quads)),
+            "    shl %temp_1, $x, 3\n\
+             \x20   put %temp_1\n\
+             \x20   shl %temp_2, $x, 2\n\
+             \x20   put %temp_2\n\
+             \x20   shr %temp_3, $x, 4\n\
+             \x20   put %temp_3\n\
+             \x20   div %temp_4, 2, $x\n\
+             \x20   put %temp_4\n\
+             \x20   mul %temp_5, $x, 6\n\
+             \x20   put %temp_5\n\
+             \x20   div %temp_6, $x, -4\n\
+             \x20   put %temp_6\n\
+             \x20   mul %temp_7, $x, 65536\n\
+             \x20   put %temp_7\n"
+        );
+    }
+
+    #[test]
+    fn test_strength_sample() {
+        // b * 2 and b / 2 shift, b doubles in the loop so it is no induction variable
+        assert_eq!(
+            ir::to_text(&strength(sample::quads()))
+                .lines()
+                .filter(|line| line.contains("shl") || line.contains("shr"))
+                .collect::<Vec<_>>(),
+            ["    shl %temp_4, $b, 1", "    shr %temp_5, $b, 1"]
+        );
+    }
+
+    #[test]
+    fn test_induction() {
+        let quads = vec![
+            // i = 0; WHILE i < n { PUT(i * 3); PUT(a * 5); x = i * 3; i = i + 2 }
+            Quad::assign(num(0), var("i")),
+            Quad::label("L1"),
+            Quad::branch(Relation::LessThan, var("i"), var("n"), "L2"),
+            Quad::binary(Opcode::Mul, var("i"), num(3), temp(1)),
+            Quad::put(temp(1)),
+            Quad::binary(Opcode::Mul, var("a"), num(5), temp(2)),
+            Quad::put(temp(2)),
+            Quad::binary(Opcode::Mul, num(3), var("i"), temp(3)),
+            Quad::assign(temp(3), var("x")),
+            Quad::binary(Opcode::Add, var("i"), num(2), temp(4)),
+            Quad::assign(temp(4), var("i")),
+            Quad::jump("L1"),
+            Quad::label("L2"),
+        ];
+
+        // Both i * 3 read one new variable, a is not an induction variable
+        assert_eq!(
+            ir::to_text(&strength(quads)),
+            "    copy $i, 0\n\
+             \x20   mul %temp_6, $i, 3\n\
+             \x20   copy %temp_5, %temp_6\n\
+             L1:\n\
+             \x20   br.lt $i, $n, @L2\n\
+             \x20   put %temp_5\n\
+             \x20   mul %temp_2, $a, 5\n\
+             \x20   put %temp_2\n\
+             \x20   copy $x, %temp_5\n\
+             \x20   add %temp_4, $i, 2\n\
+             \x20   copy $i, %temp_4\n\
+             \x20   add %temp_7, %temp_5, 6\n\
+             \x20   copy %temp_5, %temp_7\n\
+             \x20   jmp @L1\n\
+             L2:\n"
+        );
+    }
+
+    #[test]
+    fn test_induction_call() {
+        let quads = vec![
+            // PROCEDURE p { i = i + 1 }; WHILE i < 9 { PUT(i * 3); CALL p; i = i - 1 }
+            Quad::procedure("p"),
+            Quad::binary(Opcode::Add, var("i"), num(1), temp(1)),
+            Quad::assign(temp(1), var("i")),
+            Quad::ret(),
+            Quad::label("L1"),
+            Quad::branch(Relation::LessThan, var("i"), num(9), "L2"),
+            Quad::binary(Opcode::Mul, var("i"), num(3), temp(2)),
+            Quad::put(temp(2)),
+            Quad::call("p"),
+            Quad::binary(Opcode::Sub, var("i"), num(1), temp(3)),
+            Quad::assign(temp(3), var("i")),
+            Quad::jump("L1"),
+            Quad::label("L2"),
+        ];
+
+        // p changes i behind the loop's back
+        assert_eq!(strength(quads.clone()), quads);
+    }
+}
@@ -1,9 +1,9 @@
-use std::fs::File;
 use std::io::{self, BufRead};
-use std::path::Path;
 
 use crate::boolean::matrix::{self, Matrix};
 
+const HANDLES: &str = include_str!("fsa_tables/handles.txt");
+
 pub trait PrecedenceGrammar {
     fn new() -> Self;
     fn parse_input(&mut self, compute_handles: bool);
@@ -85,19 +85,12 @@ impl PrecedenceGrammar for OPG {
     }
 
     fn parse_input(&mut self, compute_handles: bool) {
-        let path = Path::new("src/compiler/fsa_tables/handles.txt");
-        let path_string = path.display();
-
-        let in_file = match File::open(&path) {
-            Err(e) => panic!("[ Error ] Trouble locating {}, {}", path_string, e),
-            Ok(file) => io::BufReader::new(file),
-        };
-
-        let mut lines = in_file.lines();
+        // Built into the binary, so the grammar does not depend on the current directory
+        let mut lines = HANDLES.lines();
 
         let mut matrix: Matrix = Vec::new();
         while let Some(line) = lines.next() {
-            let matrix_dim = line.unwrap();
+            let matrix_dim = line;
 
             // Skip any blank lines in between the matrices
             if matrix_dim.is_empty() {
@@ -118,15 +111,13 @@ impl PrecedenceGrammar for OPG {
 
             self.m_dimension = x as usize;
 
-            let matrix_name: &str = &lines.next().unwrap().ok().unwrap().to_lowercase();
+            let matrix_name: &str = &lines.next().unwrap().to_lowercase();
 
             for _ in 0..x {
                 matrix.push(
                     lines
                         .next()
                         .unwrap()
-                        .unwrap()
-                        .trim()
                         .split_whitespace()
                         .map(|x| -> bool {
                             match x.parse::<u32>().ok().unwrap() {
@@ -287,7 +278,6 @@ impl PrecedenceGrammar for SPG {
                         .next()
                         .unwrap()
                         .unwrap()
-                        .trim()
                         .split_whitespace()
                         .map(|x| -> bool {
                             match x.parse::<u32>().ok().unwrap() {
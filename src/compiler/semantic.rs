@@ -0,0 +1,214 @@
+use crate::compiler::diagnostics::Diagnostic;
+use crate::compiler::lexical::{Token, TokenClass};
+use crate::compiler::opt::{cfg, dataflow};
+use crate::compiler::syntax::Quad;
+
+// A name from a VAR or CONST list, used once anything outside the declarations refers to it
+struct Declaration<'a> {
+    token: &'a Token,
+    kind: &'a str,
+    used: bool,
+}
+
+// Checks on the names a program declares and uses. Declaring a name twice or using one that was
+// never declared is an error, every undeclared use gets its own. Declaring a name that is never
+// used is a warning. Class, procedure and call names are not variables and are skipped.
+pub fn check(tokens: &[Token]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut declared: Vec<Declaration> = Vec::new();
+    let mut part: Option<&str> = None;
+    let mut naming = false;
+
+    let mut iter = tokens.iter().peekable();
+    while let Some(token) = iter.next() {
+        match token.class {
+            TokenClass::ReservedWord => match token.name.as_str() {
+                "VAR" | "CONST" => part = Some(&token.name),
+                "CLASS" | "PROCEDURE" | "CALL" => naming = true,
+                _ => (),
+            },
+            TokenClass::Delimiter if token.name == ";" => part = None,
+            TokenClass::Identifier if naming => naming = false,
+            TokenClass::Identifier => {
+                // A constant is the name before the =, what follows it is its value
+                let declaring = match part {
+                    Some("VAR") => true,
+                    Some(_) => iter.peek().is_some_and(|next| next.name == "="),
+                    None => false,
+                };
+                let found = declared
+                    .iter_mut()
+                    .find(|declaration| declaration.token.name == token.name);
+                match (declaring, found) {
+                    (true, Some(first)) => diagnostics.push(
+                        Diagnostic::error(&format!(
+                            "the name `{}` is declared more than once",
+                            token.name
+                        ))
+                        .primary(token.span, "declared again here")
+                        .label(first.token.span, "first declared here"),
+                    ),
+                    (true, None) => declared.push(Declaration {
+                        token,
+                        kind: part.unwrap(),
+                        used: false,
+                    }),
+                    (false, Some(declaration)) => declaration.used = true,
+                    (false, None) => diagnostics.push(
+                        Diagnostic::error(&format!("`{}` is used but never declared", token.name))
+                            .primary(token.span, "not declared")
+                            .help(&format!("declare it in a VAR list: `VAR {};`", token.name)),
+                    ),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for declaration in declared.iter().filter(|declaration| !declaration.used) {
+        let kind = match declaration.kind {
+            "CONST" => "constant",
+            _ => "variable",
+        };
+        diagnostics.push(
+            Diagnostic::warning(&format!("unused {}: `{}`", kind, declaration.token.name))
+                .primary(declaration.token.span, "declared here but never used"),
+        );
+    }
+    diagnostics
+}
+
+// Variables the quads may read before any GET or assignment gives them a value. Quads carry no
+// location, the warning points at the first read of the name in the source, the first use that
+// is not a declaration, the target of an `=` or inside a GET.
+pub fn uninitialised(tokens: &[Token], quads: &[Quad]) -> Vec<Diagnostic> {
+    dataflow::uninitialised(&cfg::build(quads.to_vec()))
+        .into_iter()
+        .map(|warning| {
+            let diagnostic = Diagnostic::warning(&format!(
+                "`{}` may be used before it is given a value",
+                warning.name
+            ))
+            .help(&format!(
+                "read it with GET or assign it before this: `{} = 0;`",
+                warning.name
+            ));
+            match first_read(tokens, &warning.name) {
+                Some(token) => {
+                    diagnostic.primary(token.span, "read here before any GET or assignment")
+                }
+                None => diagnostic,
+            }
+        })
+        .collect()
+}
+
+fn first_read<'a>(tokens: &'a [Token], name: &str) -> Option<&'a Token> {
+    let mut declaring = false;
+    for (index, token) in tokens.iter().enumerate() {
+        match token.name.as_str() {
+            "VAR" | "CONST" => declaring = true,
+            ";" => declaring = false,
+            _ if declaring || token.name != name || token.class != TokenClass::Identifier => (),
+            _ => {
+                let assigned = tokens.get(index + 1).is_some_and(|next| next.name == "=");
+                let read_in = index >= 2 && tokens[index - 2].name == "GET";
+                if !assigned && !read_in {
+                    return Some(token);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::diagnostics::{Label, Level, Span};
+    use crate::compiler::lexical::Tokenize;
+    use crate::compiler::syntax::Syntax;
+
+    fn messages(source: &str) -> Vec<(Level, String)> {
+        check(&Tokenize::new(source).collect::<Vec<_>>())
+            .into_iter()
+            .map(|diagnostic| (diagnostic.level, diagnostic.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_semantic_clean() {
+        let source = "CLASS Pgm1 {\nCONST k = 2;\nVAR a, b;\nGET(a);\nb = a * k;\nPUT(b);\n}\n";
+        assert_eq!(messages(source), []);
+    }
+
+    #[test]
+    fn test_semantic_names() {
+        assert_eq!(
+            messages("CLASS Pgm1 { VAR a, b, c; GET(a); d = a; PUT(d); }"),
+            [
+                (Level::Error, String::from("`d` is used but never declared")),
+                (Level::Error, String::from("`d` is used but never declared")),
+                (Level::Warning, String::from("unused variable: `b`")),
+                (Level::Warning, String::from("unused variable: `c`")),
+            ]
+        );
+        // Each use is reported where it is
+        let tokens: Vec<_> =
+            Tokenize::new("CLASS Pgm1 { VAR a, b, c; GET(a); d = a; PUT(d); }").collect();
+        let spans: Vec<_> = check(&tokens)
+            .iter()
+            .filter(|diagnostic| diagnostic.level == Level::Error)
+            .map(|diagnostic| diagnostic.labels.clone())
+            .collect();
+        let not_declared = |start| Label {
+            span: Span::new(start, start + 1),
+            message: String::from("not declared"),
+            primary: true,
+        };
+        assert_eq!(spans, [[not_declared(34)], [not_declared(45)]]);
+
+        let tokens: Vec<_> = Tokenize::new("VAR a; CONST a = 1; PUT(a);").collect();
+        let diagnostics = check(&tokens);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "the name `a` is declared more than once"
+        );
+        assert_eq!(
+            diagnostics[0].labels,
+            [
+                Label {
+                    span: Span::new(13, 14),
+                    message: String::from("declared again here"),
+                    primary: true,
+                },
+                Label {
+                    span: Span::new(4, 5),
+                    message: String::from("first declared here"),
+                    primary: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_semantic_uninitialised() {
+        let source = "CLASS Pgm1 {\nVAR a, b, c;\nGET(a);\nc = c + 1;\nb = a + c;\nPUT(b);\n}\n";
+        let mut syn = Syntax::from_source(source);
+        syn.create_symbol_table().unwrap();
+        syn.complete_analysis().unwrap();
+        syn.consume_polish().unwrap();
+        let tokens: Vec<_> = Tokenize::new(source).collect();
+
+        let diagnostics = uninitialised(&tokens, &syn.quads);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "`c` may be used before it is given a value"
+        );
+        // The c after the =, not the one assigned to
+        assert_eq!(diagnostics[0].span(), Some(Span::new(38, 39)));
+        assert_eq!(&source[38..43], "c + 1");
+    }
+}
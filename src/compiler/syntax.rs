@@ -1,27 +1,40 @@
 use std::fmt;
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, Write};
 use std::iter::Peekable;
-use std::path::Path;
 use std::vec::IntoIter;
 
 // Take tokens from lex portion of the code
-use crate::compiler::lexical::{Token, TokenClass, Tokenize};
+use crate::compiler::backend::{Relation, Symbol, SymbolClass};
+use crate::compiler::diagnostics::{Diagnostic, Span};
+use crate::compiler::lexical::{self, Token, TokenClass, Tokenize};
 use crate::compiler::precedence::{PrecedenceGrammar, OPG};
 use crate::compiler::tableindex::TableIndex;
 
 type TokenList = Vec<Token>;
 pub type QuadList = Vec<Quad>;
 
+// Start of every Temp's name, followed by its number
+pub const TEMP_PREFIX: &str = "temp_";
+
+// Most bits a Shl or Shr moves by, the values of the program are 16 bits wide
+pub const MAX_SHIFT: i32 = 15;
+
 pub struct Syntax {
     token_iter: Peekable<IntoIter<Token>>,
-    token_stack: TokenList,
     pub polish: TokenList,
     pub quads: QuadList,
+    pub symbols: Vec<TableEntry>,
     p_func: PFunc,
-    top_of_stack: usize,
-    op_stack: TokenList,
-    prev_op: Token,
+    // Last token taken from the input, errors at the end of the input point just past it
+    last: Token,
+}
+
+// One row of the symbol table, addr is the offset of its storage in the data segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableEntry {
+    pub name: String,
+    pub class: SymbolClass,
+    pub value: i32,
+    pub addr: u32,
 }
 
 struct PFunc {
@@ -29,12 +42,57 @@ struct PFunc {
     g: Vec<i32>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// Three-address code. Arithmetic computes `left op right` into dest, which operands a quad has is
+// fixed by its opcode:
+//
+//   Add Sub Mul Div Shl Shr   left, right, dest
+//   Assign                    left into dest
+//   Branch                    left, right, dest is the Label jumped to when the relation fails
+//   Get                       dest
+//   Put                       left
+//   Label Jump Procedure Call dest is the Label
+//   Ret                       nothing
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Quad {
-    pub op: Token,
-    pub param_one: Token,
-    pub param_two: Token,
-    pub temp: Token,
+    pub op: Opcode,
+    pub left: Option<Operand>,
+    pub right: Option<Operand>,
+    pub dest: Option<Operand>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    // left * 2^right and left / 2^right rounding towards zero, right is always a Const from 0 to
+    // MAX_SHIFT
+    Shl,
+    Shr,
+    Assign,
+    // Falls through when `left rel right` holds
+    Branch(Relation),
+    Get,
+    Put,
+    Label,
+    Jump,
+    Procedure,
+    Ret,
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Operand {
+    // A variable of the program
+    Var(String),
+    Const(i32),
+    // A value consume_polish or an optimisation made up, `temp_N`. A program cannot name one,
+    // the lexer takes no `_` in an identifier.
+    Temp(String),
+    // Jump target or procedure name
+    Label(String),
+    Str(String),
 }
 
 #[derive(PartialEq, Eq)]
@@ -67,74 +125,277 @@ impl PFunc {
     }
 }
 
-impl fmt::Display for Quad {
+impl Quad {
+    pub fn binary(op: Opcode, left: Operand, right: Operand, dest: Operand) -> Self {
+        Quad {
+            op,
+            left: Some(left),
+            right: Some(right),
+            dest: Some(dest),
+        }
+    }
+
+    pub fn assign(src: Operand, dest: Operand) -> Self {
+        Quad {
+            op: Opcode::Assign,
+            left: Some(src),
+            right: None,
+            dest: Some(dest),
+        }
+    }
+
+    pub fn branch(rel: Relation, left: Operand, right: Operand, target: &str) -> Self {
+        Quad::binary(
+            Opcode::Branch(rel),
+            left,
+            right,
+            Operand::Label(target.to_string()),
+        )
+    }
+
+    pub fn get(dest: Operand) -> Self {
+        Quad {
+            op: Opcode::Get,
+            left: None,
+            right: None,
+            dest: Some(dest),
+        }
+    }
+
+    pub fn put(src: Operand) -> Self {
+        Quad {
+            op: Opcode::Put,
+            left: Some(src),
+            right: None,
+            dest: None,
+        }
+    }
+
+    pub fn label(name: &str) -> Self {
+        Quad::target(Opcode::Label, name)
+    }
+
+    pub fn jump(name: &str) -> Self {
+        Quad::target(Opcode::Jump, name)
+    }
+
+    pub fn procedure(name: &str) -> Self {
+        Quad::target(Opcode::Procedure, name)
+    }
+
+    pub fn call(name: &str) -> Self {
+        Quad::target(Opcode::Call, name)
+    }
+
+    pub fn ret() -> Self {
+        Quad {
+            op: Opcode::Ret,
+            left: None,
+            right: None,
+            dest: None,
+        }
+    }
+
+    fn target(op: Opcode, name: &str) -> Self {
+        Quad {
+            op,
+            left: None,
+            right: None,
+            dest: Some(Operand::Label(name.to_string())),
+        }
+    }
+
+    // Label a Label, Jump, Branch, Procedure or Call quad names
+    pub fn target_name(&self) -> Option<&str> {
+        match (&self.op, &self.dest) {
+            (
+                Opcode::Label | Opcode::Jump | Opcode::Branch(_) | Opcode::Procedure | Opcode::Call,
+                Some(Operand::Label(name)),
+            ) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Opcode {
+    // `+ - * / << >>`, the opcodes that compute into a temp
+    pub fn is_arith(self) -> bool {
+        matches!(
+            self,
+            Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Shl | Opcode::Shr
+        )
+    }
+
+    // Operator or reserved word the polish notation spells it with
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Add => "+",
+            Opcode::Sub => "-",
+            Opcode::Mul => "*",
+            Opcode::Div => "/",
+            Opcode::Shl => "<<",
+            Opcode::Shr => ">>",
+            Opcode::Assign => "=",
+            Opcode::Branch(rel) => rel.op(),
+            Opcode::Get => "GET",
+            Opcode::Put => "PUT",
+            Opcode::Label => "LABEL",
+            Opcode::Jump => "JMP",
+            Opcode::Procedure => "PROCEDURE",
+            Opcode::Ret => "RET",
+            Opcode::Call => "CALL",
+        }
+    }
+
+    // Arithmetic operator of an Op token, `=` is a copy and not arithmetic
+    pub fn from_op(op: &str) -> Option<Self> {
+        match op {
+            "+" => Some(Opcode::Add),
+            "-" => Some(Opcode::Sub),
+            "*" => Some(Opcode::Mul),
+            "/" => Some(Opcode::Div),
+            "<<" => Some(Opcode::Shl),
+            ">>" => Some(Opcode::Shr),
+            _ => None,
+        }
+    }
+}
+
+impl Operand {
+    pub fn var(name: &str) -> Self {
+        Operand::Var(name.to_string())
+    }
+
+    pub fn temp(id: usize) -> Self {
+        Operand::Temp(format!("{}{}", TEMP_PREFIX, id))
+    }
+
+    // Variables and literals of the polish notation, an identifier is always a Var
+    pub fn from_token(token: &Token) -> Result<Self, String> {
+        match token.class {
+            TokenClass::Literal => token
+                .name
+                .parse::<i32>()
+                .map(Operand::Const)
+                .map_err(|_| format!("[ Error ] Bad literal: {}", token.name)),
+            TokenClass::Identifier => Ok(Operand::Var(token.name.clone())),
+            _ => Err(format!("[ Error ] Not an operand: {}", token.name)),
+        }
+    }
+
+    // Name of the storage a Var or Temp is read from or written to
+    pub fn storage(&self) -> Option<&str> {
+        match self {
+            Operand::Var(name) | Operand::Temp(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn constant(&self) -> Option<i32> {
+        match self {
+            Operand::Const(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    // The same kind of operand under another name, for renaming a Var or Temp
+    pub fn renamed(&self, name: String) -> Self {
+        match self {
+            Operand::Temp(_) => Operand::Temp(name),
+            Operand::Label(_) => Operand::Label(name),
+            Operand::Str(_) => Operand::Str(name),
+            _ => Operand::Var(name),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
+        match self {
+            Operand::Var(name)
+            | Operand::Temp(name)
+            | Operand::Label(name)
+            | Operand::Str(name) => {
+                write!(f, "{}", name)
+            }
+            Operand::Const(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// The row as the symbols file had it
+impl fmt::Display for TableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
             f,
-            "{},{},{},{}\n",
-            self.op.name, self.param_one.name, self.param_two.name, self.temp.name
+            "{:<6} {:<10} {:<5} {:<7} DS",
+            self.name,
+            format!("{:?}", self.class),
+            self.value,
+            self.addr
         )
     }
 }
 
 impl Syntax {
-    pub fn new(file: &str, flag: bool) -> Self {
-        let tokens: Peekable<IntoIter<Token>>;
-        if flag {
-            tokens = Syntax::tokens_from_memory(file);
-        } else {
-            tokens = Syntax::tokens_from_file(file);
-        }
+    // Analyse source already in memory, nothing is read from or written to disk
+    pub fn from_source(source: &str) -> Self {
+        let mut stack: TokenList = vec![Token::terminator()];
+        stack.extend(Tokenize::new(source));
+
+        Syntax::with_tokens(stack.into_iter().peekable())
+    }
 
+    fn with_tokens(tokens: Peekable<IntoIter<Token>>) -> Self {
         Syntax {
-            top_of_stack: 0,
             token_iter: tokens,
-            token_stack: Vec::new(),
             polish: Vec::new(),
             quads: Vec::new(),
+            symbols: Vec::new(),
             p_func: PFunc::new(),
-            op_stack: Vec::new(),
-            prev_op: Token::empty(),
+            last: Token::empty(),
         }
     }
 
-    pub fn token_to_table(name: &str, class: &str, value: &i32, addr: &u32) {
-        let mut file: File;
-        if !Path::new("symbols").exists() {
-            file = OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open("symbols")
-                .unwrap();
-        } else {
-            file = OpenOptions::new()
-                .append(true)
-                .write(true)
-                .open("symbols")
-                .unwrap();
+    pub fn token_to_table(&mut self, name: &str, class: SymbolClass, value: i32, addr: u32) {
+        self.symbols.push(TableEntry {
+            name: name.to_string(),
+            class,
+            value,
+            addr,
+        });
+    }
+
+    // One Symbol per name in the table, the first row for a name wins
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols: Vec<Symbol> = Vec::new();
+        for entry in &self.symbols {
+            if !symbols.iter().any(|sym| sym.name == entry.name) {
+                symbols.push(Symbol {
+                    name: entry.name.clone(),
+                    class: entry.class,
+                    value: entry.value,
+                });
+            }
         }
-
-        file.write_fmt(format_args!(
-            "{:<6} {:<10} {:<5} {:<7} {}\n",
-            name, class, value, addr, "DS"
-        ))
-        .ok();
+        symbols
     }
 
-    pub fn create_symbol_table(&mut self, filename: &str) {
+    pub fn create_symbol_table(&mut self) -> Result<(), Diagnostic> {
         // Make our token iterator peekable
         let mut curr_state: usize = 0;
         let mut goto_state: usize;
         let value: i32 = 0;
         let mut addr: u32 = 0;
 
-        let mut dis_token_iter = self.token_iter.clone();
-
-        while let Some(token) = dis_token_iter.next() {
+        for token in self.token_iter.clone() {
             // Skip delimiters completly
             if token.class == TokenClass::Delimiter {
                 continue;
             }
+            if token.class == TokenClass::Unknown {
+                return Err(lexical::unexpected(&token));
+            }
 
             goto_state = Tokenize::table_lookup(
                 curr_state,
@@ -143,13 +404,8 @@ impl Syntax {
             );
 
             match goto_state {
-                0 | 3 => {
-                    if token.name == "VAR" || token.name == "CONST" {
-                        continue;
-                    }
-                }
                 1 => {
-                    Syntax::token_to_table(&token.name, "Literal", &value, &addr);
+                    self.token_to_table(&token.name, SymbolClass::Literal, value, addr);
 
                     addr += 2;
                 }
@@ -158,117 +414,72 @@ impl Syntax {
                     if token.class == TokenClass::ReservedWord {
                         continue;
                     }
-                    Syntax::token_to_table(&token.name, "Identifier", &value, &addr);
+                    self.token_to_table(&token.name, SymbolClass::Identifier, value, addr);
 
                     addr += 2;
                 }
 
-                _ => panic!("[ ERROR ] Unreachable state, handle me better"),
+                // 0 and 3, the only other states the table has, are between names
+                _ => {
+                    if token.name == "VAR" || token.name == "CONST" {
+                        continue;
+                    }
+                }
             }
 
             curr_state = goto_state;
         }
+        Ok(())
     }
 
-    // Return a stack of iterable tokens
-    pub fn tokens_from_memory(file: &str) -> Peekable<IntoIter<Token>> {
-        let mut lex = Tokenize::create_scanner(file).unwrap();
-        let mut stack: TokenList = Vec::new();
-
-        // Analysis needs a "terminator" token at the start
-        stack.push(Token::terminator());
-        while let Some(token) = lex.next() {
-            stack.push(token);
-        }
-
-        stack.into_iter().peekable()
-    }
-
-    pub fn tokens_from_file(file: &str) -> Peekable<IntoIter<Token>> {
-        let token_file = File::open(file);
-
-        match token_file {
-            Ok(file) => {
-                let buf = io::BufReader::new(file);
-                let mut stack: TokenList = Vec::new();
-
-                stack.push(Token::terminator());
-                for line in buf.lines() {
-                    Syntax::parse_token(&mut stack, line);
-                }
-                stack.into_iter().peekable()
-            }
-            Err(e) => panic!("{}", e),
-        }
-    }
-
-    pub fn consume_polish(&mut self) -> Result<(), ()> {
-        // loop until next op
-        let mut pol_iter = self.polish.iter();
-        let mut param_stack: TokenList = Vec::new();
+    pub fn consume_polish(&mut self) -> Result<(), Diagnostic> {
+        let mut param_stack: Vec<Operand> = Vec::new();
         let mut quads: QuadList = Vec::new();
         let mut temp_id = 1;
+        let mut temps = Vec::new();
+        let underflow = |token: &Token| {
+            Diagnostic::error(&format!("missing operand for `{}`", token.name))
+                .primary(token.span, "needs another operand")
+        };
 
-        loop {
-            if let Some(token) = pol_iter.next() {
-                match token.class {
-                    TokenClass::Op => {
-                        if !token.name.eq(&String::from("=")) {
-                            let temp = Token::temp_gen(temp_id);
-                            temp_id += 1;
-
-                            quads.push(Quad {
-                                op: token.to_owned(),
-                                param_one: param_stack.pop().unwrap(),
-                                param_two: param_stack.pop().unwrap(),
-                                temp: temp.clone(),
-                            });
-                            param_stack.push(temp);
-                        } else {
-                            quads.push(Quad {
-                                op: token.to_owned(),
-                                param_one: param_stack.pop().unwrap(),
-                                param_two: param_stack.pop().unwrap(),
-                                temp: Token::empty(),
-                            });
-                        }
-                    }
-                    TokenClass::Delimiter => continue,
-                    TokenClass::ReservedWord => {
-                        if token.name.eq("GET") || token.name.eq("PUT") {
-                            quads.push(Quad {
-                                op: token.to_owned(),
-                                param_one: param_stack.pop().unwrap(),
-                                param_two: Token::empty(),
-                                temp: Token::empty(),
-                            });
-                        }
+        for token in &self.polish {
+            match token.class {
+                TokenClass::Op => {
+                    // Operands are pushed left first, so the right one is on top
+                    let right = param_stack.pop().ok_or_else(|| underflow(token))?;
+                    let left = param_stack.pop().ok_or_else(|| underflow(token))?;
+                    if token.name == "=" {
+                        quads.push(Quad::assign(right, left));
+                        continue;
                     }
-                    _ => param_stack.push(token.to_owned()),
+                    let op = Opcode::from_op(&token.name).ok_or_else(|| {
+                        Diagnostic::error(&format!("unknown operator `{}`", token.name))
+                            .primary(token.span, "not an arithmetic operator")
+                    })?;
+                    let temp = Operand::temp(temp_id);
+                    temps.push(temp.to_string());
+                    temp_id += 1;
+                    quads.push(Quad::binary(op, left, right, temp.clone()));
+                    param_stack.push(temp);
                 }
-            } else {
-                break;
+                TokenClass::Delimiter => continue,
+                TokenClass::ReservedWord if token.name == "GET" => {
+                    let dest = param_stack.pop().ok_or_else(|| underflow(token))?;
+                    quads.push(Quad::get(dest));
+                }
+                TokenClass::ReservedWord if token.name == "PUT" => {
+                    let src = param_stack.pop().ok_or_else(|| underflow(token))?;
+                    quads.push(Quad::put(src));
+                }
+                TokenClass::ReservedWord => continue,
+                _ => param_stack.push(Operand::from_token(token)?),
             }
         }
         self.quads = quads;
-        Ok(())
-    }
-
-    fn parse_token(stack: &mut TokenList, line: io::Result<String>) {
-        match line {
-            Ok(token) => {
-                let mut iter = token.split_whitespace();
-                let name = iter.next().unwrap();
-                let class = TokenClass::from(iter.next().unwrap());
-
-                stack.push(Token {
-                    name: name.to_string(),
-                    class,
-                });
-            }
-
-            Err(e) => panic!("{:?}", e),
+        for temp in temps {
+            self.token_to_table(&temp, SymbolClass::Temp, 0, 0);
         }
+        Ok(())
     }
 
     fn table_lookup(&self, f: &Token, g: &Token) -> Handle {
@@ -289,216 +500,292 @@ impl Syntax {
         Handle::Equal
     }
 
-    // Advance iterator to the next operator, adding variables and literals to the stacks
-    fn next_op(&mut self) -> Option<Token> {
-        while let Some(token) = self.token_iter.next() {
-            match token.class {
-                TokenClass::ReservedWord | TokenClass::Delimiter | TokenClass::Op => {
-                    self.token_stack.push(token.clone());
-                    return Some(token);
-                }
-                TokenClass::Unknown => panic!("[ Error ] Invalid next operator."),
-
-                // Add Ident or literal to stack
-                _ => {
-                    self.top_of_stack += 1;
-                    self.polish.push(token.clone());
-                    self.token_stack.push(token);
-                }
-            }
-        }
-        None
-    }
-
-    // Search for the last operator from the processed tokens
-    fn last_op(&mut self) -> Option<Token> {
-        let mut iter = self.token_stack.iter();
-
-        while let Some(token) = iter.next_back() {
-            match token.class {
-                TokenClass::ReservedWord | TokenClass::Delimiter | TokenClass::Op => {
-                    return Some(token.to_owned());
-                }
-                _ => return None,
-            }
-        }
-        None
-    }
-
     // Advance through the input tokens
     fn next_token(&mut self) -> Option<Token> {
-        if let Some(token) = self.token_iter.next() {
-            self.token_stack.push(token.clone());
-            return Some(token);
-        } else {
-            None
-        }
+        let token = self.token_iter.next()?;
+        self.last = token.clone();
+        Some(token)
     }
 
-    pub fn complete_analysis(&mut self) {
-        // Consume first token pushing it to the stack, always a indicator to the start of input
-        self.token_stack.push(self.token_iter.next().unwrap());
-
-        self.s_stmt();
+    fn peek_name(&mut self) -> Option<&str> {
+        self.token_iter.peek().map(|token| token.name.as_str())
     }
 
-    fn program(&mut self) {
-        println!("Analysis of program.");
-        let mut token = self.next_token().unwrap();
-
-        // NOTE: any way to just get just the ref to this value?
-        let mut prev_op = self.token_stack[self.top_of_stack - 1].clone();
-        println!("Comparing: {:?} and {:?}", prev_op, token);
-
-        if self.table_lookup(&prev_op, &token) == Handle::Yields {
-            prev_op = token;
-            token = self.next_op().unwrap();
-
-            println!("Comparing: {:?} and {:?}\n", prev_op, token);
-            if self.table_lookup(&prev_op, &token) == Handle::Yields {
-                self.block();
-            } else {
-                panic!(
-                    "[ Error ] Syntax error at {} -- {}",
-                    prev_op.name, token.name
-                );
-            }
-        } else {
-            panic!(
-                "[ Error ] Syntax error at {} -- {}",
-                prev_op.name, token.name
-            );
+    // Take the next token when it is `name`
+    fn accept(&mut self, name: &str) -> Option<Token> {
+        match self.peek_name() == Some(name) {
+            true => self.next_token(),
+            false => None,
         }
     }
 
-    fn block(&mut self) {
-        println!("Analysis of block.");
-    }
-
-    fn const_def_part(&mut self) {
-        self.const_list();
-    }
-
-    fn const_list(&mut self) {
-        self.var_def_part();
-    }
-
-    fn var_def_part(&mut self) {
-        self.var_list();
-    }
-
-    fn var_list(&mut self) {
-        self.proc_def_part();
+    fn expect(&mut self, name: &str) -> Result<Token, Diagnostic> {
+        self.accept(name)
+            .ok_or_else(|| self.unexpected(&format!("`{}`", name)))
     }
 
-    fn proc_def_part(&mut self) {
-        self.stmt();
-    }
-
-    fn stmt(&mut self) {
-        self.s_stmt();
-    }
-
-    fn call_stmt(&mut self) {
-        self.param_list();
+    fn expect_class(&mut self, class: TokenClass, what: &str) -> Result<Token, Diagnostic> {
+        match self.token_iter.peek() {
+            Some(token) if token.class == class => Ok(self.next_token().unwrap()),
+            _ => Err(self.unexpected(what)),
+        }
     }
 
-    fn param_list(&mut self) {
-        self.ident_list();
+    // The next token is not what the grammar allows here, at the end of the input the error points
+    // just past the last token
+    fn unexpected(&mut self, expected: &str) -> Diagnostic {
+        match self.token_iter.peek() {
+            Some(token) => {
+                Diagnostic::error(&format!("expected {}, found `{}`", expected, token.name))
+                    .primary(token.span, &format!("expected {}", expected))
+            }
+            None => {
+                let end = Span::new(self.last.span.end, self.last.span.end);
+                Diagnostic::error(&format!(
+                    "expected {}, found the end of the input",
+                    expected
+                ))
+                .primary(end, &format!("expected {}", expected))
+            }
+        }
     }
 
-    fn ident_list(&mut self) {
-        self.comp_stmt();
-    }
+    // Parse the program into postfix in polish, a statement's postfix ends with its `;`:
+    //
+    //   program   CLASS ident { body } | body
+    //   body      { VAR ident {, ident} ; | CONST ident = literal {, ident = literal} ; | stmt }
+    //   stmt      GET ( ident ) ; | PUT expr ; | ident = expr ;
+    //
+    // Only straight-line code is covered, a control flow statement is reported as unsupported.
+    // Branches, loops and procedures reach the passes and backends only in IR read by ir::parse.
+    pub fn complete_analysis(&mut self) -> Result<(), Diagnostic> {
+        if let Some(token) = self
+            .token_iter
+            .clone()
+            .find(|token| token.class == TokenClass::Unknown)
+        {
+            return Err(lexical::unexpected(&token));
+        }
+        self.check_delimiters()?;
+        // Consume first token, always a indicator to the start of input
+        self.accept("Terminator");
 
-    fn comp_stmt(&mut self) {
-        self.stmt_list();
+        self.program()
     }
 
-    fn stmt_list(&mut self) {
-        self.if_stmt();
+    fn program(&mut self) -> Result<(), Diagnostic> {
+        if self.accept("CLASS").is_some() {
+            self.expect_class(TokenClass::Identifier, "a class name")?;
+            self.expect("{")?;
+            self.body(Some("}"))?;
+            self.expect("}")?;
+        } else {
+            self.body(None)?;
+        }
+        match self.token_iter.peek() {
+            Some(_) => Err(self.unexpected("the end of the input")),
+            None => Ok(()),
+        }
     }
 
-    fn if_stmt(&mut self) {
-        self.while_stmt();
+    fn body(&mut self, end: Option<&str>) -> Result<(), Diagnostic> {
+        while self.token_iter.peek().is_some() && self.peek_name() != end {
+            match self.peek_name() {
+                Some("VAR") => self.var_def_part()?,
+                Some("CONST") => self.const_def_part()?,
+                _ => self.stmt()?,
+            }
+        }
+        Ok(())
     }
 
-    fn while_stmt(&mut self) {
-        self.bool_exp();
+    // Declarations only reserve storage, which the symbol table already did
+    fn var_def_part(&mut self) -> Result<(), Diagnostic> {
+        self.expect("VAR")?;
+        loop {
+            self.expect_class(TokenClass::Identifier, "a variable name")?;
+            if self.accept(",").is_none() {
+                break;
+            }
+        }
+        self.expect(";").map(|_| ())
     }
 
-    fn bool_exp(&mut self) {
-        self.rel_op();
+    // A constant is assigned its value up front, `k 2 =` in postfix
+    fn const_def_part(&mut self) -> Result<(), Diagnostic> {
+        self.expect("CONST")?;
+        loop {
+            let name = self.expect_class(TokenClass::Identifier, "a constant name")?;
+            let equal = self.expect("=")?;
+            let value = self.expect_class(TokenClass::Literal, "a number")?;
+            self.polish.extend([name, value, equal]);
+            if self.accept(",").is_none() {
+                break;
+            }
+        }
+        let semi = self.expect(";")?;
+        self.polish.push(semi);
+        Ok(())
     }
 
-    fn rel_op(&mut self) {
-        self.expression();
+    fn stmt(&mut self) -> Result<(), Diagnostic> {
+        let token = match self.token_iter.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.unexpected("a statement")),
+        };
+        match (&token.class, token.name.as_str()) {
+            (TokenClass::ReservedWord, "GET") => {
+                let get = self.next_token().unwrap();
+                self.expect("(")?;
+                let var = self.expect_class(TokenClass::Identifier, "a variable name")?;
+                self.expect(")")?;
+                self.polish.extend([var, get]);
+            }
+            (TokenClass::ReservedWord, "PUT") => {
+                let put = self.next_token().unwrap();
+                self.expression()?;
+                self.polish.push(put);
+            }
+            (TokenClass::Identifier, _) => {
+                let var = self.next_token().unwrap();
+                let equal = self.expect("=")?;
+                self.polish.push(var);
+                self.expression()?;
+                self.polish.push(equal);
+            }
+            (TokenClass::ReservedWord, name) => {
+                return Err(Diagnostic::error(&format!("`{}` is not supported", name))
+                    .primary(token.span, "not supported")
+                    .help("only GET, PUT and assignments can be compiled"))
+            }
+            _ => return Err(self.unexpected("a statement")),
+        }
+        let semi = self.expect(";")?;
+        self.polish.push(semi);
+        Ok(())
     }
 
-    fn s_stmt(&mut self) {
-        self.prev_op = self.last_op().unwrap();
-        while let Some(oper) = self.next_op() {
-            match self.table_lookup(&self.prev_op, &oper) {
-                Handle::Yields => {
-                    self.prev_op = oper.clone();
-                    self.op_stack.push(oper);
-                    self.expression();
-                    self.polish.push(self.op_stack.pop().unwrap());
+    // Operator precedence parse of an expression using the precedence functions, the Terminator
+    // stands for the Nil either side of it. Operands go straight to polish, an operator once the
+    // next input no longer yields to it, and a parenthesis is dropped when its pair is reduced.
+    fn expression(&mut self) -> Result<(), Diagnostic> {
+        let bottom = Token::terminator();
+        let mut ops: TokenList = Vec::new();
+        let mut operand = true;
+        loop {
+            let input = match self.token_iter.peek() {
+                Some(token)
+                    if matches!(token.class, TokenClass::Identifier | TokenClass::Literal) =>
+                {
+                    if !operand {
+                        return Err(self.unexpected("an operator or `;`"));
+                    }
+                    let token = self.next_token().unwrap();
+                    self.polish.push(token);
+                    operand = false;
+                    continue;
                 }
-
-                Handle::Takes => break,
-                Handle::Equal => todo!(),
+                // A `)` with no `(` open belongs to whatever the expression is in
+                Some(token)
+                    if matches!(token.name.as_str(), "+" | "-" | "*" | "/" | "(")
+                        || (token.name == ")" && ops.iter().any(|op| op.name == "(")) =>
+                {
+                    token.clone()
+                }
+                _ => bottom.clone(),
+            };
+            let opens = input.name == "(";
+            if operand != opens {
+                return Err(self.unexpected(match operand {
+                    true => "an operand",
+                    false => "an operator or `;`",
+                }));
             }
 
-            while let Some(op) = self.op_stack.pop() {
-                self.polish.push(op);
+            let top = ops.last().unwrap_or(&bottom);
+            if top.name == bottom.name && input.name == bottom.name {
+                return Ok(());
             }
-        }
-    }
-
-    fn expression(&mut self) {
-        self.term();
-        while let Some(oper) = self.next_token() {
-            match self.table_lookup(&self.prev_op, &oper) {
-                Handle::Yields => {
-                    self.op_stack.push(oper.clone());
-                    self.term();
-                    self.polish.push(self.op_stack.pop().unwrap());
+            match self.table_lookup(top, &input) {
+                Handle::Yields | Handle::Equal if input.name != bottom.name => {
+                    ops.push(self.next_token().unwrap());
+                    operand = input.name != ")";
+                }
+                _ => {
+                    // A `(` is only reduced with the `)` closing it
+                    let mut closed = false;
+                    loop {
+                        let op = ops.pop().unwrap();
+                        if op.name == "(" && !closed {
+                            return Err(self.unexpected("`)`"));
+                        }
+                        closed = op.name == ")";
+                        if op.class == TokenClass::Op {
+                            self.polish.push(op.clone());
+                        }
+                        let top = ops.last().unwrap_or(&bottom);
+                        if self.table_lookup(top, &op) == Handle::Yields {
+                            break;
+                        }
+                    }
                 }
-                Handle::Takes => break,
-                Handle::Equal => self.polish.push(oper.clone()),
             }
-            self.prev_op = oper;
         }
     }
 
-    fn term(&mut self) {
-        self.factor();
-        while let Some(oper) = self.next_token() {
-            match self.table_lookup(&self.prev_op, &oper) {
-                Handle::Yields => {
-                    self.op_stack.push(oper.clone());
+    // Every bracket is closed by its own kind, checked before parsing so an unbalanced one is
+    // reported where it was opened rather than wherever the parse goes wrong
+    fn check_delimiters(&self) -> Result<(), Diagnostic> {
+        let mut open: Vec<Token> = Vec::new();
+        let mut end = 0;
+        for token in self.token_iter.clone() {
+            end = end.max(token.span.end);
+            let closing = match token.name.as_str() {
+                "{" | "(" => {
+                    open.push(token);
+                    continue;
                 }
-
-                Handle::Takes => {
-                    self.polish.push(self.op_stack.pop().unwrap());
-                    self.op_stack.push(oper.clone());
+                "}" => "{",
+                ")" => "(",
+                _ => continue,
+            };
+            match open.pop() {
+                Some(opener) if opener.name == closing => (),
+                Some(opener) => {
+                    return Err(Diagnostic::error(&format!(
+                        "mismatched closing delimiter: `{}`",
+                        token.name
+                    ))
+                    .primary(token.span, &format!("expected `{}`", closer(&opener)))
+                    .label(opener.span, "opened here"))
+                }
+                None => {
+                    return Err(Diagnostic::error(&format!(
+                        "unexpected closing delimiter: `{}`",
+                        token.name
+                    ))
+                    .primary(token.span, "nothing to close"))
                 }
-
-                Handle::Equal => continue,
             }
-            self.factor();
-            self.prev_op = oper;
+        }
+        match open.pop() {
+            Some(opener) => Err(
+                Diagnostic::error("this file contains an unclosed delimiter")
+                    .primary(
+                        Span::new(end, end),
+                        &format!("expected `{}`", closer(&opener)),
+                    )
+                    .label(opener.span, "opened here")
+                    .help(&format!("close it with `{}`", closer(&opener))),
+            ),
+            None => Ok(()),
         }
     }
+}
 
-    fn factor(&mut self) {
-        if let Some(oper) = self.next_token() {
-            if oper.class != TokenClass::Delimiter {
-                self.polish.push(oper);
-            } else {
-                self.expression();
-            }
-        }
+fn closer(opener: &Token) -> &'static str {
+    match opener.name.as_str() {
+        "(" => ")",
+        _ => "}",
     }
 }
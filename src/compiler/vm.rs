@@ -0,0 +1,341 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::compiler::backend::Relation;
+use crate::compiler::bytecode::{Op, Program};
+
+// Interpreter for the bytecode in bytecode.rs. Values are i32 and wrap on overflow, GET reads
+// whitespace separated numbers with EOF or anything that is not a number reading as 0, the same
+// as the C backend.
+pub struct Vm<'a> {
+    program: &'a Program,
+    globals: Vec<i32>,
+    stack: Vec<i32>,
+    calls: Vec<usize>,
+    pc: usize,
+    steps: u64,
+    step_limit: Option<u64>,
+    // Every instruction is written here before it runs
+    trace: Option<&'a mut dyn Write>,
+    // Numbers read from the current input line that GET has not used yet
+    pending: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum VmErr {
+    StepLimit(u64),
+    StackUnderflow(usize),
+    DivideByZero(usize),
+    BadAddress(usize),
+    BadSlot(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for VmErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmErr::StepLimit(limit) => write!(f, "[ Error ] Step limit of {} reached", limit),
+            VmErr::StackUnderflow(pc) => write!(f, "[ Error ] Stack underflow at {}", pc),
+            VmErr::DivideByZero(pc) => write!(f, "[ Error ] Division by zero at {}", pc),
+            VmErr::BadAddress(pc) => write!(f, "[ Error ] Jump out of the program at {}", pc),
+            VmErr::BadSlot(pc) => write!(f, "[ Error ] Unknown global at {}", pc),
+            VmErr::Io(e) => write!(f, "[ Error ] {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for VmErr {
+    fn from(e: io::Error) -> Self {
+        VmErr::Io(e)
+    }
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Vm {
+            program,
+            globals: program.globals.iter().map(|global| global.value).collect(),
+            stack: Vec::new(),
+            calls: Vec::new(),
+            pc: 0,
+            steps: 0,
+            step_limit: None,
+            trace: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn step_limit(mut self, limit: u64) -> Self {
+        self.step_limit = Some(limit);
+        self
+    }
+
+    pub fn trace(mut self, out: &'a mut dyn Write) -> Self {
+        self.trace = Some(out);
+        self
+    }
+
+    pub fn globals(&self) -> &[i32] {
+        &self.globals
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn pop(&mut self) -> Result<i32, VmErr> {
+        self.stack.pop().ok_or(VmErr::StackUnderflow(self.pc))
+    }
+
+    fn slot(&mut self, slot: u16) -> Result<&mut i32, VmErr> {
+        let pc = self.pc;
+        self.globals
+            .get_mut(slot as usize)
+            .ok_or(VmErr::BadSlot(pc))
+    }
+
+    fn target(&self, addr: u32) -> Result<usize, VmErr> {
+        match addr as usize {
+            addr if addr <= self.program.code.len() => Ok(addr),
+            _ => Err(VmErr::BadAddress(self.pc)),
+        }
+    }
+
+    fn read(&mut self, input: &mut dyn BufRead) -> io::Result<i32> {
+        while self.pending.is_empty() {
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(0);
+            }
+            self.pending = line.split_whitespace().rev().map(String::from).collect();
+        }
+        Ok(self.pending.pop().unwrap().parse::<i32>().unwrap_or(0))
+    }
+
+    // Runs until HALT or the end of the code
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), VmErr> {
+        while let Some(&op) = self.program.code.get(self.pc) {
+            if let Some(limit) = self.step_limit {
+                if self.steps >= limit {
+                    return Err(VmErr::StepLimit(limit));
+                }
+            }
+            self.steps += 1;
+            if let Some(trace) = self.trace.as_mut() {
+                trace.write_fmt(format_args!(
+                    "{:>4}  {:<10} {:?}\n",
+                    self.pc,
+                    op.to_string(),
+                    self.stack
+                ))?;
+            }
+
+            let pc = self.pc;
+            let mut next = pc + 1;
+            match op {
+                Op::Push(value) => self.stack.push(value),
+                Op::Load(slot) => {
+                    let value = *self.slot(slot)?;
+                    self.stack.push(value);
+                }
+                Op::Store(slot) => {
+                    let value = self.pop()?;
+                    *self.slot(slot)? = value;
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Cmp(_) => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    let value = match op {
+                        Op::Add => left.wrapping_add(right),
+                        Op::Sub => left.wrapping_sub(right),
+                        Op::Mul => left.wrapping_mul(right),
+                        Op::Div if right == 0 => return Err(VmErr::DivideByZero(pc)),
+                        Op::Div => left.wrapping_div(right),
+                        Op::Cmp(rel) => {
+                            let holds = match rel {
+                                Relation::Equal => left == right,
+                                Relation::NEqual => left != right,
+                                Relation::GreaterThan => left > right,
+                                Relation::LessThan => left < right,
+                                Relation::GEqual => left >= right,
+                                Relation::LEqual => left <= right,
+                            };
+                            holds as i32
+                        }
+                        _ => unreachable!(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::Jump(addr) => next = self.target(addr)?,
+                Op::JumpIfFalse(addr) => {
+                    if self.pop()? == 0 {
+                        next = self.target(addr)?;
+                    }
+                }
+                Op::Call(addr) => {
+                    self.calls.push(next);
+                    next = self.target(addr)?;
+                }
+                Op::Ret => match self.calls.pop() {
+                    Some(addr) => next = addr,
+                    None => return Err(VmErr::StackUnderflow(pc)),
+                },
+                Op::Get(slot) => {
+                    let value = self.read(input)?;
+                    *self.slot(slot)? = value;
+                }
+                Op::Put => {
+                    let value = self.pop()?;
+                    output.write_fmt(format_args!("{}\n", value))?;
+                }
+                Op::Halt => break,
+            }
+            self.pc = next;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::bytecode::Global;
+    use crate::compiler::lexical::{Token, TokenClass};
+
+    // The backend sample program by hand:
+    //   GET(a); GET(b); WHILE a > 0 { a = a - 1; b = b * 2; } IF a < b { CALL show; } PUT(b / 2);
+    //   PROCEDURE show; c = (a + b) * 3; PUT(c);
+    fn sample() -> Program {
+        let global = |name: &str| Global {
+            name: name.to_string(),
+            value: 0,
+        };
+        let (a, b, c) = (0, 1, 2);
+        Program {
+            globals: vec![global("a"), global("b"), global("c")],
+            code: vec![
+                Op::Get(a),
+                Op::Get(b),
+                Op::Load(a),
+                Op::Push(0),
+                Op::Cmp(Relation::GreaterThan),
+                Op::JumpIfFalse(15),
+                Op::Load(a),
+                Op::Push(1),
+                Op::Sub,
+                Op::Store(a),
+                Op::Load(b),
+                Op::Push(2),
+                Op::Mul,
+                Op::Store(b),
+                Op::Jump(2),
+                Op::Load(a),
+                Op::Load(b),
+                Op::Cmp(Relation::LessThan),
+                Op::JumpIfFalse(20),
+                Op::Call(25),
+                Op::Load(b),
+                Op::Push(2),
+                Op::Div,
+                Op::Put,
+                Op::Halt,
+                Op::Load(a),
+                Op::Load(b),
+                Op::Add,
+                Op::Push(3),
+                Op::Mul,
+                Op::Store(c),
+                Op::Load(c),
+                Op::Put,
+                Op::Ret,
+            ],
+        }
+    }
+
+    fn run(program: &Program, input: &str) -> Result<String, VmErr> {
+        let mut output = Vec::new();
+        Vm::new(program).run(&mut input.as_bytes(), &mut output)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_vm_run() {
+        let program = sample();
+
+        assert_eq!(run(&program, "3 5\n").unwrap(), "120\n20\n");
+        assert_eq!(run(&program, "7\n-3\n").unwrap(), "-192\n");
+        // EOF reads as 0, a and b stay 0 so neither the loop nor show runs
+        assert_eq!(run(&program, "").unwrap(), "0\n");
+    }
+
+    #[test]
+    fn test_vm_trace_and_limit() {
+        let program = sample();
+        let mut trace = Vec::new();
+        let mut output = Vec::new();
+        let mut vm = Vm::new(&program).trace(&mut trace);
+        vm.run(&mut "0 0".as_bytes(), &mut output).unwrap();
+        let steps = vm.steps();
+
+        let trace = String::from_utf8(trace).unwrap();
+        assert_eq!(trace.lines().count() as u64, steps);
+        assert!(trace.starts_with("   0  GET 0      []\n   1  GET 1      []\n"));
+        assert!(trace.contains("   4  GT         [0, 0]\n   5  JPC 15     [0]\n"));
+
+        // Every pass of the loop takes 13 steps, a large a cannot finish in 100
+        let mut vm = Vm::new(&program).step_limit(100);
+        match vm.run(&mut "1000 1".as_bytes(), &mut output) {
+            Err(VmErr::StepLimit(100)) => (),
+            e => panic!("{:?}", e),
+        }
+        assert_eq!(vm.globals()[1], 1 << 7);
+    }
+
+    #[test]
+    fn test_vm_from_polish() {
+        // GET(a); b = a * (a - 1); PUT(b / 2);
+        let token = |name: &str, class: TokenClass| Token::new(name, class);
+        let ident = |name: &str| token(name, TokenClass::Identifier);
+        let lit = |name: &str| token(name, TokenClass::Literal);
+        let op = |name: &str| token(name, TokenClass::Op);
+        let polish = vec![
+            ident("a"),
+            token("GET", TokenClass::ReservedWord),
+            ident("b"),
+            ident("a"),
+            ident("a"),
+            lit("1"),
+            op("-"),
+            op("*"),
+            op("="),
+            ident("b"),
+            lit("2"),
+            op("/"),
+            token("PUT", TokenClass::ReservedWord),
+        ];
+        let program = Program::from_polish(&polish).unwrap();
+
+        assert_eq!(run(&program, "6\n").unwrap(), "15\n");
+    }
+
+    #[test]
+    fn test_vm_errors() {
+        let program = Program {
+            globals: Vec::new(),
+            code: vec![Op::Push(1), Op::Push(0), Op::Div],
+        };
+        assert!(matches!(run(&program, ""), Err(VmErr::DivideByZero(2))));
+
+        let program = Program {
+            globals: Vec::new(),
+            code: vec![Op::Push(1), Op::Add],
+        };
+        assert!(matches!(run(&program, ""), Err(VmErr::StackUnderflow(1))));
+
+        let program = Program {
+            globals: Vec::new(),
+            code: vec![Op::Ret],
+        };
+        assert!(matches!(run(&program, ""), Err(VmErr::StackUnderflow(0))));
+    }
+}
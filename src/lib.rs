@@ -0,0 +1,524 @@
+mod boolean;
+pub mod compiler;
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::compiler::backend::jvm::Jvm;
+use crate::compiler::backend::{Backend, Symbol, SymbolClass, Target};
+use crate::compiler::bytecode::Program;
+use crate::compiler::codegen::Generator;
+use crate::compiler::diagnostics::{Diagnostic, Emitter, Level};
+use crate::compiler::ir;
+use crate::compiler::lexical::{self, Token, TokenClass, Tokenize};
+use crate::compiler::opt::manager::PassManager;
+use crate::compiler::semantic;
+use crate::compiler::syntax::{Operand, QuadList, Syntax, TableEntry};
+
+// How far to take the source, each stage is rendered in the format it is dumped in elsewhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Tokens,
+    Symbols,
+    Polish,
+    // The bytecode of compiler::bytecode, compiled from the polish
+    Bytecode,
+    Quads,
+    Asm,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub emit: Emit,
+    pub target: Target,
+    pub passes: PassManager,
+    // Keep temps and variables in registers, only the x86-64 targets allocate them
+    pub registers: bool,
+    // Clean up the x86-64 instructions with the peephole rules
+    pub peephole: bool,
+    // Name of the program, the JVM class has to be named after the file it is written to
+    pub name: String,
+}
+
+// Everything the stages up to Options::emit produced, later stages are left empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+    pub tokens: Vec<Token>,
+    pub symbols: Vec<TableEntry>,
+    pub polish: Vec<Token>,
+    pub quads: QuadList,
+    // The IR after every pass named by --dump-after
+    pub dumps: String,
+    pub code: Vec<u8>,
+    pub executable: bool,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// Errors that stopped the compilation and the warnings found before them, in the order found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "symbols" => Ok(Emit::Symbols),
+            "polish" => Ok(Emit::Polish),
+            "bytecode" => Ok(Emit::Bytecode),
+            "quads" => Ok(Emit::Quads),
+            "asm" => Ok(Emit::Asm),
+            e => Err(format!("[ Error ] Unknown stage to emit: {}", e)),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            emit: Emit::Asm,
+            target: Target::I386,
+            passes: PassManager::default(),
+            registers: false,
+            peephole: false,
+            name: String::from("code"),
+        }
+    }
+}
+
+impl Options {
+    pub fn backend(&self) -> Box<dyn Backend> {
+        match (self.target, self.target.x86_64()) {
+            (Target::Jvm, _) => Box::new(Jvm::new(&self.name)),
+            (_, Some(mut backend)) => {
+                if self.registers {
+                    backend = backend.with_registers();
+                }
+                if self.peephole {
+                    backend = backend.with_peephole();
+                }
+                Box::new(backend)
+            }
+            (target, _) => target.backend(),
+        }
+    }
+}
+
+impl Output {
+    // The stage as it is written out
+    pub fn emitted(&self, emit: Emit) -> Vec<u8> {
+        let tokens = |tokens: &[Token]| -> String {
+            tokens
+                .iter()
+                .map(|token| format!("{} {:?}\n", token.name, token.class))
+                .collect()
+        };
+        match emit {
+            Emit::Tokens => tokens(&self.tokens).into_bytes(),
+            Emit::Symbols => self
+                .symbols
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect::<String>()
+                .into_bytes(),
+            Emit::Polish => tokens(&self.polish).into_bytes(),
+            Emit::Quads => ir::to_text(&self.quads).into_bytes(),
+            Emit::Bytecode | Emit::Asm => self.code.clone(),
+        }
+    }
+}
+
+impl Diagnostics {
+    // Warnings first, then the errors, as the emitter renders them against the source
+    pub fn render(&self, emitter: &Emitter) -> String {
+        emitter.render_all(&self.warnings) + &emitter.render_all(&self.errors)
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(error: Diagnostic) -> Self {
+        Diagnostics {
+            errors: vec![error],
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl From<String> for Diagnostics {
+    fn from(error: String) -> Self {
+        Diagnostics::from(Diagnostic::from(error))
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for diagnostic in self.warnings.iter().chain(&self.errors) {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+// Compile source entirely in memory, nothing is read from or written to the current directory so
+// any number of compilations can run at once
+pub fn compile(source: &str, options: &Options) -> Result<Output, Diagnostics> {
+    let mut output = Output::default();
+    match stages(source, options, &mut output) {
+        Ok(()) => Ok(output),
+        Err(mut diagnostics) => {
+            diagnostics.warnings = output.warnings;
+            Err(diagnostics)
+        }
+    }
+}
+
+// Run the stages up to options.emit, filling in output as each one finishes
+fn stages(source: &str, options: &Options, output: &mut Output) -> Result<(), Diagnostics> {
+    output.tokens = Tokenize::new(source).collect();
+    if let Some(token) = output
+        .tokens
+        .iter()
+        .find(|token| token.class == TokenClass::Unknown)
+    {
+        return Err(lexical::unexpected(token).into());
+    }
+    if options.emit == Emit::Tokens {
+        return Ok(());
+    }
+
+    let mut syn = Syntax::from_source(source);
+    syn.create_symbol_table()?;
+    output.symbols = syn.symbols.clone();
+    if options.emit == Emit::Symbols {
+        return Ok(());
+    }
+
+    syn.complete_analysis()?;
+    let (errors, warnings) = semantic::check(&output.tokens)
+        .into_iter()
+        .partition(|diagnostic| diagnostic.level == Level::Error);
+    output.warnings = warnings;
+    if !errors.is_empty() {
+        return Err(Diagnostics {
+            errors,
+            warnings: Vec::new(),
+        });
+    }
+    output.polish = syn.polish.clone();
+    if options.emit == Emit::Polish {
+        return Ok(());
+    }
+    if options.emit == Emit::Bytecode {
+        Program::from_polish(&output.polish)?
+            .write(&mut output.code)
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    syn.consume_polish()?;
+    output
+        .warnings
+        .extend(semantic::uninitialised(&output.tokens, &syn.quads));
+    let mut dumps = Vec::new();
+    output.quads = options
+        .passes
+        .run(std::mem::take(&mut syn.quads), &mut dumps)?;
+    output.dumps = String::from_utf8(dumps).unwrap();
+    output.symbols = syn.symbols.clone();
+    if options.emit == Emit::Quads {
+        return Ok(());
+    }
+
+    let backend = options.backend();
+    output.executable = backend.executable();
+    let symbols = storage(syn.symbols(), &output.quads);
+    let mut gen = Generator::with_writer(output.quads.clone(), symbols, backend, Vec::new());
+    gen.consume_quads().map_err(|e| e.to_string())?;
+    output.code = gen.into_inner();
+    Ok(())
+}
+
+// The symbol table only has the temps consume_polish made, one a pass made up still needs
+// storage. Every variable was declared, semantic::check stops the compile otherwise.
+fn storage(mut symbols: Vec<Symbol>, quads: &QuadList) -> Vec<Symbol> {
+    for operand in quads
+        .iter()
+        .flat_map(|quad| [&quad.left, &quad.right, &quad.dest])
+        .flatten()
+    {
+        let Operand::Temp(name) = operand else {
+            continue;
+        };
+        if !symbols.iter().any(|sym| &sym.name == name) {
+            symbols.push(Symbol {
+                name: name.clone(),
+                class: SymbolClass::Temp,
+                value: 0,
+            });
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::diagnostics::Color;
+    use crate::compiler::vm::Vm;
+    use std::thread;
+
+    const PROGRAM: &str = "CLASS Pgm1 {\nVAR a, b;\nGET(a);\nb = a + 5 * 2;\nPUT(b);\n}\n";
+
+    fn emit(emit: Emit) -> Options {
+        Options {
+            emit,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_compile_stages() {
+        let tokens = compile(PROGRAM, &emit(Emit::Tokens)).unwrap();
+        assert_eq!(tokens.tokens[0].name, "CLASS");
+        assert!(tokens.symbols.is_empty());
+
+        let symbols = compile(PROGRAM, &emit(Emit::Symbols)).unwrap();
+        assert_eq!(symbols.tokens, tokens.tokens);
+        assert!(symbols.symbols.iter().any(|entry| entry.name == "a"));
+        assert!(symbols.polish.is_empty());
+
+        // b = a + 5 * 2 multiplies before adding, so GET 3 puts 13
+        let polish = compile(PROGRAM, &emit(Emit::Polish)).unwrap();
+        let names: Vec<_> = polish.polish.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["a", "GET", ";", "b", "a", "5", "2", "*", "+", "=", ";", "b", "PUT", ";"]
+        );
+        let bytecode = compile(PROGRAM, &emit(Emit::Bytecode)).unwrap();
+        assert!(bytecode.quads.is_empty());
+        let program = Program::read(&mut bytecode.emitted(Emit::Bytecode).as_slice()).unwrap();
+        let mut output = Vec::new();
+        Vm::new(&program)
+            .run(&mut "3\n".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "13\n");
+
+        let quads = compile(PROGRAM, &emit(Emit::Quads)).unwrap();
+        assert_eq!(
+            String::from_utf8(quads.emitted(Emit::Quads)).unwrap(),
+            "    get $a\n\
+             \x20   mul %temp_1, 5, 2\n\
+             \x20   add %temp_2, $a, %temp_1\n\
+             \x20   copy $b, %temp_2\n\
+             \x20   put $b\n"
+        );
+        assert!(quads.code.is_empty());
+
+        let asm = compile(PROGRAM, &Options::default()).unwrap();
+        assert_eq!(asm.quads, quads.quads);
+        let code = String::from_utf8(asm.code).unwrap();
+        assert!(code.contains("\tmov ax,5\n\tmov bx,2\n\tmul bx\n\tmov [temp_1],ax\n"));
+        assert!(code.contains("\tmov ax,[a]\n\tadd ax,[temp_1]\n\tmov [temp_2],ax\n"));
+    }
+
+    #[test]
+    fn test_compile_temp_names() {
+        // A variable named like a temp keeps its own storage
+        let source = "VAR temp1, b;\ntemp1 = 5;\nb = 2 * 3 + temp1;\nPUT(b);\nPUT(temp1);\n";
+        let quads = compile(source, &emit(Emit::Quads)).unwrap();
+        assert_eq!(
+            ir::to_text(&quads.quads),
+            "    copy $temp1, 5\n\
+             \x20   mul %temp_1, 2, 3\n\
+             \x20   add %temp_2, %temp_1, $temp1\n\
+             \x20   copy $b, %temp_2\n\
+             \x20   put $b\n\
+             \x20   put $temp1\n"
+        );
+
+        let bytecode = compile(source, &emit(Emit::Bytecode)).unwrap();
+        let program = Program::read(&mut bytecode.emitted(Emit::Bytecode).as_slice()).unwrap();
+        let mut output = Vec::new();
+        Vm::new(&program)
+            .run(&mut "".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "11\n5\n");
+
+        let code = String::from_utf8(compile(source, &Options::default()).unwrap().code).unwrap();
+        assert!(code.contains("\tmov ax,[temp_1]\n\tadd ax,[temp1]\n\tmov [temp_2],ax\n"));
+    }
+
+    #[test]
+    fn test_compile_registers() {
+        let source = "CLASS Pgm1 {\nVAR a, b, c;\nGET(a);\nGET(b);\n\
+                      c = (a + b) * (a - b) / 2;\nPUT(c + a);\n}\n";
+        let code = |registers: bool| {
+            let options = Options {
+                target: Target::X86_64,
+                registers,
+                ..Options::default()
+            };
+            String::from_utf8(compile(source, &options).unwrap().code).unwrap()
+        };
+        let instructions = |code: &str| code.lines().filter(|line| line.starts_with('\t')).count();
+
+        // Every variable and temp fits in a register
+        let memory = code(false);
+        let registers = code(true);
+        assert_eq!(instructions(&memory) - instructions(&registers), 3);
+        for name in ["a", "b", "c", "temp_1", "temp_5"] {
+            let access = format!("[rel v_{}]", name);
+            assert!(memory.contains(&access));
+            assert!(!registers.contains(&access), "{} is in memory", name);
+        }
+        // Only the x86-64 targets allocate registers, the rest ignore the option
+        assert_eq!(
+            compile(
+                source,
+                &Options {
+                    registers: true,
+                    ..Options::default()
+                }
+            )
+            .unwrap()
+            .code,
+            compile(source, &Options::default()).unwrap().code
+        );
+    }
+
+    #[test]
+    fn test_compile_peephole() {
+        let source = "CLASS Pgm1 {\nVAR a, b, c;\nGET(a);\nGET(b);\n\
+                      c = (a + b) * (a - b) / 2;\nPUT(c + a);\n}\n";
+        let code = |registers: bool, peephole: bool| {
+            let options = Options {
+                target: Target::X86_64,
+                registers,
+                peephole,
+                ..Options::default()
+            };
+            String::from_utf8(compile(source, &options).unwrap().code).unwrap()
+        };
+        let instructions = |code: &str| code.lines().filter(|line| line.starts_with('\t')).count();
+
+        // temp_3, temp_4 and temp_5 are loaded straight back after they are stored, as is c
+        assert_eq!(instructions(&code(false, false)), 82);
+        assert_eq!(instructions(&code(false, true)), 78);
+        assert_eq!(instructions(&code(true, true)), 76);
+        assert!(!code(false, true).contains("\tmov [rel v_temp_3],eax\n\tmov eax,[rel v_temp_3]\n"));
+    }
+
+    #[test]
+    fn test_compile_isolated() {
+        // Nothing is carried over from an earlier compilation, the table is not appended to
+        let first = compile(PROGRAM, &Options::default()).unwrap();
+        let second = compile(PROGRAM, &Options::default()).unwrap();
+        assert_eq!(first, second);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| thread::spawn(|| compile(PROGRAM, &Options::default())))
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Ok(first.clone()));
+        }
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let error = |source: &str| {
+            let diagnostics = compile(source, &Options::default()).unwrap_err();
+            let emitter = Emitter::new("input.java", source, Color::Never);
+            emitter.render(&diagnostics.errors[0])
+        };
+
+        assert_eq!(
+            error("CLASS Pgm1 { VAR a; a = 1 # 2; }"),
+            "error: unexpected character `#`\n\
+             \x20--> input.java:1:27\n\
+             \x20 |\n\
+             1 | CLASS Pgm1 { VAR a; a = 1 # 2; }\n\
+             \x20 |                           ^ not part of any token\n"
+        );
+        assert_eq!(
+            error("CLASS Pgm1 {\nVAR a;\na = = 1;\n}"),
+            "error: expected an operand, found `=`\n\
+             \x20--> input.java:3:5\n\
+             \x20 |\n\
+             3 | a = = 1;\n\
+             \x20 |     ^ expected an operand\n"
+        );
+        assert_eq!(
+            error("CLASS Pgm1 {\nVAR a;\nGET(a);\n"),
+            "error: this file contains an unclosed delimiter\n\
+             \x20--> input.java:3:8\n\
+             \x20 |\n\
+             1 | CLASS Pgm1 {\n\
+             \x20 |            - opened here\n\
+             2 | VAR a;\n\
+             3 | GET(a);\n\
+             \x20 |        ^ expected `}`\n\
+             \x20 |\n\
+             \x20 = help: close it with `}`\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_warnings() {
+        let output = compile(
+            "CLASS Pgm1 { VAR a, b; GET(a); PUT(a); }",
+            &Options::default(),
+        );
+        let warnings: Vec<_> = output
+            .unwrap()
+            .warnings
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(warnings, ["warning: unused variable: `b`"]);
+
+        let source = "CLASS Pgm1 {\nVAR a, b;\nb = a + 1;\nPUT(b);\n}\n";
+        let output = compile(source, &Options::default()).unwrap();
+        let emitter = Emitter::new("input.java", source, Color::Never);
+        assert_eq!(
+            emitter.render_all(&output.warnings),
+            "warning: `a` may be used before it is given a value\n\
+             \x20--> input.java:3:5\n\
+             \x20 |\n\
+             3 | b = a + 1;\n\
+             \x20 |     ^ read here before any GET or assignment\n\
+             \x20 |\n\
+             \x20 = help: read it with GET or assign it before this: `a = 0;`\n\n"
+        );
+
+        // Warnings found before an error are kept with it
+        let diagnostics =
+            compile("CLASS Pgm1 { VAR a, b, a; GET(c); }", &Options::default()).unwrap_err();
+        assert_eq!(
+            diagnostics.to_string(),
+            "warning: unused variable: `a`\n\
+             warning: unused variable: `b`\n\
+             error: the name `a` is declared more than once\n\
+             error: `c` is used but never declared\n"
+        );
+
+        // An undeclared name stops the compile, pointing at the use
+        let source = "CLASS Pgm1 {\nVAR a;\nGET(a);\nPUT(a + b);\n}\n";
+        let diagnostics = compile(source, &Options::default()).unwrap_err();
+        let emitter = Emitter::new("input.java", source, Color::Never);
+        assert_eq!(
+            emitter.render_all(&diagnostics.errors),
+            "error: `b` is used but never declared\n\
+             \x20--> input.java:4:9\n\
+             \x20 |\n\
+             4 | PUT(a + b);\n\
+             \x20 |         ^ not declared\n\
+             \x20 |\n\
+             \x20 = help: declare it in a VAR list: `VAR b;`\n\n"
+        );
+        assert!(diagnostics.warnings.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
-mod boolean;
-mod compiler;
+mod cli;
+
+use std::env;
+use std::process;
 
 fn main() {
-    println!("HELLO");
+    let args: Vec<String> = env::args().skip(1).collect();
+    process::exit(cli::run(&args));
 }